@@ -0,0 +1,76 @@
+#[macro_use]
+extern crate criterion;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use criterion::{black_box, Criterion, ParameterizedBenchmark};
+use filecoin_proofs::api::bench::{FileSystemKvs, KeyValueStore, MmapKeyValueStore};
+
+const VALUE_BYTES: usize = 256;
+
+fn entries(num_entries: usize) -> BTreeMap<String, Vec<u8>> {
+    (0..num_entries)
+        .map(|i| (format!("sector-{}", i), vec![(i % 256) as u8; VALUE_BYTES]))
+        .collect()
+}
+
+fn populate_mmap(dir: &Path, num_entries: usize) {
+    let db = MmapKeyValueStore::initialize(dir).unwrap();
+
+    for (k, v) in &entries(num_entries) {
+        db.put(k.as_bytes(), v).unwrap();
+    }
+}
+
+// Stores the entries as a single CBOR-encoded map, as is done for sector
+// builder snapshots.
+fn populate_cbor(dir: &Path, num_entries: usize) {
+    let db = FileSystemKvs::initialize(dir).unwrap();
+
+    db.put(
+        b"state",
+        &serde_cbor::to_vec(&entries(num_entries)).unwrap(),
+    )
+    .unwrap();
+}
+
+// The time taken to load a single value from a freshly-opened store.
+fn cold_load_benchmark(c: &mut Criterion) {
+    let params = vec![10, 100, 1000];
+
+    c.bench(
+        "kv-store-cold-load",
+        ParameterizedBenchmark::new(
+            "mmap",
+            |b, num_entries| {
+                let dir = tempfile::tempdir().unwrap();
+                populate_mmap(dir.path(), *num_entries);
+
+                let key = format!("sector-{}", num_entries / 2);
+
+                b.iter(|| {
+                    let db = MmapKeyValueStore::initialize(dir.path()).unwrap();
+                    black_box(db.get(key.as_bytes()).unwrap())
+                })
+            },
+            params,
+        )
+        .with_function("cbor", |b, num_entries| {
+            let dir = tempfile::tempdir().unwrap();
+            populate_cbor(dir.path(), *num_entries);
+
+            let key = format!("sector-{}", num_entries / 2);
+
+            b.iter(|| {
+                let db = FileSystemKvs::initialize(dir.path()).unwrap();
+                let blob = db.get(b"state").unwrap().unwrap();
+                let state: BTreeMap<String, Vec<u8>> = serde_cbor::from_slice(&blob).unwrap();
+                black_box(state[&key].clone())
+            })
+        }),
+    );
+}
+
+criterion_group!(benches, cold_load_benchmark);
+criterion_main!(benches);
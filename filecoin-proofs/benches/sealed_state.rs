@@ -0,0 +1,34 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{black_box, Criterion, ParameterizedBenchmark};
+use filecoin_proofs::api::bench::{load_sealed_state, persist_sealed_state};
+
+// The time taken to load a sector builder state with many sealed sectors,
+// before and after it has been compacted.
+fn load_sealed_state_benchmark(c: &mut Criterion) {
+    let params = vec![100, 1000, 5000];
+
+    c.bench(
+        "sealed-state-load",
+        ParameterizedBenchmark::new(
+            "monolithic",
+            |b, num_sealed_sectors| {
+                let dir = tempfile::tempdir().unwrap();
+                persist_sealed_state(dir.path(), *num_sealed_sectors, false).unwrap();
+
+                b.iter(|| black_box(load_sealed_state(dir.path()).unwrap()))
+            },
+            params,
+        )
+        .with_function("compacted", |b, num_sealed_sectors| {
+            let dir = tempfile::tempdir().unwrap();
+            persist_sealed_state(dir.path(), *num_sealed_sectors, true).unwrap();
+
+            b.iter(|| black_box(load_sealed_state(dir.path()).unwrap()))
+        }),
+    );
+}
+
+criterion_group!(benches, load_sealed_state_benchmark);
+criterion_main!(benches);
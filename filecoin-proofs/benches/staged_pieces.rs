@@ -0,0 +1,45 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{black_box, Criterion, ParameterizedBenchmark};
+use filecoin_proofs::api::bench::{find_piece_at_offset, staged_sector};
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+const PIECE_BYTES: u64 = 127;
+
+fn staged_pieces_benchmark(c: &mut Criterion) {
+    let params = vec![1, 100, 1000, 10_000];
+
+    c.bench(
+        "staged-pieces",
+        ParameterizedBenchmark::new(
+            "find-piece-at-offset",
+            |b, num_pieces| {
+                let sector = staged_sector(*num_pieces, PIECE_BYTES);
+                // a byte in the middle of the last piece
+                let offset = UnpaddedBytesAmount((num_pieces - 1) * PIECE_BYTES + PIECE_BYTES / 2);
+
+                b.iter(|| black_box(find_piece_at_offset(&sector, offset)))
+            },
+            params,
+        )
+        .with_function("linear-scan", |b, num_pieces| {
+            let sector = staged_sector(*num_pieces, PIECE_BYTES);
+            let offset = (num_pieces - 1) * PIECE_BYTES + PIECE_BYTES / 2;
+
+            b.iter(|| {
+                let mut start = 0;
+
+                black_box(sector.pieces.values().find(|piece| {
+                    let end = start + u64::from(piece.num_bytes);
+                    let found = offset < end;
+                    start = end;
+                    found
+                }))
+            })
+        }),
+    );
+}
+
+criterion_group!(benches, staged_pieces_benchmark);
+criterion_main!(benches);
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::fs::{copy, remove_file, File, OpenOptions};
-use std::io::{BufWriter, Read};
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, BufWriter, Read};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -20,6 +20,7 @@ use sector_base::api::porep_config::PoRepConfig;
 use sector_base::api::porep_proof_partitions::PoRepProofPartitions;
 use sector_base::api::post_config::PoStConfig;
 use sector_base::api::post_proof_partitions::PoStProofPartitions;
+use sector_base::api::staged_sector_file::SectorFileHandle;
 use sector_base::api::SINGLE_PARTITION_PROOF_LEN;
 use sector_base::io::fr32::write_unpadded;
 use storage_proofs::circuit::multi_proof::MultiProof;
@@ -487,6 +488,17 @@ fn make_merkle_tree<T: Into<PathBuf> + AsRef<Path>>(
     public_params(bytes, 1).graph.merkle_tree(&data)
 }
 
+// The phases of sealing a sector, in order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SealPhase {
+    // copying the staged bytes into the (to be) replica
+    Encoding,
+    // replicating the sector: encoding each layer and building its tree
+    TreeBuilding,
+    // generating the SNARK which proves the replication
+    SnarkProving,
+}
+
 #[derive(Clone, Debug)]
 pub struct SealOutput {
     pub comm_r: Commitment,
@@ -518,19 +530,98 @@ impl<T: AsRef<Path>> Drop for FileCleanup<T> {
     }
 }
 
+// Seals the body of the staging sector file at in_path, writing the replica to
+// out_path.
 pub fn seal<T: Into<PathBuf> + AsRef<Path>>(
     porep_config: PoRepConfig,
     in_path: T,
     out_path: T,
     prover_id_in: &FrSafe,
     sector_id_in: &FrSafe,
+) -> error::Result<SealOutput> {
+    let staged = open_staged_sector_file(porep_config, in_path)?;
+
+    seal_from_reader(
+        porep_config,
+        staged,
+        out_path,
+        prover_id_in,
+        sector_id_in,
+        None,
+        &prove_seal_on_cpu,
+        &|_| (),
+    )
+}
+
+// The inputs to the SNARK proving a sector's replication, which may be
+// generated elsewhere than on the CPU (see sector_builder::gpu).
+pub struct SealProofInputs<'a> {
+    pub porep_config: PoRepConfig,
+    pub public_params:
+        &'a compound_proof::PublicParams<'a, Bls12, ZigZagDrgPoRep<'a, DefaultTreeHasher>>,
+    pub public_inputs: &'a layered_drgporep::PublicInputs<<DefaultTreeHasher as Hasher>::Domain>,
+    pub private_inputs: &'a layered_drgporep::PrivateInputs<DefaultTreeHasher>,
+}
+
+// Generates the (serialized) proof of a sector's replication on the CPU.
+pub fn prove_seal_on_cpu(inputs: &SealProofInputs) -> error::Result<Vec<u8>> {
+    let groth_params = get_zigzag_params(inputs.porep_config)?;
+
+    info!(FCP_LOG, "got groth params ({}) while sealing", u64::from(PaddedBytesAmount::from(inputs.porep_config)); "target" => "params");
+
+    let proof = ZigZagCompound::prove(
+        inputs.public_params,
+        inputs.public_inputs,
+        inputs.private_inputs,
+        &groth_params,
+    )?;
+
+    let mut buf = Vec::with_capacity(
+        SINGLE_PARTITION_PROOF_LEN * usize::from(PoRepProofPartitions::from(inputs.porep_config)),
+    );
+
+    proof.write(&mut buf)?;
+
+    Ok(buf)
+}
+
+// Opens the staging sector file at in_path for sealing, checking that its
+// header is intact and that it was staged for sectors of the configured size.
+pub fn open_staged_sector_file<T: AsRef<Path>>(
+    porep_config: PoRepConfig,
+    in_path: T,
+) -> error::Result<SectorFileHandle> {
+    let sector_bytes = u64::from(PaddedBytesAmount::from(porep_config));
+
+    Ok(SectorFileHandle::open_and_verify(
+        in_path.as_ref(),
+        sector_bytes,
+        false,
+    )?)
+}
+
+// Like seal, but seals the unsealed bytes read from the provided reader (e.g.
+// the body of a staging sector file), generating its proof with prove_seal.
+// on_phase is called as replication and proving begin. If the sector's commD
+// is already known (e.g. it was computed as pieces were staged), sealing
+// fails before the (expensive) proof is generated if the replicated bytes
+// produce another.
+pub fn seal_from_reader<R: Read, T: Into<PathBuf> + AsRef<Path>>(
+    porep_config: PoRepConfig,
+    mut staged: R,
+    out_path: T,
+    prover_id_in: &FrSafe,
+    sector_id_in: &FrSafe,
+    expected_comm_d: Option<Commitment>,
+    prove_seal: &Fn(&SealProofInputs) -> error::Result<Vec<u8>>,
+    on_phase: &Fn(SealPhase),
 ) -> error::Result<SealOutput> {
     let sector_bytes = u64::from(PaddedBytesAmount::from(porep_config));
 
     let mut cleanup = FileCleanup::new(&out_path);
 
     // Copy unsealed data to output location, where it will be sealed in place.
-    copy(&in_path, &out_path)?;
+    io::copy(&mut staged, &mut File::create(&out_path)?)?;
     let f_data = OpenOptions::new().read(true).write(true).open(&out_path)?;
 
     // Zero-pad the data to the requested size by extending the underlying file if needed.
@@ -555,6 +646,8 @@ pub fn seal<T: Into<PathBuf> + AsRef<Path>>(
 
     let compound_public_params = ZigZagCompound::setup(&compound_setup_params)?;
 
+    on_phase(SealPhase::TreeBuilding);
+
     let (tau, aux) = ZigZagDrgPoRep::replicate(
         &compound_public_params.vanilla_params,
         &replica_id,
@@ -568,6 +661,20 @@ pub fn seal<T: Into<PathBuf> + AsRef<Path>>(
 
     let public_tau = tau.simplify();
 
+    // Replication builds the data tree along with the others, as proving opens
+    // its leaves, so its root comes for free.
+    let comm_d = commitment_from_fr::<Bls12>(public_tau.comm_d.into());
+
+    if let Some(expected_comm_d) = expected_comm_d {
+        if expected_comm_d != comm_d {
+            return Err(format_err!(
+                "replicated commD {:?} differs from expected commD {:?}",
+                comm_d,
+                expected_comm_d
+            ));
+        }
+    }
+
     let public_inputs = layered_drgporep::PublicInputs {
         replica_id,
         tau: Some(public_tau),
@@ -580,25 +687,16 @@ pub fn seal<T: Into<PathBuf> + AsRef<Path>>(
         tau: tau.layer_taus,
     };
 
-    let groth_params = get_zigzag_params(porep_config)?;
+    on_phase(SealPhase::SnarkProving);
 
-    info!(FCP_LOG, "got groth params ({}) while sealing", u64::from(PaddedBytesAmount::from(porep_config)); "target" => "params");
-
-    let proof = ZigZagCompound::prove(
-        &compound_public_params,
-        &public_inputs,
-        &private_inputs,
-        &groth_params,
-    )?;
-
-    let mut buf = Vec::with_capacity(
-        SINGLE_PARTITION_PROOF_LEN * usize::from(PoRepProofPartitions::from(porep_config)),
-    );
-
-    proof.write(&mut buf)?;
+    let buf = prove_seal(&SealProofInputs {
+        porep_config,
+        public_params: &compound_public_params,
+        public_inputs: &public_inputs,
+        private_inputs: &private_inputs,
+    })?;
 
     let comm_r = commitment_from_fr::<Bls12>(public_tau.comm_r.into());
-    let comm_d = commitment_from_fr::<Bls12>(public_tau.comm_d.into());
     let comm_r_star = commitment_from_fr::<Bls12>(tau.comm_r_star.into());
 
     // Verification is cheap when parameters are cached,
@@ -631,15 +729,46 @@ pub fn get_unsealed_range<T: Into<PathBuf> + AsRef<Path>>(
     offset: u64,
     num_bytes: UnpaddedBytesAmount,
 ) -> error::Result<(UnpaddedBytesAmount)> {
-    let prover_id = pad_safe_fr(prover_id_in);
-    let sector_id = pad_safe_fr(sector_id_in);
-    let replica_id = replica_id::<DefaultTreeHasher>(prover_id, sector_id);
+    let data = read_sealed_replica(porep_config, File::open(sealed_path)?)?;
 
-    let f_in = File::open(sealed_path)?;
+    get_unsealed_range_from_replica(
+        porep_config,
+        &data,
+        output_path,
+        prover_id_in,
+        sector_id_in,
+        offset,
+        num_bytes,
+    )
+}
+
+// Reads the replica of a sealed sector into memory, for unsealing.
+pub fn read_sealed_replica<R: Read>(
+    porep_config: PoRepConfig,
+    replica: R,
+) -> error::Result<Vec<u8>> {
     let mut data = Vec::new();
-    f_in.take(u64::from(PaddedBytesAmount::from(porep_config)))
+    replica
+        .take(u64::from(PaddedBytesAmount::from(porep_config)))
         .read_to_end(&mut data)?;
 
+    Ok(data)
+}
+
+// Like get_unsealed_range, but unseals a replica already read into memory.
+pub fn get_unsealed_range_from_replica<T: Into<PathBuf> + AsRef<Path>>(
+    porep_config: PoRepConfig,
+    data: &[u8],
+    output_path: T,
+    prover_id_in: &FrSafe,
+    sector_id_in: &FrSafe,
+    offset: u64,
+    num_bytes: UnpaddedBytesAmount,
+) -> error::Result<(UnpaddedBytesAmount)> {
+    let prover_id = pad_safe_fr(prover_id_in);
+    let sector_id = pad_safe_fr(sector_id_in);
+    let replica_id = replica_id::<DefaultTreeHasher>(prover_id, sector_id);
+
     let f_out = File::create(output_path)?;
     let mut buf_writer = BufWriter::new(f_out);
 
@@ -649,7 +778,7 @@ pub fn get_unsealed_range<T: Into<PathBuf> + AsRef<Path>>(
             usize::from(PoRepProofPartitions::from(porep_config)),
         ),
         &replica_id,
-        &data,
+        data,
     )?;
 
     let written = write_unpadded(
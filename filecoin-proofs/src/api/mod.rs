@@ -10,7 +10,6 @@ use crate::api::responses::FCPResponseStatus;
 use crate::api::responses::FFIPieceMetadata;
 use crate::api::responses::FFISealStatus;
 use crate::api::sector_builder::metadata::SealStatus;
-use crate::api::sector_builder::SectorBuilder;
 use crate::FCP_LOG;
 use ffi_toolkit::rust_str_to_c_str;
 use ffi_toolkit::{c_str_to_rust_str, raw_ptr};
@@ -31,6 +30,12 @@ pub mod post_adapter;
 pub mod responses;
 mod sector_builder;
 
+#[doc(hidden)]
+pub use self::sector_builder::bench;
+
+#[doc(hidden)]
+pub use self::sector_builder::{Active, Initialized, SectorBuilder, Uninitialized};
+
 /// Verifies the output of seal.
 ///
 #[no_mangle]
@@ -215,6 +220,7 @@ pub unsafe extern "C" fn init_sector_builder(
             c_str_to_rust_str(sealed_sector_dir).to_string(),
             c_str_to_rust_str(staged_sector_dir).to_string(),
             max_num_staged_sectors,
+            Default::default(),
         )
     });
 
@@ -472,7 +478,7 @@ pub unsafe extern "C" fn get_staged_sectors(
                 .map(|meta| {
                     let pieces = meta
                         .pieces
-                        .iter()
+                        .values()
                         .map(|p| FFIPieceMetadata {
                             piece_key: rust_str_to_c_str(p.piece_key.to_string()),
                             num_bytes: p.num_bytes.into(),
@@ -132,6 +132,13 @@ pub fn err_code_and_msg(err: &Error) -> (FCPResponseStatus, *const libc::c_char)
         Some(SectorBuilderErr::IncompleteWriteError { .. }) => return (FCPReceiverError, ptr),
         Some(SectorBuilderErr::Unrecoverable(_, _)) => return (FCPReceiverError, ptr),
         Some(SectorBuilderErr::PieceNotFound(_)) => return (FCPCallerError, ptr),
+        Some(SectorBuilderErr::InvalidSectorAccess { .. }) => return (FCPReceiverError, ptr),
+        Some(SectorBuilderErr::PostGenerationTimeout(_)) => return (FCPReceiverError, ptr),
+        Some(SectorBuilderErr::SealAlreadyInProgress(_)) => return (FCPCallerError, ptr),
+        Some(SectorBuilderErr::SectorTooEmpty { .. }) => return (FCPCallerError, ptr),
+        Some(SectorBuilderErr::AccessDenied(_)) => return (FCPCallerError, ptr),
+        Some(SectorBuilderErr::PieceRedacted(_)) => return (FCPCallerError, ptr),
+        Some(SectorBuilderErr::BackPressure { .. }) => return (FCPReceiverError, ptr),
         None => (),
     }
 
@@ -139,6 +146,8 @@ pub fn err_code_and_msg(err: &Error) -> (FCPResponseStatus, *const libc::c_char)
         Some(SectorManagerErr::UnclassifiedError(_)) => return (FCPUnclassifiedError, ptr),
         Some(SectorManagerErr::CallerError(_)) => return (FCPCallerError, ptr),
         Some(SectorManagerErr::ReceiverError(_)) => return (FCPReceiverError, ptr),
+        Some(SectorManagerErr::UnknownSectorFileVersion { .. }) => return (FCPCallerError, ptr),
+        Some(SectorManagerErr::CorruptedSectorHeader { .. }) => return (FCPReceiverError, ptr),
         None => (),
     }
 
@@ -0,0 +1,251 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::retrieve_piece::retrieve_piece;
+use crate::api::sector_builder::io_scheduler::IoScheduler;
+use crate::api::sector_builder::metadata::{ArchiveReceipt, SealedSectorMetadata};
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error::Result;
+use sector_base::io::checksum::{ChecksummingWriter, TeeReader};
+
+// The most bytes requested at once from a backend which supports range
+// requests.
+const DOWNLOAD_CHUNK_BYTES: u64 = 1 << 20;
+
+// Cold storage for the replicas of sealed sectors (e.g. an object store).
+pub trait ArchiveBackend: Send + Sync {
+    // Stores everything read from source, returning the URL from which it can
+    // later be downloaded.
+    fn upload(&self, sector_id: SectorId, source: &mut Read) -> Result<String>;
+
+    // Writes everything stored at the URL to sink.
+    fn download(&self, url: &str, sink: &mut Write) -> Result<()>;
+
+    // Returns (up to) num_bytes of what's stored at the URL, starting at
+    // offset, or None if the backend doesn't support range requests.
+    fn download_range(&self, _url: &str, _offset: u64, _num_bytes: u64) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+// Uploads the replica of a sealed sector, returning a receipt with which it
+// can later be retrieved.
+pub fn archive_sealed_sector(
+    sector_store: &Arc<WrappedSectorStore>,
+    sealed_sector: &SealedSectorMetadata,
+    archive_backend: &ArchiveBackend,
+) -> Result<ArchiveReceipt> {
+    let sector_bytes = u64::from(sector_store.inner.sector_config().sector_bytes());
+
+    let mut replica = File::open(&sealed_sector.sector_access)?.take(sector_bytes);
+
+    let mut tee = TeeReader {
+        source: &mut replica,
+        sink: ChecksummingWriter::new(io::sink()),
+    };
+
+    let url = archive_backend.upload(sealed_sector.sector_id, &mut tee)?;
+    let (_, content_hash) = tee.sink.finalize();
+
+    Ok(ArchiveReceipt { url, content_hash })
+}
+
+// Downloads an archived replica to the provided path, checking it against the
+// receipt's content hash. Only the bytes of the replica proper are requested
+// from backends which support range requests.
+pub fn download_archived_replica(
+    archive_backend: &ArchiveBackend,
+    receipt: &ArchiveReceipt,
+    sector_bytes: u64,
+    path: &Path,
+) -> Result<()> {
+    let mut writer = ChecksummingWriter::new(File::create(path)?);
+
+    let mut offset = 0;
+    let supports_ranges = loop {
+        let num_bytes = min(DOWNLOAD_CHUNK_BYTES, sector_bytes.saturating_sub(offset));
+        if num_bytes == 0 {
+            break true;
+        }
+
+        match archive_backend.download_range(&receipt.url, offset, num_bytes)? {
+            None if offset == 0 => break false,
+            None => return Err(format_err!("archive backend stopped serving ranges")),
+            Some(chunk) => {
+                writer.write_all(&chunk)?;
+                offset += chunk.len() as u64;
+
+                if (chunk.len() as u64) < num_bytes {
+                    break true;
+                }
+            }
+        }
+    };
+
+    if !supports_ranges {
+        archive_backend.download(&receipt.url, &mut writer)?;
+    }
+
+    writer.flush()?;
+    let (_, content_hash) = writer.finalize();
+
+    if content_hash != receipt.content_hash {
+        return Err(err_unrecov(format!(
+            "archived replica at {} does not match its content hash",
+            receipt.url
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+// Unseals and returns the bytes of a piece from the archived replica of the
+// sealed sector containing it. A piece can only be unsealed from the whole of
+// its replica, so that's what is downloaded (to a temporary sealed sector
+// access, which is removed afterwards).
+pub fn retrieve_archived_piece(
+    sector_store: &Arc<WrappedSectorStore>,
+    sealed_sector: &SealedSectorMetadata,
+    prover_id: &[u8; 31],
+    piece_key: &str,
+    archive_backend: &ArchiveBackend,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+) -> Result<Vec<u8>> {
+    let receipt = sealed_sector.archive_receipt.as_ref().ok_or_else(|| {
+        err_unrecov(format!(
+            "sector {} has not been archived",
+            sealed_sector.sector_id
+        ))
+    })?;
+
+    let mgr = sector_store.inner.manager();
+    let sector_bytes = u64::from(sector_store.inner.sector_config().sector_bytes());
+    let access = mgr.new_sealed_sector_access()?;
+
+    let result =
+        download_archived_replica(archive_backend, receipt, sector_bytes, Path::new(&access))
+            .and_then(|_| {
+                let downloaded = SealedSectorMetadata {
+                    sector_access: access.clone(),
+                    ..sealed_sector.clone()
+                };
+
+                retrieve_piece(
+                    sector_store,
+                    &downloaded,
+                    prover_id,
+                    piece_key,
+                    io_scheduler,
+                    sector_locks,
+                )
+            });
+
+    mgr.delete_sealed_sector_access(&access)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils::{mock_sector_store, MockArchiveBackend};
+
+    fn setup(dir: &Path) -> (Arc<WrappedSectorStore>, SealedSectorMetadata, Vec<u8>) {
+        let (sector_store, _) = mock_sector_store();
+
+        // the replica, followed by bytes which aren't part of it
+        let replica: Vec<u8> = (0..1024).map(|x| (x * 7) as u8).collect();
+        let path = dir.join("sealed");
+        std::fs::write(&path, [&replica[..], &[0xFF; 100][..]].concat()).unwrap();
+
+        let sealed_sector = SealedSectorMetadata {
+            sector_id: 3,
+            sector_access: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+
+        (sector_store, sealed_sector, replica)
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        for &supports_ranges in &[false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let (sector_store, sealed_sector, replica) = setup(dir.path());
+            let backend = MockArchiveBackend::new(supports_ranges);
+
+            let receipt = archive_sealed_sector(&sector_store, &sealed_sector, &backend).unwrap();
+            assert_eq!(Some(replica.clone()), backend.object(&receipt.url));
+
+            let downloaded = dir.path().join("downloaded");
+            download_archived_replica(&backend, &receipt, 1024, &downloaded).unwrap();
+
+            assert_eq!(replica, std::fs::read(&downloaded).unwrap());
+            assert_eq!(1024, backend.num_bytes_served());
+        }
+    }
+
+    #[test]
+    fn test_range_requests_fetch_only_replica() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = MockArchiveBackend::new(true);
+
+        // an archive which holds more than the replica
+        let mut padded = (0..1024).map(|x| x as u8).collect::<Vec<u8>>();
+        let mut hasher = ChecksummingWriter::new(io::sink());
+        hasher.write_all(&padded).unwrap();
+        let (_, content_hash) = hasher.finalize();
+        padded.extend_from_slice(&[0; 4096]);
+
+        let url = backend.upload(1, &mut &padded[..]).unwrap();
+        let receipt = ArchiveReceipt { url, content_hash };
+
+        download_archived_replica(&backend, &receipt, 1024, &dir.path().join("replica")).unwrap();
+        assert_eq!(1024, backend.num_bytes_served());
+    }
+
+    #[test]
+    fn test_detects_corrupt_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sector_store, sealed_sector, _) = setup(dir.path());
+        let backend = MockArchiveBackend::new(false);
+
+        let receipt = archive_sealed_sector(&sector_store, &sealed_sector, &backend).unwrap();
+        backend
+            .objects
+            .lock()
+            .unwrap()
+            .get_mut(&receipt.url)
+            .unwrap()[17] ^= 1;
+
+        assert!(
+            download_archived_replica(&backend, &receipt, 1024, &dir.path().join("replica"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_requires_archived_sector() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sector_store, sealed_sector, _) = setup(dir.path());
+        let backend = MockArchiveBackend::new(false);
+
+        assert!(retrieve_archived_piece(
+            &sector_store,
+            &sealed_sector,
+            &[0; 31],
+            "a",
+            &backend,
+            &Default::default(),
+            &Default::default()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::incremental_merkle_tree::{
+    domain_to_bytes, hash_node, walk_merkle_tree, TreeDomain, NODE_SIZE,
+};
+use crate::api::sector_builder::merkle_snapshot::{merkle_snapshot_path, MerkleSnapshot};
+use crate::api::sector_builder::metadata::SealedSectorMetadata;
+use crate::api::sector_builder::{SectorBuilder, SectorId, WrappedSectorStore};
+use crate::error::Result;
+use blake2b_simd::State as Blake2b;
+use byteorder::{ByteOrder, LittleEndian};
+use storage_proofs::drgraph::DefaultTreeHasher;
+use storage_proofs::hasher::Domain;
+
+// Asks for the proof that one node (leaf) of a sealed sector's replica is
+// stored. The leaf is determined by the sector's commR and the challenge
+// index, so an auditor can check that the challenge wasn't chosen by the
+// prover, and needn't send anything but the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditChallenge {
+    pub sector_id: SectorId,
+    pub comm_r: [u8; 32],
+    pub challenge_index: u64,
+    pub num_leaves: u64,
+    pub leaf_index: u64,
+}
+
+// The challenged leaf and its Merkle inclusion proof: the sibling of each
+// node on the path from the leaf to the root (commR), leaf-most first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditResponse {
+    pub leaf: [u8; 32],
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl AuditChallenge {
+    pub fn new(
+        sealed_sector: &SealedSectorMetadata,
+        num_leaves: u64,
+        challenge_index: u64,
+    ) -> Self {
+        AuditChallenge {
+            sector_id: sealed_sector.sector_id,
+            comm_r: sealed_sector.comm_r,
+            challenge_index,
+            num_leaves,
+            leaf_index: derive_leaf_index(
+                &sealed_sector.comm_r,
+                sealed_sector.sector_id,
+                challenge_index,
+                num_leaves,
+            ),
+        }
+    }
+}
+
+fn derive_leaf_index(
+    comm_r: &[u8; 32],
+    sector_id: SectorId,
+    challenge_index: u64,
+    num_leaves: u64,
+) -> u64 {
+    let mut buf = [0; 16];
+    LittleEndian::write_u64(&mut buf[..8], sector_id);
+    LittleEndian::write_u64(&mut buf[8..], challenge_index);
+
+    let hash = Blake2b::new()
+        .update(b"filecoin-proofs audit challenge")
+        .update(comm_r)
+        .update(&buf)
+        .finalize();
+
+    LittleEndian::read_u64(&hash.as_bytes()[..8]) % num_leaves.max(1)
+}
+
+// Produces the challenge_index-th audit challenge for the sealed sector.
+pub fn generate_audit_challenge(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+    challenge_index: u64,
+) -> Result<AuditChallenge> {
+    let sealed_sector = find_sealed_sector(sector_builder, sector_id)?;
+
+    Ok(AuditChallenge::new(
+        &sealed_sector,
+        num_leaves(&sector_builder.state.sector_store),
+        challenge_index,
+    ))
+}
+
+// Answers the challenge from the sealed sector's Merkle tree snapshot, if it
+// has one (see merkle_snapshot::MerkleSnapshot), or else from its replica.
+// The replica's tree is rebuilt to do so, though only its right edge is held
+// in memory.
+pub fn respond_to_audit_challenge(
+    sector_builder: &SectorBuilder,
+    challenge: &AuditChallenge,
+) -> Result<AuditResponse> {
+    let sealed_sector = find_sealed_sector(sector_builder, challenge.sector_id)?;
+    let num_leaves = num_leaves(&sector_builder.state.sector_store);
+
+    if *challenge != AuditChallenge::new(&sealed_sector, num_leaves, challenge.challenge_index) {
+        return Err(err_unrecov(format!(
+            "challenge does not match sealed sector {}",
+            challenge.sector_id
+        ))
+        .into());
+    }
+
+    if sealed_sector.has_merkle_snapshot {
+        return MerkleSnapshot::open(merkle_snapshot_path(&sealed_sector))?
+            .prove(challenge.leaf_index);
+    }
+
+    let replica = BufReader::new(File::open(&sealed_sector.sector_access)?);
+
+    prove_leaf(replica, num_leaves, challenge.leaf_index)
+}
+
+// Checks that the response proves the challenged leaf is part of the replica
+// committed to by the challenge's commR.
+pub fn verify_audit_response(challenge: &AuditChallenge, response: &AuditResponse) -> Result<bool> {
+    if !challenge.num_leaves.is_power_of_two() {
+        return Err(err_unrecov(format!(
+            "challenge has {} leaves, which is not a power of two",
+            challenge.num_leaves
+        ))
+        .into());
+    }
+
+    // The leaf must be the one which the challenge's parameters select.
+    let expected_leaf_index = derive_leaf_index(
+        &challenge.comm_r,
+        challenge.sector_id,
+        challenge.challenge_index,
+        challenge.num_leaves,
+    );
+
+    let height = challenge.num_leaves.trailing_zeros() as usize;
+
+    if challenge.leaf_index != expected_leaf_index || response.siblings.len() != height {
+        return Ok(false);
+    }
+
+    let mut node = TreeDomain::try_from_bytes(&response.leaf)?;
+    let mut index = challenge.leaf_index;
+
+    for (h, sibling) in response.siblings.iter().enumerate() {
+        let sibling = TreeDomain::try_from_bytes(sibling)?;
+
+        node = if index & 1 == 0 {
+            hash_node::<DefaultTreeHasher>(node, sibling, h)
+        } else {
+            hash_node::<DefaultTreeHasher>(sibling, node, h)
+        };
+
+        index >>= 1;
+    }
+
+    Ok(domain_to_bytes(&node) == challenge.comm_r)
+}
+
+pub(crate) fn find_sealed_sector(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+) -> Result<SealedSectorMetadata> {
+    sector_builder
+        .get_sealed_sectors()?
+        .into_iter()
+        .find(|sector| sector.sector_id == sector_id)
+        .ok_or_else(|| err_unrecov(format!("no sealed sector with id {}", sector_id)).into())
+}
+
+pub(crate) fn num_leaves(sector_store: &WrappedSectorStore) -> u64 {
+    u64::from(sector_store.inner.sector_config().sector_bytes()) / NODE_SIZE as u64
+}
+
+// Builds the tree of the first num_leaves nodes read from the replica, keeping
+// the leaf and the sibling of the leaf's ancestor at each height as they're
+// completed.
+fn prove_leaf<R: Read>(replica: R, num_leaves: u64, leaf_index: u64) -> Result<AuditResponse> {
+    if leaf_index >= num_leaves {
+        return Err(err_unrecov(format!(
+            "leaf {} is not one of the tree's {} leaves",
+            leaf_index, num_leaves
+        ))
+        .into());
+    }
+
+    let mut leaf = [0; 32];
+    let mut siblings = vec![[0; 32]; num_leaves.trailing_zeros() as usize];
+
+    walk_merkle_tree(replica, num_leaves, &mut |height, index, subtree| {
+        if height == 0 && index == leaf_index {
+            leaf = domain_to_bytes(subtree);
+        }
+        if height < siblings.len() && index == (leaf_index >> height) ^ 1 {
+            siblings[height] = domain_to_bytes(subtree);
+        }
+    })?;
+
+    Ok(AuditResponse { leaf, siblings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils;
+
+    const NUM_LEAVES: u64 = 64;
+
+    // A replica, and the sealed sector whose commR it's the replica of.
+    fn replica() -> (Vec<u8>, SealedSectorMetadata) {
+        let (bytes, comm_r) = test_utils::replica(NUM_LEAVES);
+
+        let sealed_sector = SealedSectorMetadata {
+            sector_id: 9,
+            comm_r,
+            ..Default::default()
+        };
+
+        (bytes, sealed_sector)
+    }
+
+    #[test]
+    fn test_honest_response_verifies() {
+        let (bytes, sealed_sector) = replica();
+
+        for challenge_index in 0..8 {
+            let challenge = AuditChallenge::new(&sealed_sector, NUM_LEAVES, challenge_index);
+            let response = prove_leaf(&bytes[..], NUM_LEAVES, challenge.leaf_index).unwrap();
+
+            assert_eq!(6, response.siblings.len());
+            assert!(verify_audit_response(&challenge, &response).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_challenges_are_reproducible() {
+        let (_, sealed_sector) = replica();
+
+        let leaves: Vec<u64> = (0..16)
+            .map(|i| AuditChallenge::new(&sealed_sector, NUM_LEAVES, i).leaf_index)
+            .collect();
+
+        assert!(leaves.iter().all(|leaf| *leaf < NUM_LEAVES));
+        assert!(leaves.iter().any(|leaf| *leaf != leaves[0]));
+        assert_eq!(
+            leaves[3],
+            AuditChallenge::new(&sealed_sector, NUM_LEAVES, 3).leaf_index
+        );
+    }
+
+    #[test]
+    fn test_rejects_dishonest_responses() {
+        let (bytes, sealed_sector) = replica();
+        let challenge = AuditChallenge::new(&sealed_sector, NUM_LEAVES, 0);
+
+        // a tampered leaf
+        let mut response = prove_leaf(&bytes[..], NUM_LEAVES, challenge.leaf_index).unwrap();
+        response.leaf[0] ^= 1;
+        assert!(!verify_audit_response(&challenge, &response).unwrap());
+
+        // a proof of a leaf other than the challenged one
+        let other_leaf = (challenge.leaf_index + 1) % NUM_LEAVES;
+        let response = prove_leaf(&bytes[..], NUM_LEAVES, other_leaf).unwrap();
+        assert!(!verify_audit_response(&challenge, &response).unwrap());
+
+        let forged = AuditChallenge {
+            leaf_index: other_leaf,
+            ..challenge.clone()
+        };
+        assert!(!verify_audit_response(&forged, &response).unwrap());
+
+        // a truncated path
+        let mut response = prove_leaf(&bytes[..], NUM_LEAVES, challenge.leaf_index).unwrap();
+        response.siblings.pop();
+        assert!(!verify_audit_response(&challenge, &response).unwrap());
+    }
+}
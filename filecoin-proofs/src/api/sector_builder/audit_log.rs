@@ -0,0 +1,436 @@
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use blake2b_simd::State as Blake2b;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Operations on sectors which an operator may later need to account for.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum SectorEvent {
+    // A sector was deleted even though it had an active deal.
+    ForceDeleted { sector_id: SectorId },
+}
+
+// A sector event as recorded in the audit log. Each entry's hash covers the
+// hash of the entry before it, so that an entry can't be altered, removed or
+// inserted without breaking the chain (see verify_audit_log_integrity).
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuditLogEntry {
+    pub sequence: u64,
+    pub timestamp: SystemTime,
+    pub event: SectorEvent,
+    pub prev_hash: [u8; 32],
+    pub this_hash: [u8; 32],
+}
+
+impl AuditLogEntry {
+    fn new(
+        sequence: u64,
+        timestamp: SystemTime,
+        event: SectorEvent,
+        prev_hash: [u8; 32],
+    ) -> Result<AuditLogEntry> {
+        let this_hash = entry_hash(sequence, timestamp, &event, &prev_hash)?;
+
+        Ok(AuditLogEntry {
+            sequence,
+            timestamp,
+            event,
+            prev_hash,
+            this_hash,
+        })
+    }
+}
+
+// The outcome of checking an audit log's chain of hashes. Entries are
+// numbered from zero, in the order they appear in the log, and the first
+// tampered entry is the first at which the chain is broken: the first whose
+// hash is wrong, or which doesn't follow on from the entry before it. Entries
+// removed from the end of the log can't be detected this way, so the hash of
+// the last entry may be kept elsewhere to check it against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditLogVerificationReport {
+    pub num_entries: u64,
+    pub last_hash: Option<[u8; 32]>,
+    pub first_tampered_entry: Option<u64>,
+}
+
+// The sequence number and hash which the first entry of a log follows on
+// from.
+const GENESIS: (u64, [u8; 32]) = (0, [0; 32]);
+
+// An append-only log of sector events, stored as one JSON document (an
+// AuditLogEntry) per line.
+//
+// Logs written before entries were chained hold bare SectorEvents instead.
+// Such legacy lines, like any other line which isn't an entry, still take up
+// a sequence number, and are folded into the chain by hashing them onto the
+// hash before them (see legacy_line_hash), so that entries appended after
+// them carry on from the genesis as if they had been entries.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    // The sequence number and hash which the next entry follows on from, read
+    // from the log on the first append.
+    tail: Option<(u64, [u8; 32])>,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> AuditLog {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+            tail: None,
+        }
+    }
+
+    pub fn append(&mut self, event: &SectorEvent) -> Result<()> {
+        let (sequence, prev_hash) = match self.tail {
+            Some(tail) => tail,
+            None => chain_tail(&read_lines(&self.path)?),
+        };
+
+        let entry = AuditLogEntry::new(sequence, SystemTime::now(), event.clone(), prev_hash)?;
+
+        // If the entry can't be written, whatever made it to disk is read
+        // back on the next append.
+        self.tail = None;
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        self.tail = Some((entry.sequence + 1, entry.this_hash));
+
+        Ok(())
+    }
+
+    // The events recorded in the log, including those of legacy lines.
+    pub fn read_events(&self) -> Result<Vec<SectorEvent>> {
+        Ok(read_lines(&self.path)?
+            .iter()
+            .filter_map(|line| match serde_json::from_str::<AuditLogEntry>(line) {
+                Ok(entry) => Some(entry.event),
+                Err(_) => serde_json::from_str(line).ok(),
+            })
+            .collect())
+    }
+
+    // The entries in the log, skipping any lines which aren't entries.
+    pub fn read_entries(&self) -> Result<Vec<AuditLogEntry>> {
+        Ok(read_lines(&self.path)?
+            .iter()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+// Recomputes the hash of each entry in the audit log at log_path, checking
+// that the entries form an unbroken chain. Legacy lines (see AuditLog) are
+// accepted only before the first entry.
+pub fn verify_audit_log_integrity(log_path: &Path) -> Result<AuditLogVerificationReport> {
+    let lines = read_lines(log_path)?;
+
+    let mut prev_hash = GENESIS.1;
+    let mut seen_entry = false;
+    let mut first_tampered_entry = None;
+
+    for (n, line) in lines.iter().enumerate() {
+        let n = n as u64;
+
+        // Any other line which can't be parsed has been tampered with too.
+        let intact = match serde_json::from_str::<AuditLogEntry>(line) {
+            Ok(entry) => {
+                seen_entry = true;
+
+                let intact = entry.sequence == n
+                    && entry.prev_hash == prev_hash
+                    && entry_hash(entry.sequence, entry.timestamp, &entry.event, &prev_hash).ok()
+                        == Some(entry.this_hash);
+
+                prev_hash = entry.this_hash;
+                intact
+            }
+            Err(_) => {
+                let legacy = !seen_entry && serde_json::from_str::<SectorEvent>(line).is_ok();
+
+                prev_hash = legacy_line_hash(&prev_hash, line);
+                legacy
+            }
+        };
+
+        if !intact {
+            first_tampered_entry = Some(n);
+            break;
+        }
+    }
+
+    Ok(AuditLogVerificationReport {
+        num_entries: lines.len() as u64,
+        last_hash: if lines.is_empty() || first_tampered_entry.is_some() {
+            None
+        } else {
+            Some(prev_hash)
+        },
+        first_tampered_entry,
+    })
+}
+
+// Folds the lines of a log into the sequence number and hash which the next
+// entry follows on from. The chain is continued however broken it is: an
+// entry is followed on from as it stands, and any other line is treated as a
+// legacy line.
+fn chain_tail(lines: &[String]) -> (u64, [u8; 32]) {
+    lines.iter().fold(
+        GENESIS,
+        |(sequence, prev_hash), line| match serde_json::from_str::<AuditLogEntry>(line) {
+            Ok(entry) => (entry.sequence + 1, entry.this_hash),
+            Err(_) => (sequence + 1, legacy_line_hash(&prev_hash, line)),
+        },
+    )
+}
+
+fn legacy_line_hash(prev_hash: &[u8; 32], line: &str) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(
+        &Blake2b::new()
+            .update(b"filecoin-proofs audit log legacy line")
+            .update(prev_hash)
+            .update(line.as_bytes())
+            .finalize()
+            .as_bytes()[..32],
+    );
+
+    hash
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut lines = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        lines.push(line?);
+    }
+
+    Ok(lines)
+}
+
+fn entry_hash(
+    sequence: u64,
+    timestamp: SystemTime,
+    event: &SectorEvent,
+    prev_hash: &[u8; 32],
+) -> Result<[u8; 32]> {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH)?;
+
+    let mut buf = [0; 20];
+    LittleEndian::write_u64(&mut buf[..8], sequence);
+    LittleEndian::write_u64(&mut buf[8..16], since_epoch.as_secs());
+    LittleEndian::write_u32(&mut buf[16..], since_epoch.subsec_nanos());
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(
+        &Blake2b::new()
+            .update(prev_hash)
+            .update(&buf)
+            .update(&serde_json::to_vec(event)?)
+            .finalize()
+            .as_bytes()[..32],
+    );
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = AuditLog::new(dir.path().join("audit.log"));
+
+        assert!(log.read_events().unwrap().is_empty());
+
+        log.append(&SectorEvent::ForceDeleted { sector_id: 1 })
+            .unwrap();
+        log.append(&SectorEvent::ForceDeleted { sector_id: 2 })
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                SectorEvent::ForceDeleted { sector_id: 1 },
+                SectorEvent::ForceDeleted { sector_id: 2 },
+            ],
+            log.read_events().unwrap()
+        );
+    }
+
+    // Rewrites the nth line of the log.
+    fn rewrite_entry(path: &Path, n: usize, rewrite: &Fn(&mut AuditLogEntry)) {
+        let mut lines = read_lines(path).unwrap();
+
+        let mut entry: AuditLogEntry = serde_json::from_str(&lines[n]).unwrap();
+        rewrite(&mut entry);
+        lines[n] = serde_json::to_string(&entry).unwrap();
+
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[test]
+    fn test_detects_tampered_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut log = AuditLog::new(&path);
+
+        for sector_id in 0..10 {
+            log.append(&SectorEvent::ForceDeleted { sector_id })
+                .unwrap();
+        }
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(10, report.num_entries);
+        assert_eq!(None, report.first_tampered_entry);
+
+        let entries = log.read_entries().unwrap();
+        assert_eq!(Some(entries[9].this_hash), report.last_hash);
+        assert_eq!(entries[4].this_hash, entries[5].prev_hash);
+
+        rewrite_entry(&path, 5, &|entry| {
+            entry.event = SectorEvent::ForceDeleted { sector_id: 42 };
+        });
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(10, report.num_entries);
+        assert_eq!(Some(5), report.first_tampered_entry);
+        assert_eq!(None, report.last_hash);
+
+        // rehashing the tampered entry breaks the chain at the next one
+        rewrite_entry(&path, 5, &|entry| {
+            entry.this_hash = entry_hash(
+                entry.sequence,
+                entry.timestamp,
+                &entry.event,
+                &entry.prev_hash,
+            )
+            .unwrap();
+        });
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(Some(6), report.first_tampered_entry);
+    }
+
+    #[test]
+    fn test_detects_removed_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut log = AuditLog::new(&path);
+
+        for sector_id in 0..4 {
+            log.append(&SectorEvent::ForceDeleted { sector_id })
+                .unwrap();
+        }
+
+        let mut lines = read_lines(&path).unwrap();
+        lines.remove(1);
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(3, report.num_entries);
+        assert_eq!(Some(1), report.first_tampered_entry);
+
+        // appending to a log continues its chain, however broken
+        log.append(&SectorEvent::ForceDeleted { sector_id: 4 })
+            .unwrap();
+        assert_eq!(4, log.read_entries().unwrap()[3].sequence);
+    }
+
+    #[test]
+    fn test_chains_legacy_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        let legacy = serde_json::to_string(&SectorEvent::ForceDeleted { sector_id: 1 }).unwrap();
+        std::fs::write(&path, legacy + "\n").unwrap();
+
+        let mut log = AuditLog::new(&path);
+        log.append(&SectorEvent::ForceDeleted { sector_id: 2 })
+            .unwrap();
+
+        // the entry follows on from the legacy line
+        let entries = log.read_entries().unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(1, entries[0].sequence);
+        assert_ne!([0; 32], entries[0].prev_hash);
+
+        assert_eq!(
+            vec![
+                SectorEvent::ForceDeleted { sector_id: 1 },
+                SectorEvent::ForceDeleted { sector_id: 2 },
+            ],
+            log.read_events().unwrap()
+        );
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(2, report.num_entries);
+        assert_eq!(None, report.first_tampered_entry);
+        assert_eq!(Some(entries[0].this_hash), report.last_hash);
+    }
+
+    #[test]
+    fn test_appends_after_corrupt_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        let mut log = AuditLog::new(&path);
+        log.append(&SectorEvent::ForceDeleted { sector_id: 1 })
+            .unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"sequence\":1,\"tim\n").unwrap();
+
+        // a fresh log reads its tail from disk, past the corrupt line
+        let mut log = AuditLog::new(&path);
+        log.append(&SectorEvent::ForceDeleted { sector_id: 2 })
+            .unwrap();
+
+        assert_eq!(2, log.read_entries().unwrap()[1].sequence);
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(3, report.num_entries);
+        assert_eq!(Some(1), report.first_tampered_entry);
+    }
+
+    #[test]
+    fn test_cached_tail_matches_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        let mut log = AuditLog::new(&path);
+        for sector_id in 0..3 {
+            log.append(&SectorEvent::ForceDeleted { sector_id })
+                .unwrap();
+        }
+
+        assert_eq!(Some(chain_tail(&read_lines(&path).unwrap())), log.tail);
+
+        let report = verify_audit_log_integrity(&path).unwrap();
+        assert_eq!(None, report.first_tampered_entry);
+        assert_eq!(log.tail.map(|tail| tail.1), report.last_hash);
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::api::sector_builder::helpers::compact_sealed_state::compact_sealed_state;
+use crate::api::sector_builder::helpers::snapshots::{
+    load_snapshot, make_snapshot, persist_snapshot,
+};
+use crate::api::sector_builder::kv_store::SledKvs;
+use crate::api::sector_builder::metadata::{push_piece, PieceMetadata, SealedSectorMetadata};
+use crate::api::sector_builder::state::{SealedState, StagedState};
+use crate::api::sector_builder::WrappedKeyValueStore;
+use crate::error::Result;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+pub use crate::api::sector_builder::kv_store::{FileSystemKvs, KeyValueStore, MmapKeyValueStore};
+pub use crate::api::sector_builder::metadata::{find_piece_at_offset, StagedSectorMetadata};
+
+const PROVER_ID: [u8; 31] = [0; 31];
+
+// Persists a sector builder state with the given number of sealed sectors to
+// dir, compacting it (see compact_sealed_state) if asked to.
+pub fn persist_sealed_state(dir: &Path, num_sealed_sectors: u64, compacted: bool) -> Result<()> {
+    let kv_store = open_kv_store(dir)?;
+
+    let mut sealed_state: SealedState = Default::default();
+    for sector_id in 1..=num_sealed_sectors {
+        sealed_state.sectors.insert(
+            sector_id,
+            SealedSectorMetadata {
+                sector_id,
+                sector_access: format!("sealed/{}", sector_id),
+                pieces: vec![PieceMetadata {
+                    piece_key: format!("piece-{}", sector_id),
+                    num_bytes: UnpaddedBytesAmount(sector_id),
+                    ..Default::default()
+                }],
+                comm_r: [sector_id as u8; 32],
+                proof: vec![sector_id as u8; 192],
+                ..Default::default()
+            },
+        );
+    }
+
+    let staged_state = StagedState {
+        sector_id_nonce: num_sealed_sectors + 1,
+        ..Default::default()
+    };
+
+    let snapshot = make_snapshot(&PROVER_ID, &staged_state, &sealed_state, &[], 0);
+    persist_snapshot(&kv_store, &snapshot)?;
+
+    if compacted {
+        compact_sealed_state(&kv_store, &PROVER_ID)?;
+    }
+
+    Ok(())
+}
+
+// Loads the sector builder state persisted to dir, returning its number of
+// sealed sectors.
+pub fn load_sealed_state(dir: &Path) -> Result<usize> {
+    let snapshot = load_snapshot(&open_kv_store(dir)?, &PROVER_ID)?
+        .ok_or_else(|| format_err!("no sector builder state"))?;
+
+    Ok(snapshot.sealed.sectors.len())
+}
+
+// A staged sector holding num_pieces pieces of piece_bytes bytes each.
+pub fn staged_sector(num_pieces: u64, piece_bytes: u64) -> StagedSectorMetadata {
+    let mut sector: StagedSectorMetadata = Default::default();
+
+    for i in 0..num_pieces {
+        push_piece(
+            &mut sector,
+            PieceMetadata {
+                piece_key: format!("piece-{}", i),
+                num_bytes: UnpaddedBytesAmount(piece_bytes),
+                ..Default::default()
+            },
+        );
+    }
+
+    sector
+}
+
+fn open_kv_store(dir: &Path) -> Result<Arc<WrappedKeyValueStore<SledKvs>>> {
+    Ok(Arc::new(WrappedKeyValueStore {
+        inner: Box::new(SledKvs::initialize(dir)?),
+    }))
+}
@@ -0,0 +1,257 @@
+use crate::api::sector_builder::deal_registry::{DealRegistry, NoActiveDeals};
+use crate::api::sector_builder::gpu::{GpuFallbackPolicy, GpuSealProver};
+use crate::api::sector_builder::health::AlertSink;
+use crate::api::sector_builder::metadata::{ApiVersion, SealingLocation};
+use crate::api::sector_builder::metrics::MetricsSink;
+use crate::api::sector_builder::post_scheduler::{
+    EpochSource, LogPreCommitDeadlineSink, PreCommitDeadlineSink,
+};
+use crate::api::sector_builder::seal_verifier::SealVerifier;
+use crate::api::sector_builder::state_export::StateExporter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Default upper bound on the number of piece-bytes held in the prefetch
+// buffer. Large enough to hold the user bytes of one live sector.
+const DEFAULT_MAX_PREFETCH_BYTES: usize = 1 << 28;
+
+// By default, a sector is sealed once it's nearly full, rather than waiting
+// for pieces small enough to fill what little room it has left.
+const DEFAULT_SEAL_TRIGGER_THRESHOLD: f64 = 0.95;
+const DEFAULT_RESUME_ADD_THRESHOLD: f64 = 0.9;
+
+// By default, a sector may be sealed however little it holds.
+const DEFAULT_MIN_SEAL_FILL_RATIO: f64 = 0.0;
+
+// Number of sealed sectors beyond which their metadata is persisted (and
+// loaded) an entry per sector, rather than as part of one large entry.
+const DEFAULT_SEALED_STATE_COMPACTION_THRESHOLD: usize = 1000;
+
+// Number of generated PoSts kept so that repeated challenges needn't be
+// proven again.
+const DEFAULT_MAX_CACHED_PROOFS: usize = 16;
+
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// A day of 30-second epochs in which to pre-commit a sealed sector, of which
+// the last hour is spent warning about it.
+const DEFAULT_PRE_COMMIT_WINDOW_EPOCHS: u64 = 2880;
+const DEFAULT_PRE_COMMIT_WARNING_EPOCHS: u64 = 120;
+
+const DEFAULT_POST_SCHEDULING_INTERVAL: Duration = Duration::from_secs(30);
+
+// Half a year of 30-second epochs.
+const DEFAULT_RESEAL_INTERVAL_EPOCHS: u64 = 2880 * 182;
+
+// By default, pieces are accepted however many sectors are waiting to be
+// sealed.
+const DEFAULT_MAX_STAGED_SECTORS: usize = std::usize::MAX;
+
+// Number of sectors whose fill durations are kept (see fill_time).
+const DEFAULT_MAX_FILL_DURATIONS: usize = 1000;
+
+// Long enough to cover a transport's retries of an add_piece call.
+const DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Upper bounds (in microseconds) of the piece ingestion latency buckets,
+// spanning fast local writes through slow network writes.
+const DEFAULT_PIECE_INGESTION_BUCKETS_US: [u64; 7] = [
+    100,
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+];
+
+// Tunables (and collaborators) for a SectorBuilder which have sensible
+// defaults and which FFI consumers don't (yet) need to provide.
+#[derive(Clone)]
+pub struct SectorBuilderConfig {
+    // Upper bound on the number of piece-bytes held in memory by the piece
+    // prefetch buffer. Least-recently-used pieces are evicted first.
+    pub max_prefetch_bytes: usize,
+
+    // When set, the sector builder runs sandboxed: every sector access
+    // produced by the SectorManager must be a path relative to this root.
+    pub sector_access_root: Option<PathBuf>,
+
+    // Consulted before deleting a sector so that sectors holding data for an
+    // active deal aren't deleted by accident.
+    pub deal_registry: Arc<DealRegistry>,
+
+    // When set, events which an operator may need to account for (e.g. the
+    // forced deletion of a sector with an active deal) are appended here.
+    // Without it, sectors with an active deal can't be deleted at all.
+    pub audit_log_path: Option<PathBuf>,
+
+    // When set, PoSt generation which runs for longer than this is abandoned
+    // and an error is returned to the caller. An abandoned generation runs to
+    // completion in the background, and until it has, further PoSt
+    // generation fails fast rather than piling up more of them.
+    pub post_generation_timeout: Option<Duration>,
+
+    // When set, every change to the sector builder's state is exported (as a
+    // delta) so that a hot standby can keep its own copy up to date.
+    pub state_exporter: Option<Arc<StateExporter>>,
+
+    // When set, the proof of each newly-sealed sector is verified before the
+    // sector is handed out. That costs a full verification per seal, so it's
+    // off by default.
+    pub verify_sealed_sectors: bool,
+
+    // Verifies the proof of each newly-sealed sector (when
+    // verify_sealed_sectors is set). When not set, proofs are verified on the
+    // CPU.
+    pub seal_verifier: Option<Arc<SealVerifier>>,
+
+    // When set, recorded with each sector this sector builder seals (and in
+    // the piece manifests which describe them).
+    pub sealing_location: Option<SealingLocation>,
+
+    // When the ratio of piece-bytes held by a staged sector to the number it
+    // could hold reaches this threshold, the sector is sealed. That won't
+    // happen again for the sector (should it be left staged) until its ratio
+    // has dropped below resume_add_threshold, so that small pieces pushing
+    // the ratio back over don't each schedule another seal.
+    pub seal_trigger_threshold: f64,
+    pub resume_add_threshold: f64,
+
+    // Sectors which are less full than this (as a ratio of piece-bytes to
+    // the number they could hold) aren't sealed, so that proving isn't
+    // wasted on nearly-empty sectors, unless sealing is forced (see
+    // SectorBuilder::seal_sector_force). Sectors which are sealed to make room
+    // for a new staged sector are sealed however full they are.
+    pub min_seal_fill_ratio: f64,
+
+    // When set, the space remaining in each staged sector is filled with
+    // pseudorandom bytes before it's sealed, so that the size of its file and
+    // the time taken to seal it don't reveal how full the sector was. The
+    // bytes are derived from the (public) prover id, so they don't hide it
+    // from anyone who can read the sector.
+    pub obfuscate_fill_time: bool,
+
+    // Once there are at least this many sealed sectors, the persisted state
+    // is compacted (see compact_sealed_state).
+    pub sealed_state_compaction_threshold: usize,
+
+    // Upper bound on the number of generated PoSts held (and persisted) by
+    // the proof deduplication index. Oldest proofs are evicted first, and a
+    // value of zero disables the index.
+    pub max_cached_proofs: usize,
+
+    // The version of the sealing API with which new sectors are sealed.
+    // Sectors already sealed are verified with the version they were sealed
+    // with, so this may be changed (e.g. at a network upgrade) at any time.
+    pub default_api_version: ApiVersion,
+
+    // When set, the sector builder's metadata is checked against the sectors
+    // on disk every health_check_interval, and discrepancies which weren't
+    // found by the previous check are reported here.
+    pub alert_sink: Option<Arc<AlertSink>>,
+    pub health_check_interval: Duration,
+
+    // Upper bounds (in microseconds, ascending) of the buckets of the piece
+    // ingestion histogram, which records how long each piece took to write.
+    pub piece_ingestion_buckets_us: Vec<u64>,
+
+    // How long after a piece is added with an idempotency key that a repeated
+    // add with the same key is ignored (see add_piece_idempotent).
+    pub idempotency_key_ttl: Duration,
+
+    // When set, each sector is given a pre-commit deadline (the epoch at
+    // which it was sealed plus pre_commit_window_epochs) and, every
+    // post_scheduling_interval, the sectors which are fewer than
+    // pre_commit_warning_epochs from their deadline are reported to the
+    // pre_commit_deadline_sink and proven ahead of the others.
+    pub epoch_source: Option<Arc<EpochSource>>,
+    pub pre_commit_window_epochs: u64,
+    pub pre_commit_warning_epochs: u64,
+    pub pre_commit_deadline_sink: Arc<PreCommitDeadlineSink>,
+    pub post_scheduling_interval: Duration,
+
+    // Once there are this many staged sectors (accepting data or waiting to
+    // be sealed), pieces are refused with a back-pressure error until one of
+    // them has been sealed (see SectorBuilder::wait_for_capacity).
+    pub max_staged_sectors: usize,
+
+    // When set, the number of pieces added and sectors sealed, how long each
+    // sector took to fill and to seal, and how full each staged sector is are
+    // reported here.
+    pub metrics_sink: Option<Arc<MetricsSink>>,
+
+    // How many of the most recently sealed sectors' fill durations (the time
+    // from their provisioning to their seal being scheduled) are kept.
+    pub max_fill_durations: usize,
+
+    // The file holding the key with which the sector builder signs manifests
+    // (see SectorBuilderKey), which is generated if the file doesn't exist.
+    // When not set, a new key is generated each time the sector builder
+    // starts.
+    pub key_path: Option<PathBuf>,
+
+    // How many epochs after it was sealed that a sector is due to be sealed
+    // again, with a new sector id (see reseal::should_reseal).
+    pub reseal_interval_epochs: u64,
+
+    // When set, seal proofs are generated by this GPU prover, falling back
+    // to the CPU (or not) when it fails according to gpu_fallback_policy.
+    pub gpu_prover: Option<Arc<GpuSealProver>>,
+    pub gpu_fallback_policy: GpuFallbackPolicy,
+
+    // When set, the Merkle tree of each sector's replica is written to a
+    // sidecar file as soon as the sector is sealed (see merkle_snapshot), so
+    // that audit challenges are answered without re-reading the replica. The
+    // sidecar file is twice the size of the replica.
+    pub snapshot_merkle_trees: bool,
+
+    // When set, each piece is committed to its staged sector's write-ahead
+    // log (a file in this directory) before it's written to the sector, and
+    // committed writes which an unclean shutdown kept from reaching their
+    // sectors are replayed as the sector builder is initialized. Each log
+    // holds a copy of its sector's pieces until the sector is sealed.
+    pub staged_sector_wal_dir: Option<PathBuf>,
+}
+
+impl Default for SectorBuilderConfig {
+    fn default() -> SectorBuilderConfig {
+        SectorBuilderConfig {
+            max_prefetch_bytes: DEFAULT_MAX_PREFETCH_BYTES,
+            sector_access_root: None,
+            deal_registry: Arc::new(NoActiveDeals),
+            audit_log_path: None,
+            post_generation_timeout: None,
+            state_exporter: None,
+            verify_sealed_sectors: false,
+            seal_verifier: None,
+            sealing_location: None,
+            seal_trigger_threshold: DEFAULT_SEAL_TRIGGER_THRESHOLD,
+            resume_add_threshold: DEFAULT_RESUME_ADD_THRESHOLD,
+            min_seal_fill_ratio: DEFAULT_MIN_SEAL_FILL_RATIO,
+            obfuscate_fill_time: false,
+            sealed_state_compaction_threshold: DEFAULT_SEALED_STATE_COMPACTION_THRESHOLD,
+            max_cached_proofs: DEFAULT_MAX_CACHED_PROOFS,
+            default_api_version: Default::default(),
+            alert_sink: None,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            piece_ingestion_buckets_us: DEFAULT_PIECE_INGESTION_BUCKETS_US.to_vec(),
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+            epoch_source: None,
+            pre_commit_window_epochs: DEFAULT_PRE_COMMIT_WINDOW_EPOCHS,
+            pre_commit_warning_epochs: DEFAULT_PRE_COMMIT_WARNING_EPOCHS,
+            pre_commit_deadline_sink: Arc::new(LogPreCommitDeadlineSink),
+            post_scheduling_interval: DEFAULT_POST_SCHEDULING_INTERVAL,
+            max_staged_sectors: DEFAULT_MAX_STAGED_SECTORS,
+            metrics_sink: None,
+            max_fill_durations: DEFAULT_MAX_FILL_DURATIONS,
+            key_path: None,
+            reseal_interval_epochs: DEFAULT_RESEAL_INTERVAL_EPOCHS,
+            gpu_prover: None,
+            gpu_fallback_policy: Default::default(),
+            snapshot_merkle_trees: false,
+            staged_sector_wal_dir: None,
+        }
+    }
+}
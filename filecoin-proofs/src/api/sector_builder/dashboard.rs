@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+pub use crate::api::internal::SealPhase;
+use crate::api::sector_builder::{SectorBuilder, SectorId};
+use crate::error::ExpectWithBacktrace;
+
+const FATAL_NOLOCK: &str = "error acquiring sealing tracker lock";
+
+// How far back the dashboard counts completed and failed seals.
+const RECENT_SEALS_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SealProgress {
+    pub sector_id: SectorId,
+    pub started_at: SystemTime,
+    pub current_phase: SealPhase,
+    // From 0 to 1. Only encoding reports its progress as it goes; the other
+    // phases are at 0 until they've finished.
+    pub phase_progress: f64,
+}
+
+// A snapshot of the sealing workers, for operator dashboards.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SealingDashboard {
+    // ordered by sector id
+    pub in_progress: Vec<SealProgress>,
+    // in the order in which they were scheduled
+    pub queued: Vec<SectorId>,
+    pub completed_last_hour: u32,
+    pub failed_last_hour: u32,
+}
+
+// Kept up to date by the main worker (as it schedules seals) and the sealing
+// workers (as they seal), neither of which holds its lock for longer than it
+// takes to record a change, so that a dashboard can be taken at any time
+// without waiting on either.
+#[derive(Debug, Default)]
+pub struct SealingTracker {
+    state: Mutex<SealingTrackerState>,
+}
+
+#[derive(Debug, Default)]
+struct SealingTrackerState {
+    queued: Vec<SectorId>,
+    in_progress: BTreeMap<SectorId, SealProgress>,
+    // when each recent seal finished
+    completed: VecDeque<SystemTime>,
+    failed: VecDeque<SystemTime>,
+}
+
+impl SealingTracker {
+    pub fn queued(&self, sector_id: SectorId) {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        if !state.queued.contains(&sector_id) {
+            state.queued.push(sector_id);
+        }
+    }
+
+    // Records that a sealing worker has taken the sector from the queue,
+    // whether or not it goes on to seal it.
+    pub fn dequeued(&self, sector_id: SectorId) {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+        state.queued.retain(|queued| *queued != sector_id);
+    }
+
+    pub fn started(&self, sector_id: SectorId, now: SystemTime) {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        state.queued.retain(|queued| *queued != sector_id);
+        state.in_progress.insert(
+            sector_id,
+            SealProgress {
+                sector_id,
+                started_at: now,
+                current_phase: SealPhase::Encoding,
+                phase_progress: 0.0,
+            },
+        );
+    }
+
+    pub fn progressed(&self, sector_id: SectorId, phase: SealPhase, phase_progress: f64) {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        if let Some(progress) = state.in_progress.get_mut(&sector_id) {
+            progress.current_phase = phase;
+            progress.phase_progress = phase_progress.max(0.0).min(1.0);
+        }
+    }
+
+    pub fn finished(&self, sector_id: SectorId, succeeded: bool, now: SystemTime) {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        state.in_progress.remove(&sector_id);
+
+        if succeeded {
+            state.completed.push_back(now);
+        } else {
+            state.failed.push_back(now);
+        }
+    }
+
+    pub fn dashboard(&self, now: SystemTime) -> SealingDashboard {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        let is_recent = |finished_at: &SystemTime| {
+            now.duration_since(*finished_at)
+                .map(|age| age < RECENT_SEALS_WINDOW)
+                .unwrap_or(true)
+        };
+
+        // Seals finish in (roughly) time order, so those which have aged out
+        // are at the front.
+        while state.completed.front().map_or(false, |t| !is_recent(t)) {
+            state.completed.pop_front();
+        }
+        while state.failed.front().map_or(false, |t| !is_recent(t)) {
+            state.failed.pop_front();
+        }
+
+        SealingDashboard {
+            in_progress: state.in_progress.values().cloned().collect(),
+            queued: state.queued.clone(),
+            completed_last_hour: state.completed.iter().filter(|t| is_recent(t)).count() as u32,
+            failed_last_hour: state.failed.iter().filter(|t| is_recent(t)).count() as u32,
+        }
+    }
+}
+
+// Returns a snapshot of the seals in progress and queued, and how many
+// finished in the last hour. Neither the main worker nor the sealing workers
+// are waited on.
+pub fn get_sealing_dashboard(sector_builder: &SectorBuilder) -> SealingDashboard {
+    sector_builder
+        .state
+        .sealing_tracker
+        .dashboard(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn test_reflects_worker_state() {
+        let tracker: Arc<SealingTracker> = Default::default();
+        let now = SystemTime::now();
+
+        for sector_id in 1..=4 {
+            tracker.queued(sector_id);
+        }
+
+        // two workers are mid-seal when the dashboard is taken
+        let mid_seal = Arc::new(Barrier::new(3));
+        let resume = Arc::new(Barrier::new(3));
+
+        let workers: Vec<_> = vec![
+            (1, SealPhase::TreeBuilding, 0.0),
+            (2, SealPhase::Encoding, 0.5),
+        ]
+        .into_iter()
+        .map(|(sector_id, phase, progress)| {
+            let tracker = tracker.clone();
+            let mid_seal = mid_seal.clone();
+            let resume = resume.clone();
+
+            thread::spawn(move || {
+                tracker.started(sector_id, now);
+                tracker.progressed(sector_id, phase, progress);
+                mid_seal.wait();
+                resume.wait();
+                tracker.finished(sector_id, sector_id == 1, now);
+            })
+        })
+        .collect();
+
+        mid_seal.wait();
+        let dashboard = tracker.dashboard(now);
+
+        assert_eq!(vec![3, 4], dashboard.queued);
+        assert_eq!(
+            vec![
+                SealProgress {
+                    sector_id: 1,
+                    started_at: now,
+                    current_phase: SealPhase::TreeBuilding,
+                    phase_progress: 0.0,
+                },
+                SealProgress {
+                    sector_id: 2,
+                    started_at: now,
+                    current_phase: SealPhase::Encoding,
+                    phase_progress: 0.5,
+                },
+            ],
+            dashboard.in_progress
+        );
+        assert_eq!(0, dashboard.completed_last_hour);
+
+        resume.wait();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let dashboard = tracker.dashboard(now);
+        assert!(dashboard.in_progress.is_empty());
+        assert_eq!(1, dashboard.completed_last_hour);
+        assert_eq!(1, dashboard.failed_last_hour);
+    }
+
+    #[test]
+    fn test_counts_only_last_hour() {
+        let tracker = SealingTracker::default();
+        let now = SystemTime::now();
+
+        tracker.finished(1, true, now - Duration::from_secs(2 * 60 * 60));
+        tracker.finished(2, false, now - Duration::from_secs(61 * 60));
+        tracker.finished(3, true, now - Duration::from_secs(59 * 60));
+        tracker.finished(4, true, now);
+
+        let dashboard = tracker.dashboard(now);
+        assert_eq!(2, dashboard.completed_last_hour);
+        assert_eq!(0, dashboard.failed_last_hour);
+    }
+
+    #[test]
+    fn test_dequeued_sector_is_not_queued() {
+        let tracker = SealingTracker::default();
+
+        tracker.queued(1);
+        tracker.queued(1);
+        assert_eq!(vec![1], tracker.dashboard(SystemTime::now()).queued);
+
+        tracker.dequeued(1);
+        assert!(tracker.dashboard(SystemTime::now()).queued.is_empty());
+    }
+}
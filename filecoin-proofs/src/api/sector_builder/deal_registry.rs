@@ -0,0 +1,18 @@
+use crate::api::sector_builder::SectorId;
+
+// Knows which sectors hold data for deals which have not yet expired. The
+// sector builder consults the registry before deleting a sector.
+pub trait DealRegistry: Send + Sync {
+    fn has_active_deal(&self, sector_id: SectorId) -> bool;
+}
+
+// A registry for sector builders which aren't told about deals. Every sector
+// may be deleted.
+#[derive(Debug, Default)]
+pub struct NoActiveDeals;
+
+impl DealRegistry for NoActiveDeals {
+    fn has_active_deal(&self, _sector_id: SectorId) -> bool {
+        false
+    }
+}
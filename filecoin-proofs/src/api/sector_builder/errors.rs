@@ -1,5 +1,7 @@
+use crate::api::sector_builder::SectorId;
 use failure::Backtrace;
 use std::fmt::Display;
+use std::time::Duration;
 
 #[derive(Debug, Fail)]
 pub enum SectorBuilderErr {
@@ -24,14 +26,50 @@ pub enum SectorBuilderErr {
     #[fail(display = "no piece with key {} found", _0)]
     PieceNotFound(String),
 
+    #[fail(display = "invalid sector access {:?}: {}", access, reason)]
+    InvalidSectorAccess { access: String, reason: String },
+
+    #[fail(display = "PoSt generation did not complete within {:?}", _0)]
+    PostGenerationTimeout(Duration),
+
+    #[fail(display = "sector {} is already being sealed", _0)]
+    SealAlreadyInProgress(SectorId),
+
+    #[fail(display = "sector is too empty to seal ({} full)", fill_ratio)]
+    SectorTooEmpty { fill_ratio: f64 },
+
+    #[fail(display = "access to piece with key {} denied", _0)]
+    AccessDenied(String),
+
+    #[fail(display = "piece with key {} has been redacted", _0)]
+    PieceRedacted(String),
+
+    #[fail(
+        display = "too many staged sectors ({}) to accept pieces until some are sealed",
+        queued_sectors
+    )]
+    BackPressure { queued_sectors: usize },
+
     #[fail(display = "unrecoverable error: {}", _0)]
     Unrecoverable(String, Backtrace),
 }
 
+pub fn err_access_denied(piece_key: String) -> SectorBuilderErr {
+    SectorBuilderErr::AccessDenied(piece_key)
+}
+
+pub fn err_back_pressure(queued_sectors: usize) -> SectorBuilderErr {
+    SectorBuilderErr::BackPressure { queued_sectors }
+}
+
 pub fn err_piecenotfound(piece_key: String) -> SectorBuilderErr {
     SectorBuilderErr::PieceNotFound(piece_key)
 }
 
+pub fn err_piece_redacted(piece_key: String) -> SectorBuilderErr {
+    SectorBuilderErr::PieceRedacted(piece_key)
+}
+
 pub fn err_unrecov<S: Display>(msg: S) -> SectorBuilderErr {
     let backtrace = failure::Backtrace::new();
     SectorBuilderErr::Unrecoverable(format!("{}", msg), backtrace)
@@ -50,3 +88,22 @@ pub fn err_inc_write(num_bytes_written: u64, num_bytes_in_piece: u64) -> SectorB
         num_bytes_in_piece,
     }
 }
+
+pub fn err_invalid_access<S: Display>(access: &str, reason: S) -> SectorBuilderErr {
+    SectorBuilderErr::InvalidSectorAccess {
+        access: access.to_string(),
+        reason: format!("{}", reason),
+    }
+}
+
+pub fn err_post_timeout(timeout: Duration) -> SectorBuilderErr {
+    SectorBuilderErr::PostGenerationTimeout(timeout)
+}
+
+pub fn err_sector_too_empty(fill_ratio: f64) -> SectorBuilderErr {
+    SectorBuilderErr::SectorTooEmpty { fill_ratio }
+}
+
+pub fn err_seal_in_progress(sector_id: SectorId) -> SectorBuilderErr {
+    SectorBuilderErr::SealAlreadyInProgress(sector_id)
+}
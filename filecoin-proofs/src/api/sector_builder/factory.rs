@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+use crate::api::post_adapter::GeneratePoStDynamicSectorsCountOutput;
+use crate::api::sector_builder::archive::ArchiveBackend;
+use crate::api::sector_builder::config::SectorBuilderConfig;
+use crate::api::sector_builder::manifest::{PieceManifest, PieceManifestFilter};
+use crate::api::sector_builder::metadata::*;
+use crate::api::sector_builder::metrics::Histogram;
+use crate::api::sector_builder::piece_access::PieceCapabilityToken;
+use crate::api::sector_builder::{SectorBuilder, SectorId};
+use crate::error::Result;
+use sector_base::api::sector_class::SectorClass;
+
+// The public interface of a SectorBuilder, so that code which drives a sector
+// builder can be exercised against a mock. See SectorBuilder for the
+// documentation of each method.
+#[cfg_attr(feature = "mock", mockall::automock)]
+pub trait SectorBuilderApi {
+    fn add_piece(
+        &self,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+    ) -> Result<SectorId>;
+
+    fn add_piece_idempotent(
+        &self,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+        idempotency_key: [u8; 16],
+    ) -> Result<SectorId>;
+
+    fn wait_for_capacity(&self, timeout: Duration) -> Result<()>;
+
+    fn get_seal_status(&self, sector_id: SectorId) -> Result<SealStatus>;
+
+    fn read_piece_from_sealed_sector(&self, piece_key: String) -> Result<Vec<u8>>;
+
+    fn get_piece(&self, piece_key: String, token: Option<PieceCapabilityToken>) -> Result<Vec<u8>>;
+
+    fn restrict_piece_access(&self, piece_key: String, token: &PieceCapabilityToken) -> Result<()>;
+
+    fn prefetch_piece(
+        &self,
+        next_piece_key: String,
+        token: Option<PieceCapabilityToken>,
+    ) -> Result<()>;
+
+    fn archive_sector(
+        &self,
+        sector_id: SectorId,
+        archive_backend: &ArchiveBackend,
+    ) -> Result<ArchiveReceipt>;
+
+    fn retrieve_archived_piece(
+        &self,
+        piece_key: String,
+        token: Option<PieceCapabilityToken>,
+        archive_backend: &ArchiveBackend,
+    ) -> Result<Vec<u8>>;
+
+    fn get_sectors_by_region(&self, country_code: [u8; 2]) -> Result<Vec<SectorId>>;
+
+    fn sample_sectors_for_audit(
+        &self,
+        rng_seed: [u8; 32],
+        sample_fraction: f64,
+    ) -> Result<Vec<SectorId>>;
+
+    fn reserve_sector_id_range(&self, count: u32) -> Result<(SectorId, SectorId)>;
+
+    fn delete_sectors_batch(
+        &self,
+        sector_ids: &[SectorId],
+        force: bool,
+    ) -> Result<BatchDeleteResult>;
+
+    fn seal_all_staged_sectors(&self) -> Result<()>;
+
+    fn seal_with_api_version(&self, sector_id: SectorId, api_version: ApiVersion) -> Result<()>;
+
+    fn seal_sector(&self, sector_id: SectorId) -> Result<()>;
+
+    fn seal_sector_force(&self, sector_id: SectorId, force_seal_even_if_empty: bool) -> Result<()>;
+
+    fn get_sealed_sectors(&self) -> Result<Vec<SealedSectorMetadata>>;
+
+    fn get_sectors_approaching_pre_commit_deadline(
+        &self,
+        current_epoch: u64,
+        warning_epochs: u64,
+    ) -> Result<Vec<SectorId>>;
+
+    fn get_staged_sectors(&self) -> Result<Vec<StagedSectorMetadata>>;
+
+    fn list_pieces(&self) -> Result<Vec<PieceMetadata>>;
+
+    fn render_live_state_diagram(&self) -> Result<String>;
+
+    fn subscribe_state_diagram_updates(&self) -> Result<mpsc::Receiver<String>>;
+
+    fn get_piece_ingestion_histogram(&self) -> &Histogram;
+
+    fn reset_histogram(&self);
+
+    fn public_key(&self) -> [u8; 32];
+
+    fn sign_manifest(&self, manifest: &[u8]) -> Result<[u8; 64]>;
+
+    fn generate_piece_manifest(&self, filter: PieceManifestFilter) -> Result<PieceManifest>;
+
+    fn generate_post(
+        &self,
+        comm_rs: &[[u8; 32]],
+        challenge_seed: &[u8; 32],
+    ) -> Result<GeneratePoStDynamicSectorsCountOutput>;
+}
+
+impl SectorBuilderApi for SectorBuilder {
+    fn add_piece(
+        &self,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+    ) -> Result<SectorId> {
+        SectorBuilder::add_piece(self, piece_key, piece_bytes_amount, piece_path)
+    }
+
+    fn add_piece_idempotent(
+        &self,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+        idempotency_key: [u8; 16],
+    ) -> Result<SectorId> {
+        SectorBuilder::add_piece_idempotent(
+            self,
+            piece_key,
+            piece_bytes_amount,
+            piece_path,
+            idempotency_key,
+        )
+    }
+
+    fn wait_for_capacity(&self, timeout: Duration) -> Result<()> {
+        SectorBuilder::wait_for_capacity(self, timeout)
+    }
+
+    fn get_seal_status(&self, sector_id: SectorId) -> Result<SealStatus> {
+        SectorBuilder::get_seal_status(self, sector_id)
+    }
+
+    fn read_piece_from_sealed_sector(&self, piece_key: String) -> Result<Vec<u8>> {
+        SectorBuilder::read_piece_from_sealed_sector(self, piece_key)
+    }
+
+    fn get_piece(&self, piece_key: String, token: Option<PieceCapabilityToken>) -> Result<Vec<u8>> {
+        SectorBuilder::get_piece(self, piece_key, token)
+    }
+
+    fn restrict_piece_access(&self, piece_key: String, token: &PieceCapabilityToken) -> Result<()> {
+        SectorBuilder::restrict_piece_access(self, piece_key, token)
+    }
+
+    fn prefetch_piece(
+        &self,
+        next_piece_key: String,
+        token: Option<PieceCapabilityToken>,
+    ) -> Result<()> {
+        SectorBuilder::prefetch_piece(self, next_piece_key, token)
+    }
+
+    fn archive_sector(
+        &self,
+        sector_id: SectorId,
+        archive_backend: &ArchiveBackend,
+    ) -> Result<ArchiveReceipt> {
+        SectorBuilder::archive_sector(self, sector_id, archive_backend)
+    }
+
+    fn retrieve_archived_piece(
+        &self,
+        piece_key: String,
+        token: Option<PieceCapabilityToken>,
+        archive_backend: &ArchiveBackend,
+    ) -> Result<Vec<u8>> {
+        SectorBuilder::retrieve_archived_piece(self, piece_key, token, archive_backend)
+    }
+
+    fn get_sectors_by_region(&self, country_code: [u8; 2]) -> Result<Vec<SectorId>> {
+        SectorBuilder::get_sectors_by_region(self, country_code)
+    }
+
+    fn sample_sectors_for_audit(
+        &self,
+        rng_seed: [u8; 32],
+        sample_fraction: f64,
+    ) -> Result<Vec<SectorId>> {
+        SectorBuilder::sample_sectors_for_audit(self, rng_seed, sample_fraction)
+    }
+
+    fn reserve_sector_id_range(&self, count: u32) -> Result<(SectorId, SectorId)> {
+        SectorBuilder::reserve_sector_id_range(self, count)
+    }
+
+    fn delete_sectors_batch(
+        &self,
+        sector_ids: &[SectorId],
+        force: bool,
+    ) -> Result<BatchDeleteResult> {
+        SectorBuilder::delete_sectors_batch(self, sector_ids, force)
+    }
+
+    fn seal_all_staged_sectors(&self) -> Result<()> {
+        SectorBuilder::seal_all_staged_sectors(self)
+    }
+
+    fn seal_with_api_version(&self, sector_id: SectorId, api_version: ApiVersion) -> Result<()> {
+        SectorBuilder::seal_with_api_version(self, sector_id, api_version)
+    }
+
+    fn seal_sector(&self, sector_id: SectorId) -> Result<()> {
+        SectorBuilder::seal_sector(self, sector_id)
+    }
+
+    fn seal_sector_force(&self, sector_id: SectorId, force_seal_even_if_empty: bool) -> Result<()> {
+        SectorBuilder::seal_sector_force(self, sector_id, force_seal_even_if_empty)
+    }
+
+    fn get_sealed_sectors(&self) -> Result<Vec<SealedSectorMetadata>> {
+        SectorBuilder::get_sealed_sectors(self)
+    }
+
+    fn get_sectors_approaching_pre_commit_deadline(
+        &self,
+        current_epoch: u64,
+        warning_epochs: u64,
+    ) -> Result<Vec<SectorId>> {
+        SectorBuilder::get_sectors_approaching_pre_commit_deadline(
+            self,
+            current_epoch,
+            warning_epochs,
+        )
+    }
+
+    fn get_staged_sectors(&self) -> Result<Vec<StagedSectorMetadata>> {
+        SectorBuilder::get_staged_sectors(self)
+    }
+
+    fn list_pieces(&self) -> Result<Vec<PieceMetadata>> {
+        SectorBuilder::list_pieces(self)
+    }
+
+    fn render_live_state_diagram(&self) -> Result<String> {
+        SectorBuilder::render_live_state_diagram(self)
+    }
+
+    fn subscribe_state_diagram_updates(&self) -> Result<mpsc::Receiver<String>> {
+        SectorBuilder::subscribe_state_diagram_updates(self)
+    }
+
+    fn get_piece_ingestion_histogram(&self) -> &Histogram {
+        SectorBuilder::get_piece_ingestion_histogram(self)
+    }
+
+    fn reset_histogram(&self) {
+        SectorBuilder::reset_histogram(self)
+    }
+
+    fn public_key(&self) -> [u8; 32] {
+        SectorBuilder::public_key(self)
+    }
+
+    fn sign_manifest(&self, manifest: &[u8]) -> Result<[u8; 64]> {
+        SectorBuilder::sign_manifest(self, manifest)
+    }
+
+    fn generate_piece_manifest(&self, filter: PieceManifestFilter) -> Result<PieceManifest> {
+        SectorBuilder::generate_piece_manifest(self, filter)
+    }
+
+    fn generate_post(
+        &self,
+        comm_rs: &[[u8; 32]],
+        challenge_seed: &[u8; 32],
+    ) -> Result<GeneratePoStDynamicSectorsCountOutput> {
+        SectorBuilder::generate_post(self, comm_rs, challenge_seed)
+    }
+}
+
+// Creates sector builders, so that the code which needs one needn't know how
+// it's constructed (or whether it's a mock).
+pub trait SectorBuilderFactory {
+    fn create_sector_builder(&self, config: SectorBuilderConfig) -> Result<Box<SectorBuilderApi>>;
+}
+
+// Creates SectorBuilders from the metadata persisted to disk (see
+// SectorBuilder::init_from_metadata).
+pub struct DefaultSectorBuilderFactory {
+    pub sector_class: SectorClass,
+    pub last_committed_sector_id: SectorId,
+    pub metadata_dir: String,
+    pub prover_id: [u8; 31],
+    pub sealed_sector_dir: String,
+    pub staged_sector_dir: String,
+    pub max_num_staged_sectors: u8,
+}
+
+impl DefaultSectorBuilderFactory {
+    // Like create_sector_builder, but for callers which need the concrete
+    // SectorBuilder (e.g. to redact or import into it).
+    pub fn create_concrete_sector_builder(
+        &self,
+        config: SectorBuilderConfig,
+    ) -> Result<SectorBuilder> {
+        SectorBuilder::init_from_metadata(
+            self.sector_class,
+            self.last_committed_sector_id,
+            self.metadata_dir.clone(),
+            self.prover_id,
+            self.sealed_sector_dir.clone(),
+            self.staged_sector_dir.clone(),
+            self.max_num_staged_sectors,
+            config,
+        )
+    }
+}
+
+impl SectorBuilderFactory for DefaultSectorBuilderFactory {
+    fn create_sector_builder(&self, config: SectorBuilderConfig) -> Result<Box<SectorBuilderApi>> {
+        let sector_builder = self.create_concrete_sector_builder(config)?;
+
+        Ok(Box::new(sector_builder))
+    }
+}
+
+// Hands out pre-configured sector builders (e.g. mocks), in the order they
+// were provided, and records the configs with which they were requested.
+#[derive(Default)]
+pub struct MockSectorBuilderFactory {
+    sector_builders: Mutex<VecDeque<Box<SectorBuilderApi>>>,
+    pub configs: Mutex<Vec<SectorBuilderConfig>>,
+}
+
+impl MockSectorBuilderFactory {
+    pub fn with_sector_builder(
+        self,
+        sector_builder: Box<SectorBuilderApi>,
+    ) -> MockSectorBuilderFactory {
+        self.sector_builders
+            .lock()
+            .expect("sector builder lock poisoned")
+            .push_back(sector_builder);
+
+        self
+    }
+}
+
+impl SectorBuilderFactory for MockSectorBuilderFactory {
+    fn create_sector_builder(&self, config: SectorBuilderConfig) -> Result<Box<SectorBuilderApi>> {
+        self.configs
+            .lock()
+            .map_err(|_| format_err!("config lock poisoned"))?
+            .push(config);
+
+        self.sector_builders
+            .lock()
+            .map_err(|_| format_err!("sector builder lock poisoned"))?
+            .pop_front()
+            .ok_or_else(|| format_err!("no more sector builders to create"))
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+
+    // Stands in for code which drives a sector builder.
+    fn add_and_seal(factory: &SectorBuilderFactory) -> Result<SectorId> {
+        let sector_builder = factory.create_sector_builder(Default::default())?;
+        let sector_id = sector_builder.add_piece("a".to_string(), 10, "a.dat".to_string())?;
+        sector_builder.seal_all_staged_sectors()?;
+
+        Ok(sector_id)
+    }
+
+    #[test]
+    fn test_creates_provided_mocks() {
+        let mut mock = MockSectorBuilderApi::new();
+        mock.expect_add_piece()
+            .withf(|key, num_bytes, _| key == "a" && *num_bytes == 10)
+            .times(1)
+            .returning(|_, _, _| Ok(42));
+        mock.expect_seal_all_staged_sectors()
+            .times(1)
+            .returning(|| Ok(()));
+
+        let factory = MockSectorBuilderFactory::default().with_sector_builder(Box::new(mock));
+
+        assert_eq!(42, add_and_seal(&factory).unwrap());
+        assert_eq!(1, factory.configs.lock().unwrap().len());
+
+        // the factory has run out of sector builders
+        assert!(add_and_seal(&factory).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder, SectorId};
+use crate::error::Result;
+
+// How long each of the most recently sealed sectors accepted data: from its
+// provisioning to its seal being scheduled. Kept by the main worker, oldest
+// first, and not persisted.
+#[derive(Debug)]
+pub struct FillDurationLog {
+    capacity: usize,
+    durations: VecDeque<(SectorId, Duration)>,
+}
+
+impl FillDurationLog {
+    // Keeps the fill durations of at most capacity sectors.
+    pub fn new(capacity: usize) -> FillDurationLog {
+        FillDurationLog {
+            capacity,
+            durations: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // Records the fill duration of the staged sector, whose seal was
+    // scheduled at seal_triggered_at, forgetting the oldest if the log is
+    // full. Sectors provisioned before provisioning times were recorded (or
+    // seemingly after their seal was scheduled, the clock having gone back)
+    // have no fill duration.
+    pub fn record(
+        &mut self,
+        sector: &StagedSectorMetadata,
+        seal_triggered_at: SystemTime,
+    ) -> Option<Duration> {
+        if sector.provisioned_at == UNIX_EPOCH {
+            return None;
+        }
+
+        let fill_duration = seal_triggered_at
+            .duration_since(sector.provisioned_at)
+            .ok()?;
+
+        self.durations.push_back((sector.sector_id, fill_duration));
+        while self.durations.len() > self.capacity {
+            self.durations.pop_front();
+        }
+
+        Some(fill_duration)
+    }
+
+    // The sectors' fill durations, in the order in which their seals were
+    // scheduled.
+    pub fn durations(&self) -> Vec<(SectorId, Duration)> {
+        self.durations.iter().cloned().collect()
+    }
+}
+
+// Returns the fill durations of the most recently sealed sectors (at most
+// SectorBuilderConfig::max_fill_durations of them), in the order in which
+// their seals were scheduled.
+pub fn get_sector_fill_durations(
+    sector_builder: &SectorBuilder,
+) -> Result<Vec<(SectorId, Duration)>> {
+    log_unrecov(sector_builder.run_blocking(Request::GetSectorFillDurations))
+}
+
+// Returns the given percentile (from 0 to 100) of the fill durations of the
+// most recently sealed sectors.
+pub fn get_percentile_fill_duration(
+    sector_builder: &SectorBuilder,
+    percentile: f64,
+) -> Result<Duration> {
+    percentile_fill_duration(&get_sector_fill_durations(sector_builder)?, percentile)
+}
+
+// The nearest-rank percentile: the smallest fill duration which is at least
+// as long as percentile percent of them.
+pub fn percentile_fill_duration(
+    fill_durations: &[(SectorId, Duration)],
+    percentile: f64,
+) -> Result<Duration> {
+    if !(0.0..=100.0).contains(&percentile) {
+        return Err(err_unrecov(format!(
+            "percentile must be between 0 and 100, was {}",
+            percentile
+        ))
+        .into());
+    }
+
+    let mut sorted: Vec<Duration> = fill_durations.iter().map(|(_, d)| *d).collect();
+    if sorted.is_empty() {
+        return Err(err_unrecov("no sector fill durations have been recorded").into());
+    }
+
+    sorted.sort();
+
+    let rank = (percentile * sorted.len() as f64 / 100.0).ceil() as usize;
+
+    Ok(sorted[rank.max(1) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Twenty sectors, provisioned a minute apart, the nth of which has its
+    // seal scheduled n minutes after it was provisioned (in an order which
+    // isn't that of their fill durations).
+    fn record_twenty_sectors(log: &mut FillDurationLog) {
+        let start = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+
+        for i in 0..20u64 {
+            let sector_id = (i * 7) % 20 + 1;
+            let provisioned_at = start + Duration::from_secs(60 * sector_id);
+
+            let sector = StagedSectorMetadata {
+                sector_id,
+                provisioned_at,
+                ..Default::default()
+            };
+
+            assert_eq!(
+                Some(Duration::from_secs(60 * sector_id)),
+                log.record(
+                    &sector,
+                    provisioned_at + Duration::from_secs(60 * sector_id)
+                )
+            );
+        }
+    }
+
+    fn minutes(n: u64) -> Duration {
+        Duration::from_secs(60 * n)
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut log = FillDurationLog::new(100);
+        record_twenty_sectors(&mut log);
+
+        let durations = log.durations();
+        assert_eq!(20, durations.len());
+        assert_eq!((1, minutes(1)), durations[0]);
+        assert_eq!((8, minutes(8)), durations[1]);
+
+        for (percentile, expected) in &[
+            (0.0, 1),
+            (5.0, 1),
+            (5.1, 2),
+            (50.0, 10),
+            (90.0, 18),
+            (95.0, 19),
+            (99.0, 20),
+            (100.0, 20),
+        ] {
+            assert_eq!(
+                minutes(*expected),
+                percentile_fill_duration(&durations, *percentile).unwrap(),
+                "percentile {}",
+                percentile
+            );
+        }
+
+        assert!(percentile_fill_duration(&durations, -1.0).is_err());
+        assert!(percentile_fill_duration(&durations, 100.5).is_err());
+        assert!(percentile_fill_duration(&durations, std::f64::NAN).is_err());
+        assert!(percentile_fill_duration(&[], 50.0).is_err());
+    }
+
+    #[test]
+    fn test_keeps_most_recent() {
+        let mut log = FillDurationLog::new(10);
+        record_twenty_sectors(&mut log);
+
+        // the last ten sectors recorded
+        let durations = log.durations();
+        let sector_ids: Vec<SectorId> = durations.iter().map(|(id, _)| *id).collect();
+        assert_eq!(vec![11, 18, 5, 12, 19, 6, 13, 20, 7, 14], sector_ids);
+
+        assert_eq!(
+            minutes(12),
+            percentile_fill_duration(&durations, 50.0).unwrap()
+        );
+
+        // a sector provisioned before provisioning times were recorded
+        let legacy: StagedSectorMetadata = Default::default();
+        assert_eq!(None, log.record(&legacy, SystemTime::now()));
+        assert_eq!(durations, log.durations());
+    }
+}
@@ -0,0 +1,271 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::api::internal::{prove_seal_on_cpu, SealProofInputs};
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::error::Result;
+use crate::FCP_LOG;
+use slog::*;
+
+// Where seal proofs are generated when the sector builder has a GPU prover
+// (see SectorBuilderConfig::gpu_prover). Without one, they're generated on
+// the CPU, and AlwaysGpu is refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpuFallbackPolicy {
+    // A seal whose proof the GPU fails to generate fails.
+    AlwaysGpu,
+    // A seal whose proof the GPU fails to generate (with an error or a panic)
+    // is proven again on the CPU.
+    FallbackToCpu,
+    // The GPU prover isn't used.
+    CpuOnly,
+}
+
+impl Default for GpuFallbackPolicy {
+    fn default() -> Self {
+        GpuFallbackPolicy::CpuOnly
+    }
+}
+
+// Generates seal proofs on a GPU. Drivers are liable to fail in ways which
+// the CPU doesn't (running out of device memory, say), which is what the
+// fallback policy is for.
+pub trait GpuSealProver: Send + Sync {
+    fn prove_seal(&self, inputs: &SealProofInputs) -> Result<Vec<u8>>;
+}
+
+// Generates the sealer workers' proofs according to the fallback policy.
+#[derive(Clone)]
+pub struct SealProver {
+    gpu_prover: Option<Arc<GpuSealProver>>,
+    policy: GpuFallbackPolicy,
+    prove_on_cpu: fn(&SealProofInputs) -> Result<Vec<u8>>,
+}
+
+impl SealProver {
+    // Fails if the policy is AlwaysGpu but there's no GPU prover, as proofs
+    // would otherwise be generated on the CPU.
+    pub fn new(
+        gpu_prover: Option<Arc<GpuSealProver>>,
+        policy: GpuFallbackPolicy,
+    ) -> Result<SealProver> {
+        if gpu_prover.is_none() && policy == GpuFallbackPolicy::AlwaysGpu {
+            return Err(err_unrecov(
+                "GPU fallback policy is AlwaysGpu, but there is no GPU prover",
+            )
+            .into());
+        }
+
+        Ok(SealProver {
+            gpu_prover,
+            policy,
+            prove_on_cpu: prove_seal_on_cpu,
+        })
+    }
+
+    pub fn prove_seal(&self, inputs: &SealProofInputs) -> Result<Vec<u8>> {
+        match (&self.gpu_prover, self.policy) {
+            (Some(gpu_prover), GpuFallbackPolicy::AlwaysGpu) => {
+                prove_on_gpu(|| gpu_prover.prove_seal(inputs))
+            }
+            (Some(gpu_prover), GpuFallbackPolicy::FallbackToCpu) => prove_with_cpu_fallback(
+                || gpu_prover.prove_seal(inputs),
+                || (self.prove_on_cpu)(inputs),
+            ),
+            _ => (self.prove_on_cpu)(inputs),
+        }
+    }
+}
+
+// A GPU prover which panics fails the seal, rather than the sealer worker
+// whose thread it panicked on.
+fn prove_on_gpu<T, F: FnOnce() -> Result<T>>(prove: F) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(prove)).unwrap_or_else(|payload| {
+        Err(err_unrecov(format!("GPU prover panicked: {}", panic_message(&*payload))).into())
+    })
+}
+
+fn prove_with_cpu_fallback<T, G, C>(prove_with_gpu: G, prove_on_cpu: C) -> Result<T>
+where
+    G: FnOnce() -> Result<T>,
+    C: FnOnce() -> Result<T>,
+{
+    prove_on_gpu(prove_with_gpu).or_else(|err| {
+        let err = format!("{}", err);
+        warn!(FCP_LOG, "GPU proof generation failed, proving on the CPU"; "error" => err);
+
+        prove_on_cpu()
+    })
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "(no message)".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::internal::{seal_from_reader, verify_seal};
+    use crate::api::sector_builder::metadata::sector_id_as_bytes;
+    use sector_base::api::disk_backed_storage::TEST_SECTOR_SIZE;
+    use sector_base::api::porep_config::PoRepConfig;
+    use sector_base::api::porep_proof_partitions::PoRepProofPartitions;
+    use sector_base::api::sector_size::SectorSize;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_falls_back_to_cpu() {
+        let cpu_proofs = Cell::new(0);
+        let prove_on_cpu = || {
+            cpu_proofs.set(cpu_proofs.get() + 1);
+            Ok(vec![1, 2, 3])
+        };
+
+        // a GPU which errors
+        let proof = prove_with_cpu_fallback(
+            || Err(format_err!("CUDA_ERROR_OUT_OF_MEMORY")),
+            prove_on_cpu,
+        );
+        assert_eq!(vec![1, 2, 3], proof.unwrap());
+        assert_eq!(1, cpu_proofs.get());
+
+        // a GPU which panics
+        let proof = prove_with_cpu_fallback(|| panic!("driver crashed"), prove_on_cpu);
+        assert_eq!(vec![1, 2, 3], proof.unwrap());
+        assert_eq!(2, cpu_proofs.get());
+
+        // a GPU which works
+        let proof = prove_with_cpu_fallback(|| Ok(vec![4]), prove_on_cpu);
+        assert_eq!(vec![4], proof.unwrap());
+        assert_eq!(2, cpu_proofs.get());
+    }
+
+    #[test]
+    fn test_gpu_panic_fails_proof() {
+        let err = prove_on_gpu::<Vec<u8>, _>(|| panic!("driver crashed")).unwrap_err();
+        assert!(format!("{}", err).contains("driver crashed"));
+
+        let err = prove_on_gpu::<Vec<u8>, _>(|| panic!("{} processes", 3)).unwrap_err();
+        assert!(format!("{}", err).contains("3 processes"));
+    }
+
+    struct FailingGpu;
+
+    impl GpuSealProver for FailingGpu {
+        fn prove_seal(&self, _: &SealProofInputs) -> Result<Vec<u8>> {
+            panic!("GPU fell off the bus")
+        }
+    }
+
+    #[test]
+    fn test_always_gpu_requires_gpu_prover() {
+        assert!(SealProver::new(None, GpuFallbackPolicy::AlwaysGpu).is_err());
+        assert!(SealProver::new(None, GpuFallbackPolicy::FallbackToCpu).is_ok());
+        assert!(SealProver::new(None, GpuFallbackPolicy::CpuOnly).is_ok());
+        assert!(SealProver::new(Some(Arc::new(FailingGpu)), GpuFallbackPolicy::AlwaysGpu).is_ok());
+    }
+
+    static CPU_PROOFS: AtomicUsize = AtomicUsize::new(0);
+
+    // Counts the proofs asked of it, and refuses them (so that seal_from_reader
+    // doesn't go on to verify them).
+    fn counting_cpu(inputs: &SealProofInputs) -> Result<Vec<u8>> {
+        CPU_PROOFS.fetch_add(1, Ordering::SeqCst);
+        Err(format_err!("CPU proof of {:?}", inputs.porep_config))
+    }
+
+    #[test]
+    fn test_seal_falls_back_to_cpu() {
+        let porep_config = PoRepConfig(SectorSize::OneKiB, PoRepProofPartitions::Two);
+        let staged = vec![0; TEST_SECTOR_SIZE as usize];
+        let dir = tempfile::tempdir().unwrap();
+
+        let seal = |name: &str, prover: SealProver| {
+            seal_from_reader(
+                porep_config,
+                &staged[..],
+                dir.path().join(name),
+                &[1; 31],
+                &sector_id_as_bytes(7).unwrap(),
+                None,
+                &|inputs| prover.prove_seal(inputs),
+                &|_| (),
+            )
+            .map(|_| ())
+            .unwrap_err()
+        };
+
+        let always_gpu = SealProver {
+            prove_on_cpu: counting_cpu,
+            ..SealProver::new(Some(Arc::new(FailingGpu)), GpuFallbackPolicy::AlwaysGpu).unwrap()
+        };
+        let err = seal("always-gpu", always_gpu);
+        assert!(format!("{}", err).contains("GPU fell off the bus"));
+        assert_eq!(0, CPU_PROOFS.load(Ordering::SeqCst));
+
+        let fallback = SealProver {
+            prove_on_cpu: counting_cpu,
+            ..SealProver::new(Some(Arc::new(FailingGpu)), GpuFallbackPolicy::FallbackToCpu).unwrap()
+        };
+        let err = seal("fallback", fallback);
+        assert!(format!("{}", err).contains("CPU proof"));
+        assert_eq!(1, CPU_PROOFS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[ignore] // Slow test – run only when compiled for release.
+    fn test_cpu_fallback_proof_verifies() {
+        let porep_config = PoRepConfig(SectorSize::OneKiB, PoRepProofPartitions::Two);
+        let prover_id = [1; 31];
+        let sector_id = sector_id_as_bytes(7).unwrap();
+        let staged = vec![0; TEST_SECTOR_SIZE as usize];
+        let dir = tempfile::tempdir().unwrap();
+
+        let failing =
+            SealProver::new(Some(Arc::new(FailingGpu)), GpuFallbackPolicy::AlwaysGpu).unwrap();
+        assert!(seal_from_reader(
+            porep_config,
+            &staged[..],
+            dir.path().join("failing"),
+            &prover_id,
+            &sector_id,
+            None,
+            &|inputs| failing.prove_seal(inputs),
+            &|_| (),
+        )
+        .is_err());
+
+        let fallback =
+            SealProver::new(Some(Arc::new(FailingGpu)), GpuFallbackPolicy::FallbackToCpu).unwrap();
+        let output = seal_from_reader(
+            porep_config,
+            &staged[..],
+            dir.path().join("fallback"),
+            &prover_id,
+            &sector_id,
+            None,
+            &|inputs| fallback.prove_seal(inputs),
+            &|_| (),
+        )
+        .unwrap();
+
+        assert!(verify_seal(
+            porep_config,
+            output.comm_r,
+            output.comm_d,
+            output.comm_r_star,
+            &prover_id,
+            &sector_id,
+            &output.proof,
+        )
+        .unwrap());
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use crate::FCP_LOG;
+#[cfg(feature = "alert-sink-pagerduty")]
+use serde::Serialize;
+use slog::*;
+
+// Below this many discrepancies, an alert is a warning.
+const CRITICAL_NUM_DISCREPANCIES: usize = 5;
+
+// Something about a sector which doesn't square with the sector builder's
+// metadata.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Discrepancy {
+    // The staged sector's access (i.e. file) is missing.
+    MissingStagedSectorAccess(SectorId),
+    // The sealed sector's replica is missing, and it hasn't been archived.
+    MissingSealedSectorAccess(SectorId),
+    // The sealed sector's replica is shorter than a sector.
+    TruncatedReplica(SectorId),
+    // The staged sector failed to seal.
+    SealFailed(SectorId),
+    // The staged sector's file has changed since its last write, but its
+    // pieces' bytes are intact (or have no checksums to verify them by).
+    StagedSectorCrc32Mismatch(SectorId),
+    // The bytes of one of the staged sector's pieces don't match its
+    // checksum.
+    CorruptedStagedPiece(SectorId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertSeverity {
+    Warn,
+    Critical,
+}
+
+// Raised when a health check finds discrepancies which the previous check
+// didn't.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthAlert {
+    pub severity: AlertSeverity,
+    // the discrepancies found by this check but not the previous one
+    pub new_discrepancies: Vec<Discrepancy>,
+    // all discrepancies found by this check
+    pub num_discrepancies: usize,
+}
+
+// Delivers health alerts to an operator.
+pub trait AlertSink: Send + Sync {
+    fn send_alert(&self, alert: HealthAlert) -> Result<()>;
+}
+
+// Writes alerts to the log.
+pub struct LogAlertSink;
+
+impl AlertSink for LogAlertSink {
+    fn send_alert(&self, alert: HealthAlert) -> Result<()> {
+        let new_discrepancies = format!("{:?}", alert.new_discrepancies);
+
+        match alert.severity {
+            AlertSeverity::Warn => {
+                warn!(FCP_LOG, "sector builder health check found new discrepancies"; "new_discrepancies" => new_discrepancies, "num_discrepancies" => alert.num_discrepancies);
+            }
+            AlertSeverity::Critical => {
+                crit!(FCP_LOG, "sector builder health check found new discrepancies"; "new_discrepancies" => new_discrepancies, "num_discrepancies" => alert.num_discrepancies);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Sends alerts over a channel, e.g. to be forwarded by the embedding program.
+pub struct ChannelAlertSink {
+    tx: Mutex<mpsc::Sender<HealthAlert>>,
+}
+
+impl ChannelAlertSink {
+    pub fn new(tx: mpsc::Sender<HealthAlert>) -> ChannelAlertSink {
+        ChannelAlertSink { tx: Mutex::new(tx) }
+    }
+}
+
+impl AlertSink for ChannelAlertSink {
+    fn send_alert(&self, alert: HealthAlert) -> Result<()> {
+        self.tx
+            .lock()
+            .map_err(|_| format_err!("alert channel lock poisoned"))?
+            .send(alert)
+            .map_err(|_| format_err!("alert channel hung up"))
+    }
+}
+
+// The body of a PagerDuty (Events API v2) event.
+#[cfg(feature = "alert-sink-pagerduty")]
+#[derive(Serialize)]
+struct PagerDutyEvent<'a> {
+    routing_key: &'a str,
+    event_action: &'a str,
+    payload: PagerDutyPayload,
+}
+
+#[cfg(feature = "alert-sink-pagerduty")]
+#[derive(Serialize)]
+struct PagerDutyPayload {
+    summary: String,
+    source: String,
+    severity: &'static str,
+}
+
+// Triggers a PagerDuty incident for each alert.
+#[cfg(feature = "alert-sink-pagerduty")]
+pub struct PagerDutyAlertSink {
+    pub endpoint: url::Url,
+    routing_key: String,
+    source: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "alert-sink-pagerduty")]
+impl PagerDutyAlertSink {
+    // Incidents are raised against the service with the given integration
+    // (routing) key, and attributed to source (e.g. the host name).
+    pub fn new(routing_key: String, source: String) -> Result<PagerDutyAlertSink> {
+        Ok(PagerDutyAlertSink {
+            endpoint: url::Url::parse("https://events.pagerduty.com/v2/enqueue")?,
+            routing_key,
+            source,
+            client: reqwest::Client::new(),
+        })
+    }
+}
+
+#[cfg(feature = "alert-sink-pagerduty")]
+impl AlertSink for PagerDutyAlertSink {
+    fn send_alert(&self, alert: HealthAlert) -> Result<()> {
+        let event = PagerDutyEvent {
+            routing_key: &self.routing_key,
+            event_action: "trigger",
+            payload: PagerDutyPayload {
+                summary: format!(
+                    "sector builder has {} discrepancies, of which new: {:?}",
+                    alert.num_discrepancies, alert.new_discrepancies
+                ),
+                source: self.source.clone(),
+                severity: match alert.severity {
+                    AlertSeverity::Warn => "warning",
+                    AlertSeverity::Critical => "critical",
+                },
+            },
+        };
+
+        self.client
+            .post(self.endpoint.clone())
+            .json(&event)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+// Periodically checks the health of a sector builder, alerting when a check
+// finds discrepancies which the previous check didn't.
+pub struct HealthMonitor {
+    shutdown_tx: mpsc::SyncSender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl HealthMonitor {
+    pub fn start<F>(interval: Duration, alert_sink: Arc<AlertSink>, mut check: F) -> HealthMonitor
+    where
+        F: FnMut() -> Result<Vec<Discrepancy>> + Send + 'static,
+    {
+        let (shutdown_tx, shutdown_rx) = mpsc::sync_channel(1);
+
+        let thread = thread::spawn(move || {
+            let mut previous: HashSet<Discrepancy> = Default::default();
+
+            while let Err(mpsc::RecvTimeoutError::Timeout) = shutdown_rx.recv_timeout(interval) {
+                let discrepancies = match check() {
+                    Ok(discrepancies) => discrepancies,
+                    Err(err) => {
+                        let err = format!("{}", err);
+                        warn!(FCP_LOG, "sector builder health check failed"; "error" => err);
+                        continue;
+                    }
+                };
+
+                if let Some(alert) = make_alert(&previous, &discrepancies) {
+                    if let Err(err) = alert_sink.send_alert(alert) {
+                        let err = format!("{}", err);
+                        warn!(FCP_LOG, "failed to send health alert"; "error" => err);
+                    }
+                }
+
+                previous = discrepancies.into_iter().collect();
+            }
+        });
+
+        HealthMonitor {
+            shutdown_tx,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        let _ = self.shutdown_tx.send(());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread
+                .join()
+                .map_err(|err| println!("err joining health monitor thread: {:?}", err));
+        }
+    }
+}
+
+// Produces an alert if any of the discrepancies weren't previously found.
+fn make_alert(previous: &HashSet<Discrepancy>, current: &[Discrepancy]) -> Option<HealthAlert> {
+    let mut new_discrepancies: Vec<Discrepancy> = current
+        .iter()
+        .filter(|x| !previous.contains(x))
+        .cloned()
+        .collect();
+
+    if new_discrepancies.is_empty() {
+        return None;
+    }
+
+    new_discrepancies.sort();
+
+    let severity = if current.len() < CRITICAL_NUM_DISCREPANCIES {
+        AlertSeverity::Warn
+    } else {
+        AlertSeverity::Critical
+    };
+
+    Some(HealthAlert {
+        severity,
+        new_discrepancies,
+        num_discrepancies: current.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_severity_scales_with_discrepancies() {
+        let previous: HashSet<Discrepancy> = Default::default();
+
+        let current: Vec<Discrepancy> = (0..4).map(Discrepancy::SealFailed).collect();
+        let alert = make_alert(&previous, &current).unwrap();
+        assert_eq!(AlertSeverity::Warn, alert.severity);
+        assert_eq!(4, alert.num_discrepancies);
+
+        let current: Vec<Discrepancy> = (0..5).map(Discrepancy::SealFailed).collect();
+        let alert = make_alert(&previous, &current).unwrap();
+        assert_eq!(AlertSeverity::Critical, alert.severity);
+
+        // nothing new, nothing to alert
+        let previous = current.iter().cloned().collect();
+        assert_eq!(None, make_alert(&previous, &current));
+    }
+
+    #[test]
+    fn test_alerts_on_new_discrepancy() {
+        let (alert_tx, alert_rx) = mpsc::channel();
+
+        // each check finds what the test has injected
+        let injected: Arc<Mutex<Vec<Discrepancy>>> = Default::default();
+        let (check_tx, check_rx) = mpsc::channel();
+
+        let monitor = {
+            let injected = injected.clone();
+
+            HealthMonitor::start(
+                Duration::from_millis(10),
+                Arc::new(ChannelAlertSink::new(alert_tx)),
+                move || {
+                    let _ = check_tx.send(());
+                    Ok(injected.lock().unwrap().clone())
+                },
+            )
+        };
+
+        // a healthy sector builder raises no alerts
+        check_rx.recv().unwrap();
+        check_rx.recv().unwrap();
+        assert!(alert_rx.try_recv().is_err());
+
+        injected
+            .lock()
+            .unwrap()
+            .push(Discrepancy::MissingSealedSectorAccess(3));
+
+        let alert = alert_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            HealthAlert {
+                severity: AlertSeverity::Warn,
+                new_discrepancies: vec![Discrepancy::MissingSealedSectorAccess(3)],
+                num_discrepancies: 1,
+            },
+            alert
+        );
+
+        // the discrepancy is only alerted once
+        let num_checks = check_rx.iter().take(3).count();
+        assert_eq!(3, num_checks);
+        drop(monitor);
+        assert!(alert_rx.try_recv().is_err());
+    }
+}
@@ -1,21 +1,36 @@
 use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use crate::api::sector_builder::errors::*;
+use crate::api::sector_builder::helpers::validate_sector_access::validate_sector_access;
+use crate::api::sector_builder::io_scheduler::{IoClass, IoScheduler, ScheduledReader};
+use crate::api::sector_builder::metadata::push_piece;
 use crate::api::sector_builder::metadata::sum_piece_bytes;
 use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::api::sector_builder::metrics::Histogram;
+use crate::api::sector_builder::sector_id_allocator::SectorIdAllocator;
+use crate::api::sector_builder::sector_locks::SectorLocks;
 use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::wal::WalWriter;
 use crate::api::sector_builder::*;
 use crate::error;
 use sector_base::api::bytes_amount::UnpaddedBytesAmount;
 use sector_base::api::sector_store::SectorManager;
+use sector_base::io::checksum::TeeReader;
 
-pub fn add_piece(
+// Chooses the staged sector to which a piece of the given size is to be
+// written, provisioning a new one if none of those accepting data has room
+// for it.
+pub fn piece_destination(
     sector_store: &Arc<WrappedSectorStore>,
     mut staged_state: &mut StagedState,
-    piece_key: String,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    sector_id_allocator: &mut SectorIdAllocator,
     piece_bytes_amount: u64,
-    piece_path: String,
+    sector_access_root: Option<&Path>,
 ) -> error::Result<SectorId> {
     let sector_mgr = sector_store.inner.manager();
     let sector_max = sector_store
@@ -36,39 +51,137 @@ pub fn add_piece(
         compute_destination_sector_id(&candidates[..], sector_max, piece_bytes_len)?
     };
 
-    let dest_sector_id = opt_dest_sector_id
-        .ok_or(())
-        .or_else(|_| provision_new_staged_sector(sector_mgr, &mut staged_state))?;
-
-    if let Some(s) = staged_state.sectors.get_mut(&dest_sector_id) {
-        let mut file = File::open(piece_path)?;
-
-        sector_store
-            .inner
-            .manager()
-            .write_and_preprocess(&s.sector_access, &mut file)
-            .map_err(Into::into)
-            .and_then(|num_bytes_written| {
-                if num_bytes_written != piece_bytes_len {
-                    Err(
-                        err_inc_write(u64::from(num_bytes_written), u64::from(piece_bytes_len))
-                            .into(),
-                    )
-                } else {
-                    Ok(s.sector_id)
-                }
-            })
-            .map(|sector_id| {
-                s.pieces.push(metadata::PieceMetadata {
-                    piece_key,
-                    num_bytes: piece_bytes_len,
-                });
+    opt_dest_sector_id.ok_or(()).or_else(|_| {
+        provision_new_staged_sector(
+            sector_mgr,
+            &mut staged_state,
+            reserved_ranges,
+            sector_id_allocator,
+            sector_access_root,
+        )
+    })
+}
+
+// Writes the piece (read from piece_path) to the end of the staged sector's
+// file and syncs it, returning the piece's checksum. The piece's bytes are
+// also copied to piece_bytes as they're read. Both the reads of the piece and
+// the writes to the sector are scheduled as piece I/O. When the sector has a
+// write-ahead log, the piece is read into memory and committed to the log
+// before any of it is written to the sector.
+#[allow(clippy::too_many_arguments)]
+pub fn write_piece_bytes(
+    sector_store: &Arc<WrappedSectorStore>,
+    sector_id: SectorId,
+    sector_access: &str,
+    piece_bytes_len: UnpaddedBytesAmount,
+    piece_path: &str,
+    piece_ingestion_histogram: &Histogram,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+    wal: Option<&mut WalWriter>,
+    piece_bytes: &mut Write,
+) -> error::Result<[u8; 32]> {
+    let sector_mgr = sector_store.inner.manager();
+
+    // Only the write is timed, as that's what depends on where the piece
+    // is read from (e.g. a local disk or a network mount).
+    let timer = piece_ingestion_histogram.start_timer();
+
+    let mut file = ScheduledReader::new(File::open(piece_path)?, io_scheduler, IoClass::Piece)
+        .covering_writes();
+
+    let mut tee = TeeReader {
+        source: &mut file,
+        sink: piece_bytes,
+    };
+
+    // With a write-ahead log, the whole piece is logged (and committed) before
+    // any of it reaches the sector, so that the write can be replayed.
+    let mut logged = Vec::new();
+    let mut source: Box<Read + '_> = match wal {
+        Some(wal) => {
+            tee.read_to_end(&mut logged)?;
 
-                sector_id
+            let offset = sector_mgr.num_unsealed_bytes(sector_access)?;
+            let position = wal.append(offset, &logged)?;
+            wal.commit(position)?;
+
+            Box::new(&logged[..])
+        }
+        None => Box::new(tee),
+    };
+
+    // Nothing may have the staged sector mapped until the piece has been
+    // synced to it.
+    let written = {
+        let lock = sector_locks.get(sector_id);
+        let _guard = lock.invalidate_mmaps();
+
+        sector_mgr
+            .write_and_preprocess_with_checksum(sector_access, &mut source)
+            .and_then(|written| {
+                sector_mgr.sync_staging_sector_access(sector_access)?;
+                Ok(written)
             })
-    } else {
-        Err(err_unrecov("unable to retrieve sector from state-map").into())
+    };
+
+    timer.stop_and_observe();
+
+    let (num_bytes_written, checksum) = written?;
+
+    if num_bytes_written != piece_bytes_len {
+        return Err(err_inc_write(u64::from(num_bytes_written), u64::from(piece_bytes_len)).into());
+    }
+
+    Ok(checksum)
+}
+
+// Records a piece which has been written to the end of the staged sector.
+pub fn push_written_piece(
+    s: &mut StagedSectorMetadata,
+    piece_key: String,
+    piece_bytes_len: UnpaddedBytesAmount,
+    checksum: [u8; 32],
+) {
+    push_piece(
+        s,
+        metadata::PieceMetadata {
+            piece_key,
+            num_bytes: piece_bytes_len,
+            added_at: SystemTime::now(),
+            checksum: Some(checksum),
+            access_token_hash: None,
+        },
+    );
+}
+
+// Returns the sector to which the piece added with the provided key was
+// written, if the key is still recorded. Expired keys are forgotten by
+// expire_idempotency_keys, which should be called first.
+pub fn idempotent_add_sector_id(
+    staged_state: &StagedState,
+    idempotency_key: &[u8; 16],
+) -> Option<SectorId> {
+    staged_state
+        .idempotency_keys
+        .get(idempotency_key)
+        .map(|(sector_id, _)| *sector_id)
+}
+
+// Forgets the idempotency keys which have expired, returning them.
+pub fn expire_idempotency_keys(staged_state: &mut StagedState, now: SystemTime) -> Vec<[u8; 16]> {
+    let expired: Vec<[u8; 16]> = staged_state
+        .idempotency_keys
+        .iter()
+        .filter(|(_, (_, expires_at))| *expires_at <= now)
+        .map(|(key, _)| *key)
+        .collect();
+
+    for key in &expired {
+        staged_state.idempotency_keys.remove(key);
     }
+
+    expired
 }
 
 // Given a list of staged sectors which are accepting data, return the
@@ -91,25 +204,35 @@ fn compute_destination_sector_id(
 }
 
 // Provisions a new staged sector and returns its sector_id. Not a pure
-// function; creates a sector access (likely a file), increments the sector id
-// nonce, and mutates the StagedState.
-fn provision_new_staged_sector(
+// function; creates a sector access (likely a file), allocates a sector id
+// (consuming a reserved id or advancing the nonce), and mutates the
+// StagedState. The access is validated before a sector id is allocated, and
+// removed if it's invalid, so that nothing is left behind for a sector which
+// was never provisioned.
+pub fn provision_new_staged_sector(
     sector_manager: &SectorManager,
     staged_state: &mut StagedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    sector_id_allocator: &mut SectorIdAllocator,
+    sector_access_root: Option<&Path>,
 ) -> error::Result<SectorId> {
-    let sector_id = {
-        let n = &mut staged_state.sector_id_nonce;
-        *n += 1;
-        *n
-    };
-
     let access = sector_manager.new_staging_sector_access()?;
 
+    if let Err(err) = validate_sector_access(&access, sector_access_root) {
+        let _ = sector_manager.delete_staging_sector_access(&access);
+        return Err(err);
+    }
+
+    let sector_id = sector_id_allocator.allocate(staged_state, reserved_ranges);
+
     let meta = StagedSectorMetadata {
         pieces: Default::default(),
         sector_access: access.clone(),
         sector_id,
         seal_status: SealStatus::Pending,
+        merkle_tree_state: None,
+        last_crc32: None,
+        provisioned_at: SystemTime::now(),
     };
 
     staged_state.sectors.insert(meta.sector_id, meta.clone());
@@ -120,28 +243,45 @@ fn provision_new_staged_sector(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::sector_builder::helpers::reserve_sector_id_range::reserve_sector_id_range;
     use crate::api::sector_builder::metadata::PieceMetadata;
+    use crate::api::sector_builder::test_utils::{add_piece, mock_sector_store};
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::time::Duration;
 
     #[test]
     fn test_alpha() {
         let mut sealed_sector_a: StagedSectorMetadata = Default::default();
 
-        sealed_sector_a.pieces.push(PieceMetadata {
-            piece_key: String::from("x"),
-            num_bytes: UnpaddedBytesAmount(5),
-        });
+        push_piece(
+            &mut sealed_sector_a,
+            PieceMetadata {
+                piece_key: String::from("x"),
+                num_bytes: UnpaddedBytesAmount(5),
+                ..Default::default()
+            },
+        );
 
-        sealed_sector_a.pieces.push(PieceMetadata {
-            piece_key: String::from("x"),
-            num_bytes: UnpaddedBytesAmount(10),
-        });
+        push_piece(
+            &mut sealed_sector_a,
+            PieceMetadata {
+                piece_key: String::from("x"),
+                num_bytes: UnpaddedBytesAmount(10),
+                ..Default::default()
+            },
+        );
 
         let mut sealed_sector_b: StagedSectorMetadata = Default::default();
 
-        sealed_sector_b.pieces.push(PieceMetadata {
-            piece_key: String::from("x"),
-            num_bytes: UnpaddedBytesAmount(5),
-        });
+        push_piece(
+            &mut sealed_sector_b,
+            PieceMetadata {
+                piece_key: String::from("x"),
+                num_bytes: UnpaddedBytesAmount(5),
+                ..Default::default()
+            },
+        );
 
         let staged_sectors = vec![sealed_sector_a.clone(), sealed_sector_b.clone()];
 
@@ -189,4 +329,231 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_provisions_sectors_from_reserved_range() {
+        let (sector_store, _) = mock_sector_store();
+        let mgr = sector_store.inner.manager();
+
+        let mut staged_state: StagedState = Default::default();
+        let mut reserved_ranges = Vec::new();
+        let mut allocator = SectorIdAllocator::nonce();
+
+        provision_new_staged_sector(
+            mgr,
+            &mut staged_state,
+            &mut reserved_ranges,
+            &mut allocator,
+            None,
+        )
+        .unwrap();
+
+        let (start, end) =
+            reserve_sector_id_range(&mut staged_state, &mut reserved_ranges, 3).unwrap();
+        assert_eq!((2, 4), (start, end));
+
+        for expected in start..=end {
+            let sector_id = provision_new_staged_sector(
+                mgr,
+                &mut staged_state,
+                &mut reserved_ranges,
+                &mut allocator,
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(expected, sector_id);
+            assert!(staged_state.sectors.contains_key(&sector_id));
+        }
+
+        assert!(reserved_ranges.is_empty());
+
+        let sector_id = provision_new_staged_sector(
+            mgr,
+            &mut staged_state,
+            &mut reserved_ranges,
+            &mut allocator,
+            None,
+        )
+        .unwrap();
+        assert_eq!(5, sector_id);
+    }
+
+    #[test]
+    fn test_invalid_access_provisions_nothing() {
+        let (sector_store, mock) = mock_sector_store();
+        let mgr = sector_store.inner.manager();
+
+        let mut staged_state: StagedState = Default::default();
+        let mut reserved_ranges = Vec::new();
+        let mut allocator = SectorIdAllocator::nonce();
+
+        // a relative root can't confine anything
+        assert!(provision_new_staged_sector(
+            mgr,
+            &mut staged_state,
+            &mut reserved_ranges,
+            &mut allocator,
+            Some(Path::new("sectors")),
+        )
+        .is_err());
+
+        assert!(staged_state.sectors.is_empty());
+        assert!(mock.files.lock().unwrap().is_empty());
+
+        // and no sector id was used up
+        let sector_id = provision_new_staged_sector(
+            mgr,
+            &mut staged_state,
+            &mut reserved_ranges,
+            &mut allocator,
+            None,
+        )
+        .unwrap();
+        assert_eq!(1, sector_id);
+    }
+
+    #[test]
+    fn test_deterministic_allocator_reproduces_sector_ids() {
+        let add_pieces = || {
+            let (sector_store, _) = mock_sector_store();
+
+            let mut staged_state: StagedState = Default::default();
+            let mut reserved_ranges = Vec::new();
+            let mut allocator = SectorIdAllocator::deterministic(1234);
+
+            // each piece fills most of a sector, so every piece is written to
+            // a newly-provisioned sector
+            (0..4)
+                .map(|i| {
+                    let mut file = tempfile::NamedTempFile::new().unwrap();
+                    file.write_all(&[i; 1000]).unwrap();
+
+                    add_piece(
+                        &sector_store,
+                        &mut staged_state,
+                        &mut reserved_ranges,
+                        &mut allocator,
+                        format!("piece-{}", i),
+                        1000,
+                        file.path().to_str().unwrap().to_string(),
+                        None,
+                        &Histogram::new(vec![]).unwrap(),
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<SectorId>>()
+        };
+
+        let sector_ids = add_pieces();
+
+        assert_eq!(sector_ids, add_pieces());
+        assert_eq!(4, sector_ids.iter().collect::<HashSet<_>>().len());
+    }
+
+    #[test]
+    fn test_records_ingestion_latency() {
+        let (sector_store, mgr) = mock_sector_store();
+        let histogram = Histogram::with_clock(vec![1_900, 60_000_000], mgr.clock.clone()).unwrap();
+
+        let mut staged_state: StagedState = Default::default();
+        let mut reserved_ranges = Vec::new();
+        let mut allocator = SectorIdAllocator::nonce();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[7; 100]).unwrap();
+
+        // every tenth piece takes 2ms to write, as if over the network
+        for i in 0..1000 {
+            *mgr.write_delay.lock().unwrap() = if i % 10 == 0 {
+                Some(Duration::from_millis(2))
+            } else {
+                None
+            };
+
+            add_piece(
+                &sector_store,
+                &mut staged_state,
+                &mut reserved_ranges,
+                &mut allocator,
+                format!("piece-{}", i),
+                100,
+                file.path().to_str().unwrap().to_string(),
+                None,
+                &histogram,
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(vec![900, 100, 0], histogram.bucket_counts());
+        assert_eq!(100 * 2_000, histogram.sum());
+    }
+
+    #[test]
+    fn test_records_sector_crc32() {
+        let (sector_store, mgr) = mock_sector_store();
+
+        let mut staged_state: StagedState = Default::default();
+        let mut reserved_ranges = Vec::new();
+        let mut allocator = SectorIdAllocator::nonce();
+
+        for (i, byte) in [3u8, 5].iter().enumerate() {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(&[*byte; 100]).unwrap();
+
+            let sector_id = add_piece(
+                &sector_store,
+                &mut staged_state,
+                &mut reserved_ranges,
+                &mut allocator,
+                format!("piece-{}", i),
+                100,
+                file.path().to_str().unwrap().to_string(),
+                None,
+                &Histogram::new(vec![]).unwrap(),
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap();
+
+            // the CRC32 covers every piece written to the sector so far
+            let sector = &staged_state.sectors[&sector_id];
+            let contents = mgr.contents(&sector.sector_access).unwrap();
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&contents);
+
+            assert_eq!(100 * (i + 1), contents.len());
+            assert_eq!(
+                Some(hasher.finalize()),
+                sector.last_crc32.map(|(crc, _)| crc)
+            );
+        }
+    }
+
+    #[test]
+    fn test_expired_idempotency_keys_are_forgotten() {
+        let mut staged_state: StagedState = Default::default();
+        let now = SystemTime::now();
+
+        staged_state
+            .idempotency_keys
+            .insert([1; 16], (1, now + Duration::from_secs(60)));
+        staged_state
+            .idempotency_keys
+            .insert([2; 16], (2, now + Duration::from_secs(10)));
+
+        assert!(expire_idempotency_keys(&mut staged_state, now).is_empty());
+        assert_eq!(Some(2), idempotent_add_sector_id(&staged_state, &[2; 16]));
+
+        let expired = expire_idempotency_keys(&mut staged_state, now + Duration::from_secs(10));
+        assert_eq!(vec![[2; 16]], expired);
+
+        assert_eq!(None, idempotent_add_sector_id(&staged_state, &[2; 16]));
+        assert_eq!(Some(1), idempotent_add_sector_id(&staged_state, &[1; 16]));
+    }
 }
@@ -0,0 +1,79 @@
+use crate::api::sector_builder::errors::err_sector_too_empty;
+use crate::api::sector_builder::metadata::sum_piece_bytes;
+use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::error::Result;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+// The ratio of piece-bytes held by the staged sector to the number it could
+// hold.
+pub fn sector_fill_ratio(
+    staged_sector: &StagedSectorMetadata,
+    max_user_bytes_per_staged_sector: UnpaddedBytesAmount,
+) -> f64 {
+    let capacity = u64::from(max_user_bytes_per_staged_sector);
+    if capacity == 0 {
+        return 0.0;
+    }
+
+    u64::from(sum_piece_bytes(staged_sector)) as f64 / capacity as f64
+}
+
+// Produces an error if the staged sector is too empty to be worth sealing,
+// unless sealing is forced.
+pub fn check_seal_fill_ratio(
+    staged_sector: &StagedSectorMetadata,
+    max_user_bytes_per_staged_sector: UnpaddedBytesAmount,
+    min_seal_fill_ratio: f64,
+    force_seal_even_if_empty: bool,
+) -> Result<()> {
+    let fill_ratio = sector_fill_ratio(staged_sector, max_user_bytes_per_staged_sector);
+
+    if fill_ratio < min_seal_fill_ratio && !force_seal_even_if_empty {
+        return Err(err_sector_too_empty(fill_ratio).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::errors::SectorBuilderErr;
+    use crate::api::sector_builder::metadata::{pieces_by_offset, PieceMetadata};
+
+    fn staged_sector(num_bytes: u64) -> StagedSectorMetadata {
+        StagedSectorMetadata {
+            pieces: pieces_by_offset(vec![PieceMetadata {
+                num_bytes: UnpaddedBytesAmount(num_bytes),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_min_fill_ratio_blocks_sealing() {
+        let max = UnpaddedBytesAmount(100);
+
+        assert!(check_seal_fill_ratio(&staged_sector(10), max, 0.1, false).is_ok());
+        assert!(check_seal_fill_ratio(&staged_sector(1), max, 0.0, false).is_ok());
+
+        match check_seal_fill_ratio(&staged_sector(1), max, 0.1, false)
+            .unwrap_err()
+            .downcast_ref()
+        {
+            Some(SectorBuilderErr::SectorTooEmpty { fill_ratio }) => {
+                assert!((fill_ratio - 0.01).abs() < std::f64::EPSILON)
+            }
+            _ => panic!("expected sector to be too empty"),
+        }
+    }
+
+    #[test]
+    fn test_force_overrides_min_fill_ratio() {
+        let max = UnpaddedBytesAmount(100);
+
+        assert!(check_seal_fill_ratio(&staged_sector(1), max, 0.1, true).is_ok());
+        assert!(check_seal_fill_ratio(&staged_sector(0), max, 1.0, true).is_ok());
+    }
+}
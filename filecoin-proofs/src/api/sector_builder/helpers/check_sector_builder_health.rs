@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::api::sector_builder::health::Discrepancy;
+use crate::api::sector_builder::metadata::SealStatus;
+use crate::api::sector_builder::state::{SealedState, StagedState};
+
+// Compares the sector builder's metadata to the sectors on disk, producing
+// the (sorted) discrepancies found. Sealed sectors which have been archived
+// may have had their local replica removed.
+pub fn check_sector_builder_health(
+    staged_state: &StagedState,
+    sealed_state: &SealedState,
+    sector_bytes: u64,
+    sector_access_root: Option<&Path>,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+
+    for sector in staged_state.sectors.values() {
+        if let SealStatus::Failed(_) = sector.seal_status {
+            discrepancies.push(Discrepancy::SealFailed(sector.sector_id));
+        }
+
+        let path = resolve(&sector.sector_access, sector_access_root);
+        if !path.exists() {
+            discrepancies.push(Discrepancy::MissingStagedSectorAccess(sector.sector_id));
+        }
+    }
+
+    for sector in sealed_state.sectors.values() {
+        let path = resolve(&sector.sector_access, sector_access_root);
+
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() < sector_bytes => {
+                discrepancies.push(Discrepancy::TruncatedReplica(sector.sector_id));
+            }
+            Ok(_) => {}
+            Err(_) if sector.archive_receipt.is_some() => {}
+            Err(_) => {
+                discrepancies.push(Discrepancy::MissingSealedSectorAccess(sector.sector_id));
+            }
+        }
+    }
+
+    discrepancies.sort();
+    discrepancies
+}
+
+fn resolve(access: &str, sector_access_root: Option<&Path>) -> PathBuf {
+    match sector_access_root {
+        Some(root) => root.join(access),
+        None => PathBuf::from(access),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{
+        ArchiveReceipt, SealedSectorMetadata, StagedSectorMetadata,
+    };
+    use crate::api::sector_builder::SectorId;
+
+    fn staged(sector_id: SectorId, access: &str, seal_status: SealStatus) -> StagedSectorMetadata {
+        StagedSectorMetadata {
+            sector_id,
+            sector_access: access.to_string(),
+            seal_status,
+            ..Default::default()
+        }
+    }
+
+    fn sealed(sector_id: SectorId, access: &str, archived: bool) -> SealedSectorMetadata {
+        SealedSectorMetadata {
+            sector_id,
+            sector_access: access.to_string(),
+            archive_receipt: if archived {
+                Some(ArchiveReceipt {
+                    url: format!("archive/{}", sector_id),
+                    content_hash: [0; 32],
+                })
+            } else {
+                None
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_finds_discrepancies() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("staged-1"), b"").unwrap();
+        fs::write(dir.path().join("sealed-3"), vec![0; 1024]).unwrap();
+        fs::write(dir.path().join("sealed-4"), vec![0; 1000]).unwrap();
+
+        let mut staged_state: StagedState = Default::default();
+        let mut sealed_state: SealedState = Default::default();
+
+        let staged_sectors = vec![
+            staged(1, "staged-1", SealStatus::Pending),
+            staged(2, "staged-2", SealStatus::Failed("oops".to_string())),
+        ];
+        for sector in staged_sectors {
+            staged_state.sectors.insert(sector.sector_id, sector);
+        }
+
+        let sealed_sectors = vec![
+            sealed(3, "sealed-3", false),
+            sealed(4, "sealed-4", false),
+            sealed(5, "sealed-5", false),
+            sealed(6, "sealed-6", true),
+        ];
+        for sector in sealed_sectors {
+            sealed_state.sectors.insert(sector.sector_id, sector);
+        }
+
+        assert_eq!(
+            vec![
+                Discrepancy::MissingStagedSectorAccess(2),
+                Discrepancy::MissingSealedSectorAccess(5),
+                Discrepancy::TruncatedReplica(4),
+                Discrepancy::SealFailed(2),
+            ],
+            check_sector_builder_health(&staged_state, &sealed_state, 1024, Some(dir.path()))
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::api::sector_builder::health::Discrepancy;
+use crate::api::sector_builder::metadata::{SealStatus, StagedSectorMetadata};
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error;
+use crate::FCP_LOG;
+use sector_base::io::checksum::ChecksummingWriter;
+use sector_base::io::fr32::{padded_bytes, write_unpadded};
+use slog::*;
+
+// The size of the buffer through which a staged sector's file is read.
+const READ_BUFFER_BYTES: usize = 1 << 16;
+
+// Unpadded offsets which are multiples of this many bytes fall on the
+// boundary of (four) Fr32 elements, and so on a padded byte.
+const FR32_ALIGNED_UNPADDED_BYTES: u64 = 127;
+
+// Computes the CRC32 of the body of the staged sector's file, reading the
+// file a buffer at a time.
+pub fn compute_sector_crc32(
+    sector_store: &Arc<WrappedSectorStore>,
+    sector_access: &str,
+) -> error::Result<u32> {
+    let mut body = sector_store
+        .inner
+        .manager()
+        .open_and_verify_sector(sector_access)?;
+
+    crc32(&mut body)
+}
+
+// Records the CRC32 of the staged sector's file, e.g. after a piece has been
+// written to it. A sector whose CRC32 can't be computed isn't checked by the
+// health check until it can be.
+pub fn record_sector_crc32(
+    sector_store: &Arc<WrappedSectorStore>,
+    staged_sector: &mut StagedSectorMetadata,
+) {
+    staged_sector.last_crc32 = sector_crc32(
+        sector_store,
+        staged_sector.sector_id,
+        &staged_sector.sector_access,
+    );
+}
+
+// The CRC32 of the staged sector's file, and when it was computed, to be
+// recorded as the sector's last_crc32.
+pub fn sector_crc32(
+    sector_store: &Arc<WrappedSectorStore>,
+    sector_id: SectorId,
+    sector_access: &str,
+) -> Option<(u32, SystemTime)> {
+    match compute_sector_crc32(sector_store, sector_access) {
+        Ok(crc) => Some((crc, SystemTime::now())),
+        Err(err) => {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "failed to compute staged sector CRC32"; "sector_id" => sector_id, "error" => err);
+            None
+        }
+    }
+}
+
+// The staged sectors whose CRC32s are checked by check_staged_sector_crc32s:
+// those which are accepting data and have had their CRC32 recorded. The
+// scheduler hands these off, so that their files needn't be read on its
+// thread.
+pub fn staged_sectors_to_check(staged_state: &StagedState) -> Vec<StagedSectorMetadata> {
+    staged_state
+        .sectors
+        .values()
+        .filter(|sector| sector.seal_status == SealStatus::Pending && sector.last_crc32.is_some())
+        .cloned()
+        .collect()
+}
+
+// Compares the CRC32 of each staged sector with the one recorded after the
+// sector was last written. The pieces of a sector whose CRC32 has changed are
+// verified against their (BLAKE3) checksums, to tell a corrupted piece from a
+// change to the rest of the file.
+//
+// The sectors are a snapshot of the scheduler's metadata, so a piece may have
+// been written to one of them since. A sector's discrepancy is only reported
+// if its CRC32 is still the one recorded when the snapshot was taken, which
+// current_crc32s produces (in the order of the sector ids it's given) once
+// any write to the sectors has been recorded.
+pub fn check_staged_sector_crc32s<F>(
+    sector_store: &Arc<WrappedSectorStore>,
+    sectors: &[StagedSectorMetadata],
+    current_crc32s: F,
+) -> error::Result<Vec<Discrepancy>>
+where
+    F: FnOnce(Vec<SectorId>) -> error::Result<Vec<Option<(u32, SystemTime)>>>,
+{
+    let found: Vec<(&StagedSectorMetadata, Discrepancy)> = sectors
+        .iter()
+        .filter_map(|sector| {
+            check_staged_sector_crc32(sector_store, sector).map(|discrepancy| (sector, discrepancy))
+        })
+        .collect();
+
+    if found.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let current = current_crc32s(found.iter().map(|(sector, _)| sector.sector_id).collect())?;
+
+    Ok(found
+        .into_iter()
+        .zip(current)
+        .filter(|((sector, _), current)| sector.last_crc32 == *current)
+        .map(|((_, discrepancy), _)| discrepancy)
+        .collect())
+}
+
+fn check_staged_sector_crc32(
+    sector_store: &Arc<WrappedSectorStore>,
+    sector: &StagedSectorMetadata,
+) -> Option<Discrepancy> {
+    let expected = match (&sector.seal_status, sector.last_crc32) {
+        (SealStatus::Pending, Some((crc, _))) => crc,
+        _ => return None,
+    };
+
+    match compute_sector_crc32(sector_store, &sector.sector_access) {
+        Ok(crc) if crc == expected => None,
+        Ok(_) => match find_corrupted_piece(sector_store, sector) {
+            Ok(Some(piece_key)) => {
+                warn!(FCP_LOG, "staged piece does not match its checksum"; "sector_id" => sector.sector_id, "piece_key" => piece_key);
+                Some(Discrepancy::CorruptedStagedPiece(sector.sector_id))
+            }
+            Ok(None) => Some(Discrepancy::StagedSectorCrc32Mismatch(sector.sector_id)),
+            Err(err) => {
+                let err = format!("{}", err);
+                warn!(FCP_LOG, "failed to verify staged pieces"; "sector_id" => sector.sector_id, "error" => err);
+                Some(Discrepancy::StagedSectorCrc32Mismatch(sector.sector_id))
+            }
+        },
+        // A missing file is reported by check_sector_builder_health.
+        Err(err) => {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "failed to compute staged sector CRC32"; "sector_id" => sector.sector_id, "error" => err);
+            None
+        }
+    }
+}
+
+// Verifies the staged sector's pieces against their BLAKE3 checksums,
+// returning the key of the first which doesn't match. Pieces without a
+// checksum (e.g. those which have been redacted) aren't verified.
+fn find_corrupted_piece(
+    sector_store: &Arc<WrappedSectorStore>,
+    sector: &StagedSectorMetadata,
+) -> error::Result<Option<String>> {
+    let mut body = sector_store
+        .inner
+        .manager()
+        .open_and_verify_sector(&sector.sector_access)?;
+
+    for (offset, piece) in &sector.pieces {
+        let expected = match piece.checksum {
+            Some(checksum) => checksum,
+            None => continue,
+        };
+
+        let bytes = read_unpadded(&mut body, u64::from(*offset), u64::from(piece.num_bytes))?;
+
+        let mut hasher = ChecksummingWriter::new(io::sink());
+        hasher.write_all(&bytes)?;
+
+        if hasher.finalize().1 != expected {
+            return Ok(Some(piece.piece_key.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn crc32<R: Read>(source: &mut R) -> error::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; READ_BUFFER_BYTES];
+
+    loop {
+        match source.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finalize()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Reads the unpadded bytes [offset, offset + num_bytes) of a staged sector's
+// (Fr32-preprocessed) body, reading only the padded bytes which hold them.
+fn read_unpadded<F: Read + Seek>(
+    body: &mut F,
+    offset: u64,
+    num_bytes: u64,
+) -> error::Result<Vec<u8>> {
+    let aligned = offset - offset % FR32_ALIGNED_UNPADDED_BYTES;
+    let start = padded_bytes(aligned as usize) as u64;
+    let end = padded_bytes((offset + num_bytes) as usize) as u64;
+
+    let mut padded = Vec::with_capacity((end - start) as usize);
+    body.seek(SeekFrom::Start(start))?;
+    body.by_ref().take(end - start).read_to_end(&mut padded)?;
+
+    let mut unpadded = Vec::with_capacity(num_bytes as usize);
+    write_unpadded(
+        &padded,
+        &mut unpadded,
+        (offset - aligned) as usize,
+        num_bytes as usize,
+    )?;
+
+    Ok(unpadded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{push_piece, PieceMetadata};
+    use crate::api::sector_builder::test_utils::disk_sector_store;
+    use sector_base::io::fr32::write_padded;
+    use std::fs::OpenOptions;
+    use std::io::Cursor;
+
+    // A staged sector holding two pieces, with its CRC32 recorded.
+    fn staged_sector(sector_store: &Arc<WrappedSectorStore>) -> StagedSectorMetadata {
+        let mgr = sector_store.inner.manager();
+
+        let mut sector = StagedSectorMetadata {
+            sector_id: 1,
+            sector_access: mgr.new_staging_sector_access().unwrap(),
+            ..Default::default()
+        };
+
+        for (piece_key, byte) in &[("a", 1u8), ("b", 2)] {
+            let (num_bytes, checksum) = mgr
+                .write_and_preprocess_with_checksum(&sector.sector_access, &mut &[*byte; 200][..])
+                .unwrap();
+
+            push_piece(
+                &mut sector,
+                PieceMetadata {
+                    piece_key: piece_key.to_string(),
+                    num_bytes,
+                    checksum: Some(checksum),
+                    ..Default::default()
+                },
+            );
+        }
+
+        record_sector_crc32(sector_store, &mut sector);
+        sector
+    }
+
+    // Flips the bits of one byte of the file, counting from its end.
+    fn corrupt_byte(path: &str, from_end: i64) {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+
+        let mut byte = [0; 1];
+        file.seek(SeekFrom::End(-from_end)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+
+        byte[0] ^= 0xff;
+        file.seek(SeekFrom::End(-from_end)).unwrap();
+        file.write_all(&byte).unwrap();
+    }
+
+    #[test]
+    fn test_detects_corrupted_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let sector_store = disk_sector_store(dir.path());
+
+        let sector = staged_sector(&sector_store);
+        assert!(sector.last_crc32.is_some());
+
+        let mut staged_state: StagedState = Default::default();
+        staged_state
+            .sectors
+            .insert(sector.sector_id, sector.clone());
+
+        // the sectors' CRC32s haven't changed since they were handed off
+        let check = |staged_state: &StagedState| {
+            let sectors = staged_sectors_to_check(staged_state);
+            let current = sectors.iter().map(|sector| sector.last_crc32).collect();
+
+            check_staged_sector_crc32s(&sector_store, &sectors, |_| Ok(current)).unwrap()
+        };
+
+        // an untouched sector passes
+        assert!(check(&staged_state).is_empty());
+
+        // a byte of the second piece
+        corrupt_byte(&sector.sector_access, 100);
+
+        assert_ne!(
+            sector.last_crc32.unwrap().0,
+            compute_sector_crc32(&sector_store, &sector.sector_access).unwrap()
+        );
+        assert_eq!(
+            vec![Discrepancy::CorruptedStagedPiece(1)],
+            check(&staged_state)
+        );
+
+        // without its checksum, the piece can't be verified, so all that's
+        // known is that the file changed
+        staged_state
+            .sectors
+            .get_mut(&1)
+            .unwrap()
+            .pieces
+            .values_mut()
+            .for_each(|piece| piece.checksum = None);
+
+        assert_eq!(
+            vec![Discrepancy::StagedSectorCrc32Mismatch(1)],
+            check(&staged_state)
+        );
+    }
+
+    #[test]
+    fn test_ignores_sectors_written_since_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let sector_store = disk_sector_store(dir.path());
+
+        let sector = staged_sector(&sector_store);
+        corrupt_byte(&sector.sector_access, 100);
+
+        // a piece was written (and the sector's CRC32 recorded anew) while the
+        // sector's file was being read
+        let rewritten = Some((0, SystemTime::now()));
+
+        let discrepancies = check_staged_sector_crc32s(&sector_store, &[sector], |sector_ids| {
+            assert_eq!(vec![1], sector_ids);
+            Ok(vec![rewritten])
+        })
+        .unwrap();
+
+        assert!(discrepancies.is_empty());
+    }
+
+    #[test]
+    fn test_reads_unaligned_piece() {
+        let unpadded: Vec<u8> = (0..1000u32).map(|x| (x * 7 + 3) as u8).collect();
+
+        let mut body = Cursor::new(Vec::new());
+        write_padded(&mut &unpadded[..], &mut body).unwrap();
+
+        for (offset, num_bytes) in &[(0, 1000), (1, 10), (127, 127), (300, 555), (999, 1)] {
+            assert_eq!(
+                &unpadded[*offset..*offset + *num_bytes],
+                &read_unpadded(&mut body, *offset as u64, *num_bytes as u64).unwrap()[..]
+            );
+        }
+    }
+}
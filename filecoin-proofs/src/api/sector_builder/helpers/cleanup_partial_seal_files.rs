@@ -0,0 +1,78 @@
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error;
+use std::path::Path;
+use std::sync::Arc;
+
+const PARTIAL_SEAL_SUFFIX: &str = ".tmp";
+
+// Produces the access to which a sector is sealed before being renamed to its
+// final, sealed sector access. The partial access is a sibling of the final
+// one, so that the rename is atomic.
+pub fn partial_seal_access(sealed_sector_access: &str, sector_id: SectorId) -> String {
+    let file_name = format!("{}{}", sector_id, PARTIAL_SEAL_SUFFIX);
+
+    Path::new(sealed_sector_access)
+        .with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Removes the partially-written output of any seals which were interrupted
+// (e.g. by a crash) before they could be renamed to their final sealed sector
+// access, returning the number removed. Intended to be run at startup, before
+// any sealing has been scheduled.
+pub fn cleanup_partial_seal_files(sector_store: &Arc<WrappedSectorStore>) -> error::Result<usize> {
+    let mgr = sector_store.inner.manager();
+
+    let partial_accesses: Vec<String> = mgr
+        .list_sealed_sector_accesses()?
+        .into_iter()
+        .filter(|access| access.ends_with(PARTIAL_SEAL_SUFFIX))
+        .collect();
+
+    for access in &partial_accesses {
+        mgr.delete_sealed_sector_access(access)?;
+    }
+
+    Ok(partial_accesses.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+    use sector_base::api::sector_store::SectorManager;
+
+    #[test]
+    fn test_partial_seal_access() {
+        assert_eq!("sealed/42.tmp", partial_seal_access("sealed/abc", 42));
+    }
+
+    #[test]
+    fn test_removes_partial_seals_after_crash() {
+        let (sector_store, mgr) = mock_sector_store();
+
+        // a sector which was sealed before the crash
+        let original = mgr.new_sealed_sector_access().unwrap();
+        mgr.write_and_preprocess(&original, &mut &[1u8; 64][..])
+            .unwrap();
+
+        // and one which was being sealed when it happened
+        let sealed_sector_access = mgr.new_sealed_sector_access().unwrap();
+        let partial = partial_seal_access(&sealed_sector_access, 7);
+        mgr.files
+            .lock()
+            .unwrap()
+            .insert(partial.clone(), vec![2; 32]);
+
+        let staged = mgr.new_staging_sector_access().unwrap();
+
+        assert_eq!(1, cleanup_partial_seal_files(&sector_store).unwrap());
+
+        assert_eq!(None, mgr.contents(&partial));
+        assert_eq!(Some(vec![1; 64]), mgr.contents(&original));
+        assert_eq!(Some(vec![]), mgr.contents(&staged));
+
+        assert_eq!(0, cleanup_partial_seal_files(&sector_store).unwrap());
+    }
+}
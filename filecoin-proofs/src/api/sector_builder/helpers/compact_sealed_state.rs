@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use blake2b_simd::State as Blake2b;
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::api::sector_builder::kv_store::KeyValueStore;
+use crate::api::sector_builder::metadata::SealedSectorMetadata;
+use crate::api::sector_builder::state::*;
+use crate::api::sector_builder::SectorId;
+use crate::api::sector_builder::WrappedKeyValueStore;
+use crate::error::Result;
+
+const INDEX_KEY_SUFFIX: &[u8] = b"/index";
+const SECTOR_KEY_INFIX: &[u8] = b"/sealed/";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactionStats {
+    // size (in bytes) of the monolithic state entry which was removed
+    pub original_size: u64,
+
+    // total size (in bytes) of the index and per-sector entries
+    pub new_size: u64,
+
+    // number of per-sector entries
+    pub num_entries: usize,
+}
+
+// A sealed sector persisted under its own key, along with the digest of the
+// persisted value (so that unchanged sectors needn't be rewritten).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SealedSectorKey {
+    pub key: Vec<u8>,
+    pub digest: [u8; 32],
+}
+
+// Replaces the monolithic state entry once the state has been compacted. It
+// holds everything but the sealed sectors, which are found via their keys.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactStateIndex {
+    pub prover_id: [u8; 31],
+    pub staged: StagedState,
+    pub reserved_ranges: Vec<(SectorId, SectorId)>,
+    pub delta_sequence_number: u64,
+    pub sealed_sector_keys: Vec<SealedSectorKey>,
+}
+
+// Splits the monolithic state entry for the prover into an entry per sealed
+// sector, plus a (small) index entry, and removes the monolithic entry. From
+// then on, snapshots are persisted (and loaded) in that form.
+pub fn compact_sealed_state<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    prover_id: &[u8; 31],
+) -> Result<CompactionStats> {
+    let serialized = match kv_store.inner.get(prover_id)? {
+        Some(serialized) => serialized,
+        None => {
+            return load_index(kv_store, prover_id)?
+                .map(|index| CompactionStats {
+                    original_size: 0,
+                    new_size: 0,
+                    num_entries: index.sealed_sector_keys.len(),
+                })
+                .ok_or_else(|| format_err!("no state to compact"));
+        }
+    };
+
+    let snapshot: StateSnapshot = serde_cbor::from_slice(&serialized)?;
+    let (_, new_size) = persist_compacted(kv_store, &snapshot, None)?;
+
+    // Only once the compacted state is in place can the monolithic entry go.
+    kv_store.inner.delete(prover_id)?;
+
+    Ok(CompactionStats {
+        original_size: serialized.len() as u64,
+        new_size,
+        num_entries: snapshot.sealed.sectors.len(),
+    })
+}
+
+pub fn load_index<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    prover_id: &[u8; 31],
+) -> Result<Option<CompactStateIndex>> {
+    match kv_store.inner.get(&index_key(prover_id))? {
+        Some(val) => Ok(Some(serde_cbor::from_slice(&val)?)),
+        None => Ok(None),
+    }
+}
+
+// Splits the index into the snapshot it describes, but without any of its
+// sealed sectors, and the keys from which those can be loaded (with
+// load_sealed_sectors).
+pub fn split_index(index: CompactStateIndex) -> (StateSnapshot, Vec<SealedSectorKey>) {
+    let snapshot = StateSnapshot {
+        prover_id: index.prover_id,
+        staged: index.staged,
+        sealed: Default::default(),
+        reserved_ranges: index.reserved_ranges,
+        delta_sequence_number: index.delta_sequence_number,
+    };
+
+    (snapshot, index.sealed_sector_keys)
+}
+
+pub fn load_sealed_sectors<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    sealed_sector_keys: &[SealedSectorKey],
+) -> Result<SealedState> {
+    let mut sectors: HashMap<SectorId, SealedSectorMetadata> = Default::default();
+
+    for entry in sealed_sector_keys {
+        let val = kv_store
+            .inner
+            .get(&entry.key)?
+            .ok_or_else(|| format_err!("missing sealed sector entry in compacted state"))?;
+
+        let sector: SealedSectorMetadata = serde_cbor::from_slice(&val)?;
+        sectors.insert(sector.sector_id, sector);
+    }
+
+    Ok(SealedState { sectors })
+}
+
+// Persists the snapshot in compacted form, writing only those sealed sectors
+// which have changed since the previous index (with the given sealed sector
+// keys) was written, and returns the new index's sealed sector keys along
+// with the number of bytes written.
+pub fn persist_compacted<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    snapshot: &StateSnapshot,
+    previous: Option<&[SealedSectorKey]>,
+) -> Result<(Vec<SealedSectorKey>, u64)> {
+    let mut num_bytes_written = 0;
+
+    let mut sectors: Vec<&SealedSectorMetadata> = snapshot.sealed.sectors.values().collect();
+    sectors.sort_unstable_by_key(|x| x.sector_id);
+
+    let mut sealed_sector_keys = Vec::with_capacity(sectors.len());
+
+    for sector in sectors {
+        let serialized = serde_cbor::to_vec(sector)?;
+        let entry = SealedSectorKey {
+            key: sector_key(&snapshot.prover_id, sector.sector_id)?,
+            digest: digest(&serialized),
+        };
+
+        let unchanged = previous.map_or(false, |p| p.contains(&entry));
+
+        if !unchanged {
+            kv_store.inner.put(&entry.key, &serialized)?;
+            num_bytes_written += serialized.len() as u64;
+        }
+
+        sealed_sector_keys.push(entry);
+    }
+
+    let (sealed_sector_keys, index_size) = persist_index(kv_store, snapshot, sealed_sector_keys)?;
+    num_bytes_written += index_size;
+
+    // Entries for sectors which have since been deleted are removed last, so
+    // that an interrupted checkpoint leaves orphaned entries rather than an
+    // index referring to missing ones.
+    if let Some(previous) = previous {
+        for stale in previous {
+            if !sealed_sector_keys.iter().any(|x| x.key == stale.key) {
+                kv_store.inner.delete(&stale.key)?;
+            }
+        }
+    }
+
+    Ok((sealed_sector_keys, num_bytes_written))
+}
+
+// Persists everything in the snapshot but its sealed sectors, which haven't
+// been loaded (and so can't have changed) since the previous index, with the
+// given sealed sector keys, was written.
+pub fn persist_compacted_index<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    snapshot: &StateSnapshot,
+    sealed_sector_keys: &[SealedSectorKey],
+) -> Result<()> {
+    persist_index(kv_store, snapshot, sealed_sector_keys.to_vec())?;
+
+    Ok(())
+}
+
+// Persists the index, returning its sealed sector keys and size.
+fn persist_index<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    snapshot: &StateSnapshot,
+    sealed_sector_keys: Vec<SealedSectorKey>,
+) -> Result<(Vec<SealedSectorKey>, u64)> {
+    let index = CompactStateIndex {
+        prover_id: snapshot.prover_id,
+        staged: StagedState {
+            sector_id_nonce: snapshot.staged.sector_id_nonce,
+            sectors: snapshot.staged.sectors.clone(),
+            idempotency_keys: snapshot.staged.idempotency_keys.clone(),
+            redacted_pieces: snapshot.staged.redacted_pieces.clone(),
+        },
+        reserved_ranges: snapshot.reserved_ranges.clone(),
+        delta_sequence_number: snapshot.delta_sequence_number,
+        sealed_sector_keys,
+    };
+
+    let serialized = serde_cbor::to_vec(&index)?;
+    kv_store
+        .inner
+        .put(&index_key(&snapshot.prover_id), &serialized)?;
+
+    Ok((index.sealed_sector_keys, serialized.len() as u64))
+}
+
+fn index_key(prover_id: &[u8; 31]) -> Vec<u8> {
+    [&prover_id[..], INDEX_KEY_SUFFIX].concat()
+}
+
+fn sector_key(prover_id: &[u8; 31], sector_id: SectorId) -> Result<Vec<u8>> {
+    let mut key = [&prover_id[..], SECTOR_KEY_INFIX].concat();
+    key.write_u64::<BigEndian>(sector_id)?;
+
+    Ok(key)
+}
+
+fn digest(bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2b::new();
+    hasher.update(bytes);
+
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&hasher.finalize().as_bytes()[..32]);
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::helpers::snapshots::{
+        load_snapshot, load_snapshot_lazily, make_snapshot, persist_snapshot,
+        persist_uncompacted_snapshot,
+    };
+    use crate::api::sector_builder::kv_store::SledKvs;
+    use crate::api::sector_builder::metadata::PieceMetadata;
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+    use std::path::Path;
+
+    fn make_kv_store<P: AsRef<Path>>(dir: P) -> Arc<WrappedKeyValueStore<SledKvs>> {
+        Arc::new(WrappedKeyValueStore {
+            inner: Box::new(SledKvs::initialize(dir).unwrap()),
+        })
+    }
+
+    fn make_state(num_sealed_sectors: u64) -> (StagedState, SealedState) {
+        let staged_state = StagedState {
+            sector_id_nonce: num_sealed_sectors + 1,
+            sectors: Default::default(),
+            ..Default::default()
+        };
+
+        let mut sealed_state: SealedState = Default::default();
+
+        for sector_id in 1..=num_sealed_sectors {
+            sealed_state.sectors.insert(
+                sector_id,
+                SealedSectorMetadata {
+                    sector_id,
+                    sector_access: format!("sealed/{}", sector_id),
+                    pieces: vec![PieceMetadata {
+                        piece_key: format!("piece-{}", sector_id),
+                        num_bytes: UnpaddedBytesAmount(sector_id),
+                        ..Default::default()
+                    }],
+                    comm_r: [sector_id as u8; 32],
+                    proof: vec![sector_id as u8; 192],
+                    ..Default::default()
+                },
+            );
+        }
+
+        (staged_state, sealed_state)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let kv_store = make_kv_store(metadata_dir.path());
+        let prover_id = [3; 31];
+
+        let (staged_state, mut sealed_state) = make_state(20);
+        let snapshot = make_snapshot(&prover_id, &staged_state, &sealed_state, &[(40, 50)], 9);
+        persist_snapshot(&kv_store, &snapshot).unwrap();
+
+        let stats = compact_sealed_state(&kv_store, &prover_id).unwrap();
+        assert_eq!(20, stats.num_entries);
+        assert!(stats.original_size > 0 && stats.new_size > 0);
+
+        assert_eq!(None, kv_store.inner.get(&prover_id).unwrap());
+        assert_eq!(
+            snapshot,
+            load_snapshot(&kv_store, &prover_id).unwrap().unwrap()
+        );
+
+        // compacting again is a no-op
+        assert_eq!(
+            20,
+            compact_sealed_state(&kv_store, &prover_id)
+                .unwrap()
+                .num_entries
+        );
+
+        // later snapshots stay compacted, and drop deleted sectors
+        sealed_state.sectors.remove(&7);
+        sealed_state.sectors.get_mut(&8).unwrap().proof = vec![0; 192];
+
+        let snapshot = make_snapshot(&prover_id, &staged_state, &sealed_state, &[], 10);
+        persist_snapshot(&kv_store, &snapshot).unwrap();
+
+        assert_eq!(None, kv_store.inner.get(&prover_id).unwrap());
+        assert_eq!(
+            None,
+            kv_store
+                .inner
+                .get(&sector_key(&prover_id, 7).unwrap())
+                .unwrap()
+        );
+        assert_eq!(
+            snapshot,
+            load_snapshot(&kv_store, &prover_id).unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_nothing_to_compact() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let kv_store = make_kv_store(metadata_dir.path());
+
+        assert!(compact_sealed_state(&kv_store, &[0; 31]).is_err());
+    }
+
+    #[test]
+    fn test_persists_index_without_loading_sealed_sectors() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let kv_store = make_kv_store(metadata_dir.path());
+        let prover_id = [4; 31];
+
+        let (mut staged_state, sealed_state) = make_state(5);
+        let snapshot = make_snapshot(&prover_id, &staged_state, &sealed_state, &[], 1);
+        persist_snapshot(&kv_store, &snapshot).unwrap();
+        compact_sealed_state(&kv_store, &prover_id).unwrap();
+
+        let (loaded, sealed_sector_keys) = load_snapshot_lazily(&kv_store, &prover_id)
+            .unwrap()
+            .unwrap();
+        let sealed_sector_keys = sealed_sector_keys.unwrap();
+
+        assert!(loaded.sealed.sectors.is_empty());
+        assert_eq!(5, sealed_sector_keys.len());
+
+        // a change to the staged state alone leaves the sealed sectors be
+        staged_state.sector_id_nonce += 1;
+        let snapshot = make_snapshot(&prover_id, &staged_state, &loaded.sealed, &[], 2);
+        persist_compacted_index(&kv_store, &snapshot, &sealed_sector_keys).unwrap();
+
+        let reloaded = load_snapshot(&kv_store, &prover_id).unwrap().unwrap();
+        assert_eq!(staged_state, reloaded.staged);
+        assert_eq!(sealed_state, reloaded.sealed);
+        assert_eq!(2, reloaded.delta_sequence_number);
+        assert_eq!(
+            sealed_state,
+            load_sealed_sectors(&kv_store, &sealed_sector_keys).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_prefers_uncompacted_entry() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let kv_store = make_kv_store(metadata_dir.path());
+        let prover_id = [6; 31];
+
+        let (staged_state, mut sealed_state) = make_state(3);
+        let snapshot = make_snapshot(&prover_id, &staged_state, &sealed_state, &[], 1);
+        persist_compacted(&kv_store, &snapshot, None).unwrap();
+
+        // as left by a compaction which couldn't remove the single entry
+        sealed_state.sectors.remove(&2);
+        let snapshot = make_snapshot(&prover_id, &staged_state, &sealed_state, &[], 2);
+        persist_uncompacted_snapshot(&kv_store, &snapshot).unwrap();
+
+        // which is kept up to date in preference to the compacted state
+        let snapshot = make_snapshot(&prover_id, &staged_state, &sealed_state, &[], 3);
+        persist_snapshot(&kv_store, &snapshot).unwrap();
+
+        assert_eq!(
+            snapshot,
+            load_snapshot(&kv_store, &prover_id).unwrap().unwrap()
+        );
+
+        let (_, sealed_sector_keys) = load_snapshot_lazily(&kv_store, &prover_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(None, sealed_sector_keys);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::api::sector_builder::audit_log::{AuditLog, SectorEvent};
+use crate::api::sector_builder::deal_registry::DealRegistry;
+use crate::api::sector_builder::merkle_snapshot::merkle_snapshot_path;
+use crate::api::sector_builder::metadata::{BatchDeleteResult, SealStatus};
+use crate::api::sector_builder::state::{SealedState, StagedState};
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error;
+use crate::FCP_LOG;
+use sector_base::api::sector_store::SectorManager;
+use slog::*;
+use std::sync::Arc;
+
+// Deletes each of the provided sectors (sealed or staged) along with their
+// sector accesses. Sectors with an active deal are skipped and reported as
+// blocked unless force is set, in which case they're deleted anyway and the
+// deletion is recorded in the audit log. Without an audit log such sectors
+// aren't deleted even if forced, but reported as errored, as their deletion
+// couldn't be accounted for. A failure to delete one sector does not prevent
+// the others from being deleted.
+pub fn delete_sectors_batch(
+    sector_store: &Arc<WrappedSectorStore>,
+    staged_state: &mut StagedState,
+    sealed_state: &mut SealedState,
+    deal_registry: &DealRegistry,
+    mut audit_log: Option<&mut AuditLog>,
+    sector_ids: &[SectorId],
+    force: bool,
+) -> BatchDeleteResult {
+    let sector_mgr = sector_store.inner.manager();
+    let mut result: BatchDeleteResult = Default::default();
+
+    for &sector_id in sector_ids {
+        let has_active_deal = deal_registry.has_active_deal(sector_id);
+
+        if has_active_deal && !force {
+            result.blocked.push(sector_id);
+            continue;
+        }
+
+        if has_active_deal {
+            warn!(FCP_LOG, "force-deleting sector with active deal"; "sector_id" => sector_id, "target" => "audit");
+
+            // Refuse to delete a sector whose deletion we can't account for.
+            let audited = match audit_log.as_mut() {
+                Some(log) => log
+                    .append(&SectorEvent::ForceDeleted { sector_id })
+                    .map_err(|err| format!("{}", err)),
+                None => Err("no audit log is configured to record its deletion".to_string()),
+            };
+
+            if let Err(err) = audited {
+                error!(FCP_LOG, "refusing to force-delete sector with active deal"; "sector_id" => sector_id, "error" => &err, "target" => "audit");
+                result.errored.push((sector_id, err));
+                continue;
+            }
+        }
+
+        match delete_sector(sector_mgr, staged_state, sealed_state, sector_id) {
+            Ok(()) => result.deleted.push(sector_id),
+            Err(err) => result.errored.push((sector_id, format!("{}", err))),
+        }
+    }
+
+    result
+}
+
+fn delete_sector(
+    sector_mgr: &SectorManager,
+    staged_state: &mut StagedState,
+    sealed_state: &mut SealedState,
+    sector_id: SectorId,
+) -> error::Result<()> {
+    if let Some(sector) = sealed_state.sectors.get(&sector_id) {
+        sector_mgr.delete_sealed_sector_access(&sector.sector_access)?;
+
+        // The replica's tree is of no use without the replica.
+        if sector.has_merkle_snapshot {
+            if let Err(err) = std::fs::remove_file(merkle_snapshot_path(sector)) {
+                let err = format!("{}", err);
+                warn!(FCP_LOG, "failed to delete Merkle tree snapshot"; "sector_id" => sector_id, "error" => err);
+            }
+        }
+
+        sealed_state.sectors.remove(&sector_id);
+
+        return Ok(());
+    }
+
+    let (access, seal_status) = staged_state
+        .sectors
+        .get(&sector_id)
+        .map(|s| (s.sector_access.clone(), s.seal_status.clone()))
+        .ok_or_else(|| format_err!("no sector with id {} found", sector_id))?;
+
+    if seal_status == SealStatus::Sealing {
+        return Err(format_err!("sector {} is being sealed", sector_id));
+    }
+
+    sector_mgr.delete_staging_sector_access(&access)?;
+    staged_state.sectors.remove(&sector_id);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{SealedSectorMetadata, StagedSectorMetadata};
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+    use std::collections::HashSet;
+
+    struct TestDealRegistry {
+        sectors_with_deals: HashSet<SectorId>,
+    }
+
+    impl DealRegistry for TestDealRegistry {
+        fn has_active_deal(&self, sector_id: SectorId) -> bool {
+            self.sectors_with_deals.contains(&sector_id)
+        }
+    }
+
+    fn setup(sector_store: &Arc<WrappedSectorStore>) -> (StagedState, SealedState) {
+        let mgr = sector_store.inner.manager();
+        let mut staged_state: StagedState = Default::default();
+        let mut sealed_state: SealedState = Default::default();
+
+        for sector_id in 1..=2 {
+            staged_state.sectors.insert(
+                sector_id,
+                StagedSectorMetadata {
+                    sector_id,
+                    sector_access: mgr.new_staging_sector_access().unwrap(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        for sector_id in 3..=4 {
+            sealed_state.sectors.insert(
+                sector_id,
+                SealedSectorMetadata {
+                    sector_id,
+                    sector_access: mgr.new_sealed_sector_access().unwrap(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        (staged_state, sealed_state)
+    }
+
+    fn registry() -> TestDealRegistry {
+        TestDealRegistry {
+            sectors_with_deals: vec![2, 3].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_skips_sectors_with_active_deals() {
+        let (sector_store, mgr) = mock_sector_store();
+        let (mut staged_state, mut sealed_state) = setup(&sector_store);
+        let sealed_access = sealed_state.sectors[&3].sector_access.clone();
+
+        let result = delete_sectors_batch(
+            &sector_store,
+            &mut staged_state,
+            &mut sealed_state,
+            &registry(),
+            None,
+            &[1, 2, 3, 4, 5],
+            false,
+        );
+
+        assert_eq!(vec![1, 4], result.deleted);
+        assert_eq!(vec![2, 3], result.blocked);
+        assert_eq!(
+            vec![5],
+            result.errored.iter().map(|x| x.0).collect::<Vec<_>>()
+        );
+
+        assert!(!staged_state.sectors.contains_key(&1));
+        assert!(staged_state.sectors.contains_key(&2));
+        assert!(sealed_state.sectors.contains_key(&3));
+        assert!(!sealed_state.sectors.contains_key(&4));
+
+        // blocked sectors keep their files
+        assert!(mgr.contents(&sealed_access).is_some());
+        assert_eq!(2, mgr.files.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_force_deletes_and_audits() {
+        let (sector_store, mgr) = mock_sector_store();
+        let (mut staged_state, mut sealed_state) = setup(&sector_store);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut audit_log = AuditLog::new(dir.path().join("audit.log"));
+
+        let result = delete_sectors_batch(
+            &sector_store,
+            &mut staged_state,
+            &mut sealed_state,
+            &registry(),
+            Some(&mut audit_log),
+            &[1, 2, 3, 4],
+            true,
+        );
+
+        assert_eq!(vec![1, 2, 3, 4], result.deleted);
+        assert!(result.blocked.is_empty());
+        assert!(result.errored.is_empty());
+        assert!(staged_state.sectors.is_empty());
+        assert!(sealed_state.sectors.is_empty());
+        assert!(mgr.files.lock().unwrap().is_empty());
+
+        assert_eq!(
+            vec![
+                SectorEvent::ForceDeleted { sector_id: 2 },
+                SectorEvent::ForceDeleted { sector_id: 3 },
+            ],
+            audit_log.read_events().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_refuses_to_force_delete_without_audit_log() {
+        let (sector_store, mgr) = mock_sector_store();
+        let (mut staged_state, mut sealed_state) = setup(&sector_store);
+
+        let result = delete_sectors_batch(
+            &sector_store,
+            &mut staged_state,
+            &mut sealed_state,
+            &registry(),
+            None,
+            &[1, 2, 3, 4],
+            true,
+        );
+
+        assert_eq!(vec![1, 4], result.deleted);
+        assert!(result.blocked.is_empty());
+        assert_eq!(
+            vec![2, 3],
+            result.errored.iter().map(|x| x.0).collect::<Vec<_>>()
+        );
+
+        assert!(staged_state.sectors.contains_key(&2));
+        assert!(sealed_state.sectors.contains_key(&3));
+        assert_eq!(2, mgr.files.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_refuses_to_delete_sealing_sector() {
+        let (sector_store, _) = mock_sector_store();
+        let (mut staged_state, mut sealed_state) = setup(&sector_store);
+
+        staged_state.sectors.get_mut(&1).unwrap().seal_status = SealStatus::Sealing;
+
+        let result = delete_sectors_batch(
+            &sector_store,
+            &mut staged_state,
+            &mut sealed_state,
+            &registry(),
+            None,
+            &[1],
+            false,
+        );
+
+        assert!(result.deleted.is_empty());
+        assert_eq!(1, result.errored.len());
+        assert!(staged_state.sectors.contains_key(&1));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::api::sector_builder::manifest::{
+    PieceManifest, PieceManifestEntry, PieceManifestFilter,
+};
+use crate::api::sector_builder::metadata::PieceMetadata;
+use crate::api::sector_builder::state::SealedState;
+
+// Produces an unsigned manifest of every sealed piece matching the filter.
+// Entries are ordered by sector id and then by byte offset so that the
+// manifest (and therefore its signature) is stable across calls.
+pub fn generate_piece_manifest(
+    prover_id: &[u8; 31],
+    sealed_state: &SealedState,
+    filter: &PieceManifestFilter,
+) -> PieceManifest {
+    let mut entries: Vec<PieceManifestEntry> = Default::default();
+
+    for sealed_sector in sealed_state.sectors.values() {
+        let mut byte_offset = 0;
+
+        for piece in &sealed_sector.pieces {
+            if matches_filter(prover_id, piece, filter) {
+                entries.push(PieceManifestEntry {
+                    sector_id: sealed_sector.sector_id,
+                    comm_r: sealed_sector.comm_r,
+                    byte_offset,
+                    num_bytes: piece.num_bytes,
+                    piece_key: piece.piece_key.clone(),
+                    sealing_location: sealed_sector.sealing_location.clone(),
+                });
+            }
+
+            byte_offset += u64::from(piece.num_bytes);
+        }
+    }
+
+    entries.sort_by_key(|entry| (entry.sector_id, entry.byte_offset));
+
+    PieceManifest {
+        prover_id: *prover_id,
+        entries,
+        signature: Default::default(),
+    }
+}
+
+fn matches_filter(
+    prover_id: &[u8; 31],
+    piece: &PieceMetadata,
+    filter: &PieceManifestFilter,
+) -> bool {
+    match filter {
+        PieceManifestFilter::All => true,
+        PieceManifestFilter::Label(label) => piece.piece_key.starts_with(label.as_str()),
+        PieceManifestFilter::ProverId(id) => id == prover_id,
+        PieceManifestFilter::TimeRange { start, end } => {
+            piece.added_at >= *start && piece.added_at < *end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{SealedSectorMetadata, SealingLocation};
+    use crate::api::sector_builder::signing::SectorBuilderKey;
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn make_key(seed: u8) -> SectorBuilderKey {
+        SectorBuilderKey::from_secret(&[seed; 32]).expect("could not make key")
+    }
+
+    fn make_piece(piece_key: &str, num_bytes: u64, added_at_secs: u64) -> PieceMetadata {
+        PieceMetadata {
+            piece_key: piece_key.to_string(),
+            num_bytes: UnpaddedBytesAmount(num_bytes),
+            added_at: UNIX_EPOCH + Duration::from_secs(added_at_secs),
+            checksum: None,
+            access_token_hash: None,
+        }
+    }
+
+    fn setup() -> SealedState {
+        let mut sealed_state: SealedState = Default::default();
+
+        sealed_state.sectors.insert(
+            1,
+            SealedSectorMetadata {
+                sector_id: 1,
+                comm_r: [1; 32],
+                pieces: vec![make_piece("alice-a", 10, 100), make_piece("bob-a", 20, 200)],
+                sealing_location: Some(SealingLocation {
+                    datacenter_id: "fra-1".to_string(),
+                    region: "eu-central".to_string(),
+                    country_code: *b"DE",
+                }),
+                ..Default::default()
+            },
+        );
+
+        sealed_state.sectors.insert(
+            2,
+            SealedSectorMetadata {
+                sector_id: 2,
+                comm_r: [2; 32],
+                pieces: vec![make_piece("alice-b", 30, 300)],
+                ..Default::default()
+            },
+        );
+
+        sealed_state
+    }
+
+    #[test]
+    fn test_filters() {
+        let sealed_state = setup();
+        let prover_id = [7; 31];
+
+        let all = generate_piece_manifest(&prover_id, &sealed_state, &PieceManifestFilter::All);
+        let keys: Vec<&str> = all.entries.iter().map(|e| e.piece_key.as_str()).collect();
+        assert_eq!(vec!["alice-a", "bob-a", "alice-b"], keys);
+        assert_eq!(10, all.entries[1].byte_offset);
+        assert_eq!([1; 32], all.entries[1].comm_r);
+        assert_eq!(
+            *b"DE",
+            all.entries[1]
+                .sealing_location
+                .as_ref()
+                .unwrap()
+                .country_code
+        );
+        assert_eq!(None, all.entries[2].sealing_location);
+
+        let alice = generate_piece_manifest(
+            &prover_id,
+            &sealed_state,
+            &PieceManifestFilter::Label("alice".to_string()),
+        );
+        let keys: Vec<&str> = alice.entries.iter().map(|e| e.piece_key.as_str()).collect();
+        assert_eq!(vec!["alice-a", "alice-b"], keys);
+
+        let other_prover = generate_piece_manifest(
+            &prover_id,
+            &sealed_state,
+            &PieceManifestFilter::ProverId([8; 31]),
+        );
+        assert!(other_prover.entries.is_empty());
+
+        let ranged = generate_piece_manifest(
+            &prover_id,
+            &sealed_state,
+            &PieceManifestFilter::TimeRange {
+                start: UNIX_EPOCH + Duration::from_secs(150),
+                end: UNIX_EPOCH + Duration::from_secs(300),
+            },
+        );
+        let keys: Vec<&str> = ranged
+            .entries
+            .iter()
+            .map(|e| e.piece_key.as_str())
+            .collect();
+        assert_eq!(vec!["bob-a"], keys);
+    }
+
+    #[test]
+    fn test_sign_and_verify_round_trip() {
+        let sealed_state = setup();
+        let key = make_key(42);
+        let imposter = make_key(43);
+
+        let mut manifest =
+            generate_piece_manifest(&[7; 31], &sealed_state, &PieceManifestFilter::All);
+        manifest.sign(&key).expect("failed to sign manifest");
+
+        let json = manifest.to_json().expect("failed to serialize manifest");
+        let loaded = PieceManifest::from_json(&json).expect("failed to deserialize manifest");
+
+        assert_eq!(manifest, loaded);
+        assert!(loaded.verify_signature(&key.public_key).unwrap());
+        assert!(!loaded.verify_signature(&imposter.public_key).unwrap());
+
+        let mut tampered = loaded.clone();
+        tampered.entries[0].byte_offset += 1;
+        assert!(!tampered.verify_signature(&key.public_key).unwrap());
+
+        let mut relocated = loaded.clone();
+        relocated.entries[0]
+            .sealing_location
+            .as_mut()
+            .unwrap()
+            .country_code = *b"US";
+        assert!(!relocated.verify_signature(&key.public_key).unwrap());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::api::sector_builder::errors::{err_post_timeout, err_unrecov};
+use crate::error;
+use crate::error::ExpectWithBacktrace;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// The most abandoned PoSt generators which may still be running at once.
+pub const MAX_ABANDONED_POST_GENERATORS: usize = 1;
+
+const FATAL_NOLOCK: &str = "could not acquire PoSt generator lock";
+
+// Runs the provided PoSt generator, giving up on it if no proof has been
+// produced within the timeout. We've no way to kill a thread, so an abandoned
+// generator is instead signalled through the flag it's given. Generators which
+// can't check the flag (such as internal::generate_post) run to completion in
+// the background, holding on to their inputs and (partial) state until then.
+// To bound what's leaked this way, abandoned generators are counted in
+// abandoned, and no generator is started while MAX_ABANDONED_POST_GENERATORS
+// of them are still running.
+pub fn generate_post_with_timeout<T, F>(
+    timeout: Option<Duration>,
+    abandoned: &Arc<AtomicUsize>,
+    generate: F,
+) -> error::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(Arc<AtomicBool>) -> error::Result<T> + Send + 'static,
+{
+    let aborted = Arc::new(AtomicBool::new(false));
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return generate(aborted),
+    };
+
+    if abandoned.load(Ordering::SeqCst) >= MAX_ABANDONED_POST_GENERATORS {
+        return Err(err_unrecov(
+            "an abandoned PoSt generation is still running; try again once it has finished",
+        )
+        .into());
+    }
+
+    let (tx, rx) = mpsc::sync_channel(1);
+
+    // Set once the generator has finished or been abandoned, whichever
+    // happens first, so that an abandoned generator is uncounted exactly once.
+    let settled = Arc::new(Mutex::new(false));
+
+    let generator_aborted = aborted.clone();
+    let generator_done = GeneratorDone {
+        settled: settled.clone(),
+        abandoned: abandoned.clone(),
+    };
+    thread::spawn(move || {
+        let result = generate(generator_aborted);
+        drop(generator_done);
+
+        // The receiver is gone if we were abandoned.
+        let _ = tx.send(result);
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let mut settled = settled.lock().expects(FATAL_NOLOCK);
+            if *settled {
+                // The generator finished just as we gave up on it.
+                drop(settled);
+                return rx.recv().unwrap_or_else(|_| {
+                    Err(err_unrecov("PoSt generation thread panicked").into())
+                });
+            }
+            *settled = true;
+
+            abandoned.fetch_add(1, Ordering::SeqCst);
+            aborted.store(true, Ordering::SeqCst);
+
+            Err(err_post_timeout(timeout).into())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(err_unrecov("PoSt generation thread panicked").into())
+        }
+    }
+}
+
+// Marks a generator as finished when dropped, which it is even if the
+// generator panics.
+struct GeneratorDone {
+    settled: Arc<Mutex<bool>>,
+    abandoned: Arc<AtomicUsize>,
+}
+
+impl Drop for GeneratorDone {
+    fn drop(&mut self) {
+        let mut settled = self.settled.lock().expects(FATAL_NOLOCK);
+
+        if *settled {
+            self.abandoned.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        *settled = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::errors::SectorBuilderErr;
+    use std::time::Instant;
+
+    #[test]
+    fn test_returns_result_within_timeout() {
+        let abandoned = Arc::new(AtomicUsize::new(0));
+
+        let result =
+            generate_post_with_timeout(Some(Duration::from_secs(5)), &abandoned, |_| Ok(42));
+        assert_eq!(42, result.unwrap());
+
+        let result = generate_post_with_timeout(None, &abandoned, |_| Ok(42));
+        assert_eq!(42, result.unwrap());
+    }
+
+    #[test]
+    fn test_abandons_slow_generator() {
+        let timeout = Duration::from_millis(200);
+        let observed_abort = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let abandoned = Arc::new(AtomicUsize::new(0));
+
+        let start = Instant::now();
+
+        let observed = observed_abort.clone();
+        let result: error::Result<()> =
+            generate_post_with_timeout(Some(timeout), &abandoned, move |aborted| {
+                // A generator which checks in on its abort flag periodically.
+                for _ in 0..100 {
+                    if aborted.load(Ordering::SeqCst) {
+                        observed.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+
+                done_tx.send(()).unwrap();
+                Ok(())
+            });
+
+        let elapsed = start.elapsed();
+
+        match result.unwrap_err().downcast_ref() {
+            Some(SectorBuilderErr::PostGenerationTimeout(t)) => assert_eq!(timeout, *t),
+            _ => panic!("expected a timeout"),
+        }
+
+        assert!(elapsed >= timeout);
+        assert!(elapsed < timeout + Duration::from_millis(100));
+
+        // the generator is told to stop, and does
+        done_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(observed_abort.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_bounds_abandoned_generators() {
+        let timeout = Duration::from_millis(50);
+        let abandoned = Arc::new(AtomicUsize::new(0));
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // A generator which ignores its abort flag.
+        let result: error::Result<()> =
+            generate_post_with_timeout(Some(timeout), &abandoned, move |_| {
+                release_rx.recv().unwrap();
+                done_tx.send(()).unwrap();
+                Ok(())
+            });
+        assert!(result.is_err());
+        assert_eq!(1, abandoned.load(Ordering::SeqCst));
+
+        // no more generators are started while it runs
+        let started = Arc::new(AtomicBool::new(false));
+        let s = started.clone();
+        let result = generate_post_with_timeout(Some(timeout), &abandoned, move |_| {
+            s.store(true, Ordering::SeqCst);
+            Ok(42)
+        });
+        assert!(result.is_err());
+        assert!(!started.load(Ordering::SeqCst));
+
+        // until it finishes
+        release_tx.send(()).unwrap();
+        done_rx.recv().unwrap();
+        while abandoned.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+
+        let result =
+            generate_post_with_timeout(Some(Duration::from_secs(5)), &abandoned, |_| Ok(42));
+        assert_eq!(42, result.unwrap());
+    }
+}
@@ -67,10 +67,14 @@ mod tests {
             staged: StagedState {
                 sector_id_nonce: 0,
                 sectors: staged_sectors,
+                ..Default::default()
             },
             sealed: SealedState {
                 sectors: sealed_sectors,
             },
+            reserved_ranges: Default::default(),
+            delta_sequence_number: 0,
+            public_key: None,
         }
     }
 
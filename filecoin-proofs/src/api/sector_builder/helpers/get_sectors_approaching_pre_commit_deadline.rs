@@ -0,0 +1,85 @@
+use crate::api::sector_builder::state::SealedState;
+use crate::api::sector_builder::SectorId;
+
+// Produces the ids of the sealed sectors whose pre-commit deadline is fewer
+// than warning_epochs epochs away (or has passed), most urgent first.
+// Sectors without a deadline are never reported.
+pub fn get_sectors_approaching_pre_commit_deadline(
+    sealed_state: &SealedState,
+    current_epoch: u64,
+    warning_epochs: u64,
+) -> Vec<SectorId> {
+    let mut approaching: Vec<(u64, SectorId)> = sealed_state
+        .sectors
+        .values()
+        .filter_map(|sector| {
+            sector
+                .pre_commit_deadline
+                .filter(|deadline| deadline.saturating_sub(current_epoch) < warning_epochs)
+                .map(|deadline| (deadline, sector.sector_id))
+        })
+        .collect();
+
+    approaching.sort();
+    approaching
+        .into_iter()
+        .map(|(_, sector_id)| sector_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::SealedSectorMetadata;
+
+    fn sealed_state(deadlines: &[(SectorId, Option<u64>)]) -> SealedState {
+        let mut state: SealedState = Default::default();
+
+        for (sector_id, pre_commit_deadline) in deadlines {
+            state.sectors.insert(
+                *sector_id,
+                SealedSectorMetadata {
+                    sector_id: *sector_id,
+                    pre_commit_deadline: *pre_commit_deadline,
+                    ..Default::default()
+                },
+            );
+        }
+
+        state
+    }
+
+    #[test]
+    fn test_orders_by_deadline() {
+        let state = sealed_state(&[
+            (1, Some(150)),
+            (2, Some(120)),
+            (3, None),
+            (4, Some(500)),
+            (5, Some(90)),
+        ]);
+
+        // sector 5's deadline has passed, and it's the most urgent
+        assert_eq!(
+            vec![5, 2, 1],
+            get_sectors_approaching_pre_commit_deadline(&state, 100, 60)
+        );
+    }
+
+    #[test]
+    fn test_warning_window_boundary() {
+        let state = sealed_state(&[(1, Some(1000))]);
+
+        // the sector is reported for the last warning_epochs epochs before
+        // its deadline
+        assert!(get_sectors_approaching_pre_commit_deadline(&state, 900, 100).is_empty());
+        assert_eq!(
+            vec![1],
+            get_sectors_approaching_pre_commit_deadline(&state, 901, 100)
+        );
+        assert_eq!(
+            vec![1],
+            get_sectors_approaching_pre_commit_deadline(&state, 1000, 100)
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::api::sector_builder::state::SealedState;
+use crate::api::sector_builder::SectorId;
+
+// Produces the ids, in ascending order, of the sealed sectors which were
+// sealed in the country with the provided country code.
+pub fn get_sectors_by_region(sealed_state: &SealedState, country_code: [u8; 2]) -> Vec<SectorId> {
+    let mut sector_ids: Vec<SectorId> = sealed_state
+        .sectors
+        .values()
+        .filter(|sector| {
+            sector
+                .sealing_location
+                .as_ref()
+                .map(|location| location.country_code == country_code)
+                .unwrap_or(false)
+        })
+        .map(|sector| sector.sector_id)
+        .collect();
+
+    sector_ids.sort();
+    sector_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{SealedSectorMetadata, SealingLocation};
+
+    #[test]
+    fn test_filters_by_country_code() {
+        let frankfurt = SealingLocation {
+            datacenter_id: "fra-1".to_string(),
+            region: "eu-central".to_string(),
+            country_code: *b"DE",
+        };
+
+        let oregon = SealingLocation {
+            datacenter_id: "pdx-2".to_string(),
+            region: "us-west".to_string(),
+            country_code: *b"US",
+        };
+
+        let mut sealed_state: SealedState = Default::default();
+
+        let locations = vec![
+            (1, Some(frankfurt.clone())),
+            (2, Some(oregon.clone())),
+            (3, Some(frankfurt)),
+            (4, None),
+            (5, Some(oregon)),
+        ];
+
+        for (sector_id, sealing_location) in locations {
+            sealed_state.sectors.insert(
+                sector_id,
+                SealedSectorMetadata {
+                    sector_id,
+                    sealing_location,
+                    ..Default::default()
+                },
+            );
+        }
+
+        assert_eq!(vec![1, 3], get_sectors_by_region(&sealed_state, *b"DE"));
+        assert_eq!(vec![2, 5], get_sectors_by_region(&sealed_state, *b"US"));
+        assert!(get_sectors_by_region(&sealed_state, *b"FR").is_empty());
+    }
+}
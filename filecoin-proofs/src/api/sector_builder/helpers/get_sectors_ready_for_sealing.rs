@@ -36,6 +36,7 @@ pub fn get_sectors_ready_for_sealing(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::sector_builder::metadata::pieces_by_offset;
     use crate::api::sector_builder::metadata::PieceMetadata;
     use crate::api::sector_builder::metadata::StagedSectorMetadata;
     use crate::api::sector_builder::state::StagedState;
@@ -58,10 +59,11 @@ mod tests {
             sector_id,
             StagedSectorMetadata {
                 sector_id,
-                pieces: vec![PieceMetadata {
+                pieces: pieces_by_offset(vec![PieceMetadata {
                     piece_key: format!("{}", sector_id),
                     num_bytes: UnpaddedBytesAmount(num_bytes),
-                }],
+                    ..Default::default()
+                }]),
                 seal_status,
                 ..Default::default()
             },
@@ -78,6 +80,7 @@ mod tests {
         let state = StagedState {
             sector_id_nonce: 100,
             sectors: m,
+            ..Default::default()
         };
 
         let to_seal: Vec<SectorId> =
@@ -98,6 +101,7 @@ mod tests {
         let state = StagedState {
             sector_id_nonce: 100,
             sectors: m,
+            ..Default::default()
         };
 
         let to_seal: Vec<SectorId> =
@@ -120,6 +124,7 @@ mod tests {
         let state = StagedState {
             sector_id_nonce: 100,
             sectors: m,
+            ..Default::default()
         };
 
         let to_seal: Vec<SectorId> =
@@ -142,6 +147,7 @@ mod tests {
         let state = StagedState {
             sector_id_nonce: 100,
             sectors: m,
+            ..Default::default()
         };
 
         let to_seal: Vec<SectorId> =
@@ -164,6 +170,7 @@ mod tests {
         let state = StagedState {
             sector_id_nonce: 100,
             sectors: m,
+            ..Default::default()
         };
 
         let to_seal: Vec<SectorId> =
@@ -0,0 +1,296 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::api::sector_builder::errors::*;
+use crate::api::sector_builder::helpers::check_staged_sector_crc32::compute_sector_crc32;
+use crate::api::sector_builder::helpers::validate_sector_access::validate_sector_access;
+use crate::api::sector_builder::metadata::{push_piece, PieceMetadata};
+use crate::api::sector_builder::metadata::{SealStatus, StagedSectorMetadata};
+use crate::api::sector_builder::sector_id_allocator::SectorIdAllocator;
+use crate::api::sector_builder::state::{SealedState, StagedState};
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error;
+use crate::FCP_LOG;
+use slog::*;
+
+// A staged sector's file to which the pieces of a manifest have been written
+// (and verified), which has yet to be given a sector id.
+#[derive(Debug)]
+pub struct ImportedSector {
+    pub pieces: Vec<PieceMetadata>,
+    pub sector_access: String,
+    pub last_crc32: Option<(u32, SystemTime)>,
+}
+
+// Writes the pieces of the manifest, whose (unpadded) bytes are read from the
+// source one after another, to a new staging sector access. Each piece is
+// verified against its checksum as it's written, and the access is deleted
+// if any doesn't match (or can't be read). As the source may be slow (e.g.
+// a download), this is done on the caller's thread; the sector is added to
+// the staged state by commit_imported_sector.
+pub fn write_imported_sector(
+    sector_store: &Arc<WrappedSectorStore>,
+    piece_manifest: &[PieceMetadata],
+    source: &mut Read,
+) -> error::Result<ImportedSector> {
+    let sector_mgr = sector_store.inner.manager();
+    let sector_max = sector_store
+        .inner
+        .sector_config()
+        .max_unsealed_bytes_per_sector();
+
+    let num_bytes: u64 = piece_manifest.iter().map(|p| u64::from(p.num_bytes)).sum();
+    if num_bytes > u64::from(sector_max) {
+        return Err(err_overflow(num_bytes, u64::from(sector_max)).into());
+    }
+
+    if let Some(piece) = piece_manifest.iter().find(|p| p.checksum.is_none()) {
+        return Err(err_unrecov(format!("piece {} has no checksum", piece.piece_key)).into());
+    }
+
+    let mut piece_keys = HashSet::new();
+    if let Some(piece) = piece_manifest
+        .iter()
+        .find(|p| !piece_keys.insert(&p.piece_key))
+    {
+        return Err(err_unrecov(format!("piece {} is listed twice", piece.piece_key)).into());
+    }
+
+    let sector_access = sector_mgr.new_staging_sector_access()?;
+
+    let written = piece_manifest
+        .iter()
+        .try_for_each(|piece| -> error::Result<()> {
+            let (num_bytes_written, checksum) = sector_mgr.write_and_preprocess_with_checksum(
+                &sector_access,
+                &mut (&mut *source).take(u64::from(piece.num_bytes)),
+            )?;
+
+            if num_bytes_written != piece.num_bytes {
+                return Err(err_inc_write(
+                    u64::from(num_bytes_written),
+                    u64::from(piece.num_bytes),
+                )
+                .into());
+            }
+
+            if Some(checksum) != piece.checksum {
+                return Err(err_unrecov(format!(
+                    "piece {} does not match its checksum",
+                    piece.piece_key
+                ))
+                .into());
+            }
+
+            Ok(())
+        })
+        .and_then(|()| {
+            sector_mgr
+                .sync_staging_sector_access(&sector_access)
+                .map_err(Into::into)
+        });
+
+    if let Err(err) = written {
+        let _ = sector_mgr.delete_staging_sector_access(&sector_access);
+        return Err(err);
+    }
+
+    // A sector whose CRC32 can't be computed isn't checked by the health
+    // check until it can be (see record_sector_crc32).
+    let last_crc32 = match compute_sector_crc32(sector_store, &sector_access) {
+        Ok(crc) => Some((crc, SystemTime::now())),
+        Err(err) => {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "failed to compute imported sector CRC32"; "sector_access" => sector_access.as_str(), "error" => err);
+            None
+        }
+    };
+
+    Ok(ImportedSector {
+        pieces: piece_manifest.to_vec(),
+        sector_access,
+        last_crc32,
+    })
+}
+
+// Adds the imported sector to the staged state, allocating its sector id. The
+// sector accepts more pieces, like any other. Produces an error, having
+// changed nothing, if its access is invalid or if any of its pieces' keys is
+// that of a piece already staged or sealed; the caller deletes its access.
+pub fn commit_imported_sector(
+    staged_state: &mut StagedState,
+    sealed_state: &SealedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    sector_id_allocator: &mut SectorIdAllocator,
+    sector_access_root: Option<&Path>,
+    imported: ImportedSector,
+) -> error::Result<SectorId> {
+    validate_sector_access(&imported.sector_access, sector_access_root)?;
+
+    let mut existing_pieces = staged_state
+        .sectors
+        .values()
+        .flat_map(|sector| sector.pieces.values())
+        .chain(
+            sealed_state
+                .sectors
+                .values()
+                .flat_map(|sector| sector.pieces.iter()),
+        );
+
+    let piece_keys: HashSet<&str> = imported
+        .pieces
+        .iter()
+        .map(|p| p.piece_key.as_str())
+        .collect();
+
+    if let Some(piece) = existing_pieces.find(|p| piece_keys.contains(p.piece_key.as_str())) {
+        return Err(err_unrecov(format!("piece {} already exists", piece.piece_key)).into());
+    }
+
+    let sector_id = sector_id_allocator.allocate(staged_state, reserved_ranges);
+
+    let mut meta = StagedSectorMetadata {
+        pieces: Default::default(),
+        sector_access: imported.sector_access,
+        sector_id,
+        seal_status: SealStatus::Pending,
+        merkle_tree_state: None,
+        last_crc32: imported.last_crc32,
+        provisioned_at: SystemTime::now(),
+    };
+
+    for piece in imported.pieces {
+        push_piece(&mut meta, piece);
+    }
+
+    staged_state.sectors.insert(sector_id, meta);
+
+    Ok(sector_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::SealedSectorMetadata;
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+    use sector_base::io::checksum::ChecksummingWriter;
+    use std::io::{self, Write};
+
+    fn manifest_piece(piece_key: &str, bytes: &[u8]) -> PieceMetadata {
+        let mut hasher = ChecksummingWriter::new(io::sink());
+        hasher.write_all(bytes).unwrap();
+
+        PieceMetadata {
+            piece_key: piece_key.to_string(),
+            num_bytes: UnpaddedBytesAmount(bytes.len() as u64),
+            checksum: Some(hasher.finalize().1),
+            ..Default::default()
+        }
+    }
+
+    fn commit(
+        staged_state: &mut StagedState,
+        sealed_state: &SealedState,
+        imported: ImportedSector,
+    ) -> error::Result<SectorId> {
+        commit_imported_sector(
+            staged_state,
+            sealed_state,
+            &mut Vec::new(),
+            &mut SectorIdAllocator::nonce(),
+            None,
+            imported,
+        )
+    }
+
+    #[test]
+    fn test_imports_pieces() {
+        let (sector_store, mgr) = mock_sector_store();
+        let mut staged_state: StagedState = Default::default();
+
+        let manifest = vec![
+            manifest_piece("a", &[1; 100]),
+            manifest_piece("b", &[2; 50]),
+        ];
+        let source = [vec![1; 100], vec![2; 50]].concat();
+
+        let imported = write_imported_sector(&sector_store, &manifest, &mut &source[..]).unwrap();
+        assert!(imported.last_crc32.is_some());
+
+        let sector_id = commit(&mut staged_state, &Default::default(), imported).unwrap();
+
+        let sector = &staged_state.sectors[&sector_id];
+        assert_eq!(
+            manifest,
+            sector.pieces.values().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(source), mgr.contents(&sector.sector_access));
+    }
+
+    #[test]
+    fn test_rejects_corrupted_piece() {
+        let (sector_store, mgr) = mock_sector_store();
+
+        let manifest = vec![
+            manifest_piece("a", &[1; 100]),
+            manifest_piece("b", &[2; 50]),
+        ];
+
+        // a byte of the second piece is wrong
+        let mut source = [vec![1; 100], vec![2; 50]].concat();
+        source[120] = 3;
+
+        assert!(write_imported_sector(&sector_store, &manifest, &mut &source[..]).is_err());
+
+        // as is a source which is too short
+        assert!(write_imported_sector(&sector_store, &manifest, &mut &source[..120]).is_err());
+
+        assert!(mgr.files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rejects_duplicate_piece_keys() {
+        let (sector_store, mgr) = mock_sector_store();
+
+        // a manifest listing a key twice isn't written at all
+        let manifest = vec![manifest_piece("a", &[1; 10]), manifest_piece("a", &[2; 10])];
+        let source = [vec![1; 10], vec![2; 10]].concat();
+
+        assert!(write_imported_sector(&sector_store, &manifest, &mut &source[..]).is_err());
+        assert!(mgr.files.lock().unwrap().is_empty());
+
+        // nor is a sector committed whose pieces' keys are staged or sealed
+        let mut staged_state: StagedState = Default::default();
+        let mut sealed_state: SealedState = Default::default();
+        sealed_state.sectors.insert(
+            9,
+            SealedSectorMetadata {
+                sector_id: 9,
+                pieces: vec![manifest_piece("b", &[3; 10])],
+                ..Default::default()
+            },
+        );
+
+        let staged = vec![manifest_piece("a", &[1; 10])];
+        let imported = write_imported_sector(&sector_store, &staged, &mut &[1; 10][..]).unwrap();
+        let sector_id = commit(&mut staged_state, &sealed_state, imported).unwrap();
+
+        for piece_key in &["a", "b"] {
+            let manifest = vec![manifest_piece(piece_key, &[4; 10])];
+            let imported =
+                write_imported_sector(&sector_store, &manifest, &mut &[4; 10][..]).unwrap();
+
+            assert!(commit(&mut staged_state, &sealed_state, imported).is_err());
+        }
+
+        assert_eq!(
+            vec![sector_id],
+            staged_state.sectors.keys().cloned().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::incremental_merkle_tree::IncrementalMerkleTree;
+use crate::api::sector_builder::metadata::{
+    sum_piece_bytes, MerkleTreeState, SealStatus, StagedSectorMetadata,
+};
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use std::sync::Arc;
+
+// The partially-computed data tree (commD) of a staged sector.
+#[derive(Clone, Debug)]
+pub struct IncrementalCommD {
+    pub sector_id: SectorId,
+    tree: IncrementalMerkleTree,
+}
+
+impl IncrementalCommD {
+    // The number of unpadded piece-bytes which have been added to the tree.
+    pub fn num_unpadded_bytes(&self) -> UnpaddedBytesAmount {
+        self.tree.num_unpadded_bytes()
+    }
+
+    pub fn merkle_tree_state(&self) -> MerkleTreeState {
+        self.tree.state()
+    }
+}
+
+// Begins incremental computation of commD for a pending staged sector, from
+// the tree covering the first num_pieces of its pieces. Unless num_pieces is
+// zero, that tree must have been persisted in the sector's metadata. Every
+// piece subsequently written to the sector must be provided, in order, to
+// update_incremental_comm_d.
+pub fn start_incremental_comm_d(
+    sector_store: &Arc<WrappedSectorStore>,
+    staged_state: &StagedState,
+    sector_id: SectorId,
+    num_pieces: usize,
+) -> error::Result<IncrementalCommD> {
+    let sector = staged_state
+        .sectors
+        .get(&sector_id)
+        .ok_or_else(|| err_unrecov(format!("no staged sector with id {}", sector_id)))?;
+
+    if sector.seal_status != SealStatus::Pending {
+        return Err(err_unrecov(format!("sector {} is not accepting data", sector_id)).into());
+    }
+
+    if num_pieces == 0 {
+        let sector_bytes = sector_store.inner.sector_config().sector_bytes();
+
+        return Ok(IncrementalCommD {
+            sector_id,
+            tree: IncrementalMerkleTree::new(sector_bytes),
+        });
+    }
+
+    let num_bytes: u64 = sector
+        .pieces
+        .values()
+        .take(num_pieces)
+        .map(|p| u64::from(p.num_bytes))
+        .sum();
+
+    match sector.merkle_tree_state {
+        Some(ref state) if state.num_unpadded_bytes == num_bytes => Ok(IncrementalCommD {
+            sector_id,
+            tree: IncrementalMerkleTree::from_state(state)?,
+        }),
+        _ => Err(err_unrecov(format!(
+            "no merkle tree state covering {} pieces of sector {}",
+            num_pieces, sector_id
+        ))
+        .into()),
+    }
+}
+
+pub fn update_incremental_comm_d(
+    inc_comm_d: &mut IncrementalCommD,
+    new_piece: &[u8],
+) -> error::Result<()> {
+    inc_comm_d.tree.add_piece(new_piece)
+}
+
+// Completes the tree by zero-padding the sector and returns its root, which is
+// the commD sealing will produce for the same piece-bytes.
+pub fn finish_incremental_comm_d(inc_comm_d: &IncrementalCommD) -> error::Result<[u8; 32]> {
+    inc_comm_d.tree.root()
+}
+
+// Produces the commD of a staged sector from its persisted merkle tree state,
+// if that state covers all of the sector's pieces.
+pub fn comm_d_from_merkle_tree_state(
+    sector: &StagedSectorMetadata,
+) -> error::Result<Option<[u8; 32]>> {
+    match sector.merkle_tree_state {
+        Some(ref state) if state.num_unpadded_bytes == u64::from(sum_piece_bytes(sector)) => {
+            Ok(Some(IncrementalMerkleTree::from_state(state)?.root()?))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{self, PieceMetadata};
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+
+    fn setup() -> (Arc<WrappedSectorStore>, StagedState) {
+        let (sector_store, _) = mock_sector_store();
+        let mut staged_state: StagedState = Default::default();
+
+        staged_state.sectors.insert(
+            1,
+            StagedSectorMetadata {
+                sector_id: 1,
+                seal_status: SealStatus::Pending,
+                ..Default::default()
+            },
+        );
+
+        (sector_store, staged_state)
+    }
+
+    fn push_piece(staged_state: &mut StagedState, num_bytes: usize) {
+        metadata::push_piece(
+            staged_state.sectors.get_mut(&1).unwrap(),
+            PieceMetadata {
+                num_bytes: UnpaddedBytesAmount(num_bytes as u64),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_resumes_from_persisted_state() {
+        let (sector_store, mut staged_state) = setup();
+        let pieces: Vec<Vec<u8>> = vec![vec![1; 127], vec![2; 31], vec![3; 300]];
+
+        let mut whole = start_incremental_comm_d(&sector_store, &staged_state, 1, 0).unwrap();
+        for piece in &pieces {
+            update_incremental_comm_d(&mut whole, piece).unwrap();
+        }
+
+        for (i, piece) in pieces.iter().enumerate() {
+            let mut inc = start_incremental_comm_d(&sector_store, &staged_state, 1, i).unwrap();
+            update_incremental_comm_d(&mut inc, piece).unwrap();
+
+            push_piece(&mut staged_state, piece.len());
+            staged_state.sectors.get_mut(&1).unwrap().merkle_tree_state =
+                Some(inc.merkle_tree_state());
+        }
+
+        let sector = &staged_state.sectors[&1];
+
+        assert_eq!(whole.num_unpadded_bytes(), sum_piece_bytes(sector));
+        assert_eq!(
+            Some(finish_incremental_comm_d(&whole).unwrap()),
+            comm_d_from_merkle_tree_state(sector).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_chunked_updates_match_whole_piece() {
+        let (sector_store, staged_state) = setup();
+        let max_bytes = sector_store
+            .inner
+            .sector_config()
+            .max_unsealed_bytes_per_sector();
+
+        let piece: Vec<u8> = (0..u64::from(max_bytes)).map(|x| (x * 3) as u8).collect();
+
+        let mut whole = start_incremental_comm_d(&sector_store, &staged_state, 1, 0).unwrap();
+        update_incremental_comm_d(&mut whole, &piece).unwrap();
+
+        let mut chunked = start_incremental_comm_d(&sector_store, &staged_state, 1, 0).unwrap();
+        for chunk in piece.chunks(33) {
+            update_incremental_comm_d(&mut chunked, chunk).unwrap();
+        }
+
+        assert_eq!(
+            finish_incremental_comm_d(&whole).unwrap(),
+            finish_incremental_comm_d(&chunked).unwrap()
+        );
+        assert_eq!(whole.merkle_tree_state(), chunked.merkle_tree_state());
+
+        // the sector is now full
+        assert!(update_incremental_comm_d(&mut chunked, &[0]).is_err());
+    }
+
+    #[test]
+    fn test_requires_state_covering_pieces() {
+        let (sector_store, mut staged_state) = setup();
+
+        push_piece(&mut staged_state, 10);
+        assert!(start_incremental_comm_d(&sector_store, &staged_state, 1, 1).is_err());
+        assert_eq!(
+            None,
+            comm_d_from_merkle_tree_state(&staged_state.sectors[&1]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_requires_pending_sector() {
+        let (sector_store, mut staged_state) = setup();
+
+        assert!(start_incremental_comm_d(&sector_store, &staged_state, 2, 0).is_err());
+
+        staged_state.sectors.get_mut(&1).unwrap().seal_status = SealStatus::Sealing;
+        assert!(start_incremental_comm_d(&sector_store, &staged_state, 1, 0).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::api::sector_builder::errors::{err_overflow, err_unrecov};
+use crate::api::sector_builder::metadata::MerkleTreeState;
+use crate::error;
+use sector_base::api::bytes_amount::{PaddedBytesAmount, UnpaddedBytesAmount};
+use sector_base::io::fr32::write_padded;
+use std::io::{Cursor, Read};
+use storage_proofs::drgraph::DefaultTreeHasher;
+use storage_proofs::hasher::{Domain, Hasher};
+
+pub const NODE_SIZE: usize = 32;
+
+// The number of data bits Fr32-preprocessing packs into each 32-byte node.
+const NODE_DATA_BITS: u64 = 254;
+
+pub type TreeDomain = <DefaultTreeHasher as Hasher>::Domain;
+
+// A staged sector's data tree, built as pieces are added to the sector. Rather
+// than retaining the whole tree, we keep only the roots of the complete
+// subtrees at its right edge (the "frontier") along with the preprocessed
+// bytes which don't yet make up a complete leaf, so adding a piece hashes only
+// the nodes which the piece's leaves affect. The tree is finished by padding
+// the sector with zeroes, just as sealing does.
+#[derive(Clone, Debug)]
+pub struct IncrementalMerkleTree {
+    num_leaves: usize,
+    num_leaves_added: usize,
+    num_unpadded_bytes: u64,
+    frontier: Vec<(usize, TreeDomain)>,
+    tail: Vec<u8>,
+}
+
+impl IncrementalMerkleTree {
+    pub fn new(sector_bytes: PaddedBytesAmount) -> IncrementalMerkleTree {
+        IncrementalMerkleTree {
+            num_leaves: usize::from(sector_bytes) / NODE_SIZE,
+            num_leaves_added: 0,
+            num_unpadded_bytes: 0,
+            frontier: Default::default(),
+            tail: Default::default(),
+        }
+    }
+
+    // Resumes building a tree from its persisted state.
+    pub fn from_state(state: &MerkleTreeState) -> error::Result<IncrementalMerkleTree> {
+        if !state.num_leaves.is_power_of_two()
+            || state.num_leaves_added > state.num_leaves
+            || state.tail.len() >= NODE_SIZE
+        {
+            return Err(err_unrecov("malformed merkle tree state").into());
+        }
+
+        let frontier = state
+            .frontier
+            .iter()
+            .map(|(h, node)| Ok((*h, TreeDomain::try_from_bytes(node)?)))
+            .collect::<error::Result<Vec<_>>>()?;
+
+        Ok(IncrementalMerkleTree {
+            num_leaves: state.num_leaves,
+            num_leaves_added: state.num_leaves_added,
+            num_unpadded_bytes: state.num_unpadded_bytes,
+            frontier,
+            tail: state.tail.clone(),
+        })
+    }
+
+    pub fn state(&self) -> MerkleTreeState {
+        MerkleTreeState {
+            num_leaves: self.num_leaves,
+            num_leaves_added: self.num_leaves_added,
+            num_unpadded_bytes: self.num_unpadded_bytes,
+            frontier: self
+                .frontier
+                .iter()
+                .map(|(h, node)| (*h, domain_to_bytes(node)))
+                .collect(),
+            tail: self.tail.clone(),
+        }
+    }
+
+    // The number of unpadded piece-bytes which have been added to the tree.
+    pub fn num_unpadded_bytes(&self) -> UnpaddedBytesAmount {
+        UnpaddedBytesAmount(self.num_unpadded_bytes)
+    }
+
+    // Preprocesses the provided piece-bytes as they would be when written to
+    // the staged sector and adds each completed leaf to the tree. The piece
+    // may be provided whole or in consecutive chunks.
+    pub fn add_piece(&mut self, new_piece: &[u8]) -> error::Result<()> {
+        let max_bytes = self.num_leaves as u64 * NODE_DATA_BITS / 8;
+        let num_unpadded_bytes = self.num_unpadded_bytes + new_piece.len() as u64;
+
+        if num_unpadded_bytes > max_bytes {
+            return Err(err_overflow(num_unpadded_bytes, max_bytes).into());
+        }
+
+        // The tail always begins at a node boundary, so padding it in
+        // isolation produces the same bytes as padding the whole sector.
+        let mut cursor = Cursor::new(std::mem::replace(&mut self.tail, Vec::new()));
+        write_padded(&mut &new_piece[..], &mut cursor)?;
+        let mut padded = cursor.into_inner();
+
+        // A node is complete once all of its data bits have been written,
+        // which can't be determined from the number of padded bytes alone.
+        let num_complete_leaves = (num_unpadded_bytes * 8 / NODE_DATA_BITS) as usize;
+        let num_new_leaves = num_complete_leaves - self.num_leaves_added;
+
+        for node in padded.chunks(NODE_SIZE).take(num_new_leaves) {
+            self.frontier.push((0, TreeDomain::try_from_bytes(node)?));
+            merge_frontier(&mut self.frontier);
+        }
+
+        self.num_leaves_added = num_complete_leaves;
+        self.tail = padded.split_off(num_new_leaves * NODE_SIZE);
+        self.num_unpadded_bytes = num_unpadded_bytes;
+
+        Ok(())
+    }
+
+    // Completes (a copy of) the tree by zero-padding the sector and returns
+    // its root, which is the commD sealing will produce for the same
+    // piece-bytes.
+    pub fn root(&self) -> error::Result<[u8; 32]> {
+        let mut frontier = self.frontier.clone();
+
+        if !self.tail.is_empty() {
+            let mut node = self.tail.clone();
+            node.resize(NODE_SIZE, 0);
+
+            frontier.push((0, TreeDomain::try_from_bytes(&node)?));
+            merge_frontier(&mut frontier);
+        }
+
+        // Sector sizes are powers of two, and so are their leaf-counts.
+        let height = self.num_leaves.trailing_zeros() as usize;
+
+        // Roots of all-zero subtrees, indexed by height.
+        let zeroes = (0..height).fold(vec![TreeDomain::default()], |mut acc, h| {
+            let z = acc[h];
+            acc.push(hash_node::<DefaultTreeHasher>(z, z, h));
+            acc
+        });
+
+        let root = loop {
+            match frontier.last().cloned() {
+                None => break zeroes[height],
+                Some((h, root)) if h == height => break root,
+                Some((h, root)) => {
+                    frontier.pop();
+                    frontier.push((h + 1, hash_node::<DefaultTreeHasher>(root, zeroes[h], h)));
+                    merge_frontier(&mut frontier);
+                }
+            }
+        };
+
+        Ok(domain_to_bytes(&root))
+    }
+}
+
+// Combines adjacent subtrees of equal height until the frontier's heights are
+// strictly decreasing.
+pub fn merge_frontier(frontier: &mut Vec<(usize, TreeDomain)>) {
+    merge_frontier_with(frontier, &mut |_, _| ());
+}
+
+// As merge_frontier, passing each combined subtree (and its height) to visit.
+fn merge_frontier_with(
+    frontier: &mut Vec<(usize, TreeDomain)>,
+    visit: &mut FnMut(usize, &TreeDomain),
+) {
+    while frontier.len() > 1 && frontier[frontier.len() - 1].0 == frontier[frontier.len() - 2].0 {
+        let (h, right) = frontier.pop().expect("frontier has two elements");
+        let (_, left) = frontier.pop().expect("frontier has two elements");
+
+        let node = hash_node::<DefaultTreeHasher>(left, right, h);
+        visit(h + 1, &node);
+        frontier.push((h + 1, node));
+    }
+}
+
+// Computes the root of the tree whose leaves are the first num_leaves (a power
+// of two) nodes of a replica. The replica is read once, and only the right
+// edge of its tree is held in memory.
+pub fn merkle_root<R: Read>(replica: R, num_leaves: u64) -> error::Result<[u8; 32]> {
+    walk_merkle_tree(replica, num_leaves, &mut |_, _, _| ())
+}
+
+// As merkle_root, passing each subtree to visit as it's completed, along with
+// its height and its index among the subtrees of that height.
+pub fn walk_merkle_tree<R: Read>(
+    mut replica: R,
+    num_leaves: u64,
+    visit: &mut FnMut(usize, u64, &TreeDomain),
+) -> error::Result<[u8; 32]> {
+    let mut frontier: Vec<(usize, TreeDomain)> = Vec::new();
+    let mut node = [0; NODE_SIZE];
+
+    for num_read in 1..=num_leaves {
+        replica
+            .read_exact(&mut node)
+            .map_err(|_| err_unrecov("replica is shorter than a sector"))?;
+
+        let leaf = TreeDomain::try_from_bytes(&node)?;
+        visit(0, num_read - 1, &leaf);
+        frontier.push((0, leaf));
+
+        merge_frontier_with(&mut frontier, &mut |height, subtree| {
+            visit(height, (num_read >> height) - 1, subtree)
+        });
+    }
+
+    // The leaf-count is a power of two, so the frontier has been merged into
+    // the root.
+    match frontier.as_slice() {
+        [(_, root)] => Ok(domain_to_bytes(root)),
+        _ => Err(err_unrecov(format!(
+            "cannot compute the root of a tree of {} leaves",
+            num_leaves
+        ))
+        .into()),
+    }
+}
+
+pub fn hash_node<H: Hasher>(left: H::Domain, right: H::Domain, height: usize) -> H::Domain {
+    H::Function::default().node(left, right, height)
+}
+
+pub fn domain_to_bytes(node: &TreeDomain) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(&node.into_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils::batch_merkle_root;
+
+    // Builds the tree the way sealing does: from the whole preprocessed
+    // sector.
+    fn batch_root(pieces: &[Vec<u8>], sector_bytes: PaddedBytesAmount) -> [u8; 32] {
+        let mut cursor = Cursor::new(Vec::new());
+        for piece in pieces {
+            write_padded(&mut &piece[..], &mut cursor).unwrap();
+        }
+
+        let mut data = cursor.into_inner();
+        data.resize(usize::from(sector_bytes), 0);
+
+        batch_merkle_root(&data)
+    }
+
+    #[test]
+    fn test_incremental_matches_batch() {
+        let sector_bytes = PaddedBytesAmount(8192);
+
+        for &num_pieces in &[1, 2, 4, 8, 16] {
+            // piece sizes which don't line up with node boundaries
+            let pieces: Vec<Vec<u8>> = (0..num_pieces)
+                .map(|i| (0..(200 + i * 13)).map(|x| (x * (i + 1)) as u8).collect())
+                .collect();
+
+            let mut tree = IncrementalMerkleTree::new(sector_bytes);
+            for piece in &pieces {
+                tree.add_piece(piece).unwrap();
+            }
+
+            assert_eq!(
+                batch_root(&pieces, sector_bytes),
+                tree.root().unwrap(),
+                "roots differ for {} pieces",
+                num_pieces
+            );
+        }
+    }
+
+    #[test]
+    fn test_resumes_from_state() {
+        let sector_bytes = PaddedBytesAmount(1024);
+        let pieces: Vec<Vec<u8>> = vec![vec![1; 100], vec![2; 333], vec![3; 7]];
+
+        let mut whole = IncrementalMerkleTree::new(sector_bytes);
+        let mut resumed = IncrementalMerkleTree::new(sector_bytes);
+
+        for piece in &pieces {
+            whole.add_piece(piece).unwrap();
+
+            let state: MerkleTreeState =
+                serde_cbor::from_slice(&serde_cbor::to_vec(&resumed.state()).unwrap()).unwrap();
+
+            resumed = IncrementalMerkleTree::from_state(&state).unwrap();
+            resumed.add_piece(piece).unwrap();
+        }
+
+        assert_eq!(whole.root().unwrap(), resumed.root().unwrap());
+        assert_eq!(whole.state(), resumed.state());
+    }
+
+    #[test]
+    fn test_rejects_overflow() {
+        let mut tree = IncrementalMerkleTree::new(PaddedBytesAmount(1024));
+
+        tree.add_piece(&[0; 1016]).unwrap();
+        assert!(tree.add_piece(&[0]).is_err());
+    }
+}
@@ -1,6 +1,31 @@
 pub mod add_piece;
+pub mod check_seal_fill_ratio;
+pub mod check_sector_builder_health;
+pub mod check_staged_sector_crc32;
+pub mod cleanup_partial_seal_files;
+pub mod compact_sealed_state;
+pub mod delete_sectors_batch;
+pub mod generate_piece_manifest;
+pub mod generate_post_with_timeout;
 pub mod get_seal_status;
+pub mod get_sectors_approaching_pre_commit_deadline;
+pub mod get_sectors_by_region;
 pub mod get_sectors_ready_for_sealing;
+pub mod import_sector;
+pub mod incremental_comm_d;
+pub mod incremental_merkle_tree;
+pub mod obfuscate_fill_time;
+pub mod piece_size_model;
+pub mod prefetch_piece;
+pub mod proof_deduplication_index;
+pub mod redact_piece;
+pub mod render_state_diagram;
+pub mod reserve_sector_id_range;
 pub mod retrieve_piece;
+pub mod sample_sectors_for_audit;
 pub mod seal;
+pub mod seal_trigger;
 pub mod snapshots;
+pub mod staged_capacity;
+pub mod validate_sector_access;
+pub mod verify_cross_state_consistency;
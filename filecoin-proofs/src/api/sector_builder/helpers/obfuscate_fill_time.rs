@@ -0,0 +1,121 @@
+use std::io::{self, Read};
+
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use blake2b_simd::State as Blake2b;
+use byteorder::{ByteOrder, LittleEndian};
+use rand::{ChaChaRng, Rng, SeedableRng};
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use sector_base::api::sector_store::SectorManager;
+
+// An endless stream of ChaCha20 output, seeded by the prover and sector, so
+// that a sector's padding is reproducible. The prover id is public, so the
+// padding is no secret: anyone who can read a staged sector can tell its
+// padding from its pieces. It only hides how full the sector is from those
+// who can't (i.e. who see the size of its file, or how long it takes to
+// seal).
+struct Noise(ChaChaRng);
+
+impl Noise {
+    fn new(prover_id: &[u8; 31], sector_id: SectorId) -> Noise {
+        let mut sector_id_bytes = [0; 8];
+        LittleEndian::write_u64(&mut sector_id_bytes, sector_id);
+
+        let mut hasher = Blake2b::new();
+        hasher.update(prover_id);
+        hasher.update(&sector_id_bytes);
+        let digest = hasher.finalize();
+
+        let mut key = [0u32; 8];
+        LittleEndian::read_u32_into(&digest.as_bytes()[..32], &mut key);
+
+        Noise(ChaChaRng::from_seed(&key[..]))
+    }
+}
+
+impl Read for Noise {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.fill_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+// Fills the space remaining in a staged sector with pseudorandom bytes, so
+// that neither the size of the staged sector's file nor the time taken to
+// seal it reveals how full the sector is. The padding follows the last piece
+// and so is never read when retrieving pieces. Returns the number of bytes
+// of padding written.
+pub fn pad_staged_sector_with_noise(
+    mgr: &SectorManager,
+    access: &str,
+    max_user_bytes_per_staged_sector: UnpaddedBytesAmount,
+    prover_id: &[u8; 31],
+    sector_id: SectorId,
+) -> Result<UnpaddedBytesAmount> {
+    let num_bytes = mgr.num_unsealed_bytes(access)?;
+    let remaining = u64::from(max_user_bytes_per_staged_sector).saturating_sub(num_bytes);
+
+    if remaining == 0 {
+        return Ok(UnpaddedBytesAmount(0));
+    }
+
+    let mut noise = Noise::new(prover_id, sector_id).take(remaining);
+    let written = mgr.write_and_preprocess(access, &mut noise)?;
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+
+    #[test]
+    fn test_pads_to_sector_max() {
+        let (sector_store, mgr) = mock_sector_store();
+        let max = sector_store
+            .inner
+            .sector_config()
+            .max_unsealed_bytes_per_sector();
+
+        for num_bytes in &[0, 1, 500, 1016] {
+            let access = mgr.new_staging_sector_access().unwrap();
+            let piece = vec![7u8; *num_bytes];
+            mgr.write_and_preprocess(&access, &mut &piece[..]).unwrap();
+
+            let written =
+                pad_staged_sector_with_noise(mgr.as_ref(), &access, max, &[1; 31], 2).unwrap();
+
+            assert_eq!(1016 - *num_bytes as u64, u64::from(written));
+            assert_eq!(u64::from(max), mgr.num_unsealed_bytes(&access).unwrap());
+
+            // the piece is intact
+            assert_eq!(
+                piece,
+                mgr.contents(&access).unwrap()[0..*num_bytes].to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn test_noise_is_keyed() {
+        let (sector_store, mgr) = mock_sector_store();
+        let max = sector_store
+            .inner
+            .sector_config()
+            .max_unsealed_bytes_per_sector();
+
+        let pad = |prover_id: [u8; 31], sector_id: SectorId| {
+            let access = mgr.new_staging_sector_access().unwrap();
+            pad_staged_sector_with_noise(mgr.as_ref(), &access, max, &prover_id, sector_id)
+                .unwrap();
+            mgr.contents(&access).unwrap()
+        };
+
+        let noise = pad([1; 31], 2);
+        assert_ne!(vec![0; 1016], noise);
+        assert_eq!(noise, pad([1; 31], 2));
+        assert_ne!(noise, pad([1; 31], 3));
+        assert_ne!(noise, pad([2; 31], 2));
+    }
+}
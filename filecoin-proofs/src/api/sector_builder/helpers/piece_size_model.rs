@@ -0,0 +1,210 @@
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+// A lognormal model of the sizes of the pieces written to a sector builder,
+// i.e. the logarithms of piece sizes are assumed to be normally distributed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PieceSizeModel {
+    // mean and standard deviation of the log of piece sizes
+    pub mu: f64,
+    pub sigma: f64,
+
+    // the number of pieces from which the model was fit
+    pub num_samples: usize,
+}
+
+// Fits a lognormal model to the sizes of recently-added pieces. Empty pieces
+// are ignored.
+pub fn fit_piece_size_model(piece_history: &[UnpaddedBytesAmount]) -> PieceSizeModel {
+    let logs: Vec<f64> = piece_history
+        .iter()
+        .map(|x| u64::from(*x))
+        .filter(|x| *x > 0)
+        .map(|x| (x as f64).ln())
+        .collect();
+
+    let n = logs.len();
+    if n == 0 {
+        return PieceSizeModel {
+            mu: 0.0,
+            sigma: 0.0,
+            num_samples: 0,
+        };
+    }
+
+    let mu = logs.iter().sum::<f64>() / n as f64;
+
+    let variance = if n > 1 {
+        logs.iter().map(|x| (x - mu).powi(2)).sum::<f64>() / (n - 1) as f64
+    } else {
+        0.0
+    };
+
+    PieceSizeModel {
+        mu,
+        sigma: variance.sqrt(),
+        num_samples: n,
+    }
+}
+
+// Predicts how many more pieces (drawn from the model) will fit into the
+// remaining capacity of a sector. Returns an estimate which the actual count
+// will meet or exceed with the given confidence (in (0, 1)), along with the
+// variance of the predicted count.
+//
+// The count of pieces which fit is approximated as normally distributed (per
+// the renewal-reward theorem), and its variance includes the uncertainty in
+// the model's parameters, which dominates when few pieces have been seen.
+pub fn predict_pieces_until_full(
+    model: &PieceSizeModel,
+    remaining_capacity: UnpaddedBytesAmount,
+    confidence: f64,
+) -> (f64, f64) {
+    if model.num_samples == 0 {
+        return (0.0, std::f64::INFINITY);
+    }
+
+    let remaining = u64::from(remaining_capacity) as f64;
+    let n = model.num_samples as f64;
+    let s2 = model.sigma.powi(2);
+
+    // mean and variance of piece sizes
+    let mean = (model.mu + s2 / 2.0).exp();
+    let variance = (s2.exp() - 1.0) * (2.0 * model.mu + s2).exp();
+
+    let expected_count = remaining / mean;
+    let process_variance = remaining * variance / mean.powi(3);
+
+    // variance of the log of the estimated mean piece size
+    let parameter_variance = if model.num_samples > 1 {
+        s2 / n + s2.powi(2) / (2.0 * (n - 1.0))
+    } else {
+        0.0
+    };
+
+    let count_variance = process_variance + expected_count.powi(2) * parameter_variance;
+    let estimate = expected_count - standard_normal_quantile(confidence) * count_variance.sqrt();
+
+    (estimate.max(0.0), count_variance)
+}
+
+// Approximates the inverse of the standard normal CDF (Abramowitz and Stegun
+// 26.2.23, absolute error < 4.5e-4).
+fn standard_normal_quantile(p: f64) -> f64 {
+    debug_assert!(p > 0.0 && p < 1.0, "probability must be in (0, 1)");
+
+    let q = if p < 0.5 { p } else { 1.0 - p };
+    let t = (-2.0 * q.ln()).sqrt();
+
+    let x = t
+        - (2.515_517 + 0.802_853 * t + 0.010_328 * t * t)
+            / (1.0 + 1.432_788 * t + 0.189_269 * t * t + 0.001_308 * t * t * t);
+
+    if p < 0.5 {
+        -x
+    } else {
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::{IndependentSample, LogNormal};
+    use rand::{SeedableRng, XorShiftRng};
+
+    const MU: f64 = 6.907_755; // ln(1000)
+    const SIGMA: f64 = 0.5;
+
+    fn sample(
+        rng: &mut XorShiftRng,
+        dist: &LogNormal,
+        num_samples: usize,
+    ) -> Vec<UnpaddedBytesAmount> {
+        (0..num_samples)
+            .map(|_| UnpaddedBytesAmount(dist.ind_sample(rng).round() as u64))
+            .collect()
+    }
+
+    // the number of pieces written, one after another, before the first
+    // which doesn't fit
+    fn count_until_full(rng: &mut XorShiftRng, dist: &LogNormal, capacity: u64) -> usize {
+        let mut used = 0;
+        let mut count = 0;
+
+        loop {
+            let size = dist.ind_sample(rng).round() as u64;
+            if used + size > capacity {
+                return count;
+            }
+
+            used += size;
+            count += 1;
+        }
+    }
+
+    #[test]
+    fn test_standard_normal_quantile() {
+        assert!(standard_normal_quantile(0.5).abs() < 1e-3);
+        assert!((standard_normal_quantile(0.95) - 1.644_854).abs() < 1e-3);
+        assert!((standard_normal_quantile(0.025) + 1.959_964).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_fits_known_samples() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let dist = LogNormal::new(MU, SIGMA);
+
+        let model = fit_piece_size_model(&sample(&mut rng, &dist, 100));
+
+        assert_eq!(100, model.num_samples);
+        assert!((model.mu - MU).abs() < 0.2, "mu: {}", model.mu);
+        assert!((model.sigma - SIGMA).abs() < 0.15, "sigma: {}", model.sigma);
+    }
+
+    #[test]
+    fn test_prediction_interval_coverage() {
+        let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+        let dist = LogNormal::new(MU, SIGMA);
+        let capacity = 30_000;
+
+        let num_models = 50;
+        let num_trials = 200;
+        let mut num_covered = 0;
+
+        // each model is fit to 100 samples, and so is a little wrong in its
+        // own way
+        for _ in 0..num_models {
+            let model = fit_piece_size_model(&sample(&mut rng, &dist, 100));
+            let (estimate, variance) =
+                predict_pieces_until_full(&model, UnpaddedBytesAmount(capacity), 0.95);
+
+            assert!(estimate > 0.0 && variance > 0.0);
+
+            for _ in 0..num_trials {
+                if count_until_full(&mut rng, &dist, capacity) as f64 >= estimate {
+                    num_covered += 1;
+                }
+            }
+        }
+
+        let coverage = f64::from(num_covered) / f64::from(num_models * num_trials);
+        assert!(coverage > 0.9 && coverage < 0.98, "coverage: {}", coverage);
+    }
+
+    #[test]
+    fn test_degenerate_histories() {
+        let model = fit_piece_size_model(&[UnpaddedBytesAmount(0)]);
+        assert_eq!(0, model.num_samples);
+        let (estimate, variance) =
+            predict_pieces_until_full(&model, UnpaddedBytesAmount(1000), 0.95);
+        assert!(estimate.abs() < 1e-9);
+        assert!(variance.is_infinite());
+
+        // a single piece size is taken to be the only one
+        let model = fit_piece_size_model(&[UnpaddedBytesAmount(100)]);
+        let (estimate, variance) =
+            predict_pieces_until_full(&model, UnpaddedBytesAmount(1000), 0.95);
+        assert!((estimate - 10.0).abs() < 1e-9);
+        assert!(variance.abs() < 1e-9);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::error::ExpectWithBacktrace;
+use crate::error::Result;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+const FATAL_NOLOCK: &str = "error acquiring piece read buffer lock";
+
+// An in-memory, LRU-bounded buffer of piece-bytes which have been read ahead
+// of a client asking for them.
+#[derive(Debug)]
+pub struct PieceReadBuffer {
+    max_bytes: usize,
+    num_bytes: usize,
+    // Least-recently-used pieces at the front, most-recently-used at the back.
+    entries: VecDeque<(String, Vec<u8>)>,
+}
+
+impl PieceReadBuffer {
+    pub fn new(max_bytes: usize) -> PieceReadBuffer {
+        PieceReadBuffer {
+            max_bytes,
+            num_bytes: 0,
+            entries: Default::default(),
+        }
+    }
+
+    // Returns a copy of the buffered piece-bytes, marking the piece as
+    // most-recently-used.
+    pub fn get(&mut self, piece_key: &str) -> Option<Vec<u8>> {
+        let entry = self.remove(piece_key)?;
+        let bytes = entry.1.clone();
+        self.num_bytes += entry.1.len();
+        self.entries.push_back(entry);
+
+        Some(bytes)
+    }
+
+    // Buffers piece-bytes, evicting least-recently-used pieces until the
+    // buffer is back under its bound. Pieces which would never fit are not
+    // buffered.
+    pub fn insert(&mut self, piece_key: String, bytes: Vec<u8>) {
+        let _ = self.remove(&piece_key);
+
+        if bytes.len() > self.max_bytes {
+            return;
+        }
+
+        while self.num_bytes + bytes.len() > self.max_bytes {
+            match self.entries.pop_front() {
+                Some((_, evicted)) => self.num_bytes -= evicted.len(),
+                None => break,
+            }
+        }
+
+        self.num_bytes += bytes.len();
+        self.entries.push_back((piece_key, bytes));
+    }
+
+    // Drops any buffered bytes for the piece, e.g. because the piece has been
+    // written to.
+    pub fn invalidate(&mut self, piece_key: &str) {
+        let _ = self.remove(piece_key);
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    fn remove(&mut self, piece_key: &str) -> Option<(String, Vec<u8>)> {
+        let idx = self.entries.iter().position(|(k, _)| k == piece_key)?;
+        let entry = self.entries.remove(idx)?;
+        self.num_bytes -= entry.1.len();
+
+        Some(entry)
+    }
+}
+
+// Reads the piece and places its bytes into the buffer so that a subsequent
+// get_piece for the same key does no I/O. Intended to be run on a worker
+// thread while the client consumes the previous piece.
+pub fn prefetch_piece<F>(
+    buffer: &Mutex<PieceReadBuffer>,
+    next_piece_key: &str,
+    read_piece: F,
+) -> Result<()>
+where
+    F: FnOnce(&str) -> Result<Vec<u8>>,
+{
+    let bytes = read_piece(next_piece_key)?;
+
+    buffer
+        .lock()
+        .expects(FATAL_NOLOCK)
+        .insert(next_piece_key.to_string(), bytes);
+
+    Ok(())
+}
+
+// Returns the piece-bytes from the buffer if they were prefetched, falling
+// back to reading them.
+pub fn get_piece<F>(
+    buffer: &Mutex<PieceReadBuffer>,
+    piece_key: &str,
+    read_piece: F,
+) -> Result<Vec<u8>>
+where
+    F: FnOnce(&str) -> Result<Vec<u8>>,
+{
+    let buffered = buffer.lock().expects(FATAL_NOLOCK).get(piece_key);
+
+    match buffered {
+        Some(bytes) => Ok(bytes),
+        None => read_piece(piece_key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils::{mock_sector_store, MockSectorManager};
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+    use sector_base::api::sector_store::SectorManager;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn read_with(mgr: &MockSectorManager, access: &str, piece_key: &str) -> Result<Vec<u8>> {
+        let offset = if piece_key == "first" { 0 } else { 10 };
+
+        mgr.read_raw(access, offset, UnpaddedBytesAmount(10))
+            .map_err(Into::into)
+    }
+
+    #[test]
+    fn test_prefetched_piece_requires_no_io() {
+        let (_, mgr) = mock_sector_store();
+        let buffer = Arc::new(Mutex::new(PieceReadBuffer::new(1024)));
+
+        let access = mgr.new_staging_sector_access().unwrap();
+        mgr.write_and_preprocess(&access, &mut &[0u8; 10][..])
+            .unwrap();
+        mgr.write_and_preprocess(&access, &mut &[1u8; 10][..])
+            .unwrap();
+
+        let first = get_piece(&buffer, "first", |k| read_with(&mgr, &access, k)).unwrap();
+        assert_eq!(vec![0; 10], first);
+        assert_eq!(1, mgr.num_reads());
+
+        // prefetch the second piece on another thread while the client
+        // consumes the first
+        let handle = {
+            let mgr = mgr.clone();
+            let access = access.clone();
+            let buffer = buffer.clone();
+            thread::spawn(move || {
+                prefetch_piece(&buffer, "second", |k| read_with(&mgr, &access, k))
+            })
+        };
+        handle.join().unwrap().unwrap();
+        assert_eq!(2, mgr.num_reads());
+
+        let second = get_piece(&buffer, "second", |k| read_with(&mgr, &access, k)).unwrap();
+        assert_eq!(vec![1; 10], second);
+        assert_eq!(2, mgr.num_reads());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut buffer = PieceReadBuffer::new(30);
+
+        buffer.insert("a".to_string(), vec![0; 10]);
+        buffer.insert("b".to_string(), vec![0; 10]);
+        buffer.insert("c".to_string(), vec![0; 10]);
+
+        // touch "a" so that "b" becomes the least-recently-used piece
+        assert!(buffer.get("a").is_some());
+
+        buffer.insert("d".to_string(), vec![0; 10]);
+
+        assert!(buffer.get("b").is_none());
+        assert!(buffer.get("a").is_some());
+        assert!(buffer.get("c").is_some());
+        assert!(buffer.get("d").is_some());
+        assert_eq!(30, buffer.num_bytes());
+
+        // pieces larger than the buffer are never buffered
+        buffer.insert("e".to_string(), vec![0; 31]);
+        assert!(buffer.get("e").is_none());
+        assert_eq!(30, buffer.num_bytes());
+    }
+
+    #[test]
+    fn test_invalidate_on_write() {
+        let mut buffer = PieceReadBuffer::new(30);
+
+        buffer.insert("a".to_string(), vec![0; 10]);
+        buffer.invalidate("a");
+
+        assert!(buffer.get("a").is_none());
+        assert_eq!(0, buffer.num_bytes());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use blake2b_simd::State as Blake2b;
+
+use crate::api::post_adapter::GeneratePoStDynamicSectorsCountOutput;
+use crate::api::sector_builder::kv_store::KeyValueStore;
+use crate::api::sector_builder::WrappedKeyValueStore;
+use crate::error::Result;
+
+const KEY_SUFFIX: &[u8] = b"/proofs";
+
+// Caches the proofs produced by PoSt generation so that a PoSt requested again
+// for the same challenge (e.g. when its submission is retried) is cloned from
+// the cache rather than recomputed.
+//
+// Entries are keyed by a digest of everything which determines the proof: the
+// challenge seed and the replica commitments (comm_r) of the proven sectors.
+// Note that sectors holding identical data (i.e. with the same comm_d) still
+// have distinct replicas, and therefore distinct proofs.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProofDeduplicationIndex {
+    proofs: HashMap<[u8; 32], Vec<u8>>,
+    // keys of the cached proofs, oldest first
+    order: VecDeque<[u8; 32]>,
+}
+
+impl ProofDeduplicationIndex {
+    pub fn load<T: KeyValueStore>(
+        kv_store: &Arc<WrappedKeyValueStore<T>>,
+        prover_id: &[u8; 31],
+    ) -> Result<ProofDeduplicationIndex> {
+        match kv_store.inner.get(&index_key(prover_id))? {
+            Some(val) => Ok(serde_cbor::from_slice(&val)?),
+            None => Ok(Default::default()),
+        }
+    }
+
+    pub fn persist<T: KeyValueStore>(
+        &self,
+        kv_store: &Arc<WrappedKeyValueStore<T>>,
+        prover_id: &[u8; 31],
+    ) -> Result<()> {
+        kv_store
+            .inner
+            .put(&index_key(prover_id), &serde_cbor::to_vec(self)?)
+    }
+
+    fn get(&self, key: &[u8; 32]) -> Result<Option<GeneratePoStDynamicSectorsCountOutput>> {
+        match self.proofs.get(key) {
+            Some(val) => {
+                let (proofs, faults) = serde_cbor::from_slice(val)?;
+                Ok(Some(GeneratePoStDynamicSectorsCountOutput {
+                    proofs,
+                    faults,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Caches the output, evicting the oldest entries so that no more than
+    // max_cached_proofs are held.
+    fn insert(
+        &mut self,
+        key: [u8; 32],
+        output: &GeneratePoStDynamicSectorsCountOutput,
+        max_cached_proofs: usize,
+    ) -> Result<()> {
+        if max_cached_proofs == 0 {
+            return Ok(());
+        }
+
+        let val = serde_cbor::to_vec(&(&output.proofs, &output.faults))?;
+
+        if self.proofs.insert(key, val).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.order.len() > max_cached_proofs {
+            if let Some(oldest) = self.order.pop_front() {
+                self.proofs.remove(&oldest);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Returns the cached PoSt for the challenge if there is one, and otherwise
+// generates (and caches) it. PoSts reporting faults aren't cached, as the
+// faults may be transient. The flag returned is set if the index changed.
+pub fn generate_post_deduplicated<F>(
+    index: &mut ProofDeduplicationIndex,
+    max_cached_proofs: usize,
+    comm_rs: &[[u8; 32]],
+    challenge_seed: &[u8; 32],
+    generate: F,
+) -> Result<(GeneratePoStDynamicSectorsCountOutput, bool)>
+where
+    F: FnOnce() -> Result<GeneratePoStDynamicSectorsCountOutput>,
+{
+    let key = challenge_key(comm_rs, challenge_seed);
+
+    if let Some(output) = index.get(&key)? {
+        return Ok((output, false));
+    }
+
+    let output = generate()?;
+    let index_changed = record_post(index, max_cached_proofs, comm_rs, challenge_seed, &output)?;
+
+    Ok((output, index_changed))
+}
+
+// Caches a PoSt which was generated elsewhere (e.g. by the PoSt scheduler)
+// for the challenge, unless it reports faults. The flag returned is set if the
+// index changed.
+pub fn record_post(
+    index: &mut ProofDeduplicationIndex,
+    max_cached_proofs: usize,
+    comm_rs: &[[u8; 32]],
+    challenge_seed: &[u8; 32],
+    output: &GeneratePoStDynamicSectorsCountOutput,
+) -> Result<bool> {
+    if !output.faults.is_empty() {
+        return Ok(false);
+    }
+
+    index.insert(
+        challenge_key(comm_rs, challenge_seed),
+        output,
+        max_cached_proofs,
+    )?;
+
+    Ok(true)
+}
+
+fn challenge_key(comm_rs: &[[u8; 32]], challenge_seed: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2b::new();
+    hasher.update(challenge_seed);
+    for comm_r in comm_rs {
+        hasher.update(comm_r);
+    }
+
+    let mut key = [0; 32];
+    key.copy_from_slice(&hasher.finalize().as_bytes()[..32]);
+    key
+}
+
+fn index_key(prover_id: &[u8; 31]) -> Vec<u8> {
+    [&prover_id[..], KEY_SUFFIX].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::kv_store::FileSystemKvs;
+    use std::cell::Cell;
+
+    fn output(proof: u8, faults: Vec<u64>) -> GeneratePoStDynamicSectorsCountOutput {
+        GeneratePoStDynamicSectorsCountOutput {
+            proofs: vec![vec![proof; 192]],
+            faults,
+        }
+    }
+
+    #[test]
+    fn test_reuses_proof_for_same_challenge() {
+        let mut index: ProofDeduplicationIndex = Default::default();
+        let num_calls = Cell::new(0);
+
+        let mut prove = |comm_rs: &[[u8; 32]], seed: &[u8; 32]| {
+            generate_post_deduplicated(&mut index, 4, comm_rs, seed, || {
+                num_calls.set(num_calls.get() + 1);
+                Ok(output(num_calls.get() as u8, vec![]))
+            })
+            .unwrap()
+            .0
+            .proofs
+        };
+
+        let first = prove(&[[1; 32], [2; 32]], &[7; 32]);
+        let again = prove(&[[1; 32], [2; 32]], &[7; 32]);
+        assert_eq!(first, again);
+        assert_eq!(1, num_calls.get());
+
+        // a new challenge, or other sectors, require a new proof
+        prove(&[[1; 32], [2; 32]], &[8; 32]);
+        prove(&[[1; 32]], &[7; 32]);
+        assert_eq!(3, num_calls.get());
+    }
+
+    #[test]
+    fn test_reuses_recorded_proof() {
+        let mut index: ProofDeduplicationIndex = Default::default();
+
+        assert!(record_post(&mut index, 4, &[[1; 32]], &[7; 32], &output(1, vec![])).unwrap());
+        assert!(!record_post(&mut index, 4, &[[2; 32]], &[7; 32], &output(2, vec![0])).unwrap());
+
+        let (cached, changed) =
+            generate_post_deduplicated(&mut index, 4, &[[1; 32]], &[7; 32], || {
+                panic!("should have been cached")
+            })
+            .unwrap();
+        assert!(!changed);
+        assert_eq!(vec![vec![1; 192]], cached.proofs);
+    }
+
+    #[test]
+    fn test_does_not_cache_faults() {
+        let mut index: ProofDeduplicationIndex = Default::default();
+
+        let (_, changed) = generate_post_deduplicated(&mut index, 4, &[[1; 32]], &[7; 32], || {
+            Ok(output(1, vec![0]))
+        })
+        .unwrap();
+
+        assert!(!changed);
+        assert!(index.proofs.is_empty());
+    }
+
+    #[test]
+    fn test_bounded_and_persisted() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let kv_store = Arc::new(WrappedKeyValueStore {
+            inner: Box::new(FileSystemKvs::initialize(metadata_dir.path()).unwrap()),
+        });
+
+        let mut index: ProofDeduplicationIndex = Default::default();
+
+        for seed in 0..5 {
+            generate_post_deduplicated(&mut index, 3, &[[1; 32]], &[seed; 32], || {
+                Ok(output(seed, vec![]))
+            })
+            .unwrap();
+        }
+
+        assert_eq!(3, index.proofs.len());
+
+        index.persist(&kv_store, &[0; 31]).unwrap();
+        let loaded = ProofDeduplicationIndex::load(&kv_store, &[0; 31]).unwrap();
+        assert_eq!(index, loaded);
+
+        // the oldest were evicted
+        let (cached, changed) =
+            generate_post_deduplicated(&mut index, 3, &[[1; 32]], &[4; 32], || {
+                panic!("should have been cached")
+            })
+            .unwrap();
+        assert!(!changed);
+        assert_eq!(vec![vec![4; 192]], cached.proofs);
+
+        let (_, changed) = generate_post_deduplicated(&mut index, 3, &[[1; 32]], &[0; 32], || {
+            Ok(output(0, vec![]))
+        })
+        .unwrap();
+        assert!(changed);
+    }
+}
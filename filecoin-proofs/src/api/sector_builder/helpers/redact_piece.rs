@@ -0,0 +1,221 @@
+use crate::api::sector_builder::errors::{err_piecenotfound, err_unrecov};
+use crate::api::sector_builder::helpers::check_staged_sector_crc32::record_sector_crc32;
+use crate::api::sector_builder::metadata::{RedactionReceipt, SealStatus};
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::state::{SealedState, StagedState};
+use crate::api::sector_builder::WrappedSectorStore;
+use crate::error;
+use crate::FCP_LOG;
+use sector_base::io::fr32::FR32_PADDING_MAP;
+use slog::*;
+use std::cmp::min;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// The most zeroes written at once when overwriting a piece.
+const ZEROES_CHUNK_BYTES: u64 = 1 << 20;
+
+// Redacts the referenced piece. The bytes of a piece in a staged sector which
+// is still accepting data are overwritten with zeroes; those of a piece which
+// is being (or has been) sealed can't be, as that would invalidate the
+// sector's proof, so the piece is only recorded as redacted. Either way,
+// reads of the piece are refused from then on. Redacting a piece twice
+// returns the receipt of the first redaction.
+pub fn redact_piece(
+    sector_store: &Arc<WrappedSectorStore>,
+    staged_state: &mut StagedState,
+    sealed_state: &SealedState,
+    sector_locks: &SectorLocks,
+    piece_key: &str,
+) -> error::Result<RedactionReceipt> {
+    if let Some(receipt) = staged_state.redacted_pieces.get(piece_key) {
+        return Ok(receipt.clone());
+    }
+
+    let staged = staged_state.sectors.values_mut().find_map(|sector| {
+        let position = sector
+            .pieces
+            .iter()
+            .find(|(_, piece)| piece.piece_key == piece_key)
+            .map(|(position, _)| *position)?;
+
+        Some((sector, position))
+    });
+
+    let receipt = if let Some((sector, position)) = staged {
+        let was_overwritten = sector.seal_status == SealStatus::Pending;
+
+        if was_overwritten {
+            let (offset, _) = position;
+            let piece = &sector.pieces[&position];
+            let mgr = sector_store.inner.manager();
+
+            // Nothing may have the staged sector mapped while the piece is
+            // overwritten.
+            let lock = sector_locks.get(sector.sector_id);
+            let _guard = lock.invalidate_mmaps();
+
+            let mut file = mgr.open_and_verify_sector(&sector.sector_access)?;
+            zero_unpadded_range(&mut file, u64::from(offset), u64::from(piece.num_bytes))?;
+            file.sync_all()?;
+
+            // The sector's data tree (and the piece's checksum) no longer
+            // describes its bytes.
+            sector.merkle_tree_state = None;
+            record_sector_crc32(sector_store, sector);
+        } else {
+            warn!(FCP_LOG, "redacted piece is being sealed and cannot be overwritten"; "piece_key" => piece_key, "sector_id" => sector.sector_id);
+        }
+
+        if let Some(piece) = sector.pieces.get_mut(&position) {
+            piece.checksum = None;
+        }
+
+        RedactionReceipt {
+            piece_key: piece_key.to_string(),
+            sector_id: sector.sector_id,
+            redacted_at: SystemTime::now(),
+            was_overwritten,
+        }
+    } else {
+        let sector = sealed_state
+            .sectors
+            .values()
+            .find(|sector| sector.pieces.iter().any(|p| p.piece_key == piece_key))
+            .ok_or_else(|| err_piecenotfound(piece_key.to_string()))?;
+
+        warn!(FCP_LOG, "redacted piece is sealed and cannot be overwritten"; "piece_key" => piece_key, "sector_id" => sector.sector_id);
+
+        RedactionReceipt {
+            piece_key: piece_key.to_string(),
+            sector_id: sector.sector_id,
+            redacted_at: SystemTime::now(),
+            was_overwritten: false,
+        }
+    };
+
+    staged_state
+        .redacted_pieces
+        .insert(piece_key.to_string(), receipt.clone());
+
+    Ok(receipt)
+}
+
+// Zeroes the unpadded bytes [offset, offset + num_bytes) of a staged sector's
+// (Fr32-preprocessed) body. The padding bits between them are zero already,
+// so it's the run of bits from the first of them to the last which is zeroed.
+pub fn zero_unpadded_range<F: Read + Write + Seek>(
+    body: &mut F,
+    offset: u64,
+    num_bytes: u64,
+) -> error::Result<()> {
+    if num_bytes == 0 {
+        return Ok(());
+    }
+
+    let start = FR32_PADDING_MAP.transform_bit_offset(offset as usize * 8, true) as u64;
+    let end = FR32_PADDING_MAP.transform_bit_offset((offset + num_bytes) as usize * 8, true) as u64;
+
+    let first_byte = start / 8;
+    let last_byte = (end - 1) / 8;
+
+    if first_byte == last_byte {
+        return clear_bits(body, first_byte, start % 8, end - first_byte * 8);
+    }
+
+    clear_bits(body, first_byte, start % 8, 8)?;
+
+    let mut remaining = last_byte - first_byte - 1;
+    let zeroes = vec![0; min(remaining, ZEROES_CHUNK_BYTES) as usize];
+
+    body.seek(SeekFrom::Start(first_byte + 1))?;
+    while remaining > 0 {
+        let n = min(remaining, ZEROES_CHUNK_BYTES);
+        body.write_all(&zeroes[..n as usize])?;
+        remaining -= n;
+    }
+
+    clear_bits(body, last_byte, 0, end - last_byte * 8)
+}
+
+// Clears bits [from, to) (least-significant first) of the byte at position.
+fn clear_bits<F: Read + Write + Seek>(
+    body: &mut F,
+    position: u64,
+    from: u64,
+    to: u64,
+) -> error::Result<()> {
+    if from >= to || to > 8 {
+        return Err(err_unrecov(format!("cannot clear bits [{}, {}) of a byte", from, to)).into());
+    }
+
+    let mut byte = [0; 1];
+    body.seek(SeekFrom::Start(position))?;
+    body.read_exact(&mut byte)?;
+
+    byte[0] &= !(((1u16 << to) - (1u16 << from)) as u8);
+
+    body.seek(SeekFrom::Start(position))?;
+    body.write_all(&byte)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sector_base::io::fr32::{write_padded, write_unpadded};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_zeroes_only_the_piece() {
+        // pieces which straddle Fr32 elements, at offsets which aren't byte
+        // aligned once padded
+        let pieces: Vec<Vec<u8>> = vec![
+            (0..37u32).map(|x| (x * 7 + 1) as u8 | 0x80).collect(),
+            (0..300u32).map(|x| (x * 13 + 5) as u8 | 0x01).collect(),
+            (0..50u32).map(|x| (x * 3 + 2) as u8 | 0x10).collect(),
+        ];
+        let unpadded: Vec<u8> = pieces.concat();
+
+        let mut body = Cursor::new(Vec::new());
+        write_padded(&mut &unpadded[..], &mut body).unwrap();
+
+        zero_unpadded_range(&mut body, 37, 300).unwrap();
+
+        let mut redacted = Vec::new();
+        write_unpadded(body.get_ref(), &mut redacted, 0, unpadded.len()).unwrap();
+
+        assert_eq!(&pieces[0][..], &redacted[..37]);
+        assert!(redacted[37..337].iter().all(|b| *b == 0));
+        assert_eq!(&pieces[2][..], &redacted[337..]);
+    }
+
+    #[test]
+    fn test_zeroes_across_padding() {
+        let unpadded = vec![0xff; 64];
+
+        let mut body = Cursor::new(Vec::new());
+        write_padded(&mut &unpadded[..], &mut body).unwrap();
+
+        // the 32nd unpadded byte straddles the padding of the first element
+        zero_unpadded_range(&mut body, 31, 1).unwrap();
+
+        let mut redacted = Vec::new();
+        write_unpadded(body.get_ref(), &mut redacted, 0, unpadded.len()).unwrap();
+
+        assert_eq!(0, redacted[31]);
+        assert!(redacted[..31].iter().all(|b| *b == 0xff));
+        assert!(redacted[32..].iter().all(|b| *b == 0xff));
+
+        // a piece of one byte-aligned byte
+        zero_unpadded_range(&mut body, 0, 1).unwrap();
+
+        let mut redacted = Vec::new();
+        write_unpadded(body.get_ref(), &mut redacted, 0, unpadded.len()).unwrap();
+
+        assert_eq!(0, redacted[0]);
+        assert_eq!(0xff, redacted[1]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt::Write;
+use std::sync::mpsc;
+
+use crate::api::sector_builder::metadata::{PieceMetadata, SealStatus};
+use crate::api::sector_builder::state::{SealedState, StagedState};
+use crate::api::sector_builder::SectorId;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+// The states through which a sector moves, in the order they're rendered.
+const STATES: [&str; 4] = ["Pending", "Sealing", "Sealed", "Failed"];
+
+// Renders a Mermaid.js state diagram of every sector the sector builder knows
+// about, grouped by seal status, with the fill ratio and number of pieces of
+// each.
+pub fn render_state_diagram(
+    staged_state: &StagedState,
+    sealed_state: &SealedState,
+    max_user_bytes_per_staged_sector: UnpaddedBytesAmount,
+) -> String {
+    let mut sectors: Vec<(&str, SectorId, Vec<&PieceMetadata>)> = Default::default();
+
+    for sector in staged_state.sectors.values() {
+        let state = match sector.seal_status {
+            SealStatus::Pending => "Pending",
+            SealStatus::Sealing => "Sealing",
+            SealStatus::Sealed(_) => "Sealed",
+            SealStatus::Failed(_) => "Failed",
+        };
+
+        sectors.push((state, sector.sector_id, sector.pieces.values().collect()));
+    }
+
+    for sector in sealed_state.sectors.values() {
+        sectors.push(("Sealed", sector.sector_id, sector.pieces.iter().collect()));
+    }
+
+    sectors.sort_by_key(|&(_, sector_id, _)| sector_id);
+
+    let mut diagram = String::from("stateDiagram\n");
+
+    for state in STATES.iter() {
+        let in_state: Vec<_> = sectors.iter().filter(|x| x.0 == *state).collect();
+        if in_state.is_empty() {
+            continue;
+        }
+
+        let _ = writeln!(diagram, "    state {} {{", state);
+        for (_, sector_id, pieces) in in_state {
+            let num_bytes: u64 = pieces.iter().map(|p| u64::from(p.num_bytes)).sum();
+            let fill = 100.0 * num_bytes as f64
+                / (u64::from(max_user_bytes_per_staged_sector) as f64).max(1.0);
+
+            let _ = writeln!(
+                diagram,
+                "        sector_{} : sector {} ({:.1}% full, {} pieces)",
+                sector_id,
+                sector_id,
+                fill,
+                pieces.len()
+            );
+        }
+        diagram.push_str("    }\n");
+    }
+
+    diagram.push_str("    [*] --> Pending\n");
+    diagram.push_str("    Pending --> Sealing\n");
+    diagram.push_str("    Sealing --> Sealed\n");
+    diagram.push_str("    Sealing --> Failed\n");
+
+    diagram
+}
+
+// Pushes state diagrams to any number of subscribers, forgetting those which
+// have hung up.
+#[derive(Debug, Default)]
+pub struct StateDiagramPublisher {
+    subscribers: Vec<mpsc::Sender<String>>,
+}
+
+impl StateDiagramPublisher {
+    pub fn subscribe(&mut self) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+
+        rx
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn publish(&mut self, diagram: &str) {
+        self.subscribers
+            .retain(|tx| tx.send(diagram.to_string()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{
+        pieces_by_offset, SealedSectorMetadata, StagedSectorMetadata,
+    };
+
+    fn piece(num_bytes: u64) -> PieceMetadata {
+        PieceMetadata {
+            num_bytes: UnpaddedBytesAmount(num_bytes),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reflects_sealing() {
+        let mut staged_state: StagedState = Default::default();
+        let mut sealed_state: SealedState = Default::default();
+        let max = UnpaddedBytesAmount(1000);
+
+        for sector_id in 1..=3 {
+            staged_state.sectors.insert(
+                sector_id,
+                StagedSectorMetadata {
+                    sector_id,
+                    pieces: pieces_by_offset(vec![piece(100); sector_id as usize]),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let diagram = render_state_diagram(&staged_state, &sealed_state, max);
+        assert!(diagram.starts_with("stateDiagram\n    state Pending {\n"));
+        assert!(diagram.contains("sector_1 : sector 1 (10.0% full, 1 pieces)"));
+        assert!(diagram.contains("sector_3 : sector 3 (30.0% full, 3 pieces)"));
+        assert!(!diagram.contains("state Sealed"));
+
+        // seal two of them
+        for sector_id in 1..=2 {
+            let staged = staged_state.sectors.remove(&sector_id).unwrap();
+            sealed_state.sectors.insert(
+                sector_id,
+                SealedSectorMetadata {
+                    sector_id,
+                    pieces: staged.pieces.into_iter().map(|(_, piece)| piece).collect(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let diagram = render_state_diagram(&staged_state, &sealed_state, max);
+        let expected = "stateDiagram
+    state Pending {
+        sector_3 : sector 3 (30.0% full, 3 pieces)
+    }
+    state Sealed {
+        sector_1 : sector 1 (10.0% full, 1 pieces)
+        sector_2 : sector 2 (20.0% full, 2 pieces)
+    }
+    [*] --> Pending
+    Pending --> Sealing
+    Sealing --> Sealed
+    Sealing --> Failed
+";
+        assert_eq!(expected, diagram);
+    }
+
+    #[test]
+    fn test_publishes_to_subscribers() {
+        let mut publisher: StateDiagramPublisher = Default::default();
+        assert!(!publisher.has_subscribers());
+
+        let a = publisher.subscribe();
+        let b = publisher.subscribe();
+
+        publisher.publish("one");
+        drop(b);
+        publisher.publish("two");
+
+        assert_eq!(vec!["one", "two"], a.try_iter().collect::<Vec<String>>());
+        assert!(publisher.has_subscribers());
+
+        drop(a);
+        publisher.publish("three");
+        assert!(!publisher.has_subscribers());
+    }
+}
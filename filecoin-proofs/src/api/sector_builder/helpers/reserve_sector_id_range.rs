@@ -0,0 +1,140 @@
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::SectorId;
+use crate::error;
+
+// Reserves count consecutive sector ids, returning the first and last of
+// them. No sector accesses are provisioned; the reserved ids are handed out by
+// subsequent calls to next_sector_id (i.e. when staged sectors are
+// provisioned), before any others.
+pub fn reserve_sector_id_range(
+    staged_state: &mut StagedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    count: u32,
+) -> error::Result<(SectorId, SectorId)> {
+    if count == 0 {
+        return Err(format_err!("cannot reserve an empty range of sector ids"));
+    }
+
+    let mut start = staged_state.sector_id_nonce + 1;
+
+    // Skip over any previously-reserved ids with which the new range would
+    // collide.
+    let end = loop {
+        let end = start
+            .checked_add(SectorId::from(count) - 1)
+            .ok_or_else(|| format_err!("sector id nonce would overflow"))?;
+
+        match reserved_ranges
+            .iter()
+            .find(|(s, e)| *s <= end && start <= *e)
+        {
+            Some(&(_, e)) => start = e + 1,
+            None => break end,
+        }
+    };
+
+    staged_state.sector_id_nonce = end;
+    reserved_ranges.push((start, end));
+
+    Ok((start, end))
+}
+
+// Produces the sector id for a newly-provisioned staged sector, consuming
+// reserved ids (oldest reservation first) before advancing the nonce.
+pub fn next_sector_id(
+    staged_state: &mut StagedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+) -> SectorId {
+    if let Some(sector_id) = next_reserved_sector_id(staged_state, reserved_ranges) {
+        return sector_id;
+    }
+
+    let n = &mut staged_state.sector_id_nonce;
+    *n += 1;
+    *n
+}
+
+// Consumes the next reserved id (oldest reservation first), if any remain.
+pub fn next_reserved_sector_id(
+    staged_state: &mut StagedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+) -> Option<SectorId> {
+    if reserved_ranges.is_empty() {
+        return None;
+    }
+
+    let (start, end) = reserved_ranges[0];
+
+    if start == end {
+        reserved_ranges.remove(0);
+    } else {
+        reserved_ranges[0] = (start + 1, end);
+    }
+
+    // Never hand out a reserved id a second time, even if it was reserved
+    // above the nonce.
+    staged_state.sector_id_nonce = std::cmp::max(staged_state.sector_id_nonce, start);
+
+    Some(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_ids_are_handed_out_first() {
+        let mut staged_state = StagedState {
+            sector_id_nonce: 10,
+            sectors: Default::default(),
+            ..Default::default()
+        };
+        let mut reserved_ranges = Vec::new();
+
+        let range = reserve_sector_id_range(&mut staged_state, &mut reserved_ranges, 3).unwrap();
+        assert_eq!((11, 13), range);
+
+        let range = reserve_sector_id_range(&mut staged_state, &mut reserved_ranges, 1).unwrap();
+        assert_eq!((14, 14), range);
+
+        let ids: Vec<SectorId> = (0..6)
+            .map(|_| next_sector_id(&mut staged_state, &mut reserved_ranges))
+            .collect();
+
+        assert_eq!(vec![11, 12, 13, 14, 15, 16], ids);
+        assert!(reserved_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_reservations_skip_reserved_ids() {
+        // e.g. a reservation made before the nonce was rolled back
+        let mut staged_state = StagedState {
+            sector_id_nonce: 10,
+            sectors: Default::default(),
+            ..Default::default()
+        };
+        let mut reserved_ranges = vec![(13, 15)];
+
+        let range = reserve_sector_id_range(&mut staged_state, &mut reserved_ranges, 2).unwrap();
+        assert_eq!((11, 12), range);
+
+        let range = reserve_sector_id_range(&mut staged_state, &mut reserved_ranges, 2).unwrap();
+        assert_eq!((16, 17), range);
+
+        assert_eq!(vec![(13, 15), (11, 12), (16, 17)], reserved_ranges);
+
+        let ids: Vec<SectorId> = (0..8)
+            .map(|_| next_sector_id(&mut staged_state, &mut reserved_ranges))
+            .collect();
+
+        assert_eq!(vec![13, 14, 15, 11, 12, 16, 17, 18], ids);
+    }
+
+    #[test]
+    fn test_rejects_empty_range() {
+        let mut staged_state: StagedState = Default::default();
+
+        assert!(reserve_sector_id_range(&mut staged_state, &mut vec![], 0).is_err());
+        assert_eq!(0, staged_state.sector_id_nonce);
+    }
+}
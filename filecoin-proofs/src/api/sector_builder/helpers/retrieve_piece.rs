@@ -1,12 +1,17 @@
 use crate::api::internal;
 use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::io_scheduler::{IoClass, IoScheduler, ScheduledReader};
 use crate::api::sector_builder::metadata::sector_id_as_bytes;
 use crate::api::sector_builder::metadata::SealedSectorMetadata;
-use crate::api::sector_builder::WrappedSectorStore;
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
 use crate::error;
+use sector_base::api::bandwidth::MeteredReader;
 use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use std::fs::File;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 
 // Unseals and returns the piece-bytes for the first sector found containing
 // a piece with matching key.
@@ -15,7 +20,30 @@ pub fn retrieve_piece<'a>(
     sealed_sector: &SealedSectorMetadata,
     prover_id: &[u8; 31],
     piece_key: &'a str,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
 ) -> error::Result<Vec<u8>> {
+    retrieve_piece_timed(
+        sector_store,
+        sealed_sector,
+        prover_id,
+        piece_key,
+        io_scheduler,
+        sector_locks,
+    )
+    .map(|(bytes, _)| bytes)
+}
+
+// Like retrieve_piece, but also returns the instant at which the sealed
+// sector's replica had been read and its decoding began.
+pub fn retrieve_piece_timed<'a>(
+    sector_store: &Arc<WrappedSectorStore>,
+    sealed_sector: &SealedSectorMetadata,
+    prover_id: &[u8; 31],
+    piece_key: &'a str,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+) -> error::Result<(Vec<u8>, Instant)> {
     let staging_sector_access = sector_store
         .inner
         .manager()
@@ -28,6 +56,8 @@ pub fn retrieve_piece<'a>(
         prover_id,
         piece_key,
         &staging_sector_access,
+        io_scheduler,
+        sector_locks,
     );
 
     if result.is_ok() {
@@ -37,9 +67,9 @@ pub fn retrieve_piece<'a>(
             .delete_staging_sector_access(&staging_sector_access)?;
     }
 
-    let (_, bytes) = result?;
+    let (_, bytes, decode_started_at) = result?;
 
-    Ok(bytes)
+    Ok((bytes, decode_started_at))
 }
 
 fn retrieve_piece_aux<'a>(
@@ -48,7 +78,9 @@ fn retrieve_piece_aux<'a>(
     prover_id: &[u8; 31],
     piece_key: &'a str,
     staging_sector_access: &'a str,
-) -> error::Result<(UnpaddedBytesAmount, Vec<u8>)> {
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+) -> error::Result<(UnpaddedBytesAmount, Vec<u8>, Instant)> {
     let (start_offset, num_bytes) = piece_pos(&sealed_sector, piece_key).ok_or_else(|| {
         let msg = format!(
             "piece {} not found in sector {}",
@@ -57,12 +89,73 @@ fn retrieve_piece_aux<'a>(
         err_unrecov(msg)
     })?;
 
-    let num_bytes_unsealed = internal::get_unsealed_range(
-        (*sector_store.inner).proofs_config().porep_config(),
-        &PathBuf::from(sealed_sector.sector_access.clone()),
+    let decode_started_at = unseal_range(
+        sector_store,
+        sealed_sector.sector_id,
+        &sealed_sector.sector_access,
+        prover_id,
+        start_offset,
+        num_bytes,
+        staging_sector_access,
+        io_scheduler,
+        sector_locks,
+    )?;
+
+    let piece_bytes =
+        sector_store
+            .inner
+            .manager()
+            .read_raw(&staging_sector_access.to_string(), 0, num_bytes)?;
+
+    Ok((num_bytes, piece_bytes, decode_started_at))
+}
+
+// Unseals num_bytes of the sealed sector's unsealed bytes, starting at
+// start_offset (the pieces' bytes being one after another from the start), to
+// the staging access. Returns the instant at which the sector's replica had
+// been read and its decoding began.
+#[allow(clippy::too_many_arguments)]
+pub fn unseal_range(
+    sector_store: &Arc<WrappedSectorStore>,
+    sector_id: SectorId,
+    sealed_sector_access: &str,
+    prover_id: &[u8; 31],
+    start_offset: u64,
+    num_bytes: UnpaddedBytesAmount,
+    staging_sector_access: &str,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+) -> error::Result<Instant> {
+    let porep_config = (*sector_store.inner).proofs_config().porep_config();
+    let bandwidth = sector_store.inner.manager().bandwidth_accounting();
+
+    let replica = {
+        // The replica is read into memory, so the sector's file needn't stay
+        // mapped while it's decoded.
+        let lock = sector_locks.get(sector_id);
+        let _guard = lock.map_for_reading();
+
+        internal::read_sealed_replica(
+            porep_config,
+            MeteredReader {
+                inner: ScheduledReader::new(
+                    File::open(sealed_sector_access)?,
+                    io_scheduler,
+                    IoClass::Piece,
+                ),
+                accounting: bandwidth,
+            },
+        )?
+    };
+
+    let decode_started_at = Instant::now();
+
+    let num_bytes_unsealed = internal::get_unsealed_range_from_replica(
+        porep_config,
+        &replica,
         &PathBuf::from(staging_sector_access),
         prover_id,
-        &sector_id_as_bytes(sealed_sector.sector_id)?,
+        &sector_id_as_bytes(sector_id)?,
         start_offset,
         num_bytes,
     )?;
@@ -77,13 +170,10 @@ fn retrieve_piece_aux<'a>(
         return Err(err_unrecov(s).into());
     }
 
-    let piece_bytes = sector_store.inner.manager().read_raw(
-        &staging_sector_access.to_string(),
-        0,
-        num_bytes_unsealed,
-    )?;
+    // The unsealed bytes are written to the staging access unpadded.
+    bandwidth.record_sent(u64::from(num_bytes_unsealed));
 
-    Ok((num_bytes_unsealed, piece_bytes))
+    Ok(decode_started_at)
 }
 
 // Returns a tuple of piece bytes-offset and number-of-bytes in piece if the
@@ -128,16 +218,19 @@ mod tests {
         sealed_sector.pieces.push(PieceMetadata {
             piece_key: String::from("x"),
             num_bytes: UnpaddedBytesAmount(5),
+            ..Default::default()
         });
 
         sealed_sector.pieces.push(PieceMetadata {
             piece_key: String::from("y"),
             num_bytes: UnpaddedBytesAmount(30),
+            ..Default::default()
         });
 
         sealed_sector.pieces.push(PieceMetadata {
             piece_key: String::from("z"),
             num_bytes: UnpaddedBytesAmount(100),
+            ..Default::default()
         });
 
         match piece_pos(&sealed_sector, "x") {
@@ -0,0 +1,113 @@
+use crate::api::sector_builder::state::SealedState;
+use crate::api::sector_builder::SectorId;
+use byteorder::{ByteOrder, LittleEndian};
+use rand::{ChaChaRng, Rng, SeedableRng};
+
+// Pseudorandomly selects ceil(n * sample_fraction) of the n sealed sectors
+// (sample_fraction being clamped to [0, 1]) for an audit. The selection is
+// determined by the seed alone, so that an auditor can reproduce it.
+pub fn sample_sectors_for_audit(
+    sealed_state: &SealedState,
+    rng_seed: [u8; 32],
+    sample_fraction: f64,
+) -> Vec<SectorId> {
+    // Sampled from a canonical order, rather than the map's.
+    let mut sector_ids: Vec<SectorId> = sealed_state.sectors.keys().cloned().collect();
+    sector_ids.sort();
+
+    let n = sector_ids.len();
+    let sample_size = ((n as f64) * sample_fraction.max(0.0).min(1.0)).ceil() as usize;
+
+    let mut key = [0u32; 8];
+    LittleEndian::read_u32_into(&rng_seed, &mut key);
+    let mut rng = ChaChaRng::from_seed(&key[..]);
+
+    // A partial Fisher-Yates shuffle: the first sample_size positions end up
+    // holding a uniformly-chosen sample.
+    for i in 0..sample_size.min(n) {
+        let j = i + uniform_below(&mut rng, (n - i) as u64) as usize;
+        sector_ids.swap(i, j);
+    }
+
+    sector_ids.truncate(sample_size.min(n));
+    sector_ids.sort();
+    sector_ids
+}
+
+// Returns a uniformly-distributed number below bound (which must be
+// positive), rejecting the draws which would bias a plain modulo.
+fn uniform_below(rng: &mut ChaChaRng, bound: u64) -> u64 {
+    let zone = u64::max_value() - (u64::max_value() % bound);
+
+    loop {
+        let x = rng.next_u64();
+        if x < zone {
+            return x % bound;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::SealedSectorMetadata;
+
+    fn sealed_state(num_sectors: u64) -> SealedState {
+        let mut state: SealedState = Default::default();
+
+        for sector_id in 0..num_sectors {
+            state.sectors.insert(
+                sector_id,
+                SealedSectorMetadata {
+                    sector_id,
+                    ..Default::default()
+                },
+            );
+        }
+
+        state
+    }
+
+    #[test]
+    fn test_sampling_is_reproducible() {
+        let state = sealed_state(100);
+
+        let sample = sample_sectors_for_audit(&state, [7; 32], 0.1);
+        assert_eq!(10, sample.len());
+        assert_eq!(sample, sample_sectors_for_audit(&state, [7; 32], 0.1));
+
+        // a sector builder which loaded the same sectors (in whatever order)
+        // draws the same sample
+        let mut reloaded = sealed_state(0);
+        for sector_id in (0..100).rev() {
+            reloaded
+                .sectors
+                .insert(sector_id, state.sectors[&sector_id].clone());
+        }
+        assert_eq!(sample, sample_sectors_for_audit(&reloaded, [7; 32], 0.1));
+
+        assert_ne!(sample, sample_sectors_for_audit(&state, [8; 32], 0.1));
+    }
+
+    #[test]
+    fn test_sample_size_rounds_up() {
+        let state = sealed_state(7);
+
+        assert_eq!(1, sample_sectors_for_audit(&state, [0; 32], 0.01).len());
+        assert_eq!(4, sample_sectors_for_audit(&state, [0; 32], 0.5).len());
+        assert_eq!(7, sample_sectors_for_audit(&state, [0; 32], 1.5).len());
+        assert!(sample_sectors_for_audit(&state, [0; 32], 0.0).is_empty());
+        assert!(sample_sectors_for_audit(&sealed_state(0), [0; 32], 0.5).is_empty());
+    }
+
+    #[test]
+    fn test_samples_are_distinct() {
+        let sample = sample_sectors_for_audit(&sealed_state(50), [3; 32], 0.5);
+
+        let mut deduped = sample.clone();
+        deduped.dedup();
+
+        assert_eq!(25, sample.len());
+        assert_eq!(sample, deduped);
+    }
+}
@@ -1,50 +1,318 @@
-use crate::api::internal::seal as seal_internal;
 use crate::api::internal::SealOutput;
+use crate::api::internal::{open_staged_sector_file, seal_from_reader, SealPhase};
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::gpu::SealProver;
+use crate::api::sector_builder::helpers::cleanup_partial_seal_files::partial_seal_access;
+use crate::api::sector_builder::helpers::incremental_comm_d::comm_d_from_merkle_tree_state;
+use crate::api::sector_builder::helpers::obfuscate_fill_time::pad_staged_sector_with_noise;
+use crate::api::sector_builder::io_scheduler::{IoClass, IoScheduler, ScheduledReader};
 use crate::api::sector_builder::metadata::sector_id_as_bytes;
+use crate::api::sector_builder::metadata::ApiVersion;
 use crate::api::sector_builder::metadata::SealedSectorMetadata;
 use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::api::sector_builder::seal_verifier::{verify_sealed_sector, SealVerifier};
+use crate::api::sector_builder::sector_locks::SectorLocks;
 use crate::api::sector_builder::WrappedSectorStore;
 use crate::error;
+use sector_base::api::bandwidth::MeteredReader;
+use sector_base::api::bytes_amount::PaddedBytesAmount;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::PathBuf;
+use std::sync::mpsc;
 use std::sync::Arc;
 
+#[allow(clippy::too_many_arguments)]
 pub fn seal(
     sector_store: &Arc<WrappedSectorStore>,
     prover_id: &[u8; 31],
     staged_sector: StagedSectorMetadata,
+    api_version: ApiVersion,
+    obfuscate_fill_time: bool,
+    precomputed_comm_d: Option<mpsc::Receiver<Option<[u8; 32]>>>,
+    seal_verifier: Option<&SealVerifier>,
+    seal_prover: &SealProver,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+    on_progress: &Fn(SealPhase, f64),
 ) -> error::Result<SealedSectorMetadata> {
+    // The proofs this build links against implement the V1 sealing API only.
+    if api_version != ApiVersion::V1 {
+        return Err(err_unrecov(format!(
+            "sealing with {:?} is not supported by this build",
+            api_version
+        ))
+        .into());
+    }
+
+    let mgr = sector_store.inner.manager();
+
+    if obfuscate_fill_time {
+        pad_staged_sector_with_noise(
+            mgr,
+            &staged_sector.sector_access,
+            sector_store
+                .inner
+                .sector_config()
+                .max_unsealed_bytes_per_sector(),
+            prover_id,
+            staged_sector.sector_id,
+        )?;
+    }
+
     // Provision a new sealed sector access through the manager.
-    let sealed_sector_access = sector_store
-        .inner
-        .manager()
+    let sealed_sector_access = mgr
         .new_sealed_sector_access()
         .map_err(failure::Error::from)?;
 
+    // The sector is sealed to a temporary access which is renamed once sealing
+    // has succeeded, so that a crash mid-seal can't leave a partially-written
+    // sector at the sealed sector access.
+    let partial_access = partial_seal_access(&sealed_sector_access, staged_sector.sector_id);
+
+    let result = seal_to_partial_access(
+        sector_store,
+        prover_id,
+        staged_sector,
+        api_version,
+        obfuscate_fill_time,
+        precomputed_comm_d,
+        seal_verifier,
+        seal_prover,
+        &partial_access,
+        sealed_sector_access.clone(),
+        io_scheduler,
+        sector_locks,
+        on_progress,
+    )
+    .and_then(|sealed_sector| {
+        mgr.rename_sector_access(&partial_access, &sealed_sector_access)?;
+        Ok(sealed_sector)
+    });
+
+    if result.is_err() {
+        // The partial output may not exist, depending on where sealing failed.
+        let _ = mgr.delete_sealed_sector_access(&partial_access);
+    }
+
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn seal_to_partial_access(
+    sector_store: &Arc<WrappedSectorStore>,
+    prover_id: &[u8; 31],
+    staged_sector: StagedSectorMetadata,
+    api_version: ApiVersion,
+    obfuscate_fill_time: bool,
+    precomputed_comm_d: Option<mpsc::Receiver<Option<[u8; 32]>>>,
+    seal_verifier: Option<&SealVerifier>,
+    seal_prover: &SealProver,
+    partial_access: &str,
+    sealed_sector_access: String,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+    on_progress: &Fn(SealPhase, f64),
+) -> error::Result<SealedSectorMetadata> {
     // Run the FPS seal operation. This call will block for a long time, so make
     // sure you're not holding any locks.
 
+    let porep_config = (*sector_store.inner).proofs_config().porep_config();
+    let bandwidth = sector_store.inner.manager().bandwidth_accounting();
+
+    // The commD computed as pieces were added. The tree state persisted with
+    // the sector is preferred, as its root requires hashing only the tree's
+    // right edge, and otherwise we wait on the precompute pipeline, which
+    // will long since have hashed the sector's last piece. Neither accounts
+    // for padding with noise.
+    let expected_comm_d = if obfuscate_fill_time {
+        None
+    } else {
+        match comm_d_from_merkle_tree_state(&staged_sector).unwrap_or(None) {
+            Some(comm_d) => Some(comm_d),
+            None => precomputed_comm_d.and_then(|rx| rx.recv().ok().and_then(|x| x)),
+        }
+    };
+
+    // The staged bytes are read in turns with other sector file I/O.
+    let mut staged = open_staged_sector_file(
+        porep_config,
+        &PathBuf::from(staged_sector.sector_access.clone()),
+    )?;
+
+    // Encoding is done once the body of the staged sector's file (its pieces
+    // as written, with Fr32 padding, plus any noise) has been read.
+    let staged_bytes = staged.seek(SeekFrom::End(0))?;
+    staged.seek(SeekFrom::Start(0))?;
+
     let SealOutput {
         comm_r,
         comm_d,
         comm_r_star,
         proof,
-    } = seal_internal(
-        (*sector_store.inner).proofs_config().porep_config(),
-        &PathBuf::from(staged_sector.sector_access.clone()),
-        &PathBuf::from(sealed_sector_access.clone()),
-        prover_id,
-        &sector_id_as_bytes(staged_sector.sector_id)?,
-    )?;
+    } = {
+        // The staged bytes are copied into the replica, which is mapped, so
+        // no piece may be written to the staged sector meanwhile.
+        let lock = sector_locks.get(staged_sector.sector_id);
+        let _guard = lock.map_for_reading();
+
+        // Encoding progresses as the staged bytes are copied.
+        let mut encoding = EncodingProgressReader {
+            inner: MeteredReader {
+                inner: ScheduledReader::new(staged, io_scheduler, IoClass::Seal),
+                accounting: bandwidth,
+            },
+            num_bytes_read: 0,
+            staged_bytes,
+            on_progress,
+        };
+
+        seal_from_reader(
+            porep_config,
+            &mut encoding,
+            &PathBuf::from(partial_access),
+            prover_id,
+            &sector_id_as_bytes(staged_sector.sector_id)?,
+            expected_comm_d,
+            &|inputs| seal_prover.prove_seal(inputs),
+            &|phase| on_progress(phase, 0.0),
+        )?
+    };
+
+    // The replica, which is written in place of the staged bytes copied to
+    // the partial access, fills the sector.
+    bandwidth.record_sent(u64::from(PaddedBytesAmount::from(porep_config)));
 
     let newly_sealed_sector = SealedSectorMetadata {
         sector_id: staged_sector.sector_id,
         sector_access: sealed_sector_access,
-        pieces: staged_sector.pieces,
+        pieces: staged_sector
+            .pieces
+            .into_iter()
+            .map(|(_, piece)| piece)
+            .collect(),
         comm_r_star,
         comm_r,
         comm_d,
         proof,
+        // recorded by the scheduler, which knows the sector builder's location
+        sealing_location: None,
+        archive_receipt: None,
+        api_version,
+        // likewise set by the scheduler, which knows the current epoch
+        pre_commit_deadline: None,
+        sealed_at_epoch: None,
+        has_merkle_snapshot: false,
+        // computed by the sealer, once the replica is written
+        replica_checksum: None,
     };
 
+    // When configured to, don't hand out a sealed sector whose proof won't be
+    // accepted.
+    if let Some(seal_verifier) = seal_verifier {
+        verify_sealed_sector(seal_verifier, prover_id, &newly_sealed_sector)?;
+    }
+
     Ok(newly_sealed_sector)
 }
+
+// Reports the progress of the encoding phase as the staged bytes are read.
+struct EncodingProgressReader<'a, R> {
+    inner: R,
+    num_bytes_read: u64,
+    staged_bytes: u64,
+    on_progress: &'a Fn(SealPhase, f64),
+}
+
+impl<'a, R: Read> Read for EncodingProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        self.num_bytes_read += n as u64;
+        (self.on_progress)(
+            SealPhase::Encoding,
+            self.num_bytes_read as f64 / cmp::max(self.staged_bytes, 1) as f64,
+        );
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::sealing_test_harness::await_seal;
+    use crate::api::sector_builder::test_utils::{sector_builder_factory, TEST_CLASS};
+    use sector_base::api::bandwidth::{get_bandwidth_usage, reset_bandwidth_counters};
+    use std::fs;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_encoding_progress_reaches_one() {
+        let staged = vec![1u8; 1000];
+        let reported = Mutex::new(Vec::new());
+        let on_progress =
+            |phase: SealPhase, progress: f64| reported.lock().unwrap().push((phase, progress));
+
+        let mut encoding = EncodingProgressReader {
+            inner: &staged[..],
+            num_bytes_read: 0,
+            staged_bytes: staged.len() as u64,
+            on_progress: &on_progress,
+        };
+        io::copy(&mut encoding, &mut io::sink()).unwrap();
+
+        let reported = reported.lock().unwrap();
+        assert!(reported
+            .iter()
+            .all(|(phase, _)| *phase == SealPhase::Encoding));
+        assert_eq!(
+            Some(1.0),
+            reported.iter().map(|(_, progress)| *progress).last()
+        );
+    }
+
+    #[test]
+    #[ignore] // Slow test – run only when compiled for release.
+    fn test_seal_counts_bytes_transferred() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sector_builder = sector_builder_factory(TEST_CLASS, dir.path())
+            .unwrap()
+            .create_concrete_sector_builder(Default::default())
+            .unwrap();
+        let mgr = sector_builder.state.sector_store.inner.manager();
+
+        let piece_path = dir.path().join("piece");
+        fs::write(&piece_path, vec![7; 100]).unwrap();
+
+        let sector_id = sector_builder
+            .add_piece(
+                "piece".to_string(),
+                100,
+                piece_path.to_string_lossy().into_owned(),
+            )
+            .unwrap();
+
+        // the piece was sent to the staged sector
+        assert!(reset_bandwidth_counters(mgr).bytes_sent > 100);
+
+        sector_builder.seal_sector(sector_id).unwrap();
+        await_seal(&sector_builder, sector_id).unwrap();
+
+        // Sealing reads the staged bytes and sends the replica (which fills
+        // the sector), which is then read back to be hashed.
+        let sector_bytes = u64::from(
+            sector_builder
+                .state
+                .sector_store
+                .inner
+                .sector_config()
+                .sector_bytes(),
+        );
+        let usage = get_bandwidth_usage(mgr);
+
+        assert_eq!(sector_bytes, usage.bytes_sent);
+        assert!(usage.bytes_received > sector_bytes);
+    }
+}
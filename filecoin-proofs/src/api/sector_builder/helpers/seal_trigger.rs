@@ -0,0 +1,205 @@
+use std::collections::HashSet;
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::check_seal_fill_ratio::sector_fill_ratio;
+use crate::api::sector_builder::metadata::SealStatus;
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::SectorId;
+use crate::error;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+// Decides when a staged sector is full enough that it should be sealed, even
+// though it has room for more pieces.
+//
+// A single threshold would be crossed again and again by a sector left staged
+// (e.g. because its seal was declined) as small pieces nudge its fill ratio
+// back over it, each crossing scheduling yet another seal. Instead, once the
+// trigger has fired for a sector it stays disarmed for that sector until the
+// sector's fill ratio has dropped below the (lower) resume threshold.
+#[derive(Clone, Debug)]
+pub struct SealTrigger {
+    seal_trigger_threshold: f64,
+    resume_add_threshold: f64,
+    disarmed: HashSet<SectorId>,
+}
+
+impl SealTrigger {
+    pub fn new(
+        seal_trigger_threshold: f64,
+        resume_add_threshold: f64,
+    ) -> error::Result<SealTrigger> {
+        if resume_add_threshold < 0.0
+            || resume_add_threshold > seal_trigger_threshold
+            || seal_trigger_threshold > 1.0
+        {
+            return Err(err_unrecov(format!(
+                "invalid fill thresholds (seal: {}, resume: {})",
+                seal_trigger_threshold, resume_add_threshold
+            ))
+            .into());
+        }
+
+        Ok(SealTrigger {
+            seal_trigger_threshold,
+            resume_add_threshold,
+            disarmed: Default::default(),
+        })
+    }
+
+    // Returns true if the sector's fill ratio has crossed the seal trigger
+    // threshold since it was last below the resume threshold.
+    pub fn observe(&mut self, sector_id: SectorId, fill_ratio: f64) -> bool {
+        let armed = !self.disarmed.contains(&sector_id);
+
+        if armed && fill_ratio >= self.seal_trigger_threshold {
+            self.disarmed.insert(sector_id);
+            return true;
+        }
+
+        if !armed && fill_ratio < self.resume_add_threshold {
+            self.disarmed.remove(&sector_id);
+        }
+
+        false
+    }
+
+    // Returns the ids of the staged sectors which should be sealed, their fill
+    // ratios having just crossed the seal trigger threshold.
+    pub fn check(
+        &mut self,
+        staged_state: &StagedState,
+        max_user_bytes_per_staged_sector: UnpaddedBytesAmount,
+    ) -> Vec<SectorId> {
+        // Sectors which are no longer staged won't cross either threshold.
+        self.disarmed
+            .retain(|sector_id| staged_state.sectors.contains_key(sector_id));
+
+        let mut to_be_sealed: Vec<SectorId> = staged_state
+            .sectors
+            .values()
+            .filter(|x| x.seal_status == SealStatus::Pending)
+            .filter(|x| {
+                self.observe(
+                    x.sector_id,
+                    sector_fill_ratio(x, max_user_bytes_per_staged_sector),
+                )
+            })
+            .map(|x| x.sector_id)
+            .collect();
+
+        to_be_sealed.sort();
+        to_be_sealed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{push_piece, PieceMetadata, StagedSectorMetadata};
+
+    fn add_tiny_piece(staged_state: &mut StagedState, sector_id: SectorId) {
+        let sector =
+            staged_state
+                .sectors
+                .entry(sector_id)
+                .or_insert_with(|| StagedSectorMetadata {
+                    sector_id,
+                    seal_status: SealStatus::Pending,
+                    ..Default::default()
+                });
+
+        push_piece(
+            sector,
+            PieceMetadata {
+                num_bytes: UnpaddedBytesAmount(1),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_rejects_inverted_thresholds() {
+        assert!(SealTrigger::new(0.5, 0.6).is_err());
+        assert!(SealTrigger::new(1.1, 0.6).is_err());
+        assert!(SealTrigger::new(0.8, 0.8).is_ok());
+    }
+
+    #[test]
+    fn test_fires_once_per_crossing() {
+        let mut trigger = SealTrigger::new(0.8, 0.6).unwrap();
+
+        // hovering around the upper threshold
+        let fired: Vec<bool> = [0.79, 0.80, 0.79, 0.81, 0.79, 0.81, 0.61]
+            .iter()
+            .map(|r| trigger.observe(1, *r))
+            .collect();
+        assert_eq!(vec![false, true, false, false, false, false, false], fired);
+
+        // each sector crosses the threshold separately
+        assert!(trigger.observe(2, 0.80));
+
+        // dropping below the lower threshold re-arms the trigger
+        assert!(!trigger.observe(1, 0.59));
+        assert!(!trigger.observe(1, 0.79));
+        assert!(trigger.observe(1, 0.80));
+        assert!(!trigger.observe(1, 0.85));
+        assert!(!trigger.observe(2, 0.85));
+    }
+
+    #[test]
+    fn test_tiny_pieces_seal_once_per_crossing() {
+        let mut trigger = SealTrigger::new(0.8, 0.6).unwrap();
+        let mut staged_state: StagedState = Default::default();
+        let mut sealed: Vec<SectorId> = Default::default();
+
+        let mut add = |sector_id: SectorId, num_pieces: usize, sealed: &mut Vec<SectorId>| {
+            for _ in 0..num_pieces {
+                add_tiny_piece(&mut staged_state, sector_id);
+
+                // a sealed sector stays staged (as if its seal was declined)
+                sealed.extend(trigger.check(&staged_state, UnpaddedBytesAmount(100)));
+            }
+        };
+
+        // each sector is sealed as its 80th byte is added, and not again
+        // however many more it's given
+        add(1, 79, &mut sealed);
+        add(2, 70, &mut sealed);
+        assert!(sealed.is_empty());
+
+        add(1, 1, &mut sealed);
+        assert_eq!(vec![1], sealed);
+
+        add(1, 15, &mut sealed);
+        add(2, 15, &mut sealed);
+        assert_eq!(vec![1, 2], sealed);
+
+        // a sector which drops below the resume threshold (its pieces having
+        // been removed) is sealed again once it's refilled
+        staged_state.sectors.get_mut(&1).unwrap().pieces.clear();
+        add(1, 79, &mut sealed);
+        assert_eq!(vec![1, 2], sealed);
+        add(1, 1, &mut sealed);
+        assert_eq!(vec![1, 2, 1], sealed);
+    }
+
+    #[test]
+    fn test_forgets_sectors_no_longer_staged() {
+        let mut trigger = SealTrigger::new(0.8, 0.6).unwrap();
+        let mut staged_state: StagedState = Default::default();
+
+        for _ in 0..80 {
+            add_tiny_piece(&mut staged_state, 1);
+        }
+        assert_eq!(
+            vec![1],
+            trigger.check(&staged_state, UnpaddedBytesAmount(100))
+        );
+
+        staged_state.sectors.remove(&1);
+        assert!(trigger
+            .check(&staged_state, UnpaddedBytesAmount(100))
+            .is_empty());
+        assert!(trigger.disarmed.is_empty());
+    }
+}
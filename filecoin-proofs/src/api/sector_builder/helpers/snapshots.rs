@@ -1,7 +1,11 @@
 use std::sync::Arc;
 
+use crate::api::sector_builder::helpers::compact_sealed_state::{
+    load_index, load_sealed_sectors, persist_compacted, split_index, SealedSectorKey,
+};
 use crate::api::sector_builder::kv_store::KeyValueStore;
 use crate::api::sector_builder::state::*;
+use crate::api::sector_builder::SectorId;
 use crate::api::sector_builder::WrappedKeyValueStore;
 use crate::error::Result;
 
@@ -9,20 +13,58 @@ pub fn load_snapshot<T: KeyValueStore>(
     kv_store: &Arc<WrappedKeyValueStore<T>>,
     prover_id: &[u8; 31],
 ) -> Result<Option<StateSnapshot>> {
-    let result: Option<Vec<u8>> = kv_store.inner.get(prover_id)?;
+    match load_snapshot_lazily(kv_store, prover_id)? {
+        Some((mut snapshot, Some(sealed_sector_keys))) => {
+            snapshot.sealed = load_sealed_sectors(kv_store, &sealed_sector_keys)?;
+            Ok(Some(snapshot))
+        }
+        Some((snapshot, None)) => Ok(Some(snapshot)),
+        None => Ok(None),
+    }
+}
 
-    if let Some(val) = result {
-        return serde_cbor::from_slice(&val[..])
-            .map_err(failure::Error::from)
-            .map(Option::Some);
+// Loads the snapshot, except (if it has been compacted) for its sealed
+// sectors, which are left to be loaded with load_sealed_sectors, using the
+// sealed sector keys returned alongside it, when they're first needed.
+pub fn load_snapshot_lazily<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    prover_id: &[u8; 31],
+) -> Result<Option<(StateSnapshot, Option<Vec<SealedSectorKey>>)>> {
+    // The state is persisted as a single entry until it has been compacted,
+    // and compaction only removes that entry once the compacted state is in
+    // place. So, if the entry is still there, it's the one to load.
+    if let Some(val) = kv_store.inner.get(prover_id)? {
+        let snapshot = serde_cbor::from_slice(&val[..])?;
+
+        return Ok(Some((snapshot, None)));
     }
 
-    Ok(None)
+    Ok(load_index(kv_store, prover_id)?.map(|index| {
+        let (snapshot, sealed_sector_keys) = split_index(index);
+
+        (snapshot, Some(sealed_sector_keys))
+    }))
 }
 
 pub fn persist_snapshot<T: KeyValueStore>(
     kv_store: &Arc<WrappedKeyValueStore<T>>,
     snapshot: &StateSnapshot,
+) -> Result<()> {
+    if kv_store.inner.get(&snapshot.prover_id)?.is_none() {
+        if let Some(index) = load_index(kv_store, &snapshot.prover_id)? {
+            persist_compacted(kv_store, snapshot, Some(&index.sealed_sector_keys[..]))?;
+            return Ok(());
+        }
+    }
+
+    persist_uncompacted_snapshot(kv_store, snapshot)
+}
+
+// Persists the snapshot as a single entry, for a state which is known not to
+// have been compacted.
+pub fn persist_uncompacted_snapshot<T: KeyValueStore>(
+    kv_store: &Arc<WrappedKeyValueStore<T>>,
+    snapshot: &StateSnapshot,
 ) -> Result<()> {
     let serialized = serde_cbor::to_vec(snapshot)?;
     kv_store.inner.put(&snapshot.prover_id[..], &serialized)?;
@@ -33,16 +75,22 @@ pub fn make_snapshot(
     prover_id: &[u8; 31],
     staged_state: &StagedState,
     sealed_state: &SealedState,
+    reserved_ranges: &[(SectorId, SectorId)],
+    delta_sequence_number: u64,
 ) -> StateSnapshot {
     StateSnapshot {
         prover_id: *prover_id,
         staged: StagedState {
             sector_id_nonce: staged_state.sector_id_nonce,
             sectors: staged_state.sectors.clone(),
+            idempotency_keys: staged_state.idempotency_keys.clone(),
+            redacted_pieces: staged_state.redacted_pieces.clone(),
         },
         sealed: SealedState {
             sectors: sealed_state.sectors.clone(),
         },
+        reserved_ranges: reserved_ranges.to_vec(),
+        delta_sequence_number,
     }
 }
 
@@ -77,6 +125,7 @@ mod tests {
             let staged_state = Mutex::new(StagedState {
                 sector_id_nonce: 100,
                 sectors: m,
+                ..Default::default()
             });
 
             let sealed_state: Mutex<SealedState> = Default::default();
@@ -88,6 +137,8 @@ mod tests {
             &prover_id,
             &staged_state.lock().unwrap(),
             &sealed_state.lock().unwrap(),
+            &[(101, 105)],
+            7,
         );
 
         let _ = persist_snapshot(&kv_store, &to_persist).unwrap();
@@ -0,0 +1,163 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api::sector_builder::errors::err_back_pressure;
+use crate::api::sector_builder::metadata::SealStatus;
+use crate::api::sector_builder::state::StagedState;
+use crate::error::{self, ExpectWithBacktrace};
+
+const FATAL_NOLOCK: &str = "error acquiring staged capacity lock";
+
+// Refuses new pieces while there are max_staged_sectors staged sectors (be
+// they accepting data or waiting to be sealed), so that callers slow down
+// rather than staged sectors accumulating faster than they can be sealed.
+pub fn check_staged_capacity(
+    staged_state: &StagedState,
+    max_staged_sectors: usize,
+) -> error::Result<()> {
+    let queued_sectors = num_queued_sectors(staged_state);
+
+    if queued_sectors >= max_staged_sectors {
+        Err(err_back_pressure(queued_sectors).into())
+    } else {
+        Ok(())
+    }
+}
+
+// The number of staged sectors which count against max_staged_sectors. A
+// sector whose seal failed stays staged, so that its pieces can be
+// recovered, but it's no longer on its way to being sealed.
+pub fn num_queued_sectors(staged_state: &StagedState) -> usize {
+    staged_state
+        .sectors
+        .values()
+        .filter(|sector| match sector.seal_status {
+            SealStatus::Failed(_) => false,
+            _ => true,
+        })
+        .count()
+}
+
+// The number of staged sectors, as last published by the main worker, so
+// that callers can wait for capacity without queueing behind it.
+#[derive(Debug)]
+pub struct StagedCapacity {
+    max_staged_sectors: usize,
+    num_staged_sectors: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl StagedCapacity {
+    pub fn new(max_staged_sectors: usize) -> StagedCapacity {
+        StagedCapacity {
+            max_staged_sectors,
+            num_staged_sectors: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn publish(&self, num_staged_sectors: usize) {
+        *self.num_staged_sectors.lock().expects(FATAL_NOLOCK) = num_staged_sectors;
+        self.changed.notify_all();
+    }
+
+    // Blocks until there are fewer than max_staged_sectors staged sectors,
+    // producing a back-pressure error if that doesn't happen within timeout.
+    pub fn wait(&self, timeout: Duration) -> error::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut num_staged_sectors = self.num_staged_sectors.lock().expects(FATAL_NOLOCK);
+
+        while *num_staged_sectors >= self.max_staged_sectors {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(err_back_pressure(*num_staged_sectors).into());
+            }
+
+            num_staged_sectors = self
+                .changed
+                .wait_timeout(num_staged_sectors, deadline - now)
+                .expects(FATAL_NOLOCK)
+                .0;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::errors::SectorBuilderErr;
+    use crate::api::sector_builder::metrics::Histogram;
+    use crate::api::sector_builder::sector_id_allocator::SectorIdAllocator;
+    use crate::api::sector_builder::test_utils::add_piece;
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_back_pressure_at_max_staged_sectors() {
+        let (sector_store, _) = mock_sector_store();
+
+        let mut staged_state: StagedState = Default::default();
+        let mut reserved_ranges = Vec::new();
+        let mut allocator = SectorIdAllocator::nonce();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[7; 1000]).unwrap();
+
+        // each piece fills most of a sector, so needs a sector of its own
+        let mut add = |staged_state: &mut StagedState| {
+            check_staged_capacity(staged_state, 3)?;
+
+            add_piece(
+                &sector_store,
+                staged_state,
+                &mut reserved_ranges,
+                &mut allocator,
+                "piece".to_string(),
+                1000,
+                file.path().to_str().unwrap().to_string(),
+                None,
+                &Histogram::new(vec![]).unwrap(),
+                &Default::default(),
+                &Default::default(),
+            )
+        };
+
+        for _ in 0..3 {
+            add(&mut staged_state).unwrap();
+        }
+
+        let err = add(&mut staged_state).unwrap_err();
+        match err.downcast_ref() {
+            Some(SectorBuilderErr::BackPressure { queued_sectors }) => {
+                assert_eq!(3, *queued_sectors)
+            }
+            _ => panic!("expected back-pressure, got {:?}", err),
+        }
+        assert_eq!(3, staged_state.sectors.len());
+    }
+
+    #[test]
+    fn test_wait_for_capacity() {
+        let capacity = Arc::new(StagedCapacity::new(2));
+
+        capacity.publish(1);
+        assert!(capacity.wait(Duration::from_secs(0)).is_ok());
+
+        capacity.publish(2);
+        assert!(capacity.wait(Duration::from_millis(10)).is_err());
+
+        let waiter = {
+            let capacity = capacity.clone();
+            thread::spawn(move || capacity.wait(Duration::from_secs(60)))
+        };
+
+        thread::sleep(Duration::from_millis(10));
+        capacity.publish(1);
+
+        assert!(waiter.join().unwrap().is_ok());
+    }
+}
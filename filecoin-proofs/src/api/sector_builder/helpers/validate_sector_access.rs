@@ -0,0 +1,127 @@
+use crate::api::sector_builder::errors::err_invalid_access;
+use crate::error;
+use std::path::{Component, Path};
+
+// Rejects sector access strings which could be used to escape the directory
+// in which sectors are stored. Access strings are opaque to the sector
+// builder, but they're (almost always) file paths, so we refuse any which
+// contain parent directory components or null bytes. When sandboxed (i.e. a
+// sector access root has been configured) accesses must be relative to that
+// root or, like those of the disk-backed sector manager, absolute paths
+// within it.
+pub fn validate_sector_access(
+    access: &str,
+    sector_access_root: Option<&Path>,
+) -> error::Result<()> {
+    if access.contains('\0') {
+        return Err(err_invalid_access(access, "contains a null byte").into());
+    }
+
+    let path = Path::new(access);
+
+    if has_parent_dir(path) {
+        return Err(err_invalid_access(access, "contains a path traversal sequence").into());
+    }
+
+    if let Some(root) = sector_access_root {
+        validate_sector_access_root(root)?;
+
+        if path.is_absolute() && !path.starts_with(root) {
+            let msg = format!("must be within {}", root.display());
+            return Err(err_invalid_access(access, msg).into());
+        }
+    }
+
+    Ok(())
+}
+
+// Rejects a sector access root which doesn't pin down a directory: one which
+// is relative (and so depends on the working directory) or which contains
+// parent directory components.
+pub fn validate_sector_access_root(root: &Path) -> error::Result<()> {
+    let root_str = root.to_string_lossy();
+
+    if !root.is_absolute() {
+        return Err(err_invalid_access(&root_str, "sector access root must be absolute").into());
+    }
+
+    if has_parent_dir(root) {
+        return Err(err_invalid_access(
+            &root_str,
+            "sector access root contains a path traversal sequence",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn has_parent_dir(path: &Path) -> bool {
+    path.components().any(|c| c == Component::ParentDir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        let root = Path::new("/var/sectors");
+
+        for access in &[
+            "../etc/passwd",
+            "staged/../../etc/passwd",
+            "staged/..",
+            "/var/sectors/../etc/passwd",
+        ] {
+            assert!(validate_sector_access(access, None).is_err());
+            assert!(validate_sector_access(access, Some(root)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_accepts_dots_within_names() {
+        let root = Path::new("/var/sectors");
+
+        assert!(validate_sector_access("staged/a..b", Some(root)).is_ok());
+        assert!(validate_sector_access("staged/..abc", None).is_ok());
+        assert!(validate_sector_access("./staged/abc", Some(root)).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_null_bytes() {
+        assert!(validate_sector_access("staged/abc\0.txt", None).is_err());
+    }
+
+    #[test]
+    fn test_confines_absolute_paths_when_sandboxed() {
+        let root = Path::new("/var/sectors");
+
+        assert!(validate_sector_access("/etc/passwd", Some(root)).is_err());
+        assert!(validate_sector_access("/var/sectors-other/abc", Some(root)).is_err());
+
+        // such as those of the disk-backed sector manager
+        assert!(validate_sector_access("/var/sectors/staged/abc", Some(root)).is_ok());
+
+        // absolute paths are fine when not sandboxed
+        assert!(validate_sector_access("/etc/passwd", None).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_unconfined_roots() {
+        for root in &["sectors", "/var/sectors/../etc"] {
+            assert!(validate_sector_access_root(Path::new(root)).is_err());
+            assert!(validate_sector_access("abc", Some(Path::new(root))).is_err());
+        }
+
+        assert!(validate_sector_access_root(Path::new("/var/sectors")).is_ok());
+    }
+
+    #[test]
+    fn test_accepts_well_formed_accesses() {
+        let root = Path::new("/var/sectors");
+
+        assert!(validate_sector_access("staged/abc", Some(root)).is_ok());
+        assert!(validate_sector_access("abc", Some(root)).is_ok());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use crate::api::sector_builder::metadata::SealStatus;
+use crate::api::sector_builder::state::{SealedState, StagedState};
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+
+// A way in which the staged and sealed states disagree, e.g. because the
+// sector builder was interrupted while moving a sector from one to the other.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConsistencyViolation {
+    // The sector is both staged and sealed.
+    SectorInBothStates(SectorId),
+    // The piece is in both a staged sector and a sealed sector.
+    PieceInBothStates {
+        piece_key: String,
+        staged_sector_id: SectorId,
+        sealed_sector_id: SectorId,
+    },
+    // The piece is in a sector which is being sealed, but has already been
+    // sealed into another.
+    SealingPieceAlreadySealed {
+        piece_key: String,
+        sealing_sector_id: SectorId,
+        sealed_sector_id: SectorId,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsistencyReport {
+    // every violation found, sorted
+    pub violations: Vec<ConsistencyViolation>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+// Checks that no sector or piece is in both the staged and the sealed state.
+// A piece of a sector which is being sealed may only appear in the sealed
+// state once that sector's seal completes (at which point it's no longer
+// staged).
+pub fn verify_cross_state_consistency(
+    staged_state: &StagedState,
+    sealed_state: &SealedState,
+) -> Result<ConsistencyReport> {
+    let mut violations = Vec::new();
+
+    let mut sealed_pieces: HashMap<&str, SectorId> = HashMap::new();
+    for sector in sealed_state.sectors.values() {
+        for piece in &sector.pieces {
+            sealed_pieces.insert(&piece.piece_key, sector.sector_id);
+        }
+    }
+
+    for sector in staged_state.sectors.values() {
+        if sealed_state.sectors.contains_key(&sector.sector_id) {
+            violations.push(ConsistencyViolation::SectorInBothStates(sector.sector_id));
+        }
+
+        for piece in sector.pieces.values() {
+            let sealed_sector_id = match sealed_pieces.get(piece.piece_key.as_str()) {
+                Some(sealed_sector_id) => *sealed_sector_id,
+                None => continue,
+            };
+
+            let piece_key = piece.piece_key.clone();
+
+            violations.push(match sector.seal_status {
+                SealStatus::Sealing => ConsistencyViolation::SealingPieceAlreadySealed {
+                    piece_key,
+                    sealing_sector_id: sector.sector_id,
+                    sealed_sector_id,
+                },
+                _ => ConsistencyViolation::PieceInBothStates {
+                    piece_key,
+                    staged_sector_id: sector.sector_id,
+                    sealed_sector_id,
+                },
+            });
+        }
+    }
+
+    violations.sort();
+
+    Ok(ConsistencyReport { violations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::metadata::{
+        pieces_by_offset, PieceMetadata, SealedSectorMetadata, StagedSectorMetadata,
+    };
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+
+    fn pieces(piece_keys: &[&str]) -> Vec<PieceMetadata> {
+        piece_keys
+            .iter()
+            .map(|piece_key| PieceMetadata {
+                piece_key: piece_key.to_string(),
+                num_bytes: UnpaddedBytesAmount(1),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn staged(
+        sector_id: SectorId,
+        seal_status: SealStatus,
+        piece_keys: &[&str],
+    ) -> StagedSectorMetadata {
+        StagedSectorMetadata {
+            sector_id,
+            seal_status,
+            pieces: pieces_by_offset(pieces(piece_keys)),
+            ..Default::default()
+        }
+    }
+
+    fn sealed(sector_id: SectorId, piece_keys: &[&str]) -> SealedSectorMetadata {
+        SealedSectorMetadata {
+            sector_id,
+            pieces: pieces(piece_keys),
+            ..Default::default()
+        }
+    }
+
+    fn states(
+        staged_sectors: Vec<StagedSectorMetadata>,
+        sealed_sectors: Vec<SealedSectorMetadata>,
+    ) -> (StagedState, SealedState) {
+        let mut staged_state: StagedState = Default::default();
+        let mut sealed_state: SealedState = Default::default();
+
+        for sector in staged_sectors {
+            staged_state.sectors.insert(sector.sector_id, sector);
+        }
+
+        for sector in sealed_sectors {
+            sealed_state.sectors.insert(sector.sector_id, sector);
+        }
+
+        (staged_state, sealed_state)
+    }
+
+    #[test]
+    fn test_consistent_states() {
+        let (staged_state, sealed_state) = states(
+            vec![
+                staged(3, SealStatus::Pending, &["c"]),
+                staged(4, SealStatus::Sealing, &["d"]),
+            ],
+            vec![sealed(1, &["a"]), sealed(2, &["b"])],
+        );
+
+        let report = verify_cross_state_consistency(&staged_state, &sealed_state).unwrap();
+        assert!(report.is_consistent());
+    }
+
+    #[test]
+    fn test_reports_every_violation() {
+        let (staged_state, sealed_state) = states(
+            vec![
+                // sealed, but never removed from the staged state
+                staged(1, SealStatus::Pending, &["a"]),
+                // a piece added to two sectors
+                staged(3, SealStatus::Pending, &["c", "b"]),
+                // being sealed, yet its piece is already sealed
+                staged(4, SealStatus::Sealing, &["e", "d"]),
+            ],
+            vec![sealed(1, &["a"]), sealed(2, &["b"]), sealed(5, &["d"])],
+        );
+
+        let report = verify_cross_state_consistency(&staged_state, &sealed_state).unwrap();
+
+        assert_eq!(
+            vec![
+                ConsistencyViolation::SectorInBothStates(1),
+                ConsistencyViolation::PieceInBothStates {
+                    piece_key: "a".to_string(),
+                    staged_sector_id: 1,
+                    sealed_sector_id: 1,
+                },
+                ConsistencyViolation::PieceInBothStates {
+                    piece_key: "b".to_string(),
+                    staged_sector_id: 3,
+                    sealed_sector_id: 2,
+                },
+                ConsistencyViolation::SealingPieceAlreadySealed {
+                    piece_key: "d".to_string(),
+                    sealing_sector_id: 4,
+                    sealed_sector_id: 5,
+                },
+            ],
+            report.violations
+        );
+        assert!(!report.is_consistent());
+    }
+}
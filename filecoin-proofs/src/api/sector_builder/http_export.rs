@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::piece_access::PieceCapabilityToken;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder, SectorId};
+use crate::error::Result;
+use sector_base::api::bandwidth::{MeteredReader, NetworkBandwidthAccounting};
+use sector_base::io::checksum::ChecksummingWriter;
+
+// The most bytes of a sector file read (and written) at once when exporting.
+pub const EXPORT_CHUNK_BYTES: usize = 1 << 16;
+
+// Streams the sealed sector's replica into the writer (e.g. the body of an
+// HTTP response), a chunk at a time. The provided tokens must grant access to
+// each of the sector's restricted pieces, none of which may have been
+// redacted.
+pub fn export_sector_http_streaming(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+    tokens: &[PieceCapabilityToken],
+    response_writer: &mut dyn Write,
+) -> Result<()> {
+    let tokens = tokens.to_vec();
+    let sealed_sector = log_unrecov(
+        sector_builder.run_blocking(|tx| Request::GetExportableSector(sector_id, tokens, tx)),
+    )?;
+
+    let file = File::open(&sealed_sector.sector_access)?;
+    let len = file.metadata()?.len();
+
+    let mut replica = MeteredReader {
+        inner: file,
+        accounting: sector_builder
+            .state
+            .sector_store
+            .inner
+            .manager()
+            .bandwidth_accounting(),
+    };
+
+    copy_range(&mut replica, 0, len, response_writer)
+}
+
+// The BLAKE3 hash of the replica at sector_access, by which it's identified
+// when exported (e.g. as its ETag). The sealer computes it once the replica
+// is written.
+pub fn replica_checksum<P: AsRef<Path>>(
+    sector_access: P,
+    bandwidth: &NetworkBandwidthAccounting,
+) -> Result<[u8; 32]> {
+    let file = File::open(sector_access)?;
+    let len = file.metadata()?.len();
+
+    let mut replica = MeteredReader {
+        inner: file,
+        accounting: bandwidth,
+    };
+
+    let mut hasher = ChecksummingWriter::new(io::sink());
+    copy_range(&mut replica, 0, len, &mut hasher)?;
+
+    Ok(hasher.finalize().1)
+}
+
+// Copies num_bytes of the source, from start, into the sink a chunk at a
+// time, failing if the source ends before they've all been copied.
+pub fn copy_range<R: Read + Seek + ?Sized>(
+    source: &mut R,
+    start: u64,
+    num_bytes: u64,
+    sink: &mut dyn Write,
+) -> Result<()> {
+    source.seek(SeekFrom::Start(start))?;
+
+    let mut remaining = source.take(num_bytes);
+    let mut buf = vec![0; EXPORT_CHUNK_BYTES];
+    let mut copied = 0;
+
+    loop {
+        let n = match remaining.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        sink.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+
+    if copied != num_bytes {
+        return Err(err_unrecov(format!(
+            "copied {} of {} bytes from offset {}",
+            copied, num_bytes, start
+        ))
+        .into());
+    }
+
+    sink.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_copies_range_in_chunks() {
+        let bytes: Vec<u8> = (0..(3 * EXPORT_CHUNK_BYTES as u32 + 17))
+            .map(|x| (x * 7) as u8)
+            .collect();
+
+        let mut copied = Vec::new();
+        copy_range(&mut Cursor::new(&bytes), 0, bytes.len() as u64, &mut copied).unwrap();
+        assert_eq!(bytes, copied);
+
+        let mut copied = Vec::new();
+        copy_range(&mut Cursor::new(&bytes), 100, 70_000, &mut copied).unwrap();
+        assert_eq!(&bytes[100..70_100], &copied[..]);
+
+        // a source which is too short
+        let mut copied = Vec::new();
+        assert!(copy_range(
+            &mut Cursor::new(&bytes),
+            100,
+            bytes.len() as u64,
+            &mut copied
+        )
+        .is_err());
+    }
+}
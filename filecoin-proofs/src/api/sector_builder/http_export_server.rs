@@ -0,0 +1,964 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::api::sector_builder::errors::{err_unrecov, SectorBuilderErr};
+use crate::api::sector_builder::helpers::retrieve_piece::unseal_range;
+use crate::api::sector_builder::http_export::{copy_range, replica_checksum};
+use crate::api::sector_builder::piece_access::PieceCapabilityToken;
+use crate::api::sector_builder::scheduler::Request as SchedulerRequest;
+use crate::api::sector_builder::{SectorBuilder, SectorId, WrappedSectorStore};
+use crate::error::Result;
+use crate::FCP_LOG;
+use futures::future;
+use futures::sink::Wait;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, RANGE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use sector_base::api::bandwidth::{MeteredReader, NetworkBandwidthAccounting};
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use slog::*;
+
+// How many chunks of a sector file may be read ahead of the client.
+const EXPORT_CHANNEL_CHUNKS: usize = 4;
+
+// The most requests answered at once, each on a thread of its own. Further
+// requests are refused (with 503 Service Unavailable) until one of those has
+// been answered.
+const MAX_CONCURRENT_EXPORTS: usize = 16;
+
+// The header (which may be repeated) in which a client presents the
+// hex-encoded capability tokens for a sector's restricted pieces.
+pub const PIECE_TOKEN_HEADER: &str = "x-piece-token";
+
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+// The replica served for a sector, its BLAKE3 hash (its ETag) if that was
+// recorded as it was sealed, and the commitment which identifies its contents
+// (so that its ETag need only be computed once if not). Also the commitment
+// to the sector's unsealed bytes, and how many of those are its pieces'.
+pub(crate) struct ExportedSector {
+    pub path: PathBuf,
+    pub checksum: Option<[u8; 32]>,
+    pub comm_r: [u8; 32],
+    pub comm_d: [u8; 32],
+    pub pieces_len: u64,
+}
+
+// Finds the sector to be served to a request presenting the provided tokens,
+// producing an AccessDenied or PieceRedacted error if it mustn't be.
+pub(crate) type SectorLookup =
+    Fn(SectorId, &[PieceCapabilityToken]) -> Result<ExportedSector> + Send + Sync;
+
+// Writes a range (its first byte and length) of the bytes of a sector's
+// pieces, which are unsealed from its replica, to the sink.
+pub(crate) type PieceUnsealer =
+    Fn(SectorId, &ExportedSector, u64, u64, &mut Write) -> Result<()> + Send + Sync;
+
+// Serves sealed sectors over HTTP until dropped:
+//
+// - a GET of /sector/{sector_id} streams the sector's replica, which isn't
+//   read into memory, and whose ETag is its BLAKE3 hash;
+// - a GET of /sector/{sector_id}/pieces streams the (unpadded) bytes of the
+//   sector's pieces, one after another, which are unsealed as they're
+//   requested. These are what import_sector_http_streaming downloads.
+//
+// A (single) range of either may be requested, so that an interrupted
+// download can be resumed. A sector with restricted pieces is only served to
+// requests presenting a token for each of them (see PIECE_TOKEN_HEADER), and
+// one with redacted pieces isn't served at all. Sectors without restricted
+// pieces are served to anyone who can reach the server.
+pub struct SectorHttpExportServer {
+    local_addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SectorHttpExportServer {
+    // Listens on the given port of the loopback interface (or, if port is
+    // zero, on a port chosen by the operating system; see local_addr), so
+    // that only local clients are served.
+    pub fn start(port: u16, sector_builder: Arc<SectorBuilder>) -> Result<SectorHttpExportServer> {
+        SectorHttpExportServer::start_on(("127.0.0.1", port), sector_builder)
+    }
+
+    // Like start, but listens on the given address, e.g. that of an interface
+    // which remote clients download sectors through.
+    pub fn start_on<A: ToSocketAddrs>(
+        addr: A,
+        sector_builder: Arc<SectorBuilder>,
+    ) -> Result<SectorHttpExportServer> {
+        let sector_store = sector_builder.state.sector_store.clone();
+        let unsealing_sector_builder = sector_builder.clone();
+
+        start_with_lookup(
+            addr,
+            sector_store,
+            Arc::new(move |sector_id, tokens: &[PieceCapabilityToken]| {
+                let tokens = tokens.to_vec();
+                let sealed_sector = sector_builder.run_blocking(|tx| {
+                    SchedulerRequest::GetExportableSector(sector_id, tokens, tx)
+                })?;
+
+                Ok(ExportedSector {
+                    path: PathBuf::from(sealed_sector.sector_access),
+                    checksum: sealed_sector.replica_checksum,
+                    comm_r: sealed_sector.comm_r,
+                    comm_d: sealed_sector.comm_d,
+                    pieces_len: sealed_sector
+                        .pieces
+                        .iter()
+                        .map(|p| u64::from(p.num_bytes))
+                        .sum(),
+                })
+            }),
+            Arc::new(
+                move |sector_id, sector: &ExportedSector, start, num_bytes, sink: &mut Write| {
+                    unseal_pieces(
+                        &unsealing_sector_builder,
+                        sector_id,
+                        sector,
+                        start,
+                        num_bytes,
+                        sink,
+                    )
+                },
+            ),
+        )
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for SectorHttpExportServer {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread
+                .join()
+                .map_err(|err| println!("err joining sector export thread: {:?}", err));
+        }
+    }
+}
+
+// The ETags of sectors without a recorded checksum, by the commR of the
+// replica they're the hash of. Each is computed by the first export of its
+// sector, which the sector's other exports wait for.
+type EtagCache = Mutex<HashMap<[u8; 32], Arc<Mutex<Option<String>>>>>;
+
+// What the requests answered by a server share. Reads of replicas are
+// recorded by the sector store's bandwidth accounting.
+struct Exporter {
+    sector_store: Arc<WrappedSectorStore>,
+    lookup: Arc<SectorLookup>,
+    unseal: Arc<PieceUnsealer>,
+    etags: EtagCache,
+    num_exports: AtomicUsize,
+}
+
+// Held by the thread answering a request, freeing its place among the
+// MAX_CONCURRENT_EXPORTS once it's done.
+struct ExportSlot(Arc<Exporter>);
+
+impl Drop for ExportSlot {
+    fn drop(&mut self) {
+        self.0.num_exports.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn start_with_lookup<A: ToSocketAddrs>(
+    addr: A,
+    sector_store: Arc<WrappedSectorStore>,
+    lookup: Arc<SectorLookup>,
+    unseal: Arc<PieceUnsealer>,
+) -> Result<SectorHttpExportServer> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| err_unrecov("no address for the sector export server to listen on"))?;
+
+    let exporter = Arc::new(Exporter {
+        sector_store,
+        lookup,
+        unseal,
+        etags: Default::default(),
+        num_exports: AtomicUsize::new(0),
+    });
+
+    let server = Server::try_bind(&addr)?.serve(move || {
+        let exporter = exporter.clone();
+
+        service_fn(move |request| respond(exporter.clone(), request))
+    });
+
+    let local_addr = server.local_addr();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+    let server = server.with_graceful_shutdown(shutdown_rx).map_err(|err| {
+        let err = format!("{}", err);
+        warn!(FCP_LOG, "sector export server failed"; "error" => err);
+    });
+
+    let thread = thread::spawn(move || hyper::rt::run(server));
+
+    Ok(SectorHttpExportServer {
+        local_addr,
+        shutdown_tx: Some(shutdown_tx),
+        thread: Some(thread),
+    })
+}
+
+// Answers the request on a thread of its own, as finding the sector (by way
+// of the sector builder's main worker) and reading its file both block.
+fn respond(exporter: Arc<Exporter>, request: Request<Body>) -> ResponseFuture {
+    if exporter.num_exports.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_EXPORTS {
+        exporter.num_exports.fetch_sub(1, Ordering::SeqCst);
+
+        return Box::new(future::ok(status_response(StatusCode::SERVICE_UNAVAILABLE)));
+    }
+
+    let slot = ExportSlot(exporter);
+    let (response_tx, response_rx) = oneshot::channel();
+
+    thread::spawn(move || {
+        let exporter = &slot.0;
+
+        let bandwidth = exporter.sector_store.inner.manager().bandwidth_accounting();
+
+        let export = match prepare_export(&*exporter.lookup, &exporter.etags, bandwidth, &request) {
+            Ok(export) => export,
+            Err(response) => {
+                let _ = response_tx.send(response);
+                return;
+            }
+        };
+
+        let (body_tx, body_rx) = mpsc::channel(EXPORT_CHANNEL_CHUNKS);
+        let body = Body::wrap_stream(
+            body_rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "export channel failed")),
+        );
+
+        let (head, ()) = export.response.into_parts();
+        if response_tx.send(Response::from_parts(head, body)).is_err() {
+            return;
+        }
+
+        let mut sink = ChunkSink(body_tx.wait());
+        let result = if export.pieces {
+            (exporter.unseal)(
+                export.sector_id,
+                &export.sector,
+                export.start,
+                export.num_bytes,
+                &mut sink,
+            )
+        } else {
+            File::open(&export.sector.path)
+                .map_err(Into::into)
+                .and_then(|file| {
+                    let mut replica = MeteredReader {
+                        inner: file,
+                        accounting: bandwidth,
+                    };
+
+                    copy_range(&mut replica, export.start, export.num_bytes, &mut sink)
+                })
+        };
+
+        // The client sees the body cut short.
+        if let Err(err) = result {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "failed to export sector"; "sector_id" => export.sector_id, "error" => err);
+        }
+    });
+
+    Box::new(response_rx.then(|response| {
+        Ok(response.unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR)))
+    }))
+}
+
+// A response whose head is ready, and the range of the sector's replica (or
+// of its pieces' bytes) which is its body.
+struct Export {
+    sector_id: SectorId,
+    response: Response<()>,
+    sector: ExportedSector,
+    pieces: bool,
+    start: u64,
+    num_bytes: u64,
+}
+
+fn prepare_export(
+    lookup: &SectorLookup,
+    etags: &EtagCache,
+    bandwidth: &NetworkBandwidthAccounting,
+    request: &Request<Body>,
+) -> std::result::Result<Export, Response<Body>> {
+    if request.method() != Method::GET {
+        return Err(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let (sector_id, pieces) =
+        parse_path(request.uri().path()).ok_or_else(|| status_response(StatusCode::NOT_FOUND))?;
+
+    let tokens: Vec<PieceCapabilityToken> = request
+        .headers()
+        .get_all(PIECE_TOKEN_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok().and_then(parse_token))
+        .collect();
+
+    let sector = lookup(sector_id, &tokens).map_err(|err| {
+        status_response(match err.downcast_ref() {
+            Some(SectorBuilderErr::AccessDenied(_)) => StatusCode::FORBIDDEN,
+            Some(SectorBuilderErr::PieceRedacted(_)) => StatusCode::GONE,
+            _ => StatusCode::NOT_FOUND,
+        })
+    })?;
+
+    let internal_error = |err: failure::Error| {
+        let err = format!("{}", err);
+        warn!(FCP_LOG, "failed to prepare sector export"; "sector_id" => sector_id, "error" => err);
+        status_response(StatusCode::INTERNAL_SERVER_ERROR)
+    };
+
+    let (len, etag) = if pieces {
+        (sector.pieces_len, pieces_etag(&sector))
+    } else {
+        let len = std::fs::metadata(&sector.path)
+            .map_err(|err| internal_error(err.into()))?
+            .len();
+
+        (
+            len,
+            sector_etag(etags, bandwidth, &sector).map_err(internal_error)?,
+        )
+    };
+
+    let mut response = Response::builder();
+    response
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, etag);
+
+    let (start, num_bytes) = match request.headers().get(RANGE) {
+        None => {
+            response.status(StatusCode::OK);
+            (0, len)
+        }
+        Some(range) => match range.to_str().ok().and_then(|x| parse_range(x, len)) {
+            Some((start, num_bytes)) => {
+                response.status(StatusCode::PARTIAL_CONTENT).header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, start + num_bytes - 1, len),
+                );
+                (start, num_bytes)
+            }
+            None => {
+                let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                if let Ok(value) = format!("bytes */{}", len).parse() {
+                    response.headers_mut().insert(CONTENT_RANGE, value);
+                }
+                return Err(response);
+            }
+        },
+    };
+
+    let response = response
+        .header(CONTENT_LENGTH, num_bytes.to_string())
+        .body(())
+        .map_err(|err| internal_error(err.into()))?;
+
+    Ok(Export {
+        sector_id,
+        response,
+        sector,
+        pieces,
+        start,
+        num_bytes,
+    })
+}
+
+// The quoted BLAKE3 hash of the sector's file, as recorded when it was
+// sealed. Every response carries it, as a client resuming a download relies on
+// it to tell whether the file has changed, so the hash of a sector sealed
+// before it was recorded is computed on the sector's first export.
+fn sector_etag(
+    etags: &EtagCache,
+    bandwidth: &NetworkBandwidthAccounting,
+    sector: &ExportedSector,
+) -> Result<String> {
+    if let Some(checksum) = sector.checksum {
+        return Ok(quoted_hex(&checksum));
+    }
+
+    let cell = lock(etags).entry(sector.comm_r).or_default().clone();
+
+    // Only the sector's own exports wait while its file is hashed.
+    let mut etag = lock(&cell);
+    if etag.is_none() {
+        *etag = Some(quoted_hex(&replica_checksum(&sector.path, bandwidth)?));
+    }
+
+    Ok(etag.clone().unwrap_or_default())
+}
+
+// The ETag of the sector's pieces' bytes, which (being unsealed as they're
+// requested) aren't hashed. They're identified by the commitment to the
+// sector's unsealed bytes instead.
+fn pieces_etag(sector: &ExportedSector) -> String {
+    format!("\"pieces-{}\"", hex(&sector.comm_d))
+}
+
+fn quoted_hex(checksum: &[u8; 32]) -> String {
+    format!("\"{}\"", hex(checksum))
+}
+
+fn hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// Unseals the range of the sector's pieces' bytes to a staging access, and
+// copies it from there to the sink.
+fn unseal_pieces(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+    sector: &ExportedSector,
+    start: u64,
+    num_bytes: u64,
+    sink: &mut Write,
+) -> Result<()> {
+    if num_bytes == 0 {
+        return Ok(());
+    }
+
+    let state = &sector_builder.state;
+    let sector_mgr = state.sector_store.inner.manager();
+    let staging_sector_access = sector_mgr.new_staging_sector_access()?;
+
+    let result = unseal_range(
+        &state.sector_store,
+        sector_id,
+        &sector.path.to_string_lossy(),
+        &state.prover_id,
+        start,
+        UnpaddedBytesAmount(num_bytes),
+        &staging_sector_access,
+        &state.io_scheduler,
+        &state.sector_locks,
+    )
+    .and_then(|_| {
+        let mut unsealed = MeteredReader {
+            inner: File::open(&staging_sector_access)?,
+            accounting: sector_mgr.bandwidth_accounting(),
+        };
+
+        copy_range(&mut unsealed, 0, num_bytes, sink)
+    });
+
+    let _ = sector_mgr.delete_staging_sector_access(&staging_sector_access);
+
+    result
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn parse_token(hex: &str) -> Option<PieceCapabilityToken> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut token = [0; 32];
+    for (i, byte) in token.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+
+    Some(token)
+}
+
+// Parses the path of a sector's replica, or of its pieces' bytes, into the
+// sector's id and whether the latter were requested.
+fn parse_path(path: &str) -> Option<(SectorId, bool)> {
+    let mut segments = path.trim_start_matches('/').split('/');
+
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some("sector"), Some(sector_id), None, None) => Some((sector_id.parse().ok()?, false)),
+        (Some("sector"), Some(sector_id), Some("pieces"), None) => {
+            Some((sector_id.parse().ok()?, true))
+        }
+        _ => None,
+    }
+}
+
+// Parses a Range header of a single range of bytes ("bytes=first-last",
+// "bytes=first-" or "bytes=-suffix_length") of a file of len bytes into the
+// range's first byte and its length. Ranges which can't be satisfied (and
+// requests for several ranges) produce None.
+fn parse_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().trim_start_matches("bytes=");
+    if spec.len() == header.trim().len() || spec.contains(',') {
+        return None;
+    }
+
+    let mut bounds = spec.splitn(2, '-');
+    let (first, last) = (bounds.next()?.trim(), bounds.next()?.trim());
+
+    let (start, end) = match (first, last) {
+        ("", suffix_length) => {
+            let suffix_length: u64 = suffix_length.parse().ok()?;
+            (len.saturating_sub(suffix_length), len)
+        }
+        (first, "") => (first.parse().ok()?, len),
+        (first, last) => {
+            let last: u64 = last.parse().ok()?;
+            (
+                first.parse().ok()?,
+                std::cmp::min(last.checked_add(1)?, len),
+            )
+        }
+    };
+
+    if start < end {
+        Some((start, end - start))
+    } else {
+        None
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// Writes each chunk into the response body's channel, blocking while the
+// channel is full (i.e. while the client is behind).
+struct ChunkSink(Wait<mpsc::Sender<Vec<u8>>>);
+
+impl Write for ChunkSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client hung up"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0
+            .flush()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client hung up"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::errors::{err_piece_redacted, err_unrecov};
+    use crate::api::sector_builder::metadata::PieceMetadata;
+    use crate::api::sector_builder::piece_access::{
+        check_pieces_access, generate_piece_token, hash_piece_token,
+    };
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+    use hyper::rt::lazy;
+    use hyper::Client;
+    use sector_base::api::bandwidth::get_bandwidth_usage;
+    use sector_base::io::checksum::ChecksummingWriter;
+    use std::sync::Barrier;
+
+    const LOCALHOST: (&str, u16) = ("127.0.0.1", 0);
+
+    // A replica whose bytes aren't a multiple of the export's chunks.
+    fn replica(dir: &std::path::Path) -> (PathBuf, Vec<u8>) {
+        let bytes: Vec<u8> = (0..300_001u32).map(|x| (x * 13 + x / 7) as u8).collect();
+        let path = dir.join("replica");
+        std::fs::write(&path, &bytes).unwrap();
+
+        (path, bytes)
+    }
+
+    // The bytes of the pieces of the replica's sector.
+    fn pieces() -> Vec<u8> {
+        (0..200_003u32).map(|x| (x * 11 + x / 5) as u8).collect()
+    }
+
+    // Serves the replica as sector 7, with its checksum recorded, and the
+    // pieces as its pieces.
+    fn server(path: PathBuf) -> SectorHttpExportServer {
+        server_with_store(mock_sector_store().0, path)
+    }
+
+    // Like server, but records the replica's reads with the store.
+    fn server_with_store(
+        sector_store: Arc<WrappedSectorStore>,
+        path: PathBuf,
+    ) -> SectorHttpExportServer {
+        let checksum = replica_checksum(&path, &Default::default()).unwrap();
+
+        start_with_lookup(
+            LOCALHOST,
+            sector_store,
+            Arc::new(move |sector_id, _: &[PieceCapabilityToken]| {
+                if sector_id == 7 {
+                    Ok(ExportedSector {
+                        path: path.clone(),
+                        checksum: Some(checksum),
+                        comm_r: [7; 32],
+                        comm_d: [9; 32],
+                        pieces_len: pieces().len() as u64,
+                    })
+                } else {
+                    Err(err_unrecov(format!("no sealed sector with id {}", sector_id)).into())
+                }
+            }),
+            unsealer(pieces()),
+        )
+        .unwrap()
+    }
+
+    // "Unseals" a range of the bytes, for any sector.
+    fn unsealer(pieces: Vec<u8>) -> Arc<PieceUnsealer> {
+        Arc::new(
+            move |_, _: &ExportedSector, start, num_bytes, sink: &mut Write| {
+                let end = (start + num_bytes) as usize;
+                Ok(sink.write_all(&pieces[start as usize..end])?)
+            },
+        )
+    }
+
+    fn get(server: &SectorHttpExportServer, path: &str, range: Option<&str>) -> Response<Vec<u8>> {
+        get_with_tokens(server, path, range, &[])
+    }
+
+    // Makes the request with a hyper client, presenting the tokens, returning
+    // the response with its body read.
+    fn get_with_tokens(
+        server: &SectorHttpExportServer,
+        path: &str,
+        range: Option<&str>,
+        tokens: &[PieceCapabilityToken],
+    ) -> Response<Vec<u8>> {
+        get_from(server.local_addr(), path, range, tokens)
+    }
+
+    fn get_from(
+        addr: SocketAddr,
+        path: &str,
+        range: Option<&str>,
+        tokens: &[PieceCapabilityToken],
+    ) -> Response<Vec<u8>> {
+        let uri = format!("http://127.0.0.1:{}{}", addr.port(), path);
+        let mut request = Request::get(uri);
+        if let Some(range) = range {
+            request.header(RANGE, range);
+        }
+        for token in tokens {
+            let hex: String = token.iter().map(|x| format!("{:02x}", x)).collect();
+            request.header(PIECE_TOKEN_HEADER, hex);
+        }
+        let request = request.body(Body::empty()).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        hyper::rt::run(lazy(move || {
+            Client::new()
+                .request(request)
+                .and_then(|response| {
+                    let (head, body) = response.into_parts();
+                    body.concat2()
+                        .map(|body| Response::from_parts(head, body.to_vec()))
+                })
+                .map(move |response| tx.send(response).unwrap())
+                .map_err(|err| panic!("request failed: {}", err))
+        }));
+
+        rx.recv().unwrap()
+    }
+
+    fn blake3_etag(bytes: &[u8]) -> String {
+        let mut hasher = ChecksummingWriter::new(io::sink());
+        hasher.write_all(bytes).unwrap();
+        let (_, checksum) = hasher.finalize();
+
+        let hex: String = checksum.iter().map(|x| format!("{:02x}", x)).collect();
+        format!("\"{}\"", hex)
+    }
+
+    #[test]
+    fn test_downloads_sector() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = replica(dir.path());
+        let server = server(path);
+
+        let response = get(&server, "/sector/7", None);
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/octet-stream", response.headers()[CONTENT_TYPE]);
+        assert_eq!("bytes", response.headers()[ACCEPT_RANGES]);
+        assert_eq!(bytes.len().to_string(), response.headers()[CONTENT_LENGTH]);
+        assert_eq!(blake3_etag(&bytes), response.headers()[ETAG]);
+        assert_eq!(&bytes, response.body());
+
+        // the cached ETag is the same
+        let response = get(&server, "/sector/7", None);
+        assert_eq!(blake3_etag(&bytes), response.headers()[ETAG]);
+
+        let response = get(&server, "/sector/8", None);
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[test]
+    fn test_resumes_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = replica(dir.path());
+        let (sector_store, mgr) = mock_sector_store();
+        let server = server_with_store(sector_store, path);
+
+        let response = get(&server, "/sector/7", Some("bytes=100000-"));
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(
+            format!("bytes 100000-300000/{}", bytes.len()),
+            response.headers()[CONTENT_RANGE]
+        );
+        assert_eq!(&bytes[100_000..], &response.body()[..]);
+
+        let response = get(&server, "/sector/7", Some("bytes=10-19"));
+        assert_eq!(&bytes[10..20], &response.body()[..]);
+
+        let response = get(&server, "/sector/7", Some("bytes=400000-"));
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status());
+
+        // only the ranges served were read from the replica
+        assert_eq!(
+            (bytes.len() - 100_000 + 10) as u64,
+            get_bandwidth_usage(&*mgr).bytes_received
+        );
+    }
+
+    #[test]
+    fn test_downloads_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = replica(dir.path());
+        let server = server(path);
+        let pieces = pieces();
+
+        let etag = format!("\"pieces-{}\"", "09".repeat(32));
+
+        let response = get(&server, "/sector/7/pieces", None);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(pieces.len().to_string(), response.headers()[CONTENT_LENGTH]);
+        assert_eq!(etag, response.headers()[ETAG]);
+        assert_eq!(&pieces, response.body());
+
+        let response = get(&server, "/sector/7/pieces", Some("bytes=150000-"));
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(etag, response.headers()[ETAG]);
+        assert_eq!(&pieces[150_000..], &response.body()[..]);
+
+        for path in &[
+            "/sector/8/pieces",
+            "/sector/7/replica",
+            "/sector/7/pieces/0",
+        ] {
+            assert_eq!(StatusCode::NOT_FOUND, get(&server, path, None).status());
+        }
+    }
+
+    #[test]
+    fn test_requires_tokens_for_restricted_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = replica(dir.path());
+
+        let token = generate_piece_token("x", b"secret");
+        let pieces = vec![PieceMetadata {
+            piece_key: "x".to_string(),
+            access_token_hash: Some(hash_piece_token(&token)),
+            ..Default::default()
+        }];
+
+        let server = start_with_lookup(
+            LOCALHOST,
+            mock_sector_store().0,
+            Arc::new(move |_, tokens: &[PieceCapabilityToken]| {
+                check_pieces_access(&pieces, tokens)?;
+
+                Ok(ExportedSector {
+                    path: path.clone(),
+                    checksum: None,
+                    comm_r: [7; 32],
+                    comm_d: [0; 32],
+                    pieces_len: 0,
+                })
+            }),
+            unsealer(Vec::new()),
+        )
+        .unwrap();
+
+        let response = get(&server, "/sector/7", None);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert!(response.body().is_empty());
+
+        // nor are its pieces' bytes served without one
+        let response = get(&server, "/sector/7/pieces", None);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        let wrong_token = generate_piece_token("x", b"other secret");
+        let response = get_with_tokens(&server, "/sector/7", None, &[wrong_token]);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        let response = get_with_tokens(&server, "/sector/7", None, &[wrong_token, token]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(&bytes, response.body());
+    }
+
+    #[test]
+    fn test_refuses_sectors_with_redacted_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = replica(dir.path());
+
+        let server = start_with_lookup(
+            LOCALHOST,
+            mock_sector_store().0,
+            Arc::new(move |sector_id, _: &[PieceCapabilityToken]| {
+                if sector_id == 7 {
+                    Err(err_piece_redacted("x".to_string()).into())
+                } else {
+                    Ok(ExportedSector {
+                        path: path.clone(),
+                        checksum: None,
+                        comm_r: [8; 32],
+                        comm_d: [0; 32],
+                        pieces_len: 0,
+                    })
+                }
+            }),
+            unsealer(Vec::new()),
+        )
+        .unwrap();
+
+        let response = get(&server, "/sector/7", None);
+        assert_eq!(StatusCode::GONE, response.status());
+        assert!(response.body().is_empty());
+
+        let response = get(&server, "/sector/8", None);
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn test_hashes_unchecksummed_sector_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = replica(dir.path());
+        let exported_path = path.clone();
+
+        let server = start_with_lookup(
+            LOCALHOST,
+            mock_sector_store().0,
+            Arc::new(move |_, _: &[PieceCapabilityToken]| {
+                Ok(ExportedSector {
+                    path: exported_path.clone(),
+                    checksum: None,
+                    comm_r: [7; 32],
+                    comm_d: [0; 32],
+                    pieces_len: 0,
+                })
+            }),
+            unsealer(Vec::new()),
+        )
+        .unwrap();
+
+        let response = get(&server, "/sector/7", None);
+        assert_eq!(blake3_etag(&bytes), response.headers()[ETAG]);
+
+        // the file isn't hashed again
+        std::fs::write(&path, b"replaced").unwrap();
+
+        let response = get(&server, "/sector/7", None);
+        assert_eq!(blake3_etag(&bytes), response.headers()[ETAG]);
+    }
+
+    #[test]
+    fn test_refuses_requests_beyond_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = replica(dir.path());
+
+        // each lookup waits until the test lets them all finish
+        let (looked_up_tx, looked_up_rx) = std::sync::mpsc::channel();
+        let looked_up_tx = Mutex::new(looked_up_tx);
+        let release = Arc::new(Barrier::new(MAX_CONCURRENT_EXPORTS + 1));
+        let lookups_release = release.clone();
+
+        let server = start_with_lookup(
+            LOCALHOST,
+            mock_sector_store().0,
+            Arc::new(move |_, _: &[PieceCapabilityToken]| {
+                looked_up_tx.lock().unwrap().send(()).unwrap();
+                lookups_release.wait();
+
+                Ok(ExportedSector {
+                    path: path.clone(),
+                    checksum: Some([0; 32]),
+                    comm_r: [7; 32],
+                    comm_d: [0; 32],
+                    pieces_len: 0,
+                })
+            }),
+            unsealer(Vec::new()),
+        )
+        .unwrap();
+
+        let addr = server.local_addr();
+        let requests: Vec<_> = (0..MAX_CONCURRENT_EXPORTS)
+            .map(|_| thread::spawn(move || get_from(addr, "/sector/7", None, &[]).status()))
+            .collect();
+
+        for _ in 0..MAX_CONCURRENT_EXPORTS {
+            looked_up_rx.recv().unwrap();
+        }
+
+        let response = get(&server, "/sector/7", None);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+        release.wait();
+
+        for request in requests {
+            assert_eq!(StatusCode::OK, request.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parses_ranges() {
+        assert_eq!(Some((0, 100)), parse_range("bytes=0-", 100));
+        assert_eq!(Some((10, 10)), parse_range("bytes=10-19", 100));
+        assert_eq!(Some((90, 10)), parse_range("bytes=90-1000", 100));
+        assert_eq!(Some((80, 20)), parse_range("bytes=-20", 100));
+        assert_eq!(Some((0, 100)), parse_range("bytes=-200", 100));
+
+        assert_eq!(None, parse_range("bytes=100-", 100));
+        assert_eq!(None, parse_range("bytes=20-10", 100));
+        assert_eq!(None, parse_range("bytes=0-1,5-6", 100));
+        assert_eq!(None, parse_range("items=0-1", 100));
+        assert_eq!(None, parse_range("bytes=x-", 100));
+    }
+}
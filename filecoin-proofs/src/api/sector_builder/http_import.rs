@@ -0,0 +1,413 @@
+use std::cmp::min;
+use std::io::{self, Read};
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::import_sector::write_imported_sector;
+use crate::api::sector_builder::metadata::PieceMetadata;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder, SectorId};
+use crate::error::Result;
+use crate::FCP_LOG;
+use reqwest::header::{HeaderValue, ETAG, RANGE};
+use reqwest::StatusCode;
+use slog::*;
+
+// How many times in a row a download may be resumed without any bytes having
+// been read since the last time.
+const MAX_CONSECUTIVE_RESUMES: usize = 3;
+
+// Downloads a sector's pieces from the URL, which serves their (unpadded)
+// bytes one after another in the manifest's order (as a sector export
+// server's /sector/{sector_id}/pieces does), to a new staged sector of their
+// own. Each piece is verified against the manifest's (BLAKE3) checksum as it's
+// written, and nothing is imported unless all of them match, nor if a piece
+// with any of their keys already exists. A download which is cut off is
+// resumed where it left off, with a range request.
+//
+// The pieces are downloaded on the caller's thread; the main worker only adds
+// the downloaded sector to its metadata.
+pub fn import_sector_http_streaming(
+    sector_builder: &SectorBuilder,
+    piece_manifest: &[PieceMetadata],
+    source_url: &str,
+) -> Result<SectorId> {
+    let sector_store = &sector_builder.state.sector_store;
+
+    let num_bytes = piece_manifest.iter().map(|p| u64::from(p.num_bytes)).sum();
+    let mut source = log_unrecov(HttpSectorSource::open(source_url, num_bytes))?;
+
+    let imported = log_unrecov(write_imported_sector(
+        sector_store,
+        piece_manifest,
+        &mut source,
+    ))?;
+    let sector_access = imported.sector_access.clone();
+
+    let result = sector_builder.run_blocking(|tx| Request::HandleImportedSector(imported, tx));
+
+    if result.is_err() {
+        let _ = sector_store
+            .inner
+            .manager()
+            .delete_staging_sector_access(&sector_access);
+    }
+
+    log_unrecov(result)
+}
+
+// Reads the first len bytes served at a URL, reconnecting (and requesting
+// the bytes not yet read) if the connection fails or is closed early.
+pub struct HttpSectorSource {
+    client: reqwest::Client,
+    url: url::Url,
+    len: u64,
+    offset: u64,
+    // that of the first response, which each resumed one must match lest the
+    // bytes be spliced from two different files
+    etag: Option<HeaderValue>,
+    response: Option<reqwest::Response>,
+    consecutive_resumes: usize,
+}
+
+impl HttpSectorSource {
+    pub fn open(source_url: &str, len: u64) -> Result<HttpSectorSource> {
+        let mut source = HttpSectorSource {
+            client: reqwest::Client::new(),
+            url: url::Url::parse(source_url)?,
+            len,
+            offset: 0,
+            etag: None,
+            response: None,
+            consecutive_resumes: 0,
+        };
+
+        source.response = Some(source.connect()?);
+
+        Ok(source)
+    }
+
+    fn connect(&mut self) -> Result<reqwest::Response> {
+        let mut request = self.client.get(self.url.clone());
+        if self.offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", self.offset));
+        }
+
+        let response = request.send()?.error_for_status()?;
+
+        if self.offset > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(err_unrecov(format!(
+                "cannot resume download of {} at byte {}: range requests unsupported",
+                self.url, self.offset
+            ))
+            .into());
+        }
+
+        let etag = response.headers().get(ETAG).cloned();
+        if self.offset > 0 && etag != self.etag {
+            return Err(err_unrecov(format!(
+                "cannot resume download of {}: it has changed since the download began",
+                self.url
+            ))
+            .into());
+        }
+
+        self.etag = etag;
+
+        Ok(response)
+    }
+}
+
+impl Read for HttpSectorSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.offset >= self.len || buf.is_empty() {
+                return Ok(0);
+            }
+
+            if self.response.is_none() {
+                let response = self
+                    .connect()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{}", err)))?;
+
+                self.response = Some(response);
+            }
+
+            let max = min(buf.len() as u64, self.len - self.offset) as usize;
+            let result = match self.response {
+                Some(ref mut response) => response.read(&mut buf[..max]),
+                None => continue,
+            };
+
+            // The connection fails, or is closed before all the bytes have
+            // been read.
+            let err = match result {
+                Ok(0) => io::ErrorKind::UnexpectedEof.into(),
+                Ok(n) => {
+                    self.offset += n as u64;
+                    self.consecutive_resumes = 0;
+
+                    return Ok(n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => err,
+            };
+
+            if self.consecutive_resumes >= MAX_CONSECUTIVE_RESUMES {
+                return Err(err);
+            }
+
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "resuming sector download"; "url" => self.url.as_str(), "offset" => self.offset, "error" => err);
+
+            self.response = None;
+            self.consecutive_resumes += 1;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sector-export-http"))]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::http_export_server::{
+        start_with_lookup, ExportedSector, SectorHttpExportServer,
+    };
+    use crate::api::sector_builder::piece_access::PieceCapabilityToken;
+    use crate::api::sector_builder::sealing_test_harness::await_seal;
+    use crate::api::sector_builder::test_utils::{
+        mock_sector_store, sector_builder, sector_builder_factory, TEST_CLASS,
+    };
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+    use sector_base::io::checksum::ChecksummingWriter;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    // Pieces of different sizes, and the manifest which describes them.
+    fn pieces() -> (Vec<Vec<u8>>, Vec<PieceMetadata>) {
+        let pieces: Vec<Vec<u8>> = [70_000u32, 100, 200_000]
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (0..*n).map(|x| (x * 7 + i as u32) as u8).collect())
+            .collect();
+
+        let manifest = pieces
+            .iter()
+            .enumerate()
+            .map(|(i, bytes)| {
+                let mut hasher = ChecksummingWriter::new(io::sink());
+                hasher.write_all(bytes).unwrap();
+
+                PieceMetadata {
+                    piece_key: format!("piece-{}", i),
+                    num_bytes: UnpaddedBytesAmount(bytes.len() as u64),
+                    checksum: Some(hasher.finalize().1),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        (pieces, manifest)
+    }
+
+    // Serves the bytes as those of the pieces of sector 7.
+    fn export_server(pieces: Vec<u8>) -> SectorHttpExportServer {
+        let pieces_len = pieces.len() as u64;
+
+        start_with_lookup(
+            ("127.0.0.1", 0),
+            mock_sector_store().0,
+            Arc::new(move |_: SectorId, _: &[PieceCapabilityToken]| {
+                Ok(ExportedSector {
+                    path: PathBuf::new(),
+                    checksum: None,
+                    comm_r: [7; 32],
+                    comm_d: [7; 32],
+                    pieces_len,
+                })
+            }),
+            Arc::new(
+                move |_: SectorId, _: &ExportedSector, start, num_bytes, sink: &mut Write| {
+                    let end = (start + num_bytes) as usize;
+                    Ok(sink.write_all(&pieces[start as usize..end])?)
+                },
+            ),
+        )
+        .unwrap()
+    }
+
+    fn pieces_url(server: &SectorHttpExportServer) -> String {
+        format!(
+            "http://127.0.0.1:{}/sector/7/pieces",
+            server.local_addr().port()
+        )
+    }
+
+    #[test]
+    fn test_imports_exported_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pieces, manifest) = pieces();
+
+        let server = export_server(pieces.concat());
+        let url = pieces_url(&server);
+
+        // the server runs on its own thread, and the pieces are downloaded on
+        // this one
+        let sector_builder = sector_builder(dir.path(), Default::default());
+        let sector_id = import_sector_http_streaming(&sector_builder, &manifest, &url).unwrap();
+
+        let staged = sector_builder
+            .get_staged_sectors()
+            .unwrap()
+            .into_iter()
+            .find(|sector| sector.sector_id == sector_id)
+            .unwrap();
+
+        assert_eq!(
+            manifest,
+            staged.pieces.values().cloned().collect::<Vec<_>>()
+        );
+
+        // the staged sector holds the exported bytes
+        let mut body = sector_builder
+            .state
+            .sector_store
+            .inner
+            .manager()
+            .open_and_verify_sector(&staged.sector_access)
+            .unwrap();
+
+        let mut padded = Vec::new();
+        body.read_to_end(&mut padded).unwrap();
+
+        let exported = pieces.concat();
+        let mut unpadded = Vec::new();
+        sector_base::io::fr32::write_unpadded(&padded, &mut unpadded, 0, exported.len()).unwrap();
+        assert_eq!(exported, unpadded);
+    }
+
+    #[test]
+    fn test_rejects_mismatched_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut pieces, manifest) = pieces();
+        pieces[2][1000] ^= 1;
+
+        let server = export_server(pieces.concat());
+        let url = pieces_url(&server);
+
+        let sector_builder = sector_builder(dir.path(), Default::default());
+
+        assert!(import_sector_http_streaming(&sector_builder, &manifest, &url).is_err());
+        assert!(sector_builder
+            .get_staged_sectors()
+            .unwrap()
+            .iter()
+            .all(|sector| sector.pieces.is_empty()));
+    }
+
+    #[test]
+    #[ignore] // Slow test – run only when compiled for release.
+    fn test_imports_sector_exported_by_another_builder() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let exporting = Arc::new(
+            sector_builder_factory(TEST_CLASS, &dir.path().join("exporting"))
+                .unwrap()
+                .create_concrete_sector_builder(Default::default())
+                .unwrap(),
+        );
+
+        let mut sealed_sector_id = 0;
+        for (i, len) in [500, 300].iter().enumerate() {
+            let piece_path = dir.path().join(format!("piece-{}", i));
+            fs::write(&piece_path, vec![i as u8 + 1; *len]).unwrap();
+
+            sealed_sector_id = exporting
+                .add_piece(
+                    format!("piece-{}", i),
+                    *len as u64,
+                    piece_path.to_string_lossy().into_owned(),
+                )
+                .unwrap();
+        }
+
+        exporting.seal_sector(sealed_sector_id).unwrap();
+        await_seal(&*exporting, sealed_sector_id).unwrap();
+
+        let manifest = exporting
+            .get_sealed_sectors()
+            .unwrap()
+            .into_iter()
+            .find(|sector| sector.sector_id == sealed_sector_id)
+            .unwrap()
+            .pieces;
+
+        let server = SectorHttpExportServer::start(0, exporting.clone()).unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/sector/{}/pieces",
+            server.local_addr().port(),
+            sealed_sector_id
+        );
+
+        let importing = sector_builder_factory(TEST_CLASS, &dir.path().join("importing"))
+            .unwrap()
+            .create_concrete_sector_builder(Default::default())
+            .unwrap();
+
+        let sector_id = import_sector_http_streaming(&importing, &manifest, &url).unwrap();
+
+        // the pieces' bytes, which were verified as they were imported, are
+        // sealed (and read back) like any others
+        importing.seal_sector(sector_id).unwrap();
+        await_seal(&importing, sector_id).unwrap();
+
+        assert_eq!(
+            vec![2; 300],
+            importing
+                .read_piece_from_sealed_sector("piece-1".to_string())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejects_existing_piece_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pieces, manifest) = pieces();
+
+        let server = export_server(pieces.concat());
+        let url = pieces_url(&server);
+
+        let sector_builder = sector_builder(dir.path(), Default::default());
+        import_sector_http_streaming(&sector_builder, &manifest, &url).unwrap();
+
+        let staged_sectors = sector_builder.get_staged_sectors().unwrap();
+        assert!(import_sector_http_streaming(&sector_builder, &manifest, &url).is_err());
+
+        // the second download is deleted, and nothing is staged for it
+        assert_eq!(staged_sectors, sector_builder.get_staged_sectors().unwrap());
+        assert_eq!(
+            staged_sectors.len(),
+            fs::read_dir(dir.path().join("staged")).unwrap().count()
+        );
+    }
+
+    #[test]
+    fn test_resumes_interrupted_download() {
+        let bytes: Vec<u8> = (0..250_000u32).map(|x| (x * 13) as u8).collect();
+
+        let server = export_server(bytes.clone());
+        let url = pieces_url(&server);
+
+        let mut source = HttpSectorSource::open(&url, bytes.len() as u64).unwrap();
+
+        let mut downloaded = vec![0; 100_000];
+        source.read_exact(&mut downloaded).unwrap();
+
+        // the connection is lost
+        source.response = None;
+
+        source.read_to_end(&mut downloaded).unwrap();
+        assert_eq!(bytes, downloaded);
+        assert_eq!(0, source.consecutive_resumes);
+    }
+}
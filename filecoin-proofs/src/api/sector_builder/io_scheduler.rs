@@ -0,0 +1,377 @@
+use std::cmp;
+use std::io::{self, Read};
+use std::sync::{Condvar, Mutex};
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::SectorBuilder;
+use crate::error::{ExpectWithBacktrace, Result};
+
+const FATAL_NOLOCK: &str = "error acquiring I/O scheduler lock";
+
+// The relative share of sector file I/O given to an operation, from 1 to 255.
+pub type IoWeight = u8;
+
+pub const DEFAULT_IO_WEIGHT: IoWeight = 128;
+
+// The most bytes read per turn, so that long operations take turns with
+// others rather than holding the disk for their duration.
+const IO_CHUNK_BYTES: usize = 64 * 1024;
+
+// The kinds of operation which compete for sector file I/O, each of which is
+// given the weight configured for its kind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoClass {
+    // writing pieces to staged sectors as they're added, or reading the
+    // replicas they're retrieved from
+    Piece,
+    // reading staged sectors as they're sealed
+    Seal,
+}
+
+// Issues sector file I/O one turn at a time, in proportion to weight, in the
+// manner of a Completely Fair Queue: each operation (flow) has a queue of
+// turns, and a turn of N bytes advances its flow's virtual clock by N / weight.
+// The waiting turn whose flow's clock would read earliest at its end goes
+// next, so a flow with twice the weight of another is given twice its bytes
+// while both are waiting.
+#[derive(Debug)]
+pub struct IoScheduler {
+    state: Mutex<IoSchedulerState>,
+    turn_finished: Condvar,
+}
+
+#[derive(Debug)]
+struct IoSchedulerState {
+    piece_weight: IoWeight,
+    seal_weight: IoWeight,
+    // the virtual start time of the turn most recently issued
+    virtual_time: u64,
+    busy: bool,
+    next_ticket: u64,
+    // the virtual finish times and tickets of waiting turns
+    waiting: Vec<(u64, u64)>,
+}
+
+// The virtual clock of an operation's turns.
+#[derive(Debug, Default)]
+pub struct IoFlow {
+    virtual_finish: u64,
+}
+
+// Held while a turn's I/O is performed; the next turn is issued once it's
+// dropped.
+pub struct IoTurn<'a> {
+    scheduler: &'a IoScheduler,
+}
+
+impl Default for IoScheduler {
+    fn default() -> IoScheduler {
+        IoScheduler {
+            state: Mutex::new(IoSchedulerState {
+                piece_weight: DEFAULT_IO_WEIGHT,
+                seal_weight: DEFAULT_IO_WEIGHT,
+                virtual_time: 0,
+                busy: false,
+                next_ticket: 0,
+                waiting: Vec::new(),
+            }),
+            turn_finished: Condvar::new(),
+        }
+    }
+}
+
+impl IoScheduler {
+    pub fn set_weight(&self, class: IoClass, weight: IoWeight) -> Result<()> {
+        if weight == 0 {
+            return Err(err_unrecov("I/O weight must be between 1 and 255").into());
+        }
+
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        match class {
+            IoClass::Piece => state.piece_weight = weight,
+            IoClass::Seal => state.seal_weight = weight,
+        }
+
+        Ok(())
+    }
+
+    pub fn weight(&self, class: IoClass) -> IoWeight {
+        let state = self.state.lock().expects(FATAL_NOLOCK);
+
+        match class {
+            IoClass::Piece => state.piece_weight,
+            IoClass::Seal => state.seal_weight,
+        }
+    }
+
+    // Blocks until it's the flow's turn to perform num_bytes of I/O.
+    pub fn acquire(&self, flow: &mut IoFlow, class: IoClass, num_bytes: usize) -> IoTurn {
+        let mut state = self.state.lock().expects(FATAL_NOLOCK);
+
+        let (ticket, virtual_start) = state.enqueue(flow, class, num_bytes);
+
+        while !state.is_next(ticket) {
+            state = self.turn_finished.wait(state).expects(FATAL_NOLOCK);
+        }
+
+        state.issue(ticket, virtual_start);
+
+        IoTurn { scheduler: self }
+    }
+}
+
+impl IoSchedulerState {
+    // Queues a turn of num_bytes for the flow, returning the turn's ticket and
+    // the virtual time at which it starts.
+    fn enqueue(
+        &mut self,
+        flow: &mut IoFlow,
+        class: IoClass,
+        num_bytes: usize,
+    ) -> ((u64, u64), u64) {
+        let weight = match class {
+            IoClass::Piece => self.piece_weight,
+            IoClass::Seal => self.seal_weight,
+        };
+
+        // A flow which has been idle starts from the current virtual time, so
+        // that it can't claim the turns it didn't take.
+        let virtual_start = cmp::max(self.virtual_time, flow.virtual_finish);
+        let virtual_finish =
+            virtual_start + cmp::max(num_bytes as u64, 1) * 255 / u64::from(weight);
+        flow.virtual_finish = virtual_finish;
+
+        let ticket = (virtual_finish, self.next_ticket);
+        self.next_ticket += 1;
+        self.waiting.push(ticket);
+
+        (ticket, virtual_start)
+    }
+
+    fn is_next(&self, ticket: (u64, u64)) -> bool {
+        !self.busy && self.waiting.iter().min() == Some(&ticket)
+    }
+
+    fn issue(&mut self, ticket: (u64, u64), virtual_start: u64) {
+        self.waiting.retain(|waiting| *waiting != ticket);
+        self.busy = true;
+        self.virtual_time = cmp::max(self.virtual_time, virtual_start);
+    }
+}
+
+impl<'a> Drop for IoTurn<'a> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().expects(FATAL_NOLOCK);
+        state.busy = false;
+        self.scheduler.turn_finished.notify_all();
+    }
+}
+
+// Reads from the inner reader in turns issued by the scheduler. A reader which
+// covers writes holds each turn until its next read (or until it's dropped),
+// so that the caller's write of what it read, e.g. to a staged sector, is made
+// during the turn, which is charged for both.
+pub struct ScheduledReader<'a, R> {
+    inner: R,
+    scheduler: &'a IoScheduler,
+    class: IoClass,
+    flow: IoFlow,
+    covers_writes: bool,
+    turn: Option<IoTurn<'a>>,
+}
+
+impl<'a, R: Read> ScheduledReader<'a, R> {
+    pub fn new(inner: R, scheduler: &'a IoScheduler, class: IoClass) -> ScheduledReader<'a, R> {
+        ScheduledReader {
+            inner,
+            scheduler,
+            class,
+            flow: Default::default(),
+            covers_writes: false,
+            turn: None,
+        }
+    }
+
+    pub fn covering_writes(mut self) -> ScheduledReader<'a, R> {
+        self.covers_writes = true;
+        self
+    }
+}
+
+impl<'a, R: Read> Read for ScheduledReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The previous turn ends before the next is waited for.
+        self.turn = None;
+
+        let len = cmp::min(buf.len(), IO_CHUNK_BYTES);
+        let charged = if self.covers_writes { 2 * len } else { len };
+
+        let scheduler = self.scheduler;
+        let turn = scheduler.acquire(&mut self.flow, self.class, charged);
+
+        let num_bytes_read = self.inner.read(&mut buf[..len])?;
+
+        if self.covers_writes && num_bytes_read > 0 {
+            self.turn = Some(turn);
+        }
+
+        Ok(num_bytes_read)
+    }
+}
+
+// Sets the share of sector file I/O given to adding and retrieving pieces.
+pub fn set_piece_io_weight(sector_builder: &SectorBuilder, weight: IoWeight) -> Result<()> {
+    sector_builder
+        .state
+        .io_scheduler
+        .set_weight(IoClass::Piece, weight)
+}
+
+// Sets the share of sector file I/O given to sealing.
+pub fn set_seal_io_weight(sector_builder: &SectorBuilder, weight: IoWeight) -> Result<()> {
+    sector_builder
+        .state
+        .io_scheduler
+        .set_weight(IoClass::Seal, weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Issues every turn of the flows (each of the given class and number of
+    // turns, all waiting from the start), returning the index of the flow to
+    // which each turn was issued, in order.
+    fn issue_turns(state: &mut IoSchedulerState, flows: &[(IoClass, usize)]) -> Vec<usize> {
+        let mut clocks: Vec<IoFlow> = flows.iter().map(|_| Default::default()).collect();
+        let mut remaining: Vec<usize> = flows.iter().map(|(_, num_turns)| *num_turns).collect();
+        let mut waiting: Vec<Option<((u64, u64), u64)>> = vec![None; flows.len()];
+        let mut issued = Vec::new();
+
+        loop {
+            for (i, (class, _)) in flows.iter().enumerate() {
+                if waiting[i].is_none() && remaining[i] > 0 {
+                    waiting[i] = Some(state.enqueue(&mut clocks[i], *class, IO_CHUNK_BYTES));
+                    remaining[i] -= 1;
+                }
+            }
+
+            let next = waiting
+                .iter()
+                .enumerate()
+                .filter_map(|(i, turn)| {
+                    turn.filter(|(ticket, _)| state.is_next(*ticket)).map(|_| i)
+                })
+                .next();
+
+            match next {
+                Some(i) => {
+                    let (ticket, virtual_start) = waiting[i].take().unwrap();
+                    state.issue(ticket, virtual_start);
+                    state.busy = false;
+                    issued.push(i);
+                }
+                None => return issued,
+            }
+        }
+    }
+
+    #[test]
+    fn test_high_weight_sealer_finishes_first() {
+        let scheduler = IoScheduler::default();
+        scheduler.set_weight(IoClass::Piece, 1).unwrap();
+        scheduler.set_weight(IoClass::Seal, 16).unwrap();
+
+        let mut state = scheduler.state.lock().unwrap();
+
+        // Four readers and a sealer (the last flow), each of 40 turns.
+        let mut flows = vec![(IoClass::Piece, 40); 4];
+        flows.push((IoClass::Seal, 40));
+
+        let issued = issue_turns(&mut state, &flows);
+        assert_eq!(200, issued.len());
+
+        // The sealer is given 16 of every 20 turns while all five wait, so it
+        // finishes its 40 turns within about the first 50, and the readers
+        // theirs after all 200.
+        let sealer_done_at = issued.iter().rposition(|&i| i == 4).unwrap();
+        assert!(
+            sealer_done_at < 55,
+            "sealer's last turn was {}",
+            sealer_done_at
+        );
+
+        for reader in 0..4 {
+            let reader_done_at = issued.iter().rposition(|&i| i == reader).unwrap();
+            assert!(
+                reader_done_at > 190,
+                "reader's last turn was {}",
+                reader_done_at
+            );
+        }
+    }
+
+    #[test]
+    fn test_equal_weights_take_turns() {
+        let scheduler = IoScheduler::default();
+        let mut state = scheduler.state.lock().unwrap();
+
+        let issued = issue_turns(&mut state, &[(IoClass::Piece, 3), (IoClass::Seal, 3)]);
+
+        assert_eq!(vec![0, 1, 0, 1, 0, 1], issued);
+    }
+
+    #[test]
+    fn test_scheduled_reader_reads_everything() {
+        let scheduler = IoScheduler::default();
+        let bytes: Vec<u8> = (0..(3 * IO_CHUNK_BYTES + 7)).map(|n| n as u8).collect();
+
+        let mut read = Vec::new();
+        ScheduledReader::new(&bytes[..], &scheduler, IoClass::Piece)
+            .read_to_end(&mut read)
+            .unwrap();
+
+        assert_eq!(bytes, read);
+    }
+
+    #[test]
+    fn test_reader_covering_writes_holds_its_turn_until_the_next_read() {
+        let scheduler = IoScheduler::default();
+        let is_busy = || scheduler.state.lock().unwrap().busy;
+
+        let bytes = vec![7u8; 2 * IO_CHUNK_BYTES];
+        let mut buf = vec![0u8; IO_CHUNK_BYTES];
+
+        let mut reader = ScheduledReader::new(&bytes[..], &scheduler, IoClass::Piece);
+        assert_eq!(IO_CHUNK_BYTES, reader.read(&mut buf).unwrap());
+        assert!(!is_busy());
+
+        let mut reader =
+            ScheduledReader::new(&bytes[..], &scheduler, IoClass::Piece).covering_writes();
+
+        // Each read ends the turn taken for the one before it.
+        for _ in 0..2 {
+            assert_eq!(IO_CHUNK_BYTES, reader.read(&mut buf).unwrap());
+            assert!(is_busy());
+        }
+
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+        assert!(!is_busy());
+
+        reader.read(&mut buf).unwrap();
+        drop(reader);
+        assert!(!is_busy());
+    }
+
+    #[test]
+    fn test_rejects_zero_weight() {
+        let scheduler = IoScheduler::default();
+
+        assert!(scheduler.set_weight(IoClass::Seal, 0).is_err());
+        assert_eq!(DEFAULT_IO_WEIGHT, scheduler.weight(IoClass::Seal));
+
+        scheduler.set_weight(IoClass::Seal, 255).unwrap();
+        assert_eq!(255, scheduler.weight(IoClass::Seal));
+    }
+}
@@ -66,6 +66,13 @@ impl KeyValueStore for FileSystemKvs {
             }
         }
     }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        match fs::remove_file(self.key_to_path(key)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            other => other.map_err(Into::into),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -87,5 +94,10 @@ mod tests {
 
         let opt = db.get(k_a).unwrap();
         assert_eq!(format!("{:x?}", opt.unwrap()), format!("{:x?}", v_a));
+
+        db.delete(k_a).unwrap();
+        db.delete(b"key-zz").unwrap();
+        assert_eq!(None, db.get(k_a).unwrap());
+        assert!(db.get(k_b).unwrap().is_some());
     }
 }
@@ -0,0 +1,442 @@
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use blake2b_simd::State as Blake2b;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use memmap::Mmap;
+
+use crate::api::sector_builder::kv_store::KeyValueStore;
+use crate::error::ExpectWithBacktrace;
+use crate::error::Result;
+
+const FATAL_NOLOCK: &str = "[MmapKeyValueStore] could not acquire lock";
+
+const FILE_NAME: &str = "kvs.mmap";
+const COMPACTING_FILE_NAME: &str = "kvs.mmap.compacting";
+const MAGIC: &[u8; 8] = b"FCPMKVS1";
+
+// magic, index offset, number of index records
+const HEADER_LEN: usize = 8 + 8 + 8;
+
+// key hash, value offset, value length
+const KEY_HASH_LEN: usize = 32;
+const RECORD_LEN: usize = KEY_HASH_LEN + 8 + 8;
+
+// The file is compacted once the space used by overwritten or deleted values
+// and superseded indexes exceeds both this and the space still in use.
+const MIN_COMPACTION_GARBAGE: u64 = 64 * 1024;
+
+// MmapKeyValueStore keeps all of its keys and values in a single file, which
+// it memory-maps so that a value is only read (and deserialized) when its key
+// is accessed. The file is laid out as:
+//
+//   header | values ... | index
+//
+// where the index is an array of fixed-size (key hash, value offset, value
+// length) records sorted by key hash, so that lookups are a binary search.
+//
+// Each put appends the new value (and each delete, nothing) followed by a
+// rewritten index and then points the header at that index, so a put which
+// fails part-way leaves the previous index intact. The space used by
+// overwritten or deleted values and superseded indexes is reclaimed by
+// compaction, which copies the live values and index into a new file and
+// renames it over the old one, so that a compaction which fails part-way
+// leaves the old file intact.
+#[derive(Debug)]
+pub struct MmapKeyValueStore {
+    path: PathBuf,
+    mapped: RwLock<MappedFile>,
+}
+
+#[derive(Debug)]
+struct MappedFile {
+    file: File,
+    mmap: Mmap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Record {
+    key_hash: [u8; KEY_HASH_LEN],
+    value_offset: u64,
+    value_len: u64,
+}
+
+impl MmapKeyValueStore {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Compacts the file if most of it is taken up by overwritten or deleted
+    // values and superseded indexes.
+    fn compact_if_wasteful(&self, mapped: &mut MappedFile) -> Result<()> {
+        let records = mapped.records()?;
+
+        let live_len = HEADER_LEN as u64
+            + records.iter().map(|r| r.value_len).sum::<u64>()
+            + (records.len() * RECORD_LEN) as u64;
+        let garbage_len = mapped.mmap.len() as u64 - live_len;
+
+        if garbage_len > MIN_COMPACTION_GARBAGE && garbage_len > live_len {
+            let compacting_path = self.path.with_file_name(COMPACTING_FILE_NAME);
+            *mapped = mapped.compact(&records, &compacting_path, &self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MappedFile {
+    fn open(path: &Path) -> Result<MappedFile> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            write_header(&mut file, HEADER_LEN as u64, 0)?;
+        }
+
+        let mapped = MappedFile::map(file)?;
+        mapped.header()?;
+
+        Ok(mapped)
+    }
+
+    fn map(file: File) -> Result<MappedFile> {
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        Ok(MappedFile { file, mmap })
+    }
+
+    fn header(&self) -> Result<(usize, usize)> {
+        if self.mmap.len() < HEADER_LEN || &self.mmap[..8] != MAGIC {
+            return Err(format_err!("not a memory-mapped key/value store"));
+        }
+
+        let index_offset = LittleEndian::read_u64(&self.mmap[8..16]) as usize;
+        let num_records = LittleEndian::read_u64(&self.mmap[16..24]) as usize;
+
+        if index_offset + num_records * RECORD_LEN > self.mmap.len() {
+            return Err(format_err!("index extends past end of key/value store"));
+        }
+
+        Ok((index_offset, num_records))
+    }
+
+    fn records(&self) -> Result<Vec<Record>> {
+        let (index_offset, num_records) = self.header()?;
+
+        Ok((0..num_records)
+            .map(|i| self.record(index_offset, i))
+            .collect())
+    }
+
+    fn record(&self, index_offset: usize, i: usize) -> Record {
+        let bytes = &self.mmap[index_offset + i * RECORD_LEN..][..RECORD_LEN];
+
+        let mut key_hash = [0; KEY_HASH_LEN];
+        key_hash.copy_from_slice(&bytes[..KEY_HASH_LEN]);
+
+        Record {
+            key_hash,
+            value_offset: LittleEndian::read_u64(&bytes[KEY_HASH_LEN..]),
+            value_len: LittleEndian::read_u64(&bytes[KEY_HASH_LEN + 8..]),
+        }
+    }
+
+    fn find(&self, key_hash: &[u8; KEY_HASH_LEN]) -> Result<Option<Record>> {
+        let (index_offset, num_records) = self.header()?;
+
+        let (mut lo, mut hi) = (0, num_records);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let record = self.record(index_offset, mid);
+
+            match record.key_hash.cmp(key_hash) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(Some(record)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Appends a value (which the records may refer to) and then the records,
+    // as the new index, to the end of the file.
+    fn append(&mut self, value: &[u8], records: &[Record]) -> Result<()> {
+        let end_of_file = self.file.seek(SeekFrom::End(0))?;
+
+        let mut buf = Vec::with_capacity(value.len() + records.len() * RECORD_LEN);
+        buf.extend_from_slice(value);
+        write_records(&mut buf, records)?;
+
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+
+        let index_offset = end_of_file + value.len() as u64;
+        write_header(&mut self.file, index_offset, records.len() as u64)?;
+
+        // The file has grown past the end of the existing mapping.
+        let file = self.file.try_clone()?;
+        *self = MappedFile::map(file)?;
+
+        Ok(())
+    }
+
+    // Writes the values which the records refer to, followed by an index of
+    // them, to a new file at compacting_path, which then replaces the file
+    // at path.
+    fn compact(
+        &self,
+        records: &[Record],
+        compacting_path: &Path,
+        path: &Path,
+    ) -> Result<MappedFile> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(compacting_path)?;
+
+        let mut buf = vec![0; HEADER_LEN];
+        let mut compacted = Vec::with_capacity(records.len());
+
+        for r in records {
+            let start = r.value_offset as usize;
+            let end = start + r.value_len as usize;
+
+            if end > self.mmap.len() {
+                return Err(format_err!("value extends past end of key/value store"));
+            }
+
+            compacted.push(Record {
+                value_offset: buf.len() as u64,
+                ..*r
+            });
+            buf.extend_from_slice(&self.mmap[start..end]);
+        }
+
+        let index_offset = buf.len() as u64;
+        write_records(&mut buf, &compacted)?;
+
+        file.write_all(&buf)?;
+        write_header(&mut file, index_offset, compacted.len() as u64)?;
+
+        fs::rename(compacting_path, path)?;
+
+        MappedFile::map(file)
+    }
+}
+
+impl KeyValueStore for MmapKeyValueStore {
+    fn initialize<P: AsRef<Path>>(root_dir: P) -> Result<Self> {
+        fs::create_dir_all(&root_dir)?;
+
+        let path = root_dir.as_ref().join(FILE_NAME);
+        let mapped = MappedFile::open(&path)?;
+
+        Ok(MmapKeyValueStore {
+            path,
+            mapped: RwLock::new(mapped),
+        })
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut mapped = self.mapped.write().expects(FATAL_NOLOCK);
+
+        let mut records = mapped.records()?;
+        let end_of_file = mapped.file.seek(SeekFrom::End(0))?;
+
+        let record = Record {
+            key_hash: hash_key(key),
+            value_offset: end_of_file,
+            value_len: value.len() as u64,
+        };
+
+        match records.binary_search_by(|r| r.key_hash.cmp(&record.key_hash)) {
+            Ok(i) => records[i] = record,
+            Err(i) => records.insert(i, record),
+        }
+
+        mapped.append(value, &records)?;
+        self.compact_if_wasteful(&mut mapped)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mapped = self.mapped.read().expects(FATAL_NOLOCK);
+
+        match mapped.find(&hash_key(key))? {
+            None => Ok(None),
+            Some(record) => {
+                let start = record.value_offset as usize;
+                let end = start + record.value_len as usize;
+
+                if end > mapped.mmap.len() {
+                    return Err(format_err!("value extends past end of key/value store"));
+                }
+
+                Ok(Some(mapped.mmap[start..end].to_vec()))
+            }
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut mapped = self.mapped.write().expects(FATAL_NOLOCK);
+
+        let mut records = mapped.records()?;
+        let key_hash = hash_key(key);
+
+        match records.binary_search_by(|r| r.key_hash.cmp(&key_hash)) {
+            Ok(i) => {
+                records.remove(i);
+                mapped.append(&[], &records)?;
+                self.compact_if_wasteful(&mut mapped)
+            }
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+fn hash_key(key: &[u8]) -> [u8; KEY_HASH_LEN] {
+    let mut hasher = Blake2b::new();
+    hasher.update(key);
+
+    let mut key_hash = [0; KEY_HASH_LEN];
+    key_hash.copy_from_slice(&hasher.finalize().as_bytes()[..KEY_HASH_LEN]);
+    key_hash
+}
+
+fn write_records(buf: &mut Vec<u8>, records: &[Record]) -> Result<()> {
+    for r in records {
+        buf.extend_from_slice(&r.key_hash);
+        buf.write_u64::<LittleEndian>(r.value_offset)?;
+        buf.write_u64::<LittleEndian>(r.value_len)?;
+    }
+
+    Ok(())
+}
+
+fn write_header(file: &mut File, index_offset: u64, num_records: u64) -> Result<()> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.write_u64::<LittleEndian>(index_offset)?;
+    header.write_u64::<LittleEndian>(num_records)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alpha() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let db = MmapKeyValueStore::initialize(metadata_dir).unwrap();
+
+        let k_a = b"key-xx";
+        let k_b = b"key-yy";
+        let v_a = b"value-aa";
+        let v_b = b"value-bb";
+
+        db.put(k_a, v_a).unwrap();
+        db.put(k_b, v_b).unwrap();
+
+        assert_eq!(Some(v_a.to_vec()), db.get(k_a).unwrap());
+        assert_eq!(Some(v_b.to_vec()), db.get(k_b).unwrap());
+        assert_eq!(None, db.get(b"key-zz").unwrap());
+
+        db.delete(k_a).unwrap();
+        db.delete(b"key-zz").unwrap();
+        assert_eq!(None, db.get(k_a).unwrap());
+        assert_eq!(Some(v_b.to_vec()), db.get(k_b).unwrap());
+    }
+
+    #[test]
+    fn test_overwrite_and_reopen() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = MmapKeyValueStore::initialize(metadata_dir.path()).unwrap();
+
+            db.put(b"a", b"first").unwrap();
+            db.put(b"b", b"").unwrap();
+            db.put(b"a", b"second, and longer").unwrap();
+
+            assert_eq!(Some(b"second, and longer".to_vec()), db.get(b"a").unwrap());
+        }
+
+        let db = MmapKeyValueStore::initialize(metadata_dir.path()).unwrap();
+
+        assert_eq!(Some(b"second, and longer".to_vec()), db.get(b"a").unwrap());
+        assert_eq!(Some(vec![]), db.get(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_grows_with_many_keys() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let db = MmapKeyValueStore::initialize(metadata_dir).unwrap();
+
+        for i in 0..500u32 {
+            db.put(format!("key-{}", i).as_bytes(), &vec![i as u8; i as usize])
+                .unwrap();
+        }
+
+        for i in 0..500u32 {
+            assert_eq!(
+                Some(vec![i as u8; i as usize]),
+                db.get(format!("key-{}", i).as_bytes()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejects_foreign_file() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        fs::write(metadata_dir.path().join(FILE_NAME), b"definitely not a kvs").unwrap();
+
+        assert!(MmapKeyValueStore::initialize(metadata_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_compacts_wasted_space() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = MmapKeyValueStore::initialize(metadata_dir.path()).unwrap();
+
+            for i in 0..1000u32 {
+                db.put(b"overwritten", &vec![i as u8; 1024]).unwrap();
+                db.put(format!("deleted-{}", i).as_bytes(), &[i as u8; 256])
+                    .unwrap();
+                db.delete(format!("deleted-{}", i).as_bytes()).unwrap();
+            }
+
+            db.put(b"kept", b"kept").unwrap();
+
+            // ~1.3MiB was written, nearly all of which has been reclaimed
+            let file_len = fs::metadata(db.path()).unwrap().len();
+            assert!(file_len < 3 * MIN_COMPACTION_GARBAGE, "{}", file_len);
+            assert!(!metadata_dir.path().join(COMPACTING_FILE_NAME).exists());
+
+            assert_eq!(Some(vec![231; 1024]), db.get(b"overwritten").unwrap());
+            assert_eq!(None, db.get(b"deleted-999").unwrap());
+        }
+
+        let db = MmapKeyValueStore::initialize(metadata_dir.path()).unwrap();
+
+        assert_eq!(Some(vec![231; 1024]), db.get(b"overwritten").unwrap());
+        assert_eq!(Some(b"kept".to_vec()), db.get(b"kept").unwrap());
+        assert_eq!(None, db.get(b"deleted-0").unwrap());
+    }
+}
@@ -3,13 +3,21 @@ use std::path::Path;
 use crate::error::Result;
 
 pub mod fs;
+pub mod mmap;
 pub mod sled;
 
 pub use self::fs::FileSystemKvs;
+pub use self::mmap::MmapKeyValueStore;
 pub use self::sled::SledKvs;
 
 pub trait KeyValueStore: Sized {
     fn initialize<P: AsRef<Path>>(root_dir: P) -> Result<Self>;
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    // Stores which can't delete entries can still hold a sector builder's
+    // state, but it's never compacted (see compact_sealed_state).
+    fn delete(&self, _key: &[u8]) -> Result<()> {
+        Err(format_err!("key/value store doesn't support deletion"))
+    }
 }
@@ -24,6 +24,11 @@ impl KeyValueStore for SledKvs {
         let value = self.db.get(key)?;
         Ok(value.map(|x| x.to_vec()))
     }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.db.del(key)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -46,5 +51,10 @@ mod tests {
 
         let opt = db.get(k_a).unwrap();
         assert_eq!(format!("{:x?}", opt.unwrap()), format!("{:x?}", v_a));
+
+        db.delete(k_a).unwrap();
+        db.delete(b"key-zz").unwrap();
+        assert_eq!(None, db.get(k_a).unwrap());
+        assert!(db.get(k_b).unwrap().is_some());
     }
 }
@@ -0,0 +1,94 @@
+use crate::api::sector_builder::metadata::SealingLocation;
+use crate::api::sector_builder::signing::{sign_manifest, verify_manifest, SectorBuilderKey};
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+// Selects which pieces are included in a PieceManifest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PieceManifestFilter {
+    // Every piece in a sealed sector.
+    All,
+
+    // Pieces whose key starts with the provided label.
+    Label(String),
+
+    // Every piece, if the SectorBuilder belongs to the provided prover.
+    ProverId([u8; 31]),
+
+    // Pieces added within [start, end).
+    TimeRange { start: SystemTime, end: SystemTime },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PieceManifestEntry {
+    pub sector_id: SectorId,
+    pub comm_r: [u8; 32],
+    pub byte_offset: u64,
+    pub num_bytes: UnpaddedBytesAmount,
+    pub piece_key: String,
+    // Covered by the manifest's signature, like the rest of the entry.
+    #[serde(default)]
+    pub sealing_location: Option<SealingLocation>,
+}
+
+// A JSON document, signed with the sector builder's key, describing where a
+// client's pieces are stored. Handed to a new storage provider when
+// responsibility for the pieces changes hands.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PieceManifest {
+    pub prover_id: [u8; 31],
+    pub entries: Vec<PieceManifestEntry>,
+    pub signature: Vec<u8>,
+}
+
+// The portion of the manifest covered by its signature.
+#[derive(Serialize)]
+struct SignedContent<'a> {
+    prover_id: &'a [u8; 31],
+    entries: &'a [PieceManifestEntry],
+}
+
+impl PieceManifest {
+    // Signs the manifest with the sector builder's key, replacing any existing
+    // signature.
+    pub fn sign(&mut self, key: &SectorBuilderKey) -> Result<()> {
+        let content = self.signed_content()?;
+        self.signature = sign_manifest(key, &content)?.to_vec();
+
+        Ok(())
+    }
+
+    // Returns true if the manifest was signed by the secret key corresponding
+    // to the provided public key.
+    pub fn verify_signature(&self, public_key: &[u8; 32]) -> Result<bool> {
+        if self.signature.len() != 64 {
+            return Ok(false);
+        }
+
+        let mut signature = [0; 64];
+        signature.copy_from_slice(&self.signature);
+
+        let content = self.signed_content()?;
+
+        Ok(verify_manifest(public_key, &content, &signature))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(Into::into)
+    }
+
+    pub fn from_json(json: &str) -> Result<PieceManifest> {
+        serde_json::from_str(json).map_err(Into::into)
+    }
+
+    fn signed_content(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&SignedContent {
+            prover_id: &self.prover_id,
+            entries: &self.entries,
+        })
+        .map_err(Into::into)
+    }
+}
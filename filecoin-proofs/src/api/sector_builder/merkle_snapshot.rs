@@ -0,0 +1,533 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::api::internal;
+use crate::api::post_adapter::{
+    GeneratePoStDynamicSectorsCountInput, GeneratePoStDynamicSectorsCountOutput,
+};
+use crate::api::sector_builder::audit::{
+    find_sealed_sector, num_leaves, AuditChallenge, AuditResponse,
+};
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::incremental_merkle_tree::{
+    domain_to_bytes, walk_merkle_tree, NODE_SIZE,
+};
+use crate::api::sector_builder::metadata::SealedSectorMetadata;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder, SectorId, WrappedSectorStore};
+use crate::error::Result;
+use blake2b_simd::State as Blake2b;
+use byteorder::{ByteOrder, LittleEndian};
+use sector_base::api::bandwidth::MeteredReader;
+
+const MAGIC: &[u8; 8] = b"FCPTREE2";
+
+// The magic bytes, then the number of leaves (little-endian).
+const HEADER_BYTES: u64 = 16;
+
+// The Merkle tree of a sealed sector's replica (whose root is the sector's
+// commR), kept in a sidecar file next to the replica. The file holds every
+// node of the tree, each just after its subtree (i.e. in post-order), which
+// is the order in which the tree is built as the replica is streamed. The
+// path from any leaf to the root is read with one seek per level rather than
+// by re-reading (and re-hashing) the replica. The leaves are the replica's
+// own nodes, so the file is twice the size of the replica.
+pub struct MerkleSnapshot {
+    file: File,
+    num_leaves: u64,
+}
+
+impl MerkleSnapshot {
+    // Builds the tree of the first num_leaves (a power of two) nodes read
+    // from the replica, writing it to path.
+    pub fn build<R: Read, P: AsRef<Path>>(
+        replica: R,
+        num_leaves: u64,
+        path: P,
+    ) -> Result<MerkleSnapshot> {
+        if !num_leaves.is_power_of_two() {
+            return Err(err_unrecov(format!(
+                "cannot snapshot a tree of {} leaves, which is not a power of two",
+                num_leaves
+            ))
+            .into());
+        }
+
+        let mut header = [0; HEADER_BYTES as usize];
+        header[..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u64(&mut header[8..], num_leaves);
+
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(&header)?;
+
+        // Each node is visited once its subtree is complete, so it's written
+        // in place.
+        let mut written = Ok(());
+        walk_merkle_tree(replica, num_leaves, &mut |_, _, node| {
+            if written.is_ok() {
+                written = writer.write_all(&domain_to_bytes(node));
+            }
+        })?;
+        written?;
+
+        writer.into_inner()?.sync_all()?;
+
+        MerkleSnapshot::open(path)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MerkleSnapshot> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let mut header = [0; HEADER_BYTES as usize];
+        file.read_exact(&mut header)?;
+
+        if &header[..8] != MAGIC {
+            return Err(err_unrecov("not a Merkle tree snapshot").into());
+        }
+
+        let num_leaves = LittleEndian::read_u64(&header[8..]);
+
+        // Every level is there, down to the root.
+        let expected_len = HEADER_BYTES + (2 * num_leaves - 1) * NODE_SIZE as u64;
+        if !num_leaves.is_power_of_two() || file.metadata()?.len() != expected_len {
+            return Err(err_unrecov("Merkle tree snapshot is truncated").into());
+        }
+
+        Ok(MerkleSnapshot { file, num_leaves })
+    }
+
+    pub fn num_leaves(&self) -> u64 {
+        self.num_leaves
+    }
+
+    pub fn root(&mut self) -> Result<[u8; 32]> {
+        let height = self.num_leaves.trailing_zeros() as usize;
+        self.node(height, 0)
+    }
+
+    // Returns the leaf and the sibling of each node on the path from it to
+    // the root, leaf-most first (i.e. the proof of the leaf's inclusion in
+    // the replica).
+    pub fn prove(&mut self, leaf_index: u64) -> Result<AuditResponse> {
+        if leaf_index >= self.num_leaves {
+            return Err(err_unrecov(format!(
+                "leaf {} is beyond the {} leaves of the tree",
+                leaf_index, self.num_leaves
+            ))
+            .into());
+        }
+
+        let leaf = self.node(0, leaf_index)?;
+
+        let height = self.num_leaves.trailing_zeros() as usize;
+        let mut siblings = Vec::with_capacity(height);
+        let mut index = leaf_index;
+
+        for h in 0..height {
+            siblings.push(self.node(h, index ^ 1)?);
+            index >>= 1;
+        }
+
+        Ok(AuditResponse { leaf, siblings })
+    }
+
+    // Writes the leaves, in order (i.e. the replica from which the tree was
+    // built). The file is read through once, skipping the nodes above them.
+    pub fn write_leaves<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        self.file.seek(SeekFrom::Start(HEADER_BYTES))?;
+        let mut reader = BufReader::new(&mut self.file);
+
+        let mut position = 0;
+        let mut leaf = [0; 32];
+
+        for index in 0..self.num_leaves {
+            let leaf_position = post_order_position(0, index);
+
+            io::copy(
+                &mut (&mut reader).take((leaf_position - position) * NODE_SIZE as u64),
+                &mut io::sink(),
+            )?;
+
+            reader.read_exact(&mut leaf)?;
+            out.write_all(&leaf)?;
+
+            position = leaf_position + 1;
+        }
+
+        Ok(())
+    }
+
+    // Reads the index-th node of the level height levels above the leaves.
+    fn node(&mut self, height: usize, index: u64) -> Result<[u8; 32]> {
+        let offset = HEADER_BYTES + post_order_position(height, index) * NODE_SIZE as u64;
+
+        let mut node = [0; 32];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut node)?;
+
+        Ok(node)
+    }
+}
+
+// The number of nodes before the index-th node of the level height levels
+// above the leaves, in post-order. Each bit set in the index of the first
+// leaf under the node stands for a complete subtree to its left, of
+// 2^(bit + 1) - 1 nodes, and the node's own subtree precedes it too.
+fn post_order_position(height: usize, index: u64) -> u64 {
+    let first_leaf = index << height;
+
+    let preceding: u64 = (0..64)
+        .filter(|bit| first_leaf & (1 << bit) != 0)
+        .map(|bit| (2 << bit) - 1)
+        .sum();
+
+    preceding + (2 << height) - 2
+}
+
+// Where the Merkle tree snapshot of a sealed sector is kept: {sector_id}.tree,
+// alongside its replica.
+pub fn merkle_snapshot_path(sealed_sector: &SealedSectorMetadata) -> PathBuf {
+    Path::new(&sealed_sector.sector_access)
+        .with_file_name(format!("{}.tree", sealed_sector.sector_id))
+}
+
+// Writes the Merkle tree of the sealed sector's replica to its sidecar file.
+// The proofs library doesn't hand out the tree it builds while sealing, so
+// the tree is built (once) by streaming the replica.
+pub(crate) fn write_merkle_snapshot(
+    sector_store: &WrappedSectorStore,
+    sealed_sector: &SealedSectorMetadata,
+) -> Result<MerkleSnapshot> {
+    let replica = MeteredReader {
+        inner: File::open(&sealed_sector.sector_access)?,
+        accounting: sector_store.inner.manager().bandwidth_accounting(),
+    };
+
+    MerkleSnapshot::build(
+        BufReader::new(replica),
+        num_leaves(sector_store),
+        merkle_snapshot_path(sealed_sector),
+    )
+}
+
+// Writes the Merkle tree of a sector which has already been sealed to its
+// sidecar file, so that audit challenges and PoSts of the sector needn't
+// read its replica (see generate_post_from_snapshot). Sectors sealed with snapshot_merkle_trees set (see
+// SectorBuilderConfig) are snapshotted as they're sealed.
+pub fn snapshot_merkle_tree(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+) -> Result<MerkleSnapshot> {
+    let sealed_sector = find_sealed_sector(sector_builder, sector_id)?;
+
+    let snapshot = write_merkle_snapshot(&sector_builder.state.sector_store, &sealed_sector)?;
+
+    log_unrecov(sector_builder.run_blocking(|tx| Request::HandleMerkleSnapshot(sector_id, tx)))?;
+
+    Ok(snapshot)
+}
+
+// Answers num_challenges audit challenges of the sealed sector, whose leaves
+// are chosen by the challenge seed, from its Merkle tree snapshot. Each
+// response is checked with audit::verify_audit_response. These aren't a
+// PoSt: the snapshot holds the replica's leaves, so it's as good as the
+// replica for answering challenges, and the answers show only that one of
+// the two is stored.
+pub fn generate_audit_proofs_from_snapshot(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+    challenge_seed: &[u8; 32],
+    num_challenges: u64,
+) -> Result<Vec<(AuditChallenge, AuditResponse)>> {
+    let sealed_sector = find_sealed_sector(sector_builder, sector_id)?;
+
+    if !sealed_sector.has_merkle_snapshot {
+        return Err(err_unrecov(format!(
+            "sealed sector {} has no Merkle tree snapshot",
+            sector_id
+        ))
+        .into());
+    }
+
+    let mut snapshot = MerkleSnapshot::open(merkle_snapshot_path(&sealed_sector))?;
+
+    prove_from_snapshot(
+        &mut snapshot,
+        &sealed_sector,
+        challenge_seed,
+        num_challenges,
+    )
+}
+
+// Generates a PoSt of the sealed sectors with the provided commRs (in the
+// same way as SectorBuilder::generate_post), proving each sector which has a
+// Merkle tree snapshot from the snapshot rather than from its replica, which
+// needn't be at hand. The proofs library builds the tree it proves from a
+// replica file, so the snapshot's leaves (which are the replica's nodes) are
+// written out to a temporary file next to the snapshot, which is removed
+// once the PoSt has been generated. This is done on the caller's thread.
+pub fn generate_post_from_snapshot(
+    sector_builder: &SectorBuilder,
+    comm_rs: &[[u8; 32]],
+    challenge_seed: &[u8; 32],
+) -> Result<GeneratePoStDynamicSectorsCountOutput> {
+    let sealed_sectors = sector_builder.get_sealed_sectors()?;
+
+    // kept until the PoSt has been generated
+    let mut replicas = Vec::new();
+    let mut input_parts = Vec::with_capacity(comm_rs.len());
+
+    for comm_r in comm_rs {
+        let sealed_sector = sealed_sectors
+            .iter()
+            .find(|sector| sector.comm_r == *comm_r)
+            .ok_or_else(|| err_unrecov(format!("no sealed sector with commR {:?}", comm_r)))?;
+
+        if !sealed_sector.has_merkle_snapshot {
+            input_parts.push((Some(sealed_sector.sector_access.clone()), *comm_r));
+            continue;
+        }
+
+        let path = merkle_snapshot_path(sealed_sector);
+        let mut snapshot = MerkleSnapshot::open(&path)?;
+
+        if snapshot.root()? != *comm_r {
+            return Err(err_unrecov(format!(
+                "Merkle tree snapshot of sector {} does not match its commR",
+                sealed_sector.sector_id
+            ))
+            .into());
+        }
+
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let replica = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut writer = BufWriter::new(replica.as_file());
+            snapshot.write_leaves(&mut writer)?;
+            writer.flush()?;
+        }
+
+        input_parts.push((Some(replica.path().to_string_lossy().into_owned()), *comm_r));
+        replicas.push(replica);
+    }
+
+    internal::generate_post(GeneratePoStDynamicSectorsCountInput {
+        post_config: sector_builder
+            .state
+            .sector_store
+            .inner
+            .proofs_config()
+            .post_config(),
+        challenge_seed: *challenge_seed,
+        input_parts,
+    })
+}
+
+fn prove_from_snapshot(
+    snapshot: &mut MerkleSnapshot,
+    sealed_sector: &SealedSectorMetadata,
+    challenge_seed: &[u8; 32],
+    num_challenges: u64,
+) -> Result<Vec<(AuditChallenge, AuditResponse)>> {
+    if snapshot.root()? != sealed_sector.comm_r {
+        return Err(err_unrecov(format!(
+            "Merkle tree snapshot of sector {} does not match its commR",
+            sealed_sector.sector_id
+        ))
+        .into());
+    }
+
+    (0..num_challenges)
+        .map(|n| {
+            let challenge = AuditChallenge::new(
+                sealed_sector,
+                snapshot.num_leaves(),
+                derive_challenge_index(challenge_seed, n),
+            );
+
+            let response = snapshot.prove(challenge.leaf_index)?;
+
+            Ok((challenge, response))
+        })
+        .collect()
+}
+
+fn derive_challenge_index(challenge_seed: &[u8; 32], n: u64) -> u64 {
+    let mut buf = [0; 8];
+    LittleEndian::write_u64(&mut buf, n);
+
+    let hash = Blake2b::new()
+        .update(b"filecoin-proofs snapshot audit")
+        .update(challenge_seed)
+        .update(&buf)
+        .finalize();
+
+    LittleEndian::read_u64(&hash.as_bytes()[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::post_adapter::VerifyPoStDynamicSectorsCountInput;
+    use crate::api::sector_builder::audit::verify_audit_response;
+    use crate::api::sector_builder::config::SectorBuilderConfig;
+    use crate::api::sector_builder::sealing_test_harness::await_seal;
+    use crate::api::sector_builder::test_utils::{self, sector_builder_factory, TEST_CLASS};
+    use std::fs;
+
+    const NUM_LEAVES: u64 = 64;
+
+    // A replica, written to a file, and its sealed sector.
+    fn replica(dir: &Path) -> SealedSectorMetadata {
+        let (bytes, comm_r) = test_utils::replica(NUM_LEAVES);
+
+        let sector_access = dir.join("replica");
+        std::fs::write(&sector_access, &bytes).unwrap();
+
+        SealedSectorMetadata {
+            sector_id: 12,
+            sector_access: sector_access.to_string_lossy().into_owned(),
+            comm_r,
+            has_merkle_snapshot: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_audit_proofs_from_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let sealed_sector = replica(dir.path());
+        let path = merkle_snapshot_path(&sealed_sector);
+
+        assert_eq!(dir.path().join("12.tree"), path);
+
+        MerkleSnapshot::build(
+            File::open(&sealed_sector.sector_access).unwrap(),
+            NUM_LEAVES,
+            &path,
+        )
+        .unwrap();
+
+        let mut snapshot = MerkleSnapshot::open(&path).unwrap();
+        assert_eq!(sealed_sector.comm_r, snapshot.root().unwrap());
+
+        // leaves all over the tree, each of whose paths is read from where it
+        // was written
+        for challenge_index in 0..4 * NUM_LEAVES {
+            let challenge = AuditChallenge::new(&sealed_sector, NUM_LEAVES, challenge_index);
+            let response = snapshot.prove(challenge.leaf_index).unwrap();
+            assert!(verify_audit_response(&challenge, &response).unwrap());
+        }
+
+        let proofs = prove_from_snapshot(&mut snapshot, &sealed_sector, &[5; 32], 8).unwrap();
+        assert_eq!(8, proofs.len());
+
+        for (challenge, response) in &proofs {
+            assert_eq!(6, response.siblings.len());
+            assert!(verify_audit_response(challenge, response).unwrap());
+        }
+
+        // another seed challenges other leaves
+        let other = prove_from_snapshot(&mut snapshot, &sealed_sector, &[6; 32], 8).unwrap();
+        assert_ne!(proofs, other);
+
+        // the leaves are the replica
+        let mut leaves = Vec::new();
+        snapshot.write_leaves(&mut leaves).unwrap();
+        assert_eq!(fs::read(&sealed_sector.sector_access).unwrap(), leaves);
+    }
+
+    #[test]
+    #[ignore] // Slow test – run only when compiled for release.
+    fn test_post_from_snapshot_without_replica() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sector_builder = sector_builder_factory(TEST_CLASS, dir.path())
+            .unwrap()
+            .create_concrete_sector_builder(SectorBuilderConfig {
+                snapshot_merkle_trees: true,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let piece_path = dir.path().join("piece");
+        fs::write(&piece_path, vec![7; 100]).unwrap();
+
+        let sector_id = sector_builder
+            .add_piece(
+                "piece".to_string(),
+                100,
+                piece_path.to_string_lossy().into_owned(),
+            )
+            .unwrap();
+        sector_builder.seal_sector(sector_id).unwrap();
+        await_seal(&sector_builder, sector_id).unwrap();
+
+        let sealed_sector = find_sealed_sector(&sector_builder, sector_id).unwrap();
+        assert!(sealed_sector.has_merkle_snapshot);
+
+        fs::remove_file(&sealed_sector.sector_access).unwrap();
+
+        let comm_rs = [sealed_sector.comm_r];
+        let output = generate_post_from_snapshot(&sector_builder, &comm_rs, &[9; 32]).unwrap();
+        assert!(output.faults.is_empty());
+
+        let verified = internal::verify_post(VerifyPoStDynamicSectorsCountInput {
+            post_config: sector_builder
+                .state
+                .sector_store
+                .inner
+                .proofs_config()
+                .post_config(),
+            comm_rs: comm_rs.to_vec(),
+            challenge_seed: [9; 32],
+            proofs: output.proofs,
+            faults: output.faults,
+        })
+        .unwrap();
+        assert!(verified.is_valid);
+
+        // the replica written out for the PoSt is gone, too
+        let sealed_sector_dir = merkle_snapshot_path(&sealed_sector)
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        assert!(fs::read_dir(sealed_sector_dir).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(".tmp")));
+    }
+
+    #[test]
+    fn test_rejects_mismatched_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let sealed_sector = replica(dir.path());
+        let path = merkle_snapshot_path(&sealed_sector);
+
+        MerkleSnapshot::build(
+            File::open(&sealed_sector.sector_access).unwrap(),
+            NUM_LEAVES,
+            &path,
+        )
+        .unwrap();
+
+        let mut snapshot = MerkleSnapshot::open(&path).unwrap();
+        let resealed = SealedSectorMetadata {
+            comm_r: [0; 32],
+            ..sealed_sector
+        };
+        assert!(prove_from_snapshot(&mut snapshot, &resealed, &[5; 32], 1).is_err());
+
+        // a truncated snapshot
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert!(MerkleSnapshot::open(&path).is_err());
+    }
+}
@@ -3,15 +3,32 @@ use crate::error;
 use byteorder::LittleEndian;
 use byteorder::WriteBytesExt;
 use sector_base::api::bytes_amount::UnpaddedBytesAmount;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct StagedSectorMetadata {
     pub sector_id: SectorId,
     pub sector_access: String,
-    pub pieces: Vec<PieceMetadata>,
+    // Keyed by each piece's position in the sector (see PiecePosition).
+    #[serde(deserialize_with = "deserialize_staged_pieces")]
+    pub pieces: BTreeMap<PiecePosition, PieceMetadata>,
     pub seal_status: SealStatus,
+    // The sector's data tree, as of its most recently hashed piece. See
+    // IncrementalMerkleTree.
+    #[serde(default)]
+    pub merkle_tree_state: Option<MerkleTreeState>,
+    // The CRC32 of the sector's file (body), and when it was computed: after
+    // the most recent write to the sector. The health check compares it with
+    // the file's, so as to only checksum the pieces of sectors which changed.
+    #[serde(default)]
+    pub last_crc32: Option<(u32, SystemTime)>,
+    // When the sector was provisioned. The Unix epoch for sectors provisioned
+    // before this was recorded.
+    #[serde(default = "unix_epoch")]
+    pub provisioned_at: SystemTime,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -23,12 +40,99 @@ pub struct SealedSectorMetadata {
     pub comm_r: [u8; 32],
     pub comm_d: [u8; 32],
     pub proof: Vec<u8>,
+    // Where the sector was sealed, if the sector builder was configured with
+    // its location.
+    #[serde(default)]
+    pub sealing_location: Option<SealingLocation>,
+    // Set once the sector's replica has been copied to cold storage, after
+    // which the local copy may be removed.
+    #[serde(default)]
+    pub archive_receipt: Option<ArchiveReceipt>,
+    // The version of the sealing API with which the sector was sealed, which
+    // determines how its proof is verified.
+    #[serde(default)]
+    pub api_version: ApiVersion,
+    // The chain epoch by which the sector must be pre-committed, if the
+    // sector builder was configured with an epoch source when it was sealed.
+    #[serde(default)]
+    pub pre_commit_deadline: Option<u64>,
+    // The chain epoch at which the sector was sealed, if the sector builder
+    // was configured with an epoch source when it was sealed.
+    #[serde(default)]
+    pub sealed_at_epoch: Option<u64>,
+    // True once the Merkle tree of the sector's replica has been written to
+    // its sidecar file (see merkle_snapshot::MerkleSnapshot).
+    #[serde(default)]
+    pub has_merkle_snapshot: bool,
+    // The BLAKE3 hash of the sector's replica, computed once it was sealed
+    // (see http_export::replica_checksum). Sectors sealed before it was
+    // recorded have none.
+    #[serde(default)]
+    pub replica_checksum: Option<[u8; 32]>,
+}
+
+// Versions of the sealing (PoRep) API. Sectors sealed before the network
+// upgrades to a new version remain valid, and are verified with the version
+// they were sealed with. Sectors persisted before versions were recorded
+// were sealed with V1.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl Default for ApiVersion {
+    fn default() -> ApiVersion {
+        ApiVersion::V1
+    }
+}
+
+// The facility in which a sector was sealed, for jurisdictions which require
+// that it be known.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SealingLocation {
+    pub datacenter_id: String,
+    pub region: String,
+    // ISO 3166-1 alpha-2, e.g. b"DE"
+    pub country_code: [u8; 2],
+}
+
+// Where a sealed sector's replica was archived, and the BLAKE3 hash of the
+// archived bytes.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ArchiveReceipt {
+    pub url: String,
+    pub content_hash: [u8; 32],
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct PieceMetadata {
     pub piece_key: String,
     pub num_bytes: UnpaddedBytesAmount,
+    // The Unix epoch for pieces added before this was recorded.
+    #[serde(default = "unix_epoch")]
+    pub added_at: SystemTime,
+    // BLAKE3 checksum of the piece-bytes, computed as they were written. Not
+    // available for pieces added before checksums were recorded.
+    #[serde(default)]
+    pub checksum: Option<[u8; 32]>,
+    // The hash of the capability token which must be presented to read the
+    // piece. Anyone may read a piece without one.
+    #[serde(default)]
+    pub access_token_hash: Option<[u8; 32]>,
+}
+
+// The persisted form of an IncrementalMerkleTree: the number of leaves in the
+// finished tree and the number added so far, the roots (with their heights) of
+// the complete subtrees at the tree's right edge, and the preprocessed bytes
+// of the incomplete leaf which follows them.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct MerkleTreeState {
+    pub num_leaves: usize,
+    pub num_leaves_added: usize,
+    pub num_unpadded_bytes: u64,
+    pub frontier: Vec<(usize, [u8; 32])>,
+    pub tail: Vec<u8>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -39,6 +143,30 @@ pub enum SealStatus {
     Sealing,
 }
 
+// A record of a piece's redaction. Pieces which had been sealed (or were
+// being sealed) when they were redacted can't have been overwritten.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RedactionReceipt {
+    pub piece_key: String,
+    pub sector_id: SectorId,
+    pub redacted_at: SystemTime,
+    pub was_overwritten: bool,
+}
+
+// The outcome of deleting a batch of sectors.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchDeleteResult {
+    // Sectors which were deleted. Their sector accesses (e.g. files) have
+    // been removed, too.
+    pub deleted: Vec<SectorId>,
+    // Sectors which were not deleted because they have an active deal and
+    // deletion wasn't forced.
+    pub blocked: Vec<SectorId>,
+    // Sectors which could not be deleted (including those with an active
+    // deal whose forced deletion couldn't be audited), and why.
+    pub errored: Vec<(SectorId, String)>,
+}
+
 impl PartialEq for SealedSectorMetadata {
     fn eq(&self, other: &SealedSectorMetadata) -> bool {
         self.sector_id == other.sector_id
@@ -48,6 +176,13 @@ impl PartialEq for SealedSectorMetadata {
             && self.comm_r == other.comm_r
             && self.comm_d == other.comm_d
             && self.proof.iter().eq(other.proof.iter())
+            && self.sealing_location == other.sealing_location
+            && self.archive_receipt == other.archive_receipt
+            && self.api_version == other.api_version
+            && self.pre_commit_deadline == other.pre_commit_deadline
+            && self.sealed_at_epoch == other.sealed_at_epoch
+            && self.has_merkle_snapshot == other.has_merkle_snapshot
+            && self.replica_checksum == other.replica_checksum
     }
 }
 
@@ -58,13 +193,28 @@ impl Default for StagedSectorMetadata {
             sector_access: Default::default(),
             pieces: Default::default(),
             seal_status: SealStatus::Pending,
+            merkle_tree_state: None,
+            last_crc32: None,
+            provisioned_at: UNIX_EPOCH,
+        }
+    }
+}
+
+impl Default for PieceMetadata {
+    fn default() -> PieceMetadata {
+        PieceMetadata {
+            piece_key: Default::default(),
+            num_bytes: UnpaddedBytesAmount(0),
+            added_at: UNIX_EPOCH,
+            checksum: None,
+            access_token_hash: None,
         }
     }
 }
 
 impl fmt::Debug for SealedSectorMetadata {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SealedSectorMetadata {{ sector_id: {}, sector_access: {}, pieces: {:?}, comm_r_star: {:?}, comm_r: {:?}, comm_d: {:?} }}", self.sector_id, self.sector_access, self.pieces, self.comm_r_star, self.comm_r, self.comm_d)
+        write!(f, "SealedSectorMetadata {{ sector_id: {}, sector_access: {}, pieces: {:?}, comm_r_star: {:?}, comm_r: {:?}, comm_d: {:?}, sealing_location: {:?}, archive_receipt: {:?}, api_version: {:?}, pre_commit_deadline: {:?}, sealed_at_epoch: {:?}, has_merkle_snapshot: {}, replica_checksum: {:?} }}", self.sector_id, self.sector_access, self.pieces, self.comm_r_star, self.comm_r, self.comm_d, self.sealing_location, self.archive_receipt, self.api_version, self.pre_commit_deadline, self.sealed_at_epoch, self.has_merkle_snapshot, self.replica_checksum)
     }
 }
 
@@ -78,14 +228,93 @@ impl Default for SealedSectorMetadata {
             comm_r: Default::default(),
             comm_d: Default::default(),
             proof: Default::default(),
+            sealing_location: None,
+            archive_receipt: None,
+            api_version: Default::default(),
+            pre_commit_deadline: None,
+            sealed_at_epoch: None,
+            has_merkle_snapshot: false,
+            replica_checksum: None,
         }
     }
 }
 
+// The offset of a staged piece's (unpadded) bytes in its sector, and the
+// piece's index among the sector's pieces. Pieces are ordered by offset, the
+// index telling a piece of no bytes from the piece after it.
+pub type PiecePosition = (UnpaddedBytesAmount, usize);
+
 pub fn sum_piece_bytes(s: &StagedSectorMetadata) -> UnpaddedBytesAmount {
     s.pieces
         .iter()
-        .fold(UnpaddedBytesAmount(0), |acc, x| acc + x.num_bytes)
+        .next_back()
+        .map_or(UnpaddedBytesAmount(0), |((offset, _), piece)| {
+            *offset + piece.num_bytes
+        })
+}
+
+// Appends the piece to the staged sector, after its last piece.
+pub fn push_piece(s: &mut StagedSectorMetadata, piece: PieceMetadata) {
+    let position = (sum_piece_bytes(s), s.pieces.len());
+    s.pieces.insert(position, piece);
+}
+
+// Returns the piece whose bytes include the one at the provided offset.
+pub fn find_piece_at_offset(
+    s: &StagedSectorMetadata,
+    offset: UnpaddedBytesAmount,
+) -> Option<&PieceMetadata> {
+    s.pieces
+        .range(..=(offset, usize::max_value()))
+        .next_back()
+        .filter(|((start, _), piece)| offset < *start + piece.num_bytes)
+        .map(|(_, piece)| piece)
+}
+
+// Keys pieces, which were appended to a sector in the order provided, by
+// their positions.
+pub fn pieces_by_offset<I>(pieces: I) -> BTreeMap<PiecePosition, PieceMetadata>
+where
+    I: IntoIterator<Item = PieceMetadata>,
+{
+    let mut offset = UnpaddedBytesAmount(0);
+
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, piece)| {
+            let start = offset;
+            offset = offset + piece.num_bytes;
+            ((start, index), piece)
+        })
+        .collect()
+}
+
+// Staged sectors persisted before pieces were keyed by position list them in
+// the order in which they were added, or key them by offset alone.
+fn deserialize_staged_pieces<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<PiecePosition, PieceMetadata>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StagedPieces {
+        ByPosition(BTreeMap<PiecePosition, PieceMetadata>),
+        ByOffset(BTreeMap<UnpaddedBytesAmount, PieceMetadata>),
+        InOrder(Vec<PieceMetadata>),
+    }
+
+    Ok(match StagedPieces::deserialize(deserializer)? {
+        StagedPieces::ByPosition(pieces) => pieces,
+        StagedPieces::ByOffset(pieces) => pieces_by_offset(pieces.into_iter().map(|(_, p)| p)),
+        StagedPieces::InOrder(pieces) => pieces_by_offset(pieces),
+    })
+}
+
+pub fn unix_epoch() -> SystemTime {
+    UNIX_EPOCH
 }
 
 pub fn sector_id_as_bytes(sector_id: SectorId) -> error::Result<[u8; 31]> {
@@ -97,3 +326,94 @@ pub fn sector_id_as_bytes(sector_id: SectorId) -> error::Result<[u8; 31]> {
 
     Ok(sector_id_as_bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staged_sector(piece_sizes: &[u64]) -> StagedSectorMetadata {
+        let mut sector: StagedSectorMetadata = Default::default();
+
+        for (i, num_bytes) in piece_sizes.iter().enumerate() {
+            push_piece(
+                &mut sector,
+                PieceMetadata {
+                    piece_key: format!("piece-{}", i),
+                    num_bytes: UnpaddedBytesAmount(*num_bytes),
+                    ..Default::default()
+                },
+            );
+        }
+
+        sector
+    }
+
+    #[test]
+    fn test_finds_piece_at_offset() {
+        let sector = staged_sector(&[10, 1, 5]);
+        let key_at = |offset| {
+            find_piece_at_offset(&sector, UnpaddedBytesAmount(offset)).map(|p| p.piece_key.as_str())
+        };
+
+        assert_eq!(Some("piece-0"), key_at(0));
+        assert_eq!(Some("piece-0"), key_at(9));
+        assert_eq!(Some("piece-1"), key_at(10));
+        assert_eq!(Some("piece-2"), key_at(11));
+        assert_eq!(Some("piece-2"), key_at(15));
+        assert_eq!(None, key_at(16));
+
+        assert_eq!(UnpaddedBytesAmount(16), sum_piece_bytes(&sector));
+        assert_eq!(
+            None,
+            find_piece_at_offset(&staged_sector(&[]), UnpaddedBytesAmount(0))
+        );
+    }
+
+    #[test]
+    fn test_keeps_pieces_of_no_bytes() {
+        let sector = staged_sector(&[0, 10, 0, 0, 5, 0]);
+        let key_at = |offset| {
+            find_piece_at_offset(&sector, UnpaddedBytesAmount(offset)).map(|p| p.piece_key.as_str())
+        };
+
+        assert_eq!(6, sector.pieces.len());
+        assert_eq!(Some("piece-1"), key_at(0));
+        assert_eq!(Some("piece-4"), key_at(10));
+        assert_eq!(None, key_at(15));
+        assert_eq!(UnpaddedBytesAmount(15), sum_piece_bytes(&sector));
+
+        let keys: Vec<&str> = sector
+            .pieces
+            .values()
+            .map(|p| p.piece_key.as_str())
+            .collect();
+        assert_eq!(
+            vec!["piece-0", "piece-1", "piece-2", "piece-3", "piece-4", "piece-5"],
+            keys
+        );
+    }
+
+    // A staged sector holding two pieces (of 3 and 4 bytes), as persisted by
+    // the sector builder before pieces were keyed by offset or had any
+    // metadata besides their key and size.
+    const LEGACY_STAGED_SECTOR_CBOR: &[u8] = b"\xa4\
+        isector_id\x00\
+        msector_access`\
+        fpieces\x82\
+        \xa2ipiece_keygpiece-0inum_bytes\x03\
+        \xa2ipiece_keygpiece-1inum_bytes\x04\
+        kseal_statusgPending";
+
+    #[test]
+    fn test_reads_pieces_persisted_as_list() {
+        let sector = staged_sector(&[3, 4]);
+
+        assert_eq!(
+            sector,
+            serde_cbor::from_slice(LEGACY_STAGED_SECTOR_CBOR).unwrap()
+        );
+
+        let current = serde_cbor::to_vec(&sector).unwrap();
+        assert_eq!(sector, serde_cbor::from_slice(&current).unwrap());
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+
+// A histogram of u64 observations (e.g. latencies, in microseconds) which may
+// be updated from any number of threads without locking.
+#[derive(Debug)]
+pub struct Histogram {
+    // inclusive upper bound of each bucket, ascending
+    bounds: Vec<u64>,
+    // one counter per bucket, plus one for observations above the last bound
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    // what the histogram's timers measure durations by
+    clock: Arc<Clock>,
+}
+
+// The source of the instants between which durations are measured.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Measures the time from its creation until it's stopped, which the
+// histogram observes in microseconds.
+pub struct HistogramTimer<'a> {
+    histogram: &'a Histogram,
+    started_at: Instant,
+}
+
+impl<'a> HistogramTimer<'a> {
+    pub fn stop_and_observe(self) {
+        let elapsed = self.histogram.clock.now() - self.started_at;
+        self.histogram.observe_duration(elapsed);
+    }
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<u64>) -> Result<Histogram> {
+        Histogram::with_clock(bounds, Arc::new(SystemClock))
+    }
+
+    // Like new, but with timers which measure durations by the given clock.
+    pub fn with_clock(bounds: Vec<u64>, clock: Arc<Clock>) -> Result<Histogram> {
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format_err!(
+                "histogram bucket bounds must be strictly ascending: {:?}",
+                bounds
+            ));
+        }
+
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+
+        Ok(Histogram {
+            bounds,
+            counts,
+            sum: AtomicU64::new(0),
+            clock,
+        })
+    }
+
+    pub fn start_timer(&self) -> HistogramTimer {
+        HistogramTimer {
+            histogram: self,
+            started_at: self.clock.now(),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or_else(|| self.bounds.len());
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    // Observes the duration in microseconds.
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros()));
+    }
+
+    pub fn bounds(&self) -> &[u64] {
+        &self.bounds
+    }
+
+    // The number of observations in each bucket (not cumulative), the last of
+    // which holds those above the last bound.
+    pub fn bucket_counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .map(|x| x.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.bucket_counts().iter().sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    // Zeroes every counter. Observations made concurrently with a reset may
+    // or may not survive it.
+    pub fn reset(&self) {
+        for count in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+
+        self.sum.store(0, Ordering::Relaxed);
+    }
+}
+
+// Receives the sector builder's metrics as they change, e.g. to expose them
+// to a monitoring system (see prometheus_endpoint::PrometheusMetrics).
+pub trait MetricsSink: Send + Sync {
+    // A piece has been written to a staged sector.
+    fn piece_added(&self);
+
+    // The staged sectors have changed. Holds the ratio of piece-bytes to the
+    // number it could hold of every staged sector.
+    fn staged_sectors_changed(&self, fill_ratios: &[(SectorId, f64)]);
+
+    // A sector has been sealed, which took seal_duration.
+    fn sector_sealed(&self, seal_duration: Duration);
+
+    // A sector has been scheduled to be sealed, fill_duration after it was
+    // provisioned.
+    fn sector_filled(&self, _fill_duration: Duration) {}
+
+    // The histogram of piece ingestion latencies (in microseconds), handed to
+    // the sink once when the sector builder starts, so that it may be
+    // exposed along with the other metrics.
+    fn piece_ingestion_histogram(&self, _histogram: Arc<Histogram>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let histogram = Histogram::new(vec![10, 100]).unwrap();
+
+        for value in &[0, 10, 11, 100, 101, 5000] {
+            histogram.observe(*value);
+        }
+
+        assert_eq!(vec![2, 2, 2], histogram.bucket_counts());
+        assert_eq!(6, histogram.count());
+        assert_eq!(5222, histogram.sum());
+
+        histogram.reset();
+        assert_eq!(vec![0, 0, 0], histogram.bucket_counts());
+        assert_eq!(0, histogram.sum());
+    }
+
+    #[test]
+    fn test_rejects_unordered_bounds() {
+        assert!(Histogram::new(vec![10, 10]).is_err());
+        assert!(Histogram::new(vec![100, 10]).is_err());
+        assert!(Histogram::new(vec![]).is_ok());
+    }
+}
@@ -1,13 +1,37 @@
 use slog::*;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::api::internal;
 use crate::api::post_adapter::*;
-use crate::api::sector_builder::errors::SectorBuilderErr;
+use crate::api::sector_builder::archive::{
+    archive_sealed_sector, retrieve_archived_piece, ArchiveBackend,
+};
+use crate::api::sector_builder::config::SectorBuilderConfig;
+use crate::api::sector_builder::dashboard::SealingTracker;
+use crate::api::sector_builder::errors::{err_unrecov, SectorBuilderErr};
+use crate::api::sector_builder::gpu::SealProver;
+use crate::api::sector_builder::health::{Discrepancy, HealthMonitor};
+use crate::api::sector_builder::helpers::check_staged_sector_crc32::check_staged_sector_crc32s;
+use crate::api::sector_builder::helpers::cleanup_partial_seal_files::cleanup_partial_seal_files;
+use crate::api::sector_builder::helpers::prefetch_piece::PieceReadBuffer;
+use crate::api::sector_builder::helpers::seal_trigger::SealTrigger;
+use crate::api::sector_builder::helpers::snapshots::load_snapshot_lazily;
+use crate::api::sector_builder::helpers::staged_capacity::StagedCapacity;
+use crate::api::sector_builder::io_scheduler::IoScheduler;
 use crate::api::sector_builder::kv_store::{KeyValueStore, SledKvs};
+use crate::api::sector_builder::manifest::{PieceManifest, PieceManifestFilter};
 use crate::api::sector_builder::metadata::*;
+use crate::api::sector_builder::metrics::Histogram;
+use crate::api::sector_builder::piece_access::{hash_piece_token, PieceCapabilityToken};
+use crate::api::sector_builder::post_scheduler::PostScheduler;
 use crate::api::sector_builder::scheduler::Request;
 use crate::api::sector_builder::scheduler::Scheduler;
+use crate::api::sector_builder::seal_verifier::CpuSealVerifier;
 use crate::api::sector_builder::sealer::*;
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::signing::{sign_manifest, SectorBuilderKey};
+use crate::api::sector_builder::wal::replay_staged_sector_wals;
 use crate::error::ExpectWithBacktrace;
 use crate::error::Result;
 use crate::FCP_LOG;
@@ -15,13 +39,57 @@ use sector_base::api::disk_backed_storage::new_sector_store;
 use sector_base::api::sector_class::SectorClass;
 use sector_base::api::sector_store::SectorStore;
 
+pub mod archive;
+pub mod audit;
+pub mod audit_log;
+// A narrow interface onto the internals measured by the benchmarks (in
+// benches/), which isn't otherwise part of the API.
+#[doc(hidden)]
+pub mod bench;
+pub mod config;
+pub mod dashboard;
+pub mod deal_registry;
 pub mod errors;
+pub mod factory;
+pub mod fill_time;
+pub mod gpu;
+pub mod health;
 mod helpers;
+pub mod http_export;
+#[cfg(feature = "sector-export-http")]
+pub mod http_export_server;
+#[cfg(feature = "sector-import-http")]
+pub mod http_import;
+pub mod io_scheduler;
 mod kv_store;
+pub mod manifest;
+pub mod merkle_snapshot;
 pub mod metadata;
+pub mod metrics;
+pub mod piece_access;
+mod piece_writer;
+pub mod post_scheduler;
+mod precompute;
+#[cfg(feature = "metrics-prometheus")]
+pub mod prometheus_endpoint;
+pub mod redaction;
+pub mod replica_consistency;
+pub mod reseal;
 mod scheduler;
+pub mod seal_verifier;
 mod sealer;
+#[cfg(test)]
+mod sealing_test_harness;
+mod sector_id_allocator;
+pub mod sector_locks;
+pub mod signing;
 mod state;
+pub mod state_export;
+pub mod telemetry;
+#[cfg(test)]
+mod test_utils;
+pub mod wal;
+pub mod window_post;
 
 const NUM_SEAL_WORKERS: usize = 2;
 
@@ -30,9 +98,91 @@ const FATAL_NORECV_TASK: &str = "[run_blocking] could not recv";
 
 pub type SectorId = u64;
 
-pub struct SectorBuilder {
+// A sector builder, in one of the states of its lifecycle:
+//
+// - Uninitialized: configured (see SectorBuilder::new), but nothing has been
+//   opened.
+// - Initialized: its metadata store and sector directories have been opened,
+//   partial seals cleaned up and its key loaded, so that nothing which
+//   remains to be done to start it can fail.
+// - Active: its workers have been started. Only an active sector builder
+//   stages pieces, seals sectors and generates proofs.
+//
+// Each transition (initialize, then start) consumes the sector builder, and
+// each state's methods are implemented for it alone, so that e.g. pieces
+// can't be added to a sector builder which hasn't been started, nor one be
+// started without having been initialized or initialized twice: such calls
+// don't type-check.
+//
+// Having sealed a sector isn't a state of its own, though. generate_post is a
+// method of every active sector builder, which checks at runtime (when a PoSt
+// is requested) that at least one sector has been sealed, and produces an
+// error if not.
+//
+// The examples below are compiled as doc tests (through the hidden
+// re-exports in api), which check that only an active sector builder adds
+// pieces.
+///
+/// ```no_run
+/// use filecoin_proofs::api::{Active, SectorBuilder};
+///
+/// fn add(sector_builder: &SectorBuilder<Active>) {
+///     let _ = sector_builder.add_piece("piece".to_string(), 100, "/tmp/piece".to_string());
+/// }
+/// ```
+///
+/// ```compile_fail
+/// use filecoin_proofs::api::{SectorBuilder, Uninitialized};
+///
+/// fn add(sector_builder: &SectorBuilder<Uninitialized>) {
+///     let _ = sector_builder.add_piece("piece".to_string(), 100, "/tmp/piece".to_string());
+/// }
+/// ```
+///
+/// ```compile_fail
+/// use filecoin_proofs::api::{Initialized, SectorBuilder};
+///
+/// fn add(sector_builder: &SectorBuilder<Initialized>) {
+///     let _ = sector_builder.add_piece("piece".to_string(), 100, "/tmp/piece".to_string());
+/// }
+/// ```
+pub struct SectorBuilder<S = Active> {
+    state: S,
+}
+
+// A sector builder's configuration, before it has been initialized.
+pub struct Uninitialized {
+    sector_class: SectorClass,
+    last_committed_sector_id: SectorId,
+    metadata_dir: String,
+    prover_id: [u8; 31],
+    sealed_sector_dir: String,
+    staged_sector_dir: String,
+    max_num_staged_sectors: u8,
+    config: SectorBuilderConfig,
+}
+
+// The stores (and collaborators) of a sector builder whose workers haven't
+// been started.
+pub struct Initialized {
+    kv_store: Arc<WrappedKeyValueStore<SledKvs>>,
+    sector_store: Arc<WrappedSectorStore>,
+    last_committed_sector_id: SectorId,
+    prover_id: [u8; 31],
+    max_num_staged_sectors: u8,
+    seal_trigger: SealTrigger,
+    seal_prover: SealProver,
+    piece_ingestion_histogram: Arc<Histogram>,
+    key: SectorBuilderKey,
+    config: SectorBuilderConfig,
+}
+
+// A sector builder whose workers are running.
+pub struct Active {
     // Prevents FFI consumers from queueing behind long-running seal operations.
-    sealers_tx: mpsc::Sender<SealerInput>,
+    // Only used to shut the sealers down, but locked so that the sector
+    // builder may be shared between threads.
+    sealers_tx: Mutex<mpsc::Sender<SealerInput>>,
 
     // For additional seal concurrency, add more workers here.
     sealers: Vec<SealerWorker>,
@@ -42,13 +192,48 @@ pub struct SectorBuilder {
 
     // The main worker. Owns all mutable state for the SectorBuilder.
     scheduler: Scheduler,
+
+    // For work done on the caller's thread (e.g. archival), which mustn't
+    // hold up the main worker.
+    sector_store: Arc<WrappedSectorStore>,
+    prover_id: [u8; 31],
+
+    // Checks the main worker's metadata against the sectors on disk, if an
+    // alert sink was configured.
+    health_monitor: Option<HealthMonitor>,
+
+    // Warns about, and proves, sectors approaching their pre-commit deadline,
+    // if an epoch source was configured.
+    post_scheduler: Option<PostScheduler>,
+
+    // Written to by the main worker as pieces are added.
+    piece_ingestion_histogram: Arc<Histogram>,
+
+    // Shares sector file I/O between adding and retrieving pieces, and
+    // sealing.
+    io_scheduler: Arc<IoScheduler>,
+
+    // Held by the main worker and sealers, and by callers reading sector files
+    // on their own threads, so that no file is written while it's mapped.
+    sector_locks: Arc<SectorLocks>,
+
+    // Updated by the main worker and sealers as seals are scheduled and
+    // progress, for dashboards.
+    sealing_tracker: Arc<SealingTracker>,
+
+    // Published by the main worker, so that callers waiting for room to add
+    // pieces don't hold it up.
+    staged_capacity: Arc<StagedCapacity>,
+
+    // Signs the manifests handed out by the sector builder.
+    key: SectorBuilderKey,
 }
 
-impl SectorBuilder {
-    // Initialize and return a SectorBuilder from metadata persisted to disk if
-    // it exists. Otherwise, initialize and return a fresh SectorBuilder. The
-    // metadata key is equal to the prover_id.
-    pub fn init_from_metadata<S: Into<String>>(
+impl SectorBuilder<Uninitialized> {
+    // Configures a SectorBuilder whose metadata is persisted to disk (keyed by
+    // the prover_id), which is initialized from that metadata if it exists.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<S: Into<String>>(
         sector_class: SectorClass,
         last_committed_sector_id: SectorId,
         metadata_dir: S,
@@ -56,9 +241,51 @@ impl SectorBuilder {
         sealed_sector_dir: S,
         staged_sector_dir: S,
         max_num_staged_sectors: u8,
-    ) -> Result<SectorBuilder> {
+        config: SectorBuilderConfig,
+    ) -> SectorBuilder<Uninitialized> {
+        SectorBuilder {
+            state: Uninitialized {
+                sector_class,
+                last_committed_sector_id,
+                metadata_dir: metadata_dir.into(),
+                prover_id,
+                sealed_sector_dir: sealed_sector_dir.into(),
+                staged_sector_dir: staged_sector_dir.into(),
+                max_num_staged_sectors,
+                config,
+            },
+        }
+    }
+
+    // Validates the configuration, opens the metadata store and the sector
+    // directories (removing the output of interrupted seals) and loads the
+    // sector builder's key.
+    pub fn initialize(self) -> Result<SectorBuilder<Initialized>> {
+        let Uninitialized {
+            sector_class,
+            last_committed_sector_id,
+            metadata_dir,
+            prover_id,
+            sealed_sector_dir,
+            staged_sector_dir,
+            max_num_staged_sectors,
+            config,
+        } = self.state;
+
+        let seal_trigger =
+            SealTrigger::new(config.seal_trigger_threshold, config.resume_add_threshold)?;
+
+        let seal_prover = SealProver::new(config.gpu_prover.clone(), config.gpu_fallback_policy)?;
+
+        let piece_ingestion_histogram =
+            Arc::new(Histogram::new(config.piece_ingestion_buckets_us.clone())?);
+
+        if let Some(ref metrics_sink) = config.metrics_sink {
+            metrics_sink.piece_ingestion_histogram(piece_ingestion_histogram.clone());
+        }
+
         let kv_store = Arc::new(WrappedKeyValueStore {
-            inner: Box::new(SledKvs::initialize(metadata_dir.into())?),
+            inner: Box::new(SledKvs::initialize(metadata_dir)?),
         });
 
         // Initialize a SectorStore and wrap it in an Arc so we can access it
@@ -67,11 +294,104 @@ impl SectorBuilder {
         let sector_store = Arc::new(WrappedSectorStore {
             inner: Box::new(new_sector_store(
                 sector_class,
-                sealed_sector_dir.into(),
-                staged_sector_dir.into(),
+                sealed_sector_dir,
+                staged_sector_dir,
             )),
         });
 
+        // Seals interrupted by a crash leave partial output behind, which no
+        // sealed sector refers to.
+        let num_partial_seals = cleanup_partial_seal_files(&sector_store)?;
+        if num_partial_seals > 0 {
+            info!(FCP_LOG, "removed partial seal files"; "count" => num_partial_seals);
+        }
+
+        // Writes which an unclean shutdown kept from reaching their staged
+        // sectors are replayed before anything else writes to them.
+        if let Some(ref wal_dir) = config.staged_sector_wal_dir {
+            if let Some((snapshot, _)) = load_snapshot_lazily(&kv_store, &prover_id)? {
+                let report = replay_staged_sector_wals(
+                    sector_store.inner.manager(),
+                    &snapshot.staged,
+                    wal_dir,
+                )?;
+
+                info!(FCP_LOG, "replayed staged sector write-ahead logs"; "replayed" => report.replayed, "skipped" => report.skipped, "corrupted" => report.corrupted);
+            }
+        }
+
+        let key = match config.key_path {
+            Some(ref key_path) => SectorBuilderKey::load_or_generate(key_path)?,
+            None => SectorBuilderKey::generate()?,
+        };
+
+        Ok(SectorBuilder {
+            state: Initialized {
+                kv_store,
+                sector_store,
+                last_committed_sector_id,
+                prover_id,
+                max_num_staged_sectors,
+                seal_trigger,
+                seal_prover,
+                piece_ingestion_histogram,
+                key,
+                config,
+            },
+        })
+    }
+}
+
+impl SectorBuilder<Initialized> {
+    // The public key of the key with which the sector builder will sign
+    // manifests, e.g. to register it before the sector builder is started.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.state.key.public_key
+    }
+
+    // Starts the main worker, the sealers and the background workers, after
+    // which the sector builder accepts pieces.
+    pub fn start(self) -> SectorBuilder<Active> {
+        let Initialized {
+            kv_store,
+            sector_store,
+            last_committed_sector_id,
+            prover_id,
+            max_num_staged_sectors,
+            seal_trigger,
+            seal_prover,
+            piece_ingestion_histogram,
+            key,
+            config,
+        } = self.state;
+
+        // Pieces read ahead of a client's request are shared between the
+        // sealers (which fill the buffer) and the main worker (which
+        // invalidates buffered pieces when they're written to).
+        let piece_read_buffer =
+            Arc::new(Mutex::new(PieceReadBuffer::new(config.max_prefetch_bytes)));
+
+        let io_scheduler: Arc<IoScheduler> = Default::default();
+        let sealing_tracker: Arc<SealingTracker> = Default::default();
+        let staged_capacity = Arc::new(StagedCapacity::new(config.max_staged_sectors));
+        let sector_locks: Arc<SectorLocks> = Default::default();
+
+        // If newly-sealed sectors are to be verified, unless the caller has
+        // supplied their own (e.g. hardware-backed) verifier, their proofs are
+        // verified on the CPU.
+        let seal_verifier = if config.verify_sealed_sectors {
+            Some(config.seal_verifier.clone().unwrap_or_else(|| {
+                let proofs_config = sector_store.inner.proofs_config();
+
+                Arc::new(CpuSealVerifier::new(
+                    proofs_config.porep_config(),
+                    proofs_config.post_config(),
+                ))
+            }))
+        } else {
+            None
+        };
+
         // Configure the main worker's rendezvous channel.
         let (main_tx, main_rx) = mpsc::sync_channel(0);
 
@@ -79,14 +399,98 @@ impl SectorBuilder {
         let (seal_tx, seal_workers) = {
             let (tx, rx) = mpsc::channel();
             let rx = Arc::new(Mutex::new(rx));
+            let seals_in_progress: Arc<SealsInProgress> = Default::default();
 
             let workers = (0..NUM_SEAL_WORKERS)
-                .map(|n| SealerWorker::start(n, rx.clone(), sector_store.clone(), prover_id))
+                .map(|n| {
+                    SealerWorker::start(
+                        n,
+                        rx.clone(),
+                        sector_store.clone(),
+                        piece_read_buffer.clone(),
+                        seal_verifier.clone(),
+                        seal_prover.clone(),
+                        seals_in_progress.clone(),
+                        io_scheduler.clone(),
+                        sealing_tracker.clone(),
+                        sector_locks.clone(),
+                        config.metrics_sink.clone(),
+                        prover_id,
+                        config.snapshot_merkle_trees,
+                        config.epoch_source.clone(),
+                    )
+                })
                 .collect();
 
             (tx, workers)
         };
 
+        // The health monitor checks in with the main worker like any other
+        // client would.
+        let health_monitor = config.alert_sink.clone().map(|alert_sink| {
+            let scheduler_tx = main_tx.clone();
+            let sector_store = sector_store.clone();
+
+            HealthMonitor::start(config.health_check_interval, alert_sink, move || {
+                check_health(&scheduler_tx, &sector_store)
+            })
+        });
+
+        // Near-deadline sectors are proven once per deadline (see
+        // PostScheduler), with the randomness of the epoch at which they were
+        // found. The main worker only hands over its metadata for the sectors;
+        // the proofs are generated on the PoSt scheduler's own thread, and
+        // then handed back to be cached. The proof deduplication index serves
+        // a later request for a proof only if it's made with the same
+        // randomness.
+        let post_scheduler = config.epoch_source.clone().map(|epoch_source| {
+            let approaching_tx = main_tx.clone();
+            let proven_tx = main_tx.clone();
+            let randomness_source = epoch_source.clone();
+            let warning_epochs = config.pre_commit_warning_epochs;
+            let post_config = sector_store.inner.proofs_config().post_config();
+
+            PostScheduler::start(
+                config.post_scheduling_interval,
+                epoch_source,
+                config.pre_commit_deadline_sink.clone(),
+                move |epoch| {
+                    let (tx, rx) = mpsc::sync_channel(0);
+
+                    approaching_tx
+                        .send(Request::GetSectorsApproachingPreCommitDeadline(
+                            epoch,
+                            warning_epochs,
+                            tx,
+                        ))
+                        .map_err(|_| format_err!("main worker hung up"))?;
+
+                    rx.recv().map_err(|_| format_err!("main worker hung up"))?
+                },
+                move |epoch, sectors| {
+                    let comm_rs = sectors.iter().map(|sector| sector.comm_r).collect();
+                    let randomness = randomness_source.randomness(epoch)?;
+
+                    let output = internal::generate_post(GeneratePoStDynamicSectorsCountInput {
+                        post_config,
+                        challenge_seed: randomness,
+                        input_parts: sectors
+                            .iter()
+                            .map(|sector| (Some(sector.sector_access.clone()), sector.comm_r))
+                            .collect(),
+                    })?;
+
+                    proven_tx
+                        .send(Request::HandleScheduledPoSt(
+                            comm_rs,
+                            randomness,
+                            Box::new(output),
+                        ))
+                        .map_err(|_| format_err!("main worker hung up"))
+                },
+            )
+        });
+
         // Configure main worker.
         let main_worker = Scheduler::start_with_metadata(
             main_rx,
@@ -94,17 +498,68 @@ impl SectorBuilder {
             seal_tx.clone(),
             kv_store.clone(),
             sector_store.clone(),
+            piece_read_buffer,
             last_committed_sector_id,
             max_num_staged_sectors,
             prover_id,
+            seal_trigger,
+            piece_ingestion_histogram.clone(),
+            io_scheduler.clone(),
+            sealing_tracker.clone(),
+            staged_capacity.clone(),
+            sector_locks.clone(),
+            key.public_key,
+            config,
         );
 
-        Ok(SectorBuilder {
-            scheduler_tx: main_tx,
-            scheduler: main_worker,
-            sealers_tx: seal_tx,
-            sealers: seal_workers,
-        })
+        SectorBuilder {
+            state: Active {
+                scheduler_tx: main_tx,
+                scheduler: main_worker,
+                sealers_tx: Mutex::new(seal_tx),
+                sealers: seal_workers,
+                sector_store,
+                prover_id,
+                health_monitor,
+                post_scheduler,
+                piece_ingestion_histogram,
+                io_scheduler,
+                sector_locks,
+                sealing_tracker,
+                staged_capacity,
+                key,
+            },
+        }
+    }
+}
+
+impl SectorBuilder<Active> {
+    // Initialize and return a SectorBuilder from metadata persisted to disk if
+    // it exists. Otherwise, initialize and return a fresh SectorBuilder. The
+    // metadata key is equal to the prover_id.
+    #[allow(clippy::too_many_arguments)]
+    pub fn init_from_metadata<S: Into<String>>(
+        sector_class: SectorClass,
+        last_committed_sector_id: SectorId,
+        metadata_dir: S,
+        prover_id: [u8; 31],
+        sealed_sector_dir: S,
+        staged_sector_dir: S,
+        max_num_staged_sectors: u8,
+        config: SectorBuilderConfig,
+    ) -> Result<SectorBuilder> {
+        let sector_builder = SectorBuilder::new(
+            sector_class,
+            last_committed_sector_id,
+            metadata_dir,
+            prover_id,
+            sealed_sector_dir,
+            staged_sector_dir,
+            max_num_staged_sectors,
+            config,
+        );
+
+        Ok(sector_builder.initialize()?.start())
     }
 
     // Stages user piece-bytes for sealing. Note that add_piece calls are
@@ -122,6 +577,52 @@ impl SectorBuilder {
         )
     }
 
+    // Stages user piece-bytes for sealing at most once per idempotency key,
+    // so that a caller may safely retry an add which may have failed. Until
+    // the key expires (see SectorBuilderConfig::idempotency_key_ttl), adding
+    // a piece with the same key returns the id of the sector to which it was
+    // first written.
+    pub fn add_piece_idempotent(
+        &self,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+        idempotency_key: [u8; 16],
+    ) -> Result<SectorId> {
+        log_unrecov(self.run_blocking(|tx| {
+            Request::AddPieceIdempotent(
+                piece_key,
+                piece_bytes_amount,
+                piece_path,
+                idempotency_key,
+                tx,
+            )
+        }))
+    }
+
+    // Blocks until a piece may be added without being refused for
+    // back-pressure (i.e. until there are fewer than max_staged_sectors staged
+    // sectors), producing a back-pressure error if that takes longer than
+    // timeout.
+    pub fn wait_for_capacity(&self, timeout: Duration) -> Result<()> {
+        self.state.staged_capacity.wait(timeout)
+    }
+
+    // Returns the ids of the sealed sectors whose pre-commit deadline is fewer
+    // than warning_epochs epochs after current_epoch (or has passed), most
+    // urgent first.
+    pub fn get_sectors_approaching_pre_commit_deadline(
+        &self,
+        current_epoch: u64,
+        warning_epochs: u64,
+    ) -> Result<Vec<SectorId>> {
+        let sectors = log_unrecov(self.run_blocking(|tx| {
+            Request::GetSectorsApproachingPreCommitDeadline(current_epoch, warning_epochs, tx)
+        }))?;
+
+        Ok(sectors.into_iter().map(|sector| sector.sector_id).collect())
+    }
+
     // Returns sealing status for the sector with specified id. If no sealed or
     // staged sector exists with the provided id, produce an error.
     pub fn get_seal_status(&self, sector_id: SectorId) -> Result<SealStatus> {
@@ -132,14 +633,189 @@ impl SectorBuilder {
     // bytes. Produces an error if this sector builder does not have a sealed
     // sector containing the referenced piece.
     pub fn read_piece_from_sealed_sector(&self, piece_key: String) -> Result<Vec<u8>> {
-        log_unrecov(self.run_blocking(|tx| Request::RetrievePiece(piece_key, tx)))
+        self.get_piece(piece_key, None)
+    }
+
+    // Like read_piece_from_sealed_sector, but for pieces whose access may be
+    // restricted. Produces an AccessDenied error if the piece's access is
+    // restricted and the provided token doesn't grant it.
+    pub fn get_piece(
+        &self,
+        piece_key: String,
+        token: Option<PieceCapabilityToken>,
+    ) -> Result<Vec<u8>> {
+        let started_at = Instant::now();
+
+        log_unrecov(
+            self.run_blocking(|tx| Request::RetrievePiece(piece_key, token, started_at, tx)),
+        )
+        .map(|(bytes, _)| bytes)
+    }
+
+    // Restricts reads of the referenced piece to callers presenting the
+    // provided token (see piece_access::generate_piece_token). Only the
+    // token's hash is persisted.
+    pub fn restrict_piece_access(
+        &self,
+        piece_key: String,
+        token: &PieceCapabilityToken,
+    ) -> Result<()> {
+        let access_token_hash = hash_piece_token(token);
+
+        log_unrecov(
+            self.run_blocking(|tx| Request::RestrictPieceAccess(piece_key, access_token_hash, tx)),
+        )
+    }
+
+    // Reads the referenced piece into memory in the background so that a
+    // subsequent call to read_piece_from_sealed_sector for the same piece
+    // returns without unsealing. Produces an error if this sector builder does
+    // not have a sealed sector containing the referenced piece, or if the
+    // piece's access is restricted and the provided token doesn't grant it.
+    pub fn prefetch_piece(
+        &self,
+        next_piece_key: String,
+        token: Option<PieceCapabilityToken>,
+    ) -> Result<()> {
+        log_unrecov(self.run_blocking(|tx| Request::PrefetchPiece(next_piece_key, token, tx)))
+    }
+
+    // Uploads the replica of a sealed sector to cold storage and records the
+    // resulting receipt with the sector, after which its local replica may be
+    // removed. Sectors which have already been archived aren't re-uploaded.
+    pub fn archive_sector(
+        &self,
+        sector_id: SectorId,
+        archive_backend: &ArchiveBackend,
+    ) -> Result<ArchiveReceipt> {
+        let sealed_sector = self
+            .get_sealed_sectors()?
+            .into_iter()
+            .find(|x| x.sector_id == sector_id)
+            .ok_or_else(|| err_unrecov(format!("no sealed sector with id {}", sector_id)))?;
+
+        if let Some(receipt) = sealed_sector.archive_receipt {
+            return Ok(receipt);
+        }
+
+        let receipt = log_unrecov(archive_sealed_sector(
+            &self.state.sector_store,
+            &sealed_sector,
+            archive_backend,
+        ))?;
+
+        log_unrecov(
+            self.run_blocking(|tx| Request::HandleArchiveReceipt(sector_id, receipt.clone(), tx)),
+        )?;
+
+        Ok(receipt)
+    }
+
+    // Unseals and returns the bytes of the referenced piece from the archived
+    // replica of the sector containing it. Produces an error if that sector
+    // hasn't been archived, if the piece has been redacted, or if the piece's
+    // access is restricted and the provided token doesn't grant it.
+    pub fn retrieve_archived_piece(
+        &self,
+        piece_key: String,
+        token: Option<PieceCapabilityToken>,
+        archive_backend: &ArchiveBackend,
+    ) -> Result<Vec<u8>> {
+        let sealed_sector = log_unrecov(
+            self.run_blocking(|tx| Request::GetReadablePieceSector(piece_key.clone(), token, tx)),
+        )?;
+
+        log_unrecov(retrieve_archived_piece(
+            &self.state.sector_store,
+            &sealed_sector,
+            &self.state.prover_id,
+            &piece_key,
+            archive_backend,
+            &self.state.io_scheduler,
+            &self.state.sector_locks,
+        ))
+    }
+
+    // Returns the ids of the sealed sectors which were sealed in the country
+    // with the provided (ISO 3166-1 alpha-2) code. Sectors sealed without a
+    // configured sealing location are never included.
+    pub fn get_sectors_by_region(&self, country_code: [u8; 2]) -> Result<Vec<SectorId>> {
+        log_unrecov(self.run_blocking(|tx| Request::GetSectorsByRegion(country_code, tx)))
+    }
+
+    // Pseudorandomly selects ceil(n * sample_fraction) of the n sealed sectors
+    // to be audited (see audit::generate_audit_challenge). The same seed
+    // selects the same sectors.
+    pub fn sample_sectors_for_audit(
+        &self,
+        rng_seed: [u8; 32],
+        sample_fraction: f64,
+    ) -> Result<Vec<SectorId>> {
+        log_unrecov(
+            self.run_blocking(|tx| Request::SampleSectorsForAudit(rng_seed, sample_fraction, tx)),
+        )
+    }
+
+    // Reserves count consecutive sector ids (e.g. so that they can be
+    // registered on-chain) without provisioning any sectors, returning the
+    // first and last of them. Reserved ids are used by newly-provisioned
+    // staged sectors before any others.
+    pub fn reserve_sector_id_range(&self, count: u32) -> Result<(SectorId, SectorId)> {
+        log_unrecov(self.run_blocking(|tx| Request::ReserveSectorIdRange(count, tx)))
+    }
+
+    // Deletes the provided sectors and their sector accesses. Sectors with an
+    // active deal are skipped unless force is set, and even then are only
+    // deleted if an audit log (see SectorBuilderConfig::audit_log_path) is
+    // configured to record it.
+    pub fn delete_sectors_batch(
+        &self,
+        sector_ids: &[SectorId],
+        force: bool,
+    ) -> Result<BatchDeleteResult> {
+        log_unrecov(
+            self.run_blocking(|tx| Request::DeleteSectorsBatch(Vec::from(sector_ids), force, tx)),
+        )
     }
 
-    // For demo purposes. Schedules sealing of all staged sectors.
+    // For demo purposes. Schedules sealing of all staged sectors, producing an
+    // error (e.g. SectorTooEmpty) if any of them was skipped.
     pub fn seal_all_staged_sectors(&self) -> Result<()> {
         log_unrecov(self.run_blocking(Request::SealAllStagedSectors))
     }
 
+    // Schedules sealing of a staged sector with the provided version of the
+    // sealing API, regardless of the configured default.
+    pub fn seal_with_api_version(
+        &self,
+        sector_id: SectorId,
+        api_version: ApiVersion,
+    ) -> Result<()> {
+        log_unrecov(
+            self.run_blocking(|tx| Request::SealSector(sector_id, Some(api_version), false, tx)),
+        )
+    }
+
+    // Schedules sealing of a staged sector, provided it's at least as full as
+    // the configured min_seal_fill_ratio.
+    pub fn seal_sector(&self, sector_id: SectorId) -> Result<()> {
+        self.seal_sector_force(sector_id, false)
+    }
+
+    // Schedules sealing of a staged sector which, if force_seal_even_if_empty
+    // is set, is sealed however full it is.
+    pub fn seal_sector_force(
+        &self,
+        sector_id: SectorId,
+        force_seal_even_if_empty: bool,
+    ) -> Result<()> {
+        log_unrecov(
+            self.run_blocking(|tx| {
+                Request::SealSector(sector_id, None, force_seal_even_if_empty, tx)
+            }),
+        )
+    }
+
     // Returns all sealed sector metadata.
     pub fn get_sealed_sectors(&self) -> Result<Vec<SealedSectorMetadata>> {
         log_unrecov(self.run_blocking(Request::GetSealedSectors))
@@ -150,7 +826,59 @@ impl SectorBuilder {
         log_unrecov(self.run_blocking(Request::GetStagedSectors))
     }
 
-    // Generates a proof-of-spacetime. Blocks the calling thread.
+    // Returns the metadata of every piece, be it in a sealed or a staged
+    // sector.
+    pub fn list_pieces(&self) -> Result<Vec<PieceMetadata>> {
+        log_unrecov(self.run_blocking(Request::ListPieces))
+    }
+
+    // Returns a Mermaid.js state diagram of every sector, with its seal
+    // status, fill ratio and number of pieces. For debugging.
+    pub fn render_live_state_diagram(&self) -> Result<String> {
+        log_unrecov(self.run_blocking(Request::RenderStateDiagram))
+    }
+
+    // Returns a channel over which a new state diagram (as produced by
+    // render_live_state_diagram) is sent each time the state changes.
+    pub fn subscribe_state_diagram_updates(&self) -> Result<mpsc::Receiver<String>> {
+        log_unrecov(self.run_blocking(Request::SubscribeStateDiagramUpdates))
+    }
+
+    // Returns the distribution of the time taken to write each piece added
+    // since the histogram was last reset, in microseconds.
+    pub fn get_piece_ingestion_histogram(&self) -> &Histogram {
+        &self.state.piece_ingestion_histogram
+    }
+
+    pub fn reset_histogram(&self) {
+        self.state.piece_ingestion_histogram.reset();
+    }
+
+    // The public key of the key with which the sector builder signs manifests.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.state.key.public_key
+    }
+
+    // Signs the (serialized) manifest with the sector builder's key. See
+    // signing::verify_manifest.
+    pub fn sign_manifest(&self, manifest: &[u8]) -> Result<[u8; 64]> {
+        sign_manifest(&self.state.key, manifest)
+    }
+
+    // Produces a manifest of the sealed pieces matching the filter, signed with
+    // the sector builder's key (see public_key), for handing off storage
+    // responsibilities to another provider.
+    pub fn generate_piece_manifest(&self, filter: PieceManifestFilter) -> Result<PieceManifest> {
+        let mut manifest =
+            log_unrecov(self.run_blocking(|tx| Request::GeneratePieceManifest(filter, tx)))?;
+
+        manifest.sign(&self.state.key)?;
+
+        Ok(manifest)
+    }
+
+    // Generates a proof-of-spacetime. Blocks the calling thread. Produces an
+    // error if no sector has been sealed.
     pub fn generate_post(
         &self,
         comm_rs: &[[u8; 32]],
@@ -165,7 +893,8 @@ impl SectorBuilder {
     fn run_blocking<T, F: FnOnce(mpsc::SyncSender<T>) -> Request>(&self, with_sender: F) -> T {
         let (tx, rx) = mpsc::sync_channel(0);
 
-        self.scheduler_tx
+        self.state
+            .scheduler_tx
             .clone()
             .send(with_sender(tx))
             .expects(FATAL_NOSEND_TASK);
@@ -174,17 +903,26 @@ impl SectorBuilder {
     }
 }
 
-impl Drop for SectorBuilder {
+impl Drop for Active {
     fn drop(&mut self) {
+        // The health monitor and PoSt scheduler go first, as they rely on the
+        // main worker.
+        self.health_monitor.take();
+        self.post_scheduler.take();
+
         // Shut down main worker and sealers, too.
         let _ = self
             .scheduler_tx
             .send(Request::Shutdown)
             .map_err(|err| println!("err sending Shutdown to scheduler: {:?}", err));
 
-        for _ in &mut self.sealers {
-            let _ = self
-                .sealers_tx
+        let sealers_tx = self
+            .sealers_tx
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner());
+
+        for _ in &self.sealers {
+            let _ = sealers_tx
                 .send(SealerInput::Shutdown)
                 .map_err(|err| println!("err sending Shutdown to sealer: {:?}", err));
         }
@@ -222,6 +960,40 @@ pub struct WrappedKeyValueStore<T: KeyValueStore> {
 unsafe impl<T: KeyValueStore> Sync for WrappedKeyValueStore<T> {}
 unsafe impl<T: KeyValueStore> Send for WrappedKeyValueStore<T> {}
 
+// Has the main worker compare its metadata to the sectors on disk, then
+// checks the CRC32s of the staged sectors it hands back on the caller's
+// thread, so that the main worker isn't held up reading their files.
+fn check_health(
+    scheduler_tx: &mpsc::SyncSender<Request>,
+    sector_store: &Arc<WrappedSectorStore>,
+) -> Result<Vec<Discrepancy>> {
+    let (tx, rx) = mpsc::sync_channel(0);
+
+    scheduler_tx
+        .send(Request::CheckHealth(tx))
+        .map_err(|_| format_err!("main worker hung up"))?;
+
+    let (mut discrepancies, staged_sectors) =
+        rx.recv().map_err(|_| format_err!("main worker hung up"))?;
+
+    discrepancies.extend(check_staged_sector_crc32s(
+        sector_store,
+        &staged_sectors,
+        |sector_ids| {
+            let (tx, rx) = mpsc::sync_channel(0);
+
+            scheduler_tx
+                .send(Request::GetLastCrc32s(sector_ids, tx))
+                .map_err(|_| format_err!("main worker hung up"))?;
+
+            rx.recv().map_err(|_| format_err!("main worker hung up"))
+        },
+    )?);
+    discrepancies.sort();
+
+    Ok(discrepancies)
+}
+
 fn log_unrecov<T>(result: Result<T>) -> Result<T> {
     if let Err(err) = &result {
         if let Some(SectorBuilderErr::Unrecoverable(err, backtrace)) = err.downcast_ref() {
@@ -232,3 +1004,318 @@ fn log_unrecov<T>(result: Result<T>) -> Result<T> {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::test_utils::{sector_builder, subdir, LARGE_CLASS};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::Barrier;
+    use std::thread;
+
+    const NUM_ADDERS: usize = 16;
+    const PIECES_PER_ADDER: usize = 64;
+    const NUM_LISTERS: usize = 4;
+
+    #[test]
+    fn test_concurrent_add_and_list() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // The pieces all fit in one live sector, so none is sealed.
+        let sector_builder = Arc::new(sector_builder(dir.path(), Default::default()));
+
+        let pieces_dir = subdir(dir.path(), "pieces");
+        let barrier = Arc::new(Barrier::new(NUM_ADDERS + NUM_LISTERS));
+        let adding = Arc::new(Mutex::new(NUM_ADDERS));
+
+        let adders: Vec<_> = (0..NUM_ADDERS)
+            .map(|adder| {
+                let sector_builder = sector_builder.clone();
+                let barrier = barrier.clone();
+                let adding = adding.clone();
+                let pieces_dir = pieces_dir.clone();
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    let mut added = HashMap::new();
+
+                    for i in 0..PIECES_PER_ADDER {
+                        let piece_key = format!("piece-{}-{}", adder, i);
+                        let num_bytes = (adder * PIECES_PER_ADDER + i) as u64 % 100 + 1;
+
+                        let piece_path = format!("{}/{}", pieces_dir, piece_key);
+                        fs::write(&piece_path, vec![adder as u8; num_bytes as usize]).unwrap();
+
+                        sector_builder
+                            .add_piece(piece_key.clone(), num_bytes, piece_path)
+                            .unwrap();
+
+                        added.insert(piece_key, num_bytes);
+                    }
+
+                    *adding.lock().unwrap() -= 1;
+
+                    added
+                })
+            })
+            .collect();
+
+        // Listings taken while pieces are being added hold only pieces which
+        // have been added, once each.
+        let listers: Vec<_> = (0..NUM_LISTERS)
+            .map(|_| {
+                let sector_builder = sector_builder.clone();
+                let barrier = barrier.clone();
+                let adding = adding.clone();
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    while *adding.lock().unwrap() > 0 {
+                        let pieces = sector_builder.list_pieces().unwrap();
+
+                        let mut piece_keys: Vec<&String> =
+                            pieces.iter().map(|piece| &piece.piece_key).collect();
+                        piece_keys.sort();
+                        piece_keys.dedup();
+
+                        assert_eq!(pieces.len(), piece_keys.len());
+                        assert!(pieces.iter().all(|piece| u64::from(piece.num_bytes) > 0));
+                    }
+                })
+            })
+            .collect();
+
+        let mut added = HashMap::new();
+        for adder in adders {
+            added.extend(adder.join().unwrap());
+        }
+        for lister in listers {
+            lister.join().unwrap();
+        }
+
+        let pieces = sector_builder.list_pieces().unwrap();
+        let listed: HashMap<String, u64> = pieces
+            .iter()
+            .map(|piece| (piece.piece_key.clone(), u64::from(piece.num_bytes)))
+            .collect();
+
+        assert_eq!(NUM_ADDERS * PIECES_PER_ADDER, added.len());
+        assert_eq!(NUM_ADDERS * PIECES_PER_ADDER, pieces.len());
+        assert_eq!(pieces.len(), listed.len(), "piece listed more than once");
+        assert!(pieces.iter().all(|piece| u64::from(piece.num_bytes) > 0));
+        assert_eq!(added, listed);
+    }
+
+    #[test]
+    fn test_idempotent_add_writes_once_per_key() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let start = |idempotency_key_ttl| {
+            let dir = tempfile::tempdir_in(&dir).unwrap();
+            let sector_builder = sector_builder(
+                dir.path(),
+                SectorBuilderConfig {
+                    idempotency_key_ttl,
+                    ..Default::default()
+                },
+            );
+
+            (dir, sector_builder)
+        };
+
+        let piece_path = dir.path().join("piece").to_string_lossy().into_owned();
+        fs::write(&piece_path, vec![7; 100]).unwrap();
+
+        let add = |sector_builder: &SectorBuilder, key| {
+            sector_builder
+                .add_piece_idempotent("piece".to_string(), 100, piece_path.clone(), key)
+                .unwrap()
+        };
+
+        let (_dir, sector_builder) = start(Duration::from_secs(60));
+
+        // retries within the ttl are answered without writing
+        let sector_id = add(&sector_builder, [1; 16]);
+        for _ in 0..3 {
+            assert_eq!(sector_id, add(&sector_builder, [1; 16]));
+        }
+        assert_eq!(1, sector_builder.list_pieces().unwrap().len());
+
+        // a different key is another piece
+        add(&sector_builder, [2; 16]);
+        assert_eq!(2, sector_builder.list_pieces().unwrap().len());
+
+        // once the key has expired, the piece is added again
+        let (_dir, sector_builder) = start(Duration::from_secs(0));
+
+        add(&sector_builder, [1; 16]);
+        add(&sector_builder, [1; 16]);
+        assert_eq!(2, sector_builder.list_pieces().unwrap().len());
+    }
+
+    #[test]
+    fn test_checks_crc32s_of_added_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let sector_builder = sector_builder(dir.path(), Default::default());
+
+        let piece_path = dir.path().join("piece");
+        fs::write(&piece_path, vec![9; 100]).unwrap();
+
+        sector_builder
+            .add_piece(
+                "a".to_string(),
+                100,
+                piece_path.to_string_lossy().into_owned(),
+            )
+            .unwrap();
+
+        let check = || {
+            check_health(
+                &sector_builder.state.scheduler_tx,
+                &sector_builder.state.sector_store,
+            )
+            .unwrap()
+        };
+
+        // the sector's CRC32 was recorded as the piece was written
+        let staged = sector_builder.get_staged_sectors().unwrap()[0].clone();
+        assert!(staged.last_crc32.is_some());
+        assert!(check().is_empty());
+
+        // flip a byte of the piece
+        let mut bytes = fs::read(&staged.sector_access).unwrap();
+        let n = bytes.len() - 50;
+        bytes[n] ^= 0xff;
+        fs::write(&staged.sector_access, bytes).unwrap();
+
+        assert_eq!(
+            vec![Discrepancy::CorruptedStagedPiece(staged.sector_id)],
+            check()
+        );
+    }
+
+    #[test]
+    fn test_answers_requests_while_a_piece_write_waits_for_io() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sector_builder = Arc::new(sector_builder(dir.path(), Default::default()));
+
+        let piece_path = dir.path().join("a").to_string_lossy().into_owned();
+        fs::write(&piece_path, vec![1; 10]).unwrap();
+
+        // No piece can be written while the test holds the disk.
+        let mut flow: io_scheduler::IoFlow = Default::default();
+        let turn =
+            sector_builder
+                .state
+                .io_scheduler
+                .acquire(&mut flow, io_scheduler::IoClass::Seal, 1);
+
+        let adder = {
+            let sector_builder = sector_builder.clone();
+            thread::spawn(move || sector_builder.add_piece("a".to_string(), 10, piece_path))
+        };
+
+        // The piece's sector is provisioned before the piece is written.
+        while sector_builder.get_staged_sectors().unwrap().is_empty() {
+            thread::yield_now();
+        }
+
+        assert!(sector_builder.list_pieces().unwrap().is_empty());
+
+        drop(turn);
+        let sector_id = adder.join().unwrap().unwrap();
+
+        let pieces = sector_builder.list_pieces().unwrap();
+        assert_eq!(1, pieces.len());
+        assert_eq!(
+            SealStatus::Pending,
+            sector_builder.get_seal_status(sector_id).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_failed_seal_releases_staged_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sector_builder = sector_builder(
+            dir.path(),
+            SectorBuilderConfig {
+                max_staged_sectors: 1,
+                ..Default::default()
+            },
+        );
+
+        let piece_path = dir.path().join("a").to_string_lossy().into_owned();
+        fs::write(&piece_path, vec![1; 10]).unwrap();
+
+        let add = |piece_key: &str| {
+            sector_builder.add_piece(piece_key.to_string(), 10, piece_path.clone())
+        };
+
+        let sector_id = add("a").unwrap();
+        match add("b").unwrap_err().downcast_ref() {
+            Some(SectorBuilderErr::BackPressure { .. }) => (),
+            err => panic!("expected back-pressure, got {:?}", err),
+        }
+        assert!(sector_builder
+            .wait_for_capacity(Duration::from_millis(10))
+            .is_err());
+
+        // This build can't seal with V2, so the sealer reports a failure.
+        sector_builder
+            .seal_with_api_version(sector_id, ApiVersion::V2)
+            .unwrap();
+
+        sector_builder
+            .wait_for_capacity(Duration::from_secs(60))
+            .unwrap();
+        match sector_builder.get_seal_status(sector_id).unwrap() {
+            SealStatus::Failed(_) => (),
+            status => panic!("expected seal to have failed, got {:?}", status),
+        }
+
+        // The failed sector stays staged, but no longer holds up new pieces.
+        assert_ne!(sector_id, add("b").unwrap());
+        assert_eq!(2, sector_builder.get_staged_sectors().unwrap().len());
+    }
+
+    #[test]
+    fn test_starts_in_stages() {
+        let dir = tempfile::tempdir().unwrap();
+        let uninitialized = SectorBuilder::new(
+            LARGE_CLASS,
+            0,
+            subdir(dir.path(), "metadata"),
+            [0; 31],
+            subdir(dir.path(), "sealed"),
+            subdir(dir.path(), "staged"),
+            2,
+            Default::default(),
+        );
+
+        // the key is loaded before any worker is started
+        let initialized = uninitialized.initialize().unwrap();
+        let public_key = initialized.public_key();
+
+        let sector_builder = initialized.start();
+        assert_eq!(public_key, sector_builder.public_key());
+
+        // there's nothing to prove yet
+        assert!(sector_builder.generate_post(&[[0; 32]], &[0; 32]).is_err());
+
+        let piece_path = format!("{}/a", subdir(dir.path(), "pieces"));
+        fs::write(&piece_path, vec![1; 10]).unwrap();
+
+        let sector_id = sector_builder
+            .add_piece("a".to_string(), 10, piece_path)
+            .unwrap();
+        assert_eq!(
+            SealStatus::Pending,
+            sector_builder.get_seal_status(sector_id).unwrap()
+        );
+    }
+}
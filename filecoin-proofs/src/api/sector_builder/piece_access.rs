@@ -0,0 +1,171 @@
+use crate::api::sector_builder::errors::err_access_denied;
+use crate::api::sector_builder::metadata::PieceMetadata;
+use crate::error::Result;
+use blake2b_simd::{Params, State as Blake2b, KEYBYTES};
+
+// Presented to the sector builder to read a piece whose access has been
+// restricted. Only the hash of a piece's token is stored with the piece.
+pub type PieceCapabilityToken = [u8; 32];
+
+// Derives the capability token for the referenced piece from a secret known
+// only to the deployment which issues tokens: the 32-byte BLAKE2b of the
+// piece key, keyed by the secret (or, if it's longer than a BLAKE2b key may
+// be, by its hash).
+pub fn generate_piece_token(piece_key: &str, secret: &[u8]) -> PieceCapabilityToken {
+    let hashed_secret;
+    let key = if secret.len() > KEYBYTES {
+        hashed_secret = Blake2b::new().update(secret).finalize();
+        hashed_secret.as_bytes()
+    } else {
+        secret
+    };
+
+    let mut token = [0; 32];
+    token.copy_from_slice(
+        Params::new()
+            .hash_length(32)
+            .key(key)
+            .to_state()
+            .update(piece_key.as_bytes())
+            .finalize()
+            .as_bytes(),
+    );
+    token
+}
+
+// The hash of a token, as stored in PieceMetadata::access_token_hash.
+pub fn hash_piece_token(token: &PieceCapabilityToken) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Blake2b::new().update(token).finalize().as_bytes()[..32]);
+    hash
+}
+
+// Checks that the presented token (if any) grants access to the piece. Pieces
+// without a token hash may be read by anyone.
+pub fn check_piece_access(
+    piece: &PieceMetadata,
+    token: Option<&PieceCapabilityToken>,
+) -> Result<()> {
+    let expected = match piece.access_token_hash {
+        Some(ref expected) => expected,
+        None => return Ok(()),
+    };
+
+    let granted = token.map_or(false, |token| {
+        // compare every byte, so that the time taken doesn't reveal how much
+        // of the hash matched
+        hash_piece_token(token)
+            .iter()
+            .zip(expected.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    });
+
+    if granted {
+        Ok(())
+    } else {
+        Err(err_access_denied(piece.piece_key.clone()).into())
+    }
+}
+
+// Checks that the presented tokens grant access to every piece (e.g. of a
+// sector whose replica is to be exported), each restricted piece needing one
+// of its own.
+pub fn check_pieces_access(
+    pieces: &[PieceMetadata],
+    tokens: &[PieceCapabilityToken],
+) -> Result<()> {
+    for piece in pieces {
+        let granted = piece.access_token_hash.is_none()
+            || tokens
+                .iter()
+                .any(|token| check_piece_access(piece, Some(token)).is_ok());
+
+        if !granted {
+            return Err(err_access_denied(piece.piece_key.clone()).into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restricted_piece(token: &PieceCapabilityToken) -> PieceMetadata {
+        PieceMetadata {
+            piece_key: "x".to_string(),
+            access_token_hash: Some(hash_piece_token(token)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_correct_token_grants_access() {
+        let token = generate_piece_token("x", b"secret");
+        let piece = restricted_piece(&token);
+
+        assert!(check_piece_access(&piece, Some(&token)).is_ok());
+    }
+
+    #[test]
+    fn test_incorrect_token_is_rejected() {
+        let piece = restricted_piece(&generate_piece_token("x", b"secret"));
+
+        let wrong_secret = generate_piece_token("x", b"other secret");
+        let wrong_piece = generate_piece_token("y", b"secret");
+
+        assert!(check_piece_access(&piece, Some(&wrong_secret)).is_err());
+        assert!(check_piece_access(&piece, Some(&wrong_piece)).is_err());
+        assert!(check_piece_access(&piece, None).is_err());
+    }
+
+    #[test]
+    fn test_unrestricted_piece_needs_no_token() {
+        let piece = PieceMetadata {
+            piece_key: "x".to_string(),
+            ..Default::default()
+        };
+
+        assert!(check_piece_access(&piece, None).is_ok());
+        assert!(check_piece_access(&piece, Some(&[0; 32])).is_ok());
+    }
+
+    #[test]
+    fn test_every_restricted_piece_needs_a_token() {
+        let x = generate_piece_token("x", b"secret");
+        let y = generate_piece_token("y", b"secret");
+
+        let pieces = vec![
+            restricted_piece(&x),
+            PieceMetadata {
+                piece_key: "y".to_string(),
+                access_token_hash: Some(hash_piece_token(&y)),
+                ..Default::default()
+            },
+            PieceMetadata {
+                piece_key: "z".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        assert!(check_pieces_access(&pieces, &[y, x]).is_ok());
+        assert!(check_pieces_access(&pieces, &[x]).is_err());
+        assert!(check_pieces_access(&pieces, &[]).is_err());
+        assert!(check_pieces_access(&pieces[2..], &[]).is_ok());
+    }
+
+    #[test]
+    fn test_long_secrets_are_hashed() {
+        let long_secret = vec![7; 200];
+
+        assert_eq!(
+            generate_piece_token("x", &long_secret),
+            generate_piece_token(
+                "x",
+                Blake2b::new().update(&long_secret).finalize().as_bytes()
+            )
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::api::sector_builder::helpers::add_piece::write_piece_bytes;
+use crate::api::sector_builder::helpers::check_staged_sector_crc32::sector_crc32;
+use crate::api::sector_builder::io_scheduler::IoScheduler;
+use crate::api::sector_builder::metrics::Histogram;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::wal::{sector_wal_path, WalWriter};
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error::ExpectWithBacktrace;
+use crate::error::Result;
+use crate::FCP_LOG;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use slog::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+const FATAL_RCVTSK: &str = "error receiving piece write";
+const FATAL_SNDTSK: &str = "error sending piece write";
+const FATAL_SNDRES: &str = "error sending piece write result";
+
+pub enum PieceWriterInput {
+    Write(PieceWrite),
+    DiscardLog(SectorId),
+    Shutdown,
+}
+
+// Pieces to be written, in order, to the end of a staged sector which has
+// room for them.
+#[derive(Clone, Debug)]
+pub struct PieceWrite {
+    pub sector_id: SectorId,
+    pub sector_access: String,
+    // each piece's key and number of bytes, and the path it's read from
+    pub pieces: Vec<(String, UnpaddedBytesAmount, String)>,
+    // whether a copy of each piece's bytes is kept, as they're written, for
+    // the precomputation of the sector's commD
+    pub retain_bytes: bool,
+}
+
+// The checksums of the pieces which were written (and their bytes, if they
+// were retained), and the CRC32 of their sector afterwards.
+#[derive(Debug)]
+pub struct WrittenPieces {
+    pub checksums: Vec<[u8; 32]>,
+    pub piece_bytes: Vec<Vec<u8>>,
+    pub last_crc32: Option<(u32, SystemTime)>,
+}
+
+// Writes pieces to staged sectors, one write at a time. Piece I/O is weighted
+// against sealing by the I/O scheduler, so a write may wait on the disk for
+// some time; it does so here rather than on the scheduler's thread, to which
+// the result is handed back (see Request::HandlePieceWritten) so that the
+// pieces may be recorded in the sector's metadata. When given a directory for
+// them, each sector's pieces are logged to its write-ahead log (see wal) as
+// they're written, and the log is discarded once the sector has been sealed.
+pub struct PieceWriter {
+    tx: mpsc::Sender<PieceWriterInput>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PieceWriter {
+    pub fn start(
+        scheduler_input_tx: mpsc::SyncSender<Request>,
+        sector_store: Arc<WrappedSectorStore>,
+        piece_ingestion_histogram: Arc<Histogram>,
+        io_scheduler: Arc<IoScheduler>,
+        sector_locks: Arc<SectorLocks>,
+        wal_dir: Option<PathBuf>,
+    ) -> PieceWriter {
+        let (tx, rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || loop {
+            match rx.recv().expects(FATAL_RCVTSK) {
+                PieceWriterInput::Write(write) => {
+                    let result = write_pieces(
+                        &sector_store,
+                        &write,
+                        &piece_ingestion_histogram,
+                        &io_scheduler,
+                        &sector_locks,
+                        wal_dir.as_ref().map(PathBuf::as_path),
+                    );
+
+                    // The scheduler waits for the result before shutting
+                    // down, so it's still receiving.
+                    scheduler_input_tx
+                        .send(Request::HandlePieceWritten(Box::new(result)))
+                        .expects(FATAL_SNDRES);
+                }
+                PieceWriterInput::DiscardLog(sector_id) => {
+                    if let Some(ref wal_dir) = wal_dir {
+                        let wal_path = sector_wal_path(wal_dir, sector_id);
+
+                        if let Err(err) = fs::remove_file(&wal_path) {
+                            if err.kind() != io::ErrorKind::NotFound {
+                                let err = format!("{}", err);
+                                warn!(FCP_LOG, "could not remove staged sector WAL"; "sector_id" => sector_id, "error" => err);
+                            }
+                        }
+                    }
+                }
+                PieceWriterInput::Shutdown => break,
+            }
+        });
+
+        PieceWriter {
+            tx,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn write(&self, write: PieceWrite) {
+        self.tx
+            .send(PieceWriterInput::Write(write))
+            .expects(FATAL_SNDTSK);
+    }
+
+    // Removes the sector's write-ahead log (if any), e.g. once it's sealed.
+    pub fn discard_log(&self, sector_id: SectorId) {
+        self.tx
+            .send(PieceWriterInput::DiscardLog(sector_id))
+            .expects(FATAL_SNDTSK);
+    }
+}
+
+impl Drop for PieceWriter {
+    fn drop(&mut self) {
+        let _ = self
+            .tx
+            .send(PieceWriterInput::Shutdown)
+            .map_err(|err| println!("err sending Shutdown to piece writer: {:?}", err));
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread
+                .join()
+                .map_err(|err| println!("err joining piece writer thread: {:?}", err));
+        }
+    }
+}
+
+fn write_pieces(
+    sector_store: &Arc<WrappedSectorStore>,
+    write: &PieceWrite,
+    piece_ingestion_histogram: &Histogram,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+    wal_dir: Option<&Path>,
+) -> Result<WrittenPieces> {
+    let mut wal = match wal_dir {
+        Some(wal_dir) => {
+            let sector_max = sector_store
+                .inner
+                .sector_config()
+                .max_unsealed_bytes_per_sector();
+
+            fs::create_dir_all(wal_dir)?;
+
+            Some(WalWriter::open(
+                &sector_wal_path(wal_dir, write.sector_id),
+                u64::from(sector_max),
+            )?)
+        }
+        None => None,
+    };
+
+    let mut checksums = Vec::with_capacity(write.pieces.len());
+    let mut piece_bytes = Vec::with_capacity(write.pieces.len());
+
+    for (_, piece_bytes_len, piece_path) in &write.pieces {
+        let mut bytes = Vec::new();
+
+        let checksum = {
+            let mut discarded = io::sink();
+            let sink: &mut io::Write = if write.retain_bytes {
+                &mut bytes
+            } else {
+                &mut discarded
+            };
+
+            write_piece_bytes(
+                sector_store,
+                write.sector_id,
+                &write.sector_access,
+                *piece_bytes_len,
+                piece_path,
+                piece_ingestion_histogram,
+                io_scheduler,
+                sector_locks,
+                wal.as_mut(),
+                sink,
+            )?
+        };
+
+        checksums.push(checksum);
+        piece_bytes.push(bytes);
+    }
+
+    Ok(WrittenPieces {
+        checksums,
+        piece_bytes,
+        last_crc32: sector_crc32(sector_store, write.sector_id, &write.sector_access),
+    })
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::api::sector_builder::metadata::SealedSectorMetadata;
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use crate::FCP_LOG;
+use slog::*;
+
+// The chain, as seen by the sector builder.
+pub trait EpochSource: Send + Sync {
+    fn current_epoch(&self) -> Result<u64>;
+
+    // The chain randomness at the provided epoch, from which PoSt challenges
+    // are derived.
+    fn randomness(&self, epoch: u64) -> Result<[u8; 32]>;
+}
+
+// Raised once for each sealed sector whose pre-commit deadline is near.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreCommitDeadlineWarning {
+    pub sector_id: SectorId,
+    pub pre_commit_deadline: u64,
+    pub current_epoch: u64,
+}
+
+// Delivers pre-commit deadline warnings to an operator.
+pub trait PreCommitDeadlineSink: Send + Sync {
+    fn send_warning(&self, warning: PreCommitDeadlineWarning) -> Result<()>;
+}
+
+// Writes warnings to the log.
+pub struct LogPreCommitDeadlineSink;
+
+impl PreCommitDeadlineSink for LogPreCommitDeadlineSink {
+    fn send_warning(&self, warning: PreCommitDeadlineWarning) -> Result<()> {
+        warn!(FCP_LOG, "sector approaching pre-commit deadline"; "sector_id" => warning.sector_id, "pre_commit_deadline" => warning.pre_commit_deadline, "current_epoch" => warning.current_epoch);
+
+        Ok(())
+    }
+}
+
+// Periodically finds the sealed sectors approaching their pre-commit
+// deadline, warns about each (once), and has proofs generated for them ahead
+// of any other sectors. Each sector is proven once per deadline: a sector
+// which has been proven isn't proven again while it's approaching the same
+// deadline.
+pub struct PostScheduler {
+    shutdown_tx: mpsc::SyncSender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PostScheduler {
+    // Every interval, approaching is called with the current epoch to find
+    // the sectors approaching their deadline (most urgent first), which are
+    // then passed to prove along with the epoch.
+    pub fn start<F, G>(
+        interval: Duration,
+        epoch_source: Arc<EpochSource>,
+        sink: Arc<PreCommitDeadlineSink>,
+        mut approaching: F,
+        mut prove: G,
+    ) -> PostScheduler
+    where
+        F: FnMut(u64) -> Result<Vec<SealedSectorMetadata>> + Send + 'static,
+        G: FnMut(u64, &[SealedSectorMetadata]) -> Result<()> + Send + 'static,
+    {
+        let (shutdown_tx, shutdown_rx) = mpsc::sync_channel(1);
+
+        let thread = thread::spawn(move || {
+            let mut warned: HashSet<SectorId> = Default::default();
+            let mut proven: HashMap<SectorId, Option<u64>> = Default::default();
+
+            while let Err(mpsc::RecvTimeoutError::Timeout) = shutdown_rx.recv_timeout(interval) {
+                if let Err(err) = schedule_once(
+                    &*epoch_source,
+                    &*sink,
+                    &mut warned,
+                    &mut proven,
+                    &mut approaching,
+                    &mut prove,
+                ) {
+                    let err = format!("{}", err);
+                    warn!(FCP_LOG, "PoSt scheduling failed"; "error" => err);
+                }
+            }
+        });
+
+        PostScheduler {
+            shutdown_tx,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for PostScheduler {
+    fn drop(&mut self) {
+        let _ = self.shutdown_tx.send(());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread
+                .join()
+                .map_err(|err| println!("err joining PoSt scheduler thread: {:?}", err));
+        }
+    }
+}
+
+// Warns about the approaching sectors which weren't approaching when last
+// checked, then proves those which haven't been proven for their current
+// deadline (recording the deadline for which each was proven).
+fn schedule_once<F, G>(
+    epoch_source: &EpochSource,
+    sink: &PreCommitDeadlineSink,
+    warned: &mut HashSet<SectorId>,
+    proven: &mut HashMap<SectorId, Option<u64>>,
+    approaching: &mut F,
+    prove: &mut G,
+) -> Result<()>
+where
+    F: FnMut(u64) -> Result<Vec<SealedSectorMetadata>>,
+    G: FnMut(u64, &[SealedSectorMetadata]) -> Result<()>,
+{
+    let current_epoch = epoch_source.current_epoch()?;
+    let sectors = approaching(current_epoch)?;
+
+    // Sectors which are no longer approaching (e.g. they were deleted) are
+    // forgotten.
+    let mut still_warned = HashSet::new();
+
+    for sector in &sectors {
+        still_warned.insert(sector.sector_id);
+
+        if warned.contains(&sector.sector_id) {
+            continue;
+        }
+
+        let warning = PreCommitDeadlineWarning {
+            sector_id: sector.sector_id,
+            pre_commit_deadline: sector.pre_commit_deadline.unwrap_or(current_epoch),
+            current_epoch,
+        };
+
+        if let Err(err) = sink.send_warning(warning) {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "failed to send pre-commit deadline warning"; "error" => err);
+        }
+    }
+
+    proven.retain(|sector_id, _| still_warned.contains(sector_id));
+    *warned = still_warned;
+
+    let unproven: Vec<SealedSectorMetadata> = sectors
+        .into_iter()
+        .filter(|sector| proven.get(&sector.sector_id) != Some(&sector.pre_commit_deadline))
+        .collect();
+
+    if unproven.is_empty() {
+        return Ok(());
+    }
+
+    // Sectors whose proof failed are tried again next time.
+    prove(current_epoch, &unproven)?;
+
+    for sector in unproven {
+        proven.insert(sector.sector_id, sector.pre_commit_deadline);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::helpers::get_sectors_approaching_pre_commit_deadline::get_sectors_approaching_pre_commit_deadline;
+    use crate::api::sector_builder::state::SealedState;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    struct MockEpochSource {
+        epoch: AtomicUsize,
+    }
+
+    impl EpochSource for MockEpochSource {
+        fn current_epoch(&self) -> Result<u64> {
+            Ok(self.epoch.load(Ordering::SeqCst) as u64)
+        }
+
+        fn randomness(&self, epoch: u64) -> Result<[u8; 32]> {
+            Ok([epoch as u8; 32])
+        }
+    }
+
+    #[derive(Default)]
+    struct MockSink {
+        warnings: Mutex<Vec<PreCommitDeadlineWarning>>,
+    }
+
+    impl PreCommitDeadlineSink for MockSink {
+        fn send_warning(&self, warning: PreCommitDeadlineWarning) -> Result<()> {
+            self.warnings.lock().unwrap().push(warning);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_warns_warning_epochs_before_deadline() {
+        let warning_epochs = 10;
+
+        let mut sealed_state: SealedState = Default::default();
+        for (sector_id, deadline) in &[(1, 120), (2, 110), (3, 500)] {
+            sealed_state.sectors.insert(
+                *sector_id,
+                SealedSectorMetadata {
+                    sector_id: *sector_id,
+                    pre_commit_deadline: Some(*deadline),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let epoch_source = MockEpochSource {
+            epoch: AtomicUsize::new(0),
+        };
+        let sink = MockSink::default();
+        let mut warned = HashSet::new();
+        let mut proven_deadlines = HashMap::new();
+        let mut proven: Vec<(u64, Vec<SectorId>)> = Vec::new();
+
+        for epoch in 95..=112 {
+            epoch_source.epoch.store(epoch, Ordering::SeqCst);
+
+            schedule_once(
+                &epoch_source,
+                &sink,
+                &mut warned,
+                &mut proven_deadlines,
+                &mut |current_epoch| {
+                    Ok(get_sectors_approaching_pre_commit_deadline(
+                        &sealed_state,
+                        current_epoch,
+                        warning_epochs,
+                    )
+                    .into_iter()
+                    .map(|sector_id| sealed_state.sectors[&sector_id].clone())
+                    .collect())
+                },
+                &mut |current_epoch, sectors| {
+                    let sector_ids = sectors.iter().map(|s| s.sector_id).collect();
+                    proven.push((current_epoch, sector_ids));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        }
+
+        // each sector is warned about once, as soon as its deadline is fewer
+        // than warning_epochs away
+        assert_eq!(
+            vec![
+                PreCommitDeadlineWarning {
+                    sector_id: 2,
+                    pre_commit_deadline: 110,
+                    current_epoch: 101,
+                },
+                PreCommitDeadlineWarning {
+                    sector_id: 1,
+                    pre_commit_deadline: 120,
+                    current_epoch: 111,
+                },
+            ],
+            *sink.warnings.lock().unwrap()
+        );
+
+        // nothing is proven before a sector approaches its deadline, and each
+        // sector is proven once
+        assert_eq!(vec![(101, vec![2]), (111, vec![1])], proven);
+    }
+
+    #[test]
+    fn test_proves_again_after_failure_or_new_deadline() {
+        let mut sealed_state: SealedState = Default::default();
+        sealed_state.sectors.insert(
+            1,
+            SealedSectorMetadata {
+                sector_id: 1,
+                pre_commit_deadline: Some(110),
+                ..Default::default()
+            },
+        );
+
+        let epoch_source = MockEpochSource {
+            epoch: AtomicUsize::new(105),
+        };
+        let sink = MockSink::default();
+        let mut warned = HashSet::new();
+        let mut proven_deadlines = HashMap::new();
+        let mut num_proofs = 0;
+
+        let mut schedule =
+            |sealed_state: &SealedState, succeed: bool| {
+                schedule_once(
+                    &epoch_source,
+                    &sink,
+                    &mut warned,
+                    &mut proven_deadlines,
+                    &mut |current_epoch| {
+                        Ok(get_sectors_approaching_pre_commit_deadline(
+                            sealed_state,
+                            current_epoch,
+                            10,
+                        )
+                        .into_iter()
+                        .map(|sector_id| sealed_state.sectors[&sector_id].clone())
+                        .collect())
+                    },
+                    &mut |_, _| {
+                        num_proofs += 1;
+
+                        if succeed {
+                            Ok(())
+                        } else {
+                            Err(format_err!("proof failed"))
+                        }
+                    },
+                )
+            };
+
+        // a failed proof is tried again
+        assert!(schedule(&sealed_state, false).is_err());
+        schedule(&sealed_state, true).unwrap();
+        schedule(&sealed_state, true).unwrap();
+
+        // as is a sector which has a new deadline, e.g. once resealed
+        sealed_state
+            .sectors
+            .get_mut(&1)
+            .unwrap()
+            .pre_commit_deadline = Some(112);
+        schedule(&sealed_state, true).unwrap();
+        schedule(&sealed_state, true).unwrap();
+
+        assert_eq!(3, num_proofs);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::api::sector_builder::helpers::incremental_comm_d::*;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::{SectorId, WrappedSectorStore};
+use crate::error::ExpectWithBacktrace;
+use crate::error::Result;
+use crate::FCP_LOG;
+use slog::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+const FATAL_RCVTSK: &str = "error receiving precompute task";
+const FATAL_SNDTSK: &str = "error sending precompute task";
+
+pub enum PrecomputeInput {
+    Start(IncrementalCommD),
+    AddPiece(SectorId, Vec<u8>),
+    Finish(SectorId, mpsc::SyncSender<Option<[u8; 32]>>),
+    Discard(SectorId),
+    Shutdown,
+}
+
+// Computes the data tree (commD) of each staged sector as pieces are added to
+// it, so that the work isn't left until the sector is sealed. Hashing happens
+// on a dedicated thread, in the order in which pieces were added. The tree's
+// state is handed back to the scheduler after each piece so that it may be
+// persisted with the sector's metadata.
+pub struct PrecomputePipeline {
+    tx: mpsc::Sender<PrecomputeInput>,
+    thread: Option<thread::JoinHandle<()>>,
+    tracked: HashSet<SectorId>,
+}
+
+impl PrecomputePipeline {
+    pub fn start(scheduler_input_tx: mpsc::SyncSender<Request>) -> PrecomputePipeline {
+        let (tx, rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            let mut in_progress: HashMap<SectorId, IncrementalCommD> = Default::default();
+
+            loop {
+                match rx.recv().expects(FATAL_RCVTSK) {
+                    PrecomputeInput::Start(inc_comm_d) => {
+                        in_progress.insert(inc_comm_d.sector_id, inc_comm_d);
+                    }
+                    PrecomputeInput::AddPiece(sector_id, piece_bytes) => {
+                        let result = in_progress
+                            .get_mut(&sector_id)
+                            .map(|inc_comm_d| update_incremental_comm_d(inc_comm_d, &piece_bytes));
+
+                        match result {
+                            Some(Ok(())) => {
+                                let state = in_progress[&sector_id].merkle_tree_state();
+
+                                // A missed update only means that the state
+                                // persisted for the sector lags behind, and
+                                // blocking here could deadlock a scheduler
+                                // which is shutting down.
+                                let _ = scheduler_input_tx.try_send(
+                                    Request::HandleMerkleTreeState(sector_id, Box::new(state)),
+                                );
+                            }
+                            Some(Err(err)) => {
+                                let err = format!("{}", err);
+                                warn!(FCP_LOG, "abandoning commD precomputation"; "sector_id" => sector_id, "error" => err);
+
+                                in_progress.remove(&sector_id);
+                            }
+                            None => (),
+                        }
+                    }
+                    PrecomputeInput::Finish(sector_id, return_channel) => {
+                        let comm_d = in_progress
+                            .remove(&sector_id)
+                            .and_then(|inc_comm_d| finish_incremental_comm_d(&inc_comm_d).ok());
+
+                        // The sealer may have given up waiting on us.
+                        let _ = return_channel.send(comm_d);
+                    }
+                    PrecomputeInput::Discard(sector_id) => {
+                        in_progress.remove(&sector_id);
+                    }
+                    PrecomputeInput::Shutdown => break,
+                }
+            }
+        });
+
+        PrecomputePipeline {
+            tx,
+            thread: Some(thread),
+            tracked: Default::default(),
+        }
+    }
+
+    // Feeds the newly-added piece's bytes (as retained by the piece writer) to
+    // the precomputation of its sector's commD.
+    // A sector which isn't yet being tracked (e.g. one which held pieces
+    // before this sector builder was started) is picked up from its persisted
+    // merkle tree state, if that state covers all of its earlier pieces, and
+    // is otherwise sealed without a precomputed commD.
+    pub fn piece_added(
+        &mut self,
+        sector_store: &Arc<WrappedSectorStore>,
+        staged_state: &StagedState,
+        sector_id: SectorId,
+        piece_bytes: Vec<u8>,
+    ) -> Result<()> {
+        if !self.tracked.contains(&sector_id) {
+            let num_earlier_pieces = staged_state
+                .sectors
+                .get(&sector_id)
+                .map(|sector| sector.pieces.len().saturating_sub(1))
+                .unwrap_or(0);
+
+            match start_incremental_comm_d(
+                sector_store,
+                staged_state,
+                sector_id,
+                num_earlier_pieces,
+            ) {
+                Ok(inc_comm_d) => {
+                    self.send(PrecomputeInput::Start(inc_comm_d));
+                    self.tracked.insert(sector_id);
+                }
+                Err(_) if num_earlier_pieces > 0 => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.tracked.contains(&sector_id) {
+            self.send(PrecomputeInput::AddPiece(sector_id, piece_bytes));
+        }
+
+        Ok(())
+    }
+
+    // Stops tracking the sector, returning a channel on which its commD will
+    // be delivered once all of its pieces have been hashed. Returns None if
+    // the sector wasn't being tracked.
+    pub fn finish(&mut self, sector_id: SectorId) -> Option<mpsc::Receiver<Option<[u8; 32]>>> {
+        if !self.tracked.remove(&sector_id) {
+            return None;
+        }
+
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.send(PrecomputeInput::Finish(sector_id, tx));
+
+        Some(rx)
+    }
+
+    pub fn discard(&mut self, sector_id: SectorId) {
+        if self.tracked.remove(&sector_id) {
+            self.send(PrecomputeInput::Discard(sector_id));
+        }
+    }
+
+    fn send(&self, task: PrecomputeInput) {
+        self.tx.send(task).expects(FATAL_SNDTSK);
+    }
+}
+
+impl Drop for PrecomputePipeline {
+    fn drop(&mut self) {
+        let _ = self
+            .tx
+            .send(PrecomputeInput::Shutdown)
+            .map_err(|err| println!("err sending Shutdown to precompute pipeline: {:?}", err));
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread
+                .join()
+                .map_err(|err| println!("err joining precompute thread: {:?}", err));
+        }
+    }
+}
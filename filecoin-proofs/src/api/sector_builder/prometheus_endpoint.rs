@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::api::sector_builder::metrics::{self, MetricsSink};
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use crate::FCP_LOG;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntGauge, Opts, Registry, TextEncoder,
+};
+use slog::*;
+
+// Upper bounds (in seconds) of the seal duration buckets, spanning the test
+// sector sizes (seconds) through the live ones (hours).
+pub const DEFAULT_SEAL_DURATION_BUCKETS_SECS: [f64; 8] = [
+    1.0,
+    10.0,
+    60.0,
+    300.0,
+    900.0,
+    3600.0,
+    3.0 * 3600.0,
+    12.0 * 3600.0,
+];
+
+// Upper bounds (in seconds) of the fill duration buckets, spanning sectors
+// filled by a single large piece (seconds) through slowly-trickling ones
+// (days).
+pub const DEFAULT_FILL_DURATION_BUCKETS_SECS: [f64; 8] = [
+    1.0,
+    60.0,
+    600.0,
+    3600.0,
+    6.0 * 3600.0,
+    24.0 * 3600.0,
+    3.0 * 24.0 * 3600.0,
+    7.0 * 24.0 * 3600.0,
+];
+
+// How often the endpoint checks whether it has been shut down while no
+// scrapes are arriving.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Scrapes which don't send their request within this are dropped, so that a
+// stalled client can't hold up the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The sector builder's metrics, kept in a Prometheus registry.
+pub struct PrometheusMetrics {
+    registry: Registry,
+    pieces_total: IntCounter,
+    sectors_staged: IntGauge,
+    sectors_sealed: IntCounter,
+    seal_duration_seconds: Histogram,
+    fill_duration_seconds: Histogram,
+    staged_fill_ratio: GaugeVec,
+}
+
+impl PrometheusMetrics {
+    // The seal and fill duration histograms' buckets have the given
+    // (ascending) upper bounds, in seconds.
+    pub fn new(
+        seal_duration_buckets_secs: Vec<f64>,
+        fill_duration_buckets_secs: Vec<f64>,
+    ) -> Result<PrometheusMetrics> {
+        let registry = Registry::new();
+
+        let pieces_total = IntCounter::new(
+            "sector_builder_pieces_total",
+            "Number of pieces written to staged sectors",
+        )?;
+        let sectors_staged = IntGauge::new(
+            "sector_builder_sectors_staged",
+            "Number of staged sectors (accepting data or waiting to be sealed)",
+        )?;
+        let sectors_sealed =
+            IntCounter::new("sector_builder_sectors_sealed", "Number of sectors sealed")?;
+        let seal_duration_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "sector_builder_seal_duration_seconds",
+                "How long each sector took to seal",
+            )
+            .buckets(seal_duration_buckets_secs),
+        )?;
+        let fill_duration_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "sector_builder_fill_duration_seconds",
+                "How long each sector accepted data, from its provisioning to its seal being scheduled",
+            )
+            .buckets(fill_duration_buckets_secs),
+        )?;
+        let staged_fill_ratio = GaugeVec::new(
+            Opts::new(
+                "sector_builder_staged_fill_ratio",
+                "Ratio of piece-bytes held by a staged sector to the number it could hold",
+            ),
+            &["sector_id"],
+        )?;
+
+        registry.register(Box::new(pieces_total.clone()))?;
+        registry.register(Box::new(sectors_staged.clone()))?;
+        registry.register(Box::new(sectors_sealed.clone()))?;
+        registry.register(Box::new(seal_duration_seconds.clone()))?;
+        registry.register(Box::new(fill_duration_seconds.clone()))?;
+        registry.register(Box::new(staged_fill_ratio.clone()))?;
+
+        Ok(PrometheusMetrics {
+            registry,
+            pieces_total,
+            sectors_staged,
+            sectors_sealed,
+            seal_duration_seconds,
+            fill_duration_seconds,
+            staged_fill_ratio,
+        })
+    }
+
+    // Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(buffer)
+    }
+}
+
+impl MetricsSink for PrometheusMetrics {
+    fn piece_added(&self) {
+        self.pieces_total.inc();
+    }
+
+    fn staged_sectors_changed(&self, fill_ratios: &[(SectorId, f64)]) {
+        self.sectors_staged.set(fill_ratios.len() as i64);
+
+        // Sectors which are no longer staged mustn't keep reporting the fill
+        // ratio they had.
+        self.staged_fill_ratio.reset();
+        for (sector_id, fill_ratio) in fill_ratios {
+            self.staged_fill_ratio
+                .with_label_values(&[&sector_id.to_string()])
+                .set(*fill_ratio);
+        }
+    }
+
+    fn sector_sealed(&self, seal_duration: Duration) {
+        self.sectors_sealed.inc();
+        self.seal_duration_seconds
+            .observe(as_secs_f64(seal_duration));
+    }
+
+    fn sector_filled(&self, fill_duration: Duration) {
+        self.fill_duration_seconds
+            .observe(as_secs_f64(fill_duration));
+    }
+
+    fn piece_ingestion_histogram(&self, histogram: Arc<metrics::Histogram>) {
+        let result = PieceIngestionCollector::new(histogram)
+            .and_then(|collector| Ok(self.registry.register(Box::new(collector))?));
+
+        if let Err(err) = result {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "failed to register piece ingestion histogram"; "error" => err);
+        }
+    }
+}
+
+// Exposes the sector builder's piece ingestion histogram, which it keeps
+// itself (in microseconds), as a Prometheus histogram (in seconds).
+struct PieceIngestionCollector {
+    desc: Desc,
+    histogram: Arc<metrics::Histogram>,
+}
+
+impl PieceIngestionCollector {
+    fn new(histogram: Arc<metrics::Histogram>) -> Result<PieceIngestionCollector> {
+        let desc = Desc::new(
+            "sector_builder_piece_ingestion_seconds".to_string(),
+            "How long each piece took to be written to a staged sector".to_string(),
+            vec![],
+            HashMap::new(),
+        )?;
+
+        Ok(PieceIngestionCollector { desc, histogram })
+    }
+}
+
+impl Collector for PieceIngestionCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<proto::MetricFamily> {
+        // Prometheus' buckets are cumulative, and the last holds every
+        // observation.
+        let counts = self.histogram.bucket_counts();
+
+        let mut histogram = proto::Histogram::default();
+        histogram.set_sample_count(counts.iter().sum());
+        histogram.set_sample_sum(self.histogram.sum() as f64 / 1e6);
+
+        let mut cumulative_count = 0;
+        for (bound, count) in self.histogram.bounds().iter().zip(counts.iter()) {
+            cumulative_count += count;
+
+            let mut bucket = proto::Bucket::default();
+            bucket.set_upper_bound(*bound as f64 / 1e6);
+            bucket.set_cumulative_count(cumulative_count);
+            histogram.mut_bucket().push(bucket);
+        }
+
+        let mut metric = proto::Metric::default();
+        metric.set_histogram(histogram);
+
+        let mut family = proto::MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(proto::MetricType::HISTOGRAM);
+        family.mut_metric().push(metric);
+
+        vec![family]
+    }
+}
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+// Serves the metrics to Prometheus scrapes of /metrics until dropped.
+pub struct PrometheusMetricsEndpoint {
+    local_addr: SocketAddr,
+    shutdown_tx: mpsc::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PrometheusMetricsEndpoint {
+    // Listens on the given port of the loopback interface (or, if port is
+    // zero, on a port chosen by the operating system; see local_addr), so
+    // that only local scrapes are answered.
+    pub fn start(port: u16, metrics: Arc<PrometheusMetrics>) -> Result<PrometheusMetricsEndpoint> {
+        PrometheusMetricsEndpoint::start_on(("127.0.0.1", port), metrics)
+    }
+
+    // Like start, but listens on the given address, e.g. that of an interface
+    // which a remote Prometheus server scrapes.
+    pub fn start_on<A: ToSocketAddrs>(
+        addr: A,
+        metrics: Arc<PrometheusMetrics>,
+    ) -> Result<PrometheusMetricsEndpoint> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        // The listener is polled so that the endpoint notices being shut down.
+        listener.set_nonblocking(true)?;
+
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = serve_scrape(stream, &metrics) {
+                        let err = format!("{}", err);
+                        warn!(FCP_LOG, "failed to serve metrics scrape"; "error" => err);
+                    }
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    if let Err(mpsc::RecvTimeoutError::Timeout) =
+                        shutdown_rx.recv_timeout(ACCEPT_POLL_INTERVAL)
+                    {
+                        continue;
+                    }
+
+                    break;
+                }
+                Err(err) => {
+                    let err = format!("{}", err);
+                    warn!(FCP_LOG, "failed to accept metrics scrape"; "error" => err);
+                }
+            }
+        });
+
+        Ok(PrometheusMetricsEndpoint {
+            local_addr,
+            shutdown_tx,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for PrometheusMetricsEndpoint {
+    fn drop(&mut self) {
+        let _ = self.shutdown_tx.send(());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread
+                .join()
+                .map_err(|err| println!("err joining metrics endpoint thread: {:?}", err));
+        }
+    }
+}
+
+// Answers one HTTP request: a GET of /metrics with the metrics, anything else
+// with a 404.
+fn serve_scrape(stream: TcpStream, metrics: &PrometheusMetrics) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // The request's headers are of no interest, but are read so that the
+    // client doesn't see its request cut off.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut stream = reader.into_inner();
+
+    let mut parts = request_line.split_whitespace();
+    if let (Some("GET"), Some("/metrics")) = (parts.next(), parts.next()) {
+        let body = metrics.render()?;
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            TextEncoder::new().format_type(),
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+    } else {
+        stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )?;
+    }
+
+    stream.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(endpoint: &PrometheusMetricsEndpoint, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", endpoint.local_addr().port())).unwrap();
+
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serves_metrics() {
+        let metrics =
+            Arc::new(PrometheusMetrics::new(vec![1.0, 60.0], vec![60.0, 3600.0]).unwrap());
+
+        metrics.piece_added();
+        metrics.piece_added();
+        metrics.staged_sectors_changed(&[(3, 0.25), (4, 1.0)]);
+        metrics.sector_sealed(Duration::from_secs(30));
+        metrics.sector_filled(Duration::from_secs(600));
+
+        let ingestion = Arc::new(metrics::Histogram::new(vec![1_000, 1_000_000]).unwrap());
+        metrics.piece_ingestion_histogram(ingestion.clone());
+        ingestion.observe(500);
+        ingestion.observe(2_000);
+        ingestion.observe(5_000_000);
+
+        let endpoint = PrometheusMetricsEndpoint::start(0, metrics.clone()).unwrap();
+        let response = get(&endpoint, "/metrics");
+
+        let mut sections = response.splitn(2, "\r\n\r\n");
+        let head = sections.next().unwrap();
+        let body = sections.next().unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+
+        // each metric's declared type, and its samples
+        let mut types: HashMap<&str, &str> = HashMap::new();
+        let mut samples: HashMap<&str, f64> = HashMap::new();
+
+        for line in body.lines() {
+            let mut fields = line.split_whitespace();
+
+            if line.starts_with("# TYPE") {
+                let name = fields.nth(2).unwrap();
+                types.insert(name, fields.next().unwrap());
+            } else if !line.starts_with('#') && !line.is_empty() {
+                let name = fields.next().unwrap();
+                samples.insert(name, fields.next().unwrap().parse().unwrap());
+            }
+        }
+
+        assert_eq!(Some(&"counter"), types.get("sector_builder_pieces_total"));
+        assert_eq!(Some(&"gauge"), types.get("sector_builder_sectors_staged"));
+        assert_eq!(Some(&"counter"), types.get("sector_builder_sectors_sealed"));
+        assert_eq!(
+            Some(&"histogram"),
+            types.get("sector_builder_seal_duration_seconds")
+        );
+        assert_eq!(
+            Some(&"histogram"),
+            types.get("sector_builder_fill_duration_seconds")
+        );
+        assert_eq!(
+            Some(&"gauge"),
+            types.get("sector_builder_staged_fill_ratio")
+        );
+        assert_eq!(
+            Some(&"histogram"),
+            types.get("sector_builder_piece_ingestion_seconds")
+        );
+
+        assert_eq!(Some(&2.0), samples.get("sector_builder_pieces_total"));
+        assert_eq!(Some(&2.0), samples.get("sector_builder_sectors_staged"));
+        assert_eq!(Some(&1.0), samples.get("sector_builder_sectors_sealed"));
+        assert_eq!(
+            Some(&0.0),
+            samples.get("sector_builder_seal_duration_seconds_bucket{le=\"1\"}")
+        );
+        assert_eq!(
+            Some(&1.0),
+            samples.get("sector_builder_seal_duration_seconds_bucket{le=\"60\"}")
+        );
+        assert_eq!(
+            Some(&0.0),
+            samples.get("sector_builder_fill_duration_seconds_bucket{le=\"60\"}")
+        );
+        assert_eq!(
+            Some(&1.0),
+            samples.get("sector_builder_fill_duration_seconds_bucket{le=\"3600\"}")
+        );
+        assert_eq!(
+            Some(&0.25),
+            samples.get("sector_builder_staged_fill_ratio{sector_id=\"3\"}")
+        );
+        assert_eq!(
+            Some(&1.0),
+            samples.get("sector_builder_piece_ingestion_seconds_bucket{le=\"0.001\"}")
+        );
+        assert_eq!(
+            Some(&2.0),
+            samples.get("sector_builder_piece_ingestion_seconds_bucket{le=\"1\"}")
+        );
+        assert_eq!(
+            Some(&3.0),
+            samples.get("sector_builder_piece_ingestion_seconds_count")
+        );
+
+        // a sealed sector no longer has a fill ratio
+        metrics.staged_sectors_changed(&[(4, 1.0)]);
+        let response = get(&endpoint, "/metrics");
+        assert!(!response.contains("sector_id=\"3\""));
+        assert!(response.contains("sector_id=\"4\""));
+    }
+
+    #[test]
+    fn test_serves_only_metrics() {
+        let metrics = Arc::new(PrometheusMetrics::new(vec![1.0], vec![1.0]).unwrap());
+        let endpoint = PrometheusMetricsEndpoint::start(0, metrics).unwrap();
+
+        assert!(get(&endpoint, "/").starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::api::sector_builder::metadata::RedactionReceipt;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder};
+use crate::error::Result;
+
+// Redacts the referenced piece (e.g. to honour a request to be forgotten),
+// after which reads of it are refused with a PieceRedacted error. The piece's
+// bytes are overwritten with zeroes if its sector is still accepting data.
+// Those of a piece which has been sealed can't be without invalidating the
+// sector's proof, which the receipt reports (was_overwritten is false): such
+// a sector must be deleted for the bytes to be.
+pub fn redact_piece(sector_builder: &SectorBuilder, piece_key: String) -> Result<RedactionReceipt> {
+    log_unrecov(sector_builder.run_blocking(|tx| Request::RedactPiece(piece_key, tx)))
+}
+
+pub fn is_piece_redacted(staged_state: &StagedState, piece_key: &str) -> bool {
+    staged_state.redacted_pieces.contains_key(piece_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::errors::SectorBuilderErr;
+    use crate::api::sector_builder::test_utils::{sector_builder, MockArchiveBackend};
+    use std::fs;
+
+    fn is_redacted_error(result: Result<Vec<u8>>) -> bool {
+        match result.unwrap_err().downcast_ref() {
+            Some(SectorBuilderErr::PieceRedacted(_)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_redacts_staged_piece() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let sector_builder = sector_builder(dir.path(), Default::default());
+
+            for (piece_key, byte) in &[("a", 1u8), ("b", 2), ("c", 3)] {
+                let piece_path = dir.path().join(piece_key);
+                fs::write(&piece_path, vec![*byte; 100]).unwrap();
+
+                sector_builder
+                    .add_piece(
+                        piece_key.to_string(),
+                        100,
+                        piece_path.to_string_lossy().into_owned(),
+                    )
+                    .unwrap();
+            }
+
+            let receipt = redact_piece(&sector_builder, "b".to_string()).unwrap();
+            assert_eq!("b", receipt.piece_key);
+            assert!(receipt.was_overwritten);
+
+            assert!(is_redacted_error(
+                sector_builder.read_piece_from_sealed_sector("b".to_string())
+            ));
+
+            // redacting again changes nothing
+            assert_eq!(
+                receipt,
+                redact_piece(&sector_builder, "b".to_string()).unwrap()
+            );
+
+            // the other pieces' bytes are intact
+            let staged = &sector_builder.get_staged_sectors().unwrap()[0];
+            let mut body = sector_builder
+                .state
+                .sector_store
+                .inner
+                .manager()
+                .open_and_verify_sector(&staged.sector_access)
+                .unwrap();
+
+            let mut padded = Vec::new();
+            std::io::Read::read_to_end(&mut body, &mut padded).unwrap();
+
+            let mut unpadded = Vec::new();
+            sector_base::io::fr32::write_unpadded(&padded, &mut unpadded, 0, 300).unwrap();
+
+            assert_eq!(vec![1; 100], &unpadded[..100]);
+            assert_eq!(vec![0; 100], &unpadded[100..200]);
+            assert_eq!(vec![3; 100], &unpadded[200..]);
+
+            assert!(redact_piece(&sector_builder, "d".to_string()).is_err());
+        }
+
+        // the redaction survives a restart
+        let sector_builder = sector_builder(dir.path(), Default::default());
+        assert!(is_redacted_error(
+            sector_builder.read_piece_from_sealed_sector("b".to_string())
+        ));
+        assert_eq!(
+            3,
+            sector_builder.get_staged_sectors().unwrap()[0].pieces.len()
+        );
+    }
+
+    #[test]
+    fn test_refuses_archived_read_of_redacted_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let sector_builder = sector_builder(dir.path(), Default::default());
+
+        let piece_path = dir.path().join("a");
+        fs::write(&piece_path, vec![1; 100]).unwrap();
+
+        sector_builder
+            .add_piece(
+                "a".to_string(),
+                100,
+                piece_path.to_string_lossy().into_owned(),
+            )
+            .unwrap();
+
+        redact_piece(&sector_builder, "a".to_string()).unwrap();
+
+        // the redaction is checked before the archive is looked for
+        assert!(is_redacted_error(sector_builder.retrieve_archived_piece(
+            "a".to_string(),
+            None,
+            &MockArchiveBackend::new(false)
+        )));
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use crate::api::sector_builder::audit::{find_sealed_sector, num_leaves};
+use crate::api::sector_builder::helpers::incremental_merkle_tree::merkle_root;
+use crate::api::sector_builder::{SectorBuilder, SectorId};
+use crate::error::Result;
+
+// A storage node holding copies of sealed sectors' replicas. The node
+// computes the Merkle root of its copy where the copy is (e.g. with
+// compute_sector_merkle_root), so that copies are compared without being
+// transferred.
+pub trait ReplicaNode: Send + Sync {
+    fn sector_merkle_root(&self, sector_id: SectorId) -> Result<[u8; 32]>;
+}
+
+// The outcome of comparing the copies of a sealed sector's replica with the
+// sector builder's own. The divergent replicas are indexes into
+// replica_roots (and into the nodes which were asked for them).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationConsistencyCheck {
+    pub sector_id: SectorId,
+    pub primary_root: [u8; 32],
+    pub replica_roots: Vec<[u8; 32]>,
+    pub divergent_replicas: Vec<usize>,
+}
+
+// Computes the Merkle root of the sealed sector's replica (which, unless the
+// replica has been corrupted, is its commR). The replica is read once, and
+// only the right edge of its tree is held in memory.
+pub fn compute_sector_merkle_root(
+    sector_builder: &SectorBuilder,
+    sector_id: SectorId,
+) -> Result<[u8; 32]> {
+    let sealed_sector = find_sealed_sector(sector_builder, sector_id)?;
+
+    merkle_root(
+        BufReader::new(File::open(&sealed_sector.sector_access)?),
+        num_leaves(&sector_builder.state.sector_store),
+    )
+}
+
+pub fn verify_replica_consistency(primary_root: [u8; 32], replica_root: [u8; 32]) -> bool {
+    primary_root == replica_root
+}
+
+// Asks each of the nodes for the Merkle root of its copy of the sealed
+// sector's replica, and compares it with that of the sector builder's own.
+// A node which can't report a root fails the check.
+pub fn check_all_replicas(
+    sector_builder: &SectorBuilder,
+    replica_nodes: &[Arc<ReplicaNode>],
+    sector_id: SectorId,
+) -> Result<ReplicationConsistencyCheck> {
+    let primary_root = compute_sector_merkle_root(sector_builder, sector_id)?;
+
+    check_replicas(sector_id, primary_root, replica_nodes)
+}
+
+fn check_replicas(
+    sector_id: SectorId,
+    primary_root: [u8; 32],
+    replica_nodes: &[Arc<ReplicaNode>],
+) -> Result<ReplicationConsistencyCheck> {
+    let replica_roots = replica_nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            node.sector_merkle_root(sector_id).map_err(|err| {
+                format_err!(
+                    "replica node {} failed to report a root for sector {}: {}",
+                    i,
+                    sector_id,
+                    err
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let divergent_replicas = replica_roots
+        .iter()
+        .enumerate()
+        .filter(|(_, root)| !verify_replica_consistency(primary_root, **root))
+        .map(|(i, _)| i)
+        .collect();
+
+    Ok(ReplicationConsistencyCheck {
+        sector_id,
+        primary_root,
+        replica_roots,
+        divergent_replicas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::helpers::incremental_merkle_tree::NODE_SIZE;
+    use crate::api::sector_builder::test_utils::replica;
+
+    const NUM_LEAVES: u64 = 64;
+
+    // A node whose copy of the replica is in memory.
+    struct TestReplicaNode {
+        replica: Vec<u8>,
+    }
+
+    impl ReplicaNode for TestReplicaNode {
+        fn sector_merkle_root(&self, _: SectorId) -> Result<[u8; 32]> {
+            merkle_root(&self.replica[..], NUM_LEAVES)
+        }
+    }
+
+    struct UnreachableReplicaNode;
+
+    impl ReplicaNode for UnreachableReplicaNode {
+        fn sector_merkle_root(&self, _: SectorId) -> Result<[u8; 32]> {
+            Err(format_err!("connection refused"))
+        }
+    }
+
+    #[test]
+    fn test_root_is_comm_r() {
+        let (bytes, comm_r) = replica(NUM_LEAVES);
+
+        assert_eq!(comm_r, merkle_root(&bytes[..], NUM_LEAVES).unwrap());
+        assert!(merkle_root(&bytes[..100], NUM_LEAVES).is_err());
+    }
+
+    #[test]
+    fn test_detects_divergent_replica() {
+        let (bytes, comm_r) = replica(NUM_LEAVES);
+
+        let mut corrupted = bytes.clone();
+        corrupted[NODE_SIZE * 40] ^= 1;
+
+        let nodes: Vec<Arc<ReplicaNode>> = vec![
+            Arc::new(TestReplicaNode {
+                replica: bytes.clone(),
+            }),
+            Arc::new(TestReplicaNode { replica: corrupted }),
+        ];
+
+        let check = check_replicas(3, comm_r, &nodes).unwrap();
+
+        assert_eq!(3, check.sector_id);
+        assert_eq!(comm_r, check.primary_root);
+        assert_eq!(comm_r, check.replica_roots[0]);
+        assert_ne!(comm_r, check.replica_roots[1]);
+        assert_eq!(vec![1], check.divergent_replicas);
+
+        // an unreachable node can't vouch for its copy
+        let nodes: Vec<Arc<ReplicaNode>> = vec![
+            Arc::new(TestReplicaNode { replica: bytes }),
+            Arc::new(UnreachableReplicaNode),
+        ];
+        assert!(check_replicas(3, comm_r, &nodes).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::helpers::retrieve_piece::retrieve_piece;
+use crate::api::sector_builder::metadata::SealedSectorMetadata;
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder, SectorId};
+use crate::error::Result;
+
+// Returns true once the sector has been sealed for reseal_interval_epochs
+// (see SectorBuilderConfig::reseal_interval_epochs). A sector sealed without
+// an epoch source has no known age, and is never due.
+pub fn should_reseal(
+    sealed_sector: &SealedSectorMetadata,
+    current_epoch: u64,
+    reseal_interval_epochs: u64,
+) -> bool {
+    match sealed_sector.sealed_at_epoch {
+        Some(sealed_at_epoch) => {
+            current_epoch >= sealed_at_epoch.saturating_add(reseal_interval_epochs)
+        }
+        None => false,
+    }
+}
+
+// Unseals the sealed sector's pieces and stages them in a new sector of
+// their own, which is then sealed, returning the new sector's id. Sealing
+// derives the replica from the sector id, so the new replica is unlike the
+// old one. The pieces move to the new sector along with their metadata, but
+// the sealed sector's replica is kept (and still proven): it should be
+// deleted (see SectorBuilder::delete_sectors_batch) only once the new sector
+// has been committed on-chain.
+pub fn reseal_sector(sector_builder: &SectorBuilder, sector_id: SectorId) -> Result<SectorId> {
+    let sealed_sector = sector_builder
+        .get_sealed_sectors()?
+        .into_iter()
+        .find(|sector| sector.sector_id == sector_id)
+        .ok_or_else(|| err_unrecov(format!("no sealed sector with id {}", sector_id)))?;
+
+    // The pieces are unsealed on the caller's thread, so as not to hold up
+    // the main worker, and handed to it as files.
+    let dir = tempfile::tempdir()?;
+    let mut pieces = Vec::with_capacity(sealed_sector.pieces.len());
+
+    for (i, piece) in sealed_sector.pieces.iter().enumerate() {
+        let bytes = retrieve_piece(
+            &sector_builder.state.sector_store,
+            &sealed_sector,
+            &sector_builder.state.prover_id,
+            &piece.piece_key,
+            &sector_builder.state.io_scheduler,
+            &sector_builder.state.sector_locks,
+        )?;
+
+        let piece_path = dir.path().join(i.to_string());
+        fs::write(&piece_path, &bytes)?;
+
+        pieces.push((piece.clone(), piece_path.to_string_lossy().into_owned()));
+    }
+
+    log_unrecov(sector_builder.run_blocking(|tx| Request::RestageSector(sector_id, pieces, tx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::sealing_test_harness::await_seal;
+    use crate::api::sector_builder::test_utils::{sector_builder_factory, TEST_CLASS};
+
+    #[test]
+    fn test_reseal_after_interval() {
+        let sealed_sector = SealedSectorMetadata {
+            sealed_at_epoch: Some(1000),
+            ..Default::default()
+        };
+
+        assert!(!should_reseal(&sealed_sector, 1000, 500));
+        assert!(!should_reseal(&sealed_sector, 1499, 500));
+        assert!(should_reseal(&sealed_sector, 1500, 500));
+        assert!(should_reseal(&sealed_sector, 90_000, 500));
+
+        // a clock which hasn't reached the sealing epoch
+        assert!(!should_reseal(&sealed_sector, 10, 500));
+
+        // an interval too long to add to the sealing epoch never elapses
+        assert!(!should_reseal(&sealed_sector, 1500, u64::max_value()));
+
+        // a sector sealed without an epoch source
+        let sealed_sector = SealedSectorMetadata {
+            sealed_at_epoch: None,
+            ..Default::default()
+        };
+        assert!(!should_reseal(&sealed_sector, 90_000, 500));
+    }
+
+    #[test]
+    #[ignore] // Slow test – run only when compiled for release.
+    fn test_reseal_sector() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sector_builder = sector_builder_factory(TEST_CLASS, dir.path())
+            .unwrap()
+            .create_concrete_sector_builder(Default::default())
+            .unwrap();
+
+        let piece_bytes = vec![7; 100];
+        let piece_path = dir.path().join("piece");
+        fs::write(&piece_path, &piece_bytes).unwrap();
+
+        let sealed_sector_id = sector_builder
+            .add_piece(
+                "piece".to_string(),
+                100,
+                piece_path.to_string_lossy().into_owned(),
+            )
+            .unwrap();
+        sector_builder.seal_sector(sealed_sector_id).unwrap();
+        await_seal(&sector_builder, sealed_sector_id).unwrap();
+
+        let sector_id = reseal_sector(&sector_builder, sealed_sector_id).unwrap();
+        assert_ne!(sealed_sector_id, sector_id);
+
+        // The piece belongs to the new sector alone, so it's listed once...
+        let pieces = sector_builder.list_pieces().unwrap();
+        assert_eq!(1, pieces.len());
+
+        let sealed_sector = sector_builder
+            .get_sealed_sectors()
+            .unwrap()
+            .into_iter()
+            .find(|sector| sector.sector_id == sealed_sector_id)
+            .unwrap();
+        assert!(sealed_sector.pieces.is_empty());
+
+        // ...and can't be restaged from the old sector again.
+        assert!(reseal_sector(&sector_builder, sealed_sector_id).is_err());
+
+        await_seal(&sector_builder, sector_id).unwrap();
+        assert_eq!(
+            piece_bytes,
+            sector_builder
+                .read_piece_from_sealed_sector("piece".to_string())
+                .unwrap()
+        );
+    }
+}
@@ -1,30 +1,99 @@
 use crate::api::internal;
 use crate::api::post_adapter::*;
+use crate::api::sector_builder::audit_log::AuditLog;
+use crate::api::sector_builder::config::SectorBuilderConfig;
+use crate::api::sector_builder::dashboard::SealingTracker;
+use crate::api::sector_builder::errors::err_piece_redacted;
 use crate::api::sector_builder::errors::err_piecenotfound;
+use crate::api::sector_builder::errors::err_seal_in_progress;
 use crate::api::sector_builder::errors::err_unrecov;
-use crate::api::sector_builder::helpers::add_piece::add_piece;
+use crate::api::sector_builder::fill_time::FillDurationLog;
+use crate::api::sector_builder::health::Discrepancy;
+use crate::api::sector_builder::helpers::add_piece::{
+    expire_idempotency_keys, idempotent_add_sector_id, piece_destination,
+    provision_new_staged_sector, push_written_piece,
+};
+use crate::api::sector_builder::helpers::check_seal_fill_ratio::{
+    check_seal_fill_ratio, sector_fill_ratio,
+};
+use crate::api::sector_builder::helpers::check_sector_builder_health::check_sector_builder_health;
+use crate::api::sector_builder::helpers::check_staged_sector_crc32::staged_sectors_to_check;
+use crate::api::sector_builder::helpers::compact_sealed_state::{
+    compact_sealed_state, load_index, load_sealed_sectors, persist_compacted,
+    persist_compacted_index, SealedSectorKey,
+};
+use crate::api::sector_builder::helpers::delete_sectors_batch::delete_sectors_batch;
+use crate::api::sector_builder::helpers::generate_piece_manifest::generate_piece_manifest;
+use crate::api::sector_builder::helpers::generate_post_with_timeout::generate_post_with_timeout;
 use crate::api::sector_builder::helpers::get_seal_status::get_seal_status;
+use crate::api::sector_builder::helpers::get_sectors_approaching_pre_commit_deadline::get_sectors_approaching_pre_commit_deadline;
+use crate::api::sector_builder::helpers::get_sectors_by_region::get_sectors_by_region;
 use crate::api::sector_builder::helpers::get_sectors_ready_for_sealing::get_sectors_ready_for_sealing;
-use crate::api::sector_builder::helpers::snapshots::load_snapshot;
+use crate::api::sector_builder::helpers::import_sector::{commit_imported_sector, ImportedSector};
+use crate::api::sector_builder::helpers::piece_size_model::{
+    fit_piece_size_model, predict_pieces_until_full,
+};
+use crate::api::sector_builder::helpers::prefetch_piece::PieceReadBuffer;
+use crate::api::sector_builder::helpers::proof_deduplication_index::{
+    generate_post_deduplicated, record_post, ProofDeduplicationIndex,
+};
+use crate::api::sector_builder::helpers::redact_piece::redact_piece;
+use crate::api::sector_builder::helpers::render_state_diagram::{
+    render_state_diagram, StateDiagramPublisher,
+};
+use crate::api::sector_builder::helpers::reserve_sector_id_range::reserve_sector_id_range;
+use crate::api::sector_builder::helpers::sample_sectors_for_audit::sample_sectors_for_audit;
+use crate::api::sector_builder::helpers::seal_trigger::SealTrigger;
+use crate::api::sector_builder::helpers::snapshots::load_snapshot_lazily;
 use crate::api::sector_builder::helpers::snapshots::make_snapshot;
-use crate::api::sector_builder::helpers::snapshots::persist_snapshot;
+use crate::api::sector_builder::helpers::snapshots::persist_uncompacted_snapshot;
+use crate::api::sector_builder::helpers::staged_capacity::{
+    check_staged_capacity, num_queued_sectors, StagedCapacity,
+};
+use crate::api::sector_builder::helpers::validate_sector_access::validate_sector_access;
+use crate::api::sector_builder::helpers::verify_cross_state_consistency::verify_cross_state_consistency;
+use crate::api::sector_builder::io_scheduler::IoScheduler;
 use crate::api::sector_builder::kv_store::KeyValueStore;
+use crate::api::sector_builder::manifest::{PieceManifest, PieceManifestFilter};
+use crate::api::sector_builder::metadata::sum_piece_bytes;
+use crate::api::sector_builder::metadata::ApiVersion;
+use crate::api::sector_builder::metadata::ArchiveReceipt;
+use crate::api::sector_builder::metadata::BatchDeleteResult;
+use crate::api::sector_builder::metadata::MerkleTreeState;
+use crate::api::sector_builder::metadata::PieceMetadata;
+use crate::api::sector_builder::metadata::RedactionReceipt;
 use crate::api::sector_builder::metadata::SealStatus;
 use crate::api::sector_builder::metadata::SealedSectorMetadata;
 use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::api::sector_builder::metrics::Histogram;
+use crate::api::sector_builder::piece_access::{
+    check_piece_access, check_pieces_access, PieceCapabilityToken,
+};
+use crate::api::sector_builder::piece_writer::{PieceWrite, PieceWriter, WrittenPieces};
+use crate::api::sector_builder::precompute::PrecomputePipeline;
+use crate::api::sector_builder::redaction::is_piece_redacted;
 use crate::api::sector_builder::sealer::SealerInput;
+use crate::api::sector_builder::sector_id_allocator::SectorIdAllocator;
+use crate::api::sector_builder::sector_locks::SectorLocks;
 use crate::api::sector_builder::state::SectorBuilderState;
 use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::state_export::{StateDelta, StateOperation};
+use crate::api::sector_builder::telemetry::PieceTelemetry;
 use crate::api::sector_builder::SectorId;
 use crate::api::sector_builder::{WrappedKeyValueStore, WrappedSectorStore};
 use crate::error::ExpectWithBacktrace;
 use crate::error::Result;
+use crate::FCP_LOG;
 use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use slog::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 const FATAL_NOLOAD: &str = "could not load snapshot";
 const FATAL_NORECV: &str = "could not receive task";
@@ -34,6 +103,19 @@ const FATAL_SNPSHT: &str = "could not snapshot";
 const FATAL_SLRSND: &str = "could not send to sealer";
 const FATAL_HUNGUP: &str = "could not send to ret channel";
 const FATAL_NOSECT: &str = "could not find sector";
+const FATAL_NOLOCK: &str = "could not acquire piece read buffer lock";
+const FATAL_NOPIEC: &str = "could not find written piece";
+
+// The fewest piece sizes from which a staged sector's fill is predicted, and
+// the confidence required that a staged sector can hold another piece before
+// provisioning a new one.
+const MIN_PIECE_HISTORY: usize = 8;
+const PREPROVISION_CONFIDENCE: f64 = 0.95;
+
+// Data tree states from the precompute pipeline are persisted with every
+// this-many of them (and with any other change to the staged sectors), rather
+// than each causing a checkpoint of its own.
+const MERKLE_TREE_STATES_PER_CHECKPOINT: usize = 16;
 
 pub struct Scheduler {
     pub thread: Option<thread::JoinHandle<()>>,
@@ -42,20 +124,132 @@ pub struct Scheduler {
 #[derive(Debug)]
 pub enum Request {
     AddPiece(String, u64, String, mpsc::SyncSender<Result<SectorId>>),
+    AddPieceIdempotent(
+        String,
+        u64,
+        String,
+        [u8; 16],
+        mpsc::SyncSender<Result<SectorId>>,
+    ),
+    DeleteSectorsBatch(
+        Vec<SectorId>,
+        bool,
+        mpsc::SyncSender<Result<BatchDeleteResult>>,
+    ),
     GetSealedSectors(mpsc::SyncSender<Result<Vec<SealedSectorMetadata>>>),
+    GetSectorsApproachingPreCommitDeadline(
+        u64,
+        u64,
+        mpsc::SyncSender<Result<Vec<SealedSectorMetadata>>>,
+    ),
+    GetSectorsByRegion([u8; 2], mpsc::SyncSender<Result<Vec<SectorId>>>),
     GetStagedSectors(mpsc::SyncSender<Result<Vec<StagedSectorMetadata>>>),
+    GetSectorFillDurations(mpsc::SyncSender<Result<Vec<(SectorId, Duration)>>>),
+    RestageSector(
+        SectorId,
+        Vec<(PieceMetadata, String)>,
+        mpsc::SyncSender<Result<SectorId>>,
+    ),
+    HandleImportedSector(ImportedSector, mpsc::SyncSender<Result<SectorId>>),
+    ListPieces(mpsc::SyncSender<Result<Vec<PieceMetadata>>>),
+    SampleSectorsForAudit([u8; 32], f64, mpsc::SyncSender<Result<Vec<SectorId>>>),
+    RenderStateDiagram(mpsc::SyncSender<Result<String>>),
+    SubscribeStateDiagramUpdates(mpsc::SyncSender<Result<mpsc::Receiver<String>>>),
     GetSealStatus(SectorId, mpsc::SyncSender<Result<SealStatus>>),
     GeneratePoSt(
         Vec<[u8; 32]>,
         [u8; 32],
         mpsc::SyncSender<Result<GeneratePoStDynamicSectorsCountOutput>>,
     ),
-    RetrievePiece(String, mpsc::SyncSender<Result<Vec<u8>>>),
+    GeneratePieceManifest(PieceManifestFilter, mpsc::SyncSender<Result<PieceManifest>>),
+    PrefetchPiece(
+        String,
+        Option<PieceCapabilityToken>,
+        mpsc::SyncSender<Result<()>>,
+    ),
+    GetReadablePieceSector(
+        String,
+        Option<PieceCapabilityToken>,
+        mpsc::SyncSender<Result<SealedSectorMetadata>>,
+    ),
+    GetExportableSector(
+        SectorId,
+        Vec<PieceCapabilityToken>,
+        mpsc::SyncSender<Result<SealedSectorMetadata>>,
+    ),
+    ReserveSectorIdRange(u32, mpsc::SyncSender<Result<(SectorId, SectorId)>>),
+    RestrictPieceAccess(String, [u8; 32], mpsc::SyncSender<Result<()>>),
+    RedactPiece(String, mpsc::SyncSender<Result<RedactionReceipt>>),
+    RetrievePiece(
+        String,
+        Option<PieceCapabilityToken>,
+        Instant,
+        mpsc::SyncSender<Result<(Vec<u8>, PieceTelemetry)>>,
+    ),
     SealAllStagedSectors(mpsc::SyncSender<Result<()>>),
+    CheckHealth(mpsc::SyncSender<(Vec<Discrepancy>, Vec<StagedSectorMetadata>)>),
+    GetLastCrc32s(
+        Vec<SectorId>,
+        mpsc::SyncSender<Vec<Option<(u32, SystemTime)>>>,
+    ),
+    SealSector(
+        SectorId,
+        Option<ApiVersion>,
+        bool,
+        mpsc::SyncSender<Result<()>>,
+    ),
     HandleSealResult(SectorId, Box<Result<SealedSectorMetadata>>),
+    HandleMerkleTreeState(SectorId, Box<MerkleTreeState>),
+    HandlePieceWritten(Box<Result<WrittenPieces>>),
+    HandleArchiveReceipt(SectorId, ArchiveReceipt, mpsc::SyncSender<Result<()>>),
+    HandleMerkleSnapshot(SectorId, mpsc::SyncSender<Result<()>>),
+    HandleScheduledPoSt(
+        Vec<[u8; 32]>,
+        [u8; 32],
+        Box<GeneratePoStDynamicSectorsCountOutput>,
+    ),
     Shutdown,
 }
 
+impl Request {
+    // Whether handling the request involves the sealed sectors, which (if the
+    // persisted state has been compacted) must first be loaded.
+    fn needs_sealed_state(&self) -> bool {
+        match self {
+            Request::AddPiece(..)
+            | Request::AddPieceIdempotent(..)
+            | Request::ReserveSectorIdRange(..)
+            | Request::GetStagedSectors(..)
+            | Request::GetSectorFillDurations(..)
+            | Request::HandleMerkleTreeState(..)
+            | Request::HandlePieceWritten(..)
+            | Request::HandleScheduledPoSt(..)
+            | Request::Shutdown => false,
+            _ => true,
+        }
+    }
+
+    // Whether handling the request could touch a staged sector to which the
+    // piece writer is writing, and so must wait until the write is handled.
+    // Shutting down waits too, so that written pieces aren't forgotten.
+    fn waits_for_piece_write(&self) -> bool {
+        match self {
+            Request::AddPiece(..)
+            | Request::AddPieceIdempotent(..)
+            | Request::RestageSector(..)
+            | Request::HandleImportedSector(..)
+            | Request::DeleteSectorsBatch(..)
+            | Request::RedactPiece(..)
+            | Request::SealSector(..)
+            | Request::SealAllStagedSectors(..)
+            | Request::CheckHealth(..)
+            | Request::GetLastCrc32s(..)
+            | Request::Shutdown => true,
+            _ => false,
+        }
+    }
+}
+
 impl Scheduler {
     #[allow(clippy::too_many_arguments)]
     pub fn start_with_metadata<T: 'static + KeyValueStore>(
@@ -64,72 +258,253 @@ impl Scheduler {
         sealer_input_tx: mpsc::Sender<SealerInput>,
         kv_store: Arc<WrappedKeyValueStore<T>>,
         sector_store: Arc<WrappedSectorStore>,
+        piece_read_buffer: Arc<Mutex<PieceReadBuffer>>,
         last_committed_sector_id: SectorId,
         max_num_staged_sectors: u8,
         prover_id: [u8; 31],
+        seal_trigger: SealTrigger,
+        piece_ingestion_histogram: Arc<Histogram>,
+        io_scheduler: Arc<IoScheduler>,
+        sealing_tracker: Arc<SealingTracker>,
+        staged_capacity: Arc<StagedCapacity>,
+        sector_locks: Arc<SectorLocks>,
+        public_key: [u8; 32],
+        config: SectorBuilderConfig,
     ) -> Scheduler {
         let thread = thread::spawn(move || {
             // Build the scheduler's initial state. If available, we
             // reconstitute this state from persisted metadata. If not, we
-            // create it from scratch.
-            let state = {
-                let loaded = load_snapshot(&kv_store, &prover_id)
-                    .expects(FATAL_NOLOAD)
-                    .map(Into::into);
-
-                loaded.unwrap_or_else(|| SectorBuilderState {
-                    prover_id,
-                    staged: StagedState {
-                        sector_id_nonce: last_committed_sector_id,
-                        sectors: Default::default(),
+            // create it from scratch. The sealed sectors of a compacted state
+            // are only loaded once they're needed.
+            let loaded = load_snapshot_lazily(&kv_store, &prover_id).expects(FATAL_NOLOAD);
+
+            let (state, compacted_sealed_sector_keys): (SectorBuilderState, _) = match loaded {
+                Some((snapshot, sealed_sector_keys)) => (snapshot.into(), sealed_sector_keys),
+                None => (
+                    SectorBuilderState {
+                        prover_id,
+                        staged: StagedState {
+                            sector_id_nonce: last_committed_sector_id,
+                            sectors: Default::default(),
+                            ..Default::default()
+                        },
+                        sealed: Default::default(),
+                        reserved_ranges: Default::default(),
+                        delta_sequence_number: 0,
+                        public_key: None,
                     },
-                    sealed: Default::default(),
-                })
+                    None,
+                ),
             };
 
+            let sealed_state_loaded = compacted_sealed_sector_keys.is_none();
+            if sealed_state_loaded {
+                check_state_consistency(&state);
+            }
+
+            let proof_index = ProofDeduplicationIndex::load(&kv_store, &prover_id)
+                .unwrap_or_else(|err| {
+                    warn!(FCP_LOG, "discarding unreadable proof deduplication index"; "error" => format!("{}", err));
+                    Default::default()
+                });
+
             let max_user_bytes_per_staged_sector = sector_store
                 .inner
                 .sector_config()
                 .max_unsealed_bytes_per_sector();
 
+            let piece_writer = PieceWriter::start(
+                scheduler_input_tx.clone(),
+                sector_store.clone(),
+                piece_ingestion_histogram,
+                io_scheduler,
+                sector_locks.clone(),
+                config.staged_sector_wal_dir.clone(),
+            );
+
             let mut m = SectorMetadataManager {
                 kv_store,
                 sector_store,
+                piece_read_buffer,
+                precompute: PrecomputePipeline::start(scheduler_input_tx.clone()),
+                piece_writer,
+                piece_write_in_flight: None,
+                deferred_requests: Default::default(),
+                sector_id_allocator: SectorIdAllocator::nonce(),
                 state,
                 sealer_input_tx,
                 scheduler_input_tx: scheduler_input_tx.clone(),
                 max_num_staged_sectors,
                 max_user_bytes_per_staged_sector,
+                seal_trigger,
+                seals_in_flight: Default::default(),
+                compacted_sealed_sector_keys,
+                sealed_state_loaded,
+                sealed_state_compaction_failed: false,
+                unpersisted_merkle_tree_states: 0,
+                abandoned_post_generators: Default::default(),
+                state_diagram_publisher: Default::default(),
+                proof_index,
+                sealing_tracker,
+                staged_capacity,
+                sector_locks,
+                fill_durations: FillDurationLog::new(config.max_fill_durations),
+                audit_log: config.audit_log_path.as_ref().map(AuditLog::new),
+                config,
             };
 
+            m.staged_capacity
+                .publish(num_queued_sectors(&m.state.staged));
+
+            if let Err(err) = m.set_public_key(public_key) {
+                let err = format!("{}", err);
+                warn!(FCP_LOG, "failed to checkpoint sector builder public key"; "error" => err);
+            }
+
             loop {
-                let task = scheduler_input_rx.recv().expects(FATAL_NORECV);
+                let task = match m.take_deferred_request() {
+                    Some(task) => task,
+                    None => scheduler_input_rx.recv().expects(FATAL_NORECV),
+                };
+
+                if m.piece_write_in_flight.is_some() && task.waits_for_piece_write() {
+                    m.deferred_requests.push_back(task);
+                    continue;
+                }
+
+                if task.needs_sealed_state() {
+                    m.load_sealed_state();
+                }
 
                 // Dispatch to the appropriate task-handler.
                 match task {
-                    Request::AddPiece(key, amt, path, tx) => {
-                        tx.send(m.add_piece(key, amt, path)).expects(FATAL_NOSEND);
+                    Request::AddPiece(key, amt, path, tx) => m.add_piece(key, amt, path, tx),
+                    Request::RestageSector(sector_id, pieces, tx) => {
+                        m.restage_sector(sector_id, pieces, tx)
+                    }
+                    Request::HandleImportedSector(imported, tx) => {
+                        tx.send(m.handle_imported_sector(imported))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::AddPieceIdempotent(key, amt, path, idempotency_key, tx) => {
+                        m.add_piece_idempotent(key, amt, path, idempotency_key, tx)
+                    }
+                    Request::DeleteSectorsBatch(sector_ids, force, tx) => {
+                        tx.send(m.delete_sectors_batch(&sector_ids, force))
+                            .expects(FATAL_NOSEND);
                     }
                     Request::GetSealStatus(sector_id, tx) => {
                         tx.send(m.get_seal_status(sector_id)).expects(FATAL_NOSEND);
                     }
-                    Request::RetrievePiece(piece_key, tx) => m.retrieve_piece(piece_key, tx),
+                    Request::PrefetchPiece(piece_key, token, tx) => {
+                        tx.send(m.prefetch_piece(piece_key, token))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::GetReadablePieceSector(piece_key, token, tx) => {
+                        tx.send(m.get_readable_piece_sector(&piece_key, token.as_ref()))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::GetExportableSector(sector_id, tokens, tx) => {
+                        tx.send(m.get_exportable_sector(sector_id, &tokens))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::ReserveSectorIdRange(count, tx) => {
+                        tx.send(m.reserve_sector_id_range(count))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::RestrictPieceAccess(piece_key, access_token_hash, tx) => {
+                        tx.send(m.restrict_piece_access(piece_key, access_token_hash))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::RedactPiece(piece_key, tx) => {
+                        tx.send(m.redact_piece(piece_key)).expects(FATAL_NOSEND);
+                    }
+                    Request::RetrievePiece(piece_key, token, started_at, tx) => {
+                        m.retrieve_piece(piece_key, token, started_at, tx)
+                    }
                     Request::GetSealedSectors(tx) => {
                         tx.send(m.get_sealed_sectors()).expects(FATAL_NOSEND);
                     }
+                    Request::GetSectorsByRegion(country_code, tx) => {
+                        tx.send(m.get_sectors_by_region(country_code))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::SampleSectorsForAudit(rng_seed, sample_fraction, tx) => {
+                        tx.send(m.sample_sectors_for_audit(rng_seed, sample_fraction))
+                            .expects(FATAL_NOSEND);
+                    }
                     Request::GetStagedSectors(tx) => {
                         tx.send(m.get_staged_sectors()).expect(FATAL_NOSEND);
                     }
+                    Request::GetSectorFillDurations(tx) => {
+                        tx.send(Ok(m.fill_durations.durations()))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::ListPieces(tx) => {
+                        tx.send(m.list_pieces()).expects(FATAL_NOSEND);
+                    }
+                    Request::RenderStateDiagram(tx) => {
+                        tx.send(Ok(m.render_state_diagram())).expects(FATAL_NOSEND);
+                    }
+                    Request::SubscribeStateDiagramUpdates(tx) => {
+                        tx.send(Ok(m.subscribe_state_diagram_updates()))
+                            .expects(FATAL_NOSEND);
+                    }
                     Request::SealAllStagedSectors(tx) => {
                         tx.send(m.seal_all_staged_sectors()).expects(FATAL_NOSEND);
                     }
+                    Request::GetSectorsApproachingPreCommitDeadline(epoch, warning_epochs, tx) => {
+                        tx.send(
+                            m.get_sectors_approaching_pre_commit_deadline(epoch, warning_epochs),
+                        )
+                        .expects(FATAL_NOSEND);
+                    }
+                    Request::CheckHealth(tx) => {
+                        tx.send(m.check_health()).expects(FATAL_NOSEND);
+                    }
+                    Request::GetLastCrc32s(sector_ids, tx) => {
+                        tx.send(m.get_last_crc32s(&sector_ids))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::SealSector(sector_id, api_version, force, tx) => {
+                        tx.send(m.seal_sector(sector_id, api_version, force))
+                            .expects(FATAL_NOSEND);
+                    }
                     Request::HandleSealResult(sector_id, result) => {
                         m.handle_seal_result(sector_id, *result);
                     }
+                    Request::HandleMerkleTreeState(sector_id, state) => {
+                        m.handle_merkle_tree_state(sector_id, *state);
+                    }
+                    Request::HandlePieceWritten(result) => {
+                        m.handle_piece_written(*result);
+                    }
+                    Request::HandleArchiveReceipt(sector_id, receipt, tx) => {
+                        tx.send(m.handle_archive_receipt(sector_id, receipt))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::HandleMerkleSnapshot(sector_id, tx) => {
+                        tx.send(m.handle_merkle_snapshot(sector_id))
+                            .expects(FATAL_NOSEND);
+                    }
+                    Request::GeneratePieceManifest(filter, tx) => {
+                        tx.send(m.generate_piece_manifest(&filter))
+                            .expects(FATAL_NOSEND);
+                    }
                     Request::GeneratePoSt(comm_rs, chg_seed, tx) => {
                         m.generate_post(&comm_rs, &chg_seed, tx)
                     }
-                    Request::Shutdown => break,
+                    Request::HandleScheduledPoSt(comm_rs, chg_seed, output) => {
+                        m.handle_scheduled_post(&comm_rs, &chg_seed, &output)
+                    }
+                    Request::Shutdown => {
+                        // Persist any data tree states still batched up.
+                        if m.unpersisted_merkle_tree_states > 0 {
+                            m.checkpoint().expects(FATAL_SNPSHT);
+                        }
+
+                        break;
+                    }
                 }
             }
         });
@@ -140,6 +515,20 @@ impl Scheduler {
     }
 }
 
+// What's to be done once the piece writer has written a staged sector's
+// pieces.
+enum PieceWriteFollowUp {
+    // a piece added by add_piece (or, with its idempotency key, by
+    // add_piece_idempotent)
+    AddPiece(Option<[u8; 16]>, mpsc::SyncSender<Result<SectorId>>),
+    // the pieces of a sealed sector, restaged by restage_sector
+    RestageSector(
+        SectorId,
+        Vec<PieceMetadata>,
+        mpsc::SyncSender<Result<SectorId>>,
+    ),
+}
+
 // The SectorBuilderStateManager is the owner of all sector-related metadata.
 // It dispatches expensive operations (e.g. unseal and seal) to the sealer
 // worker-threads. Other, inexpensive work (or work which needs to be performed
@@ -147,20 +536,60 @@ impl Scheduler {
 pub struct SectorMetadataManager<T: KeyValueStore> {
     kv_store: Arc<WrappedKeyValueStore<T>>,
     sector_store: Arc<WrappedSectorStore>,
+    piece_read_buffer: Arc<Mutex<PieceReadBuffer>>,
+    precompute: PrecomputePipeline,
+    piece_writer: PieceWriter,
+    // The pieces being written by the piece writer, if any, and what's to be
+    // done once they've been written.
+    piece_write_in_flight: Option<(PieceWrite, PieceWriteFollowUp)>,
+    // Requests which arrived while pieces were being written, and which wait
+    // for the write to be handled (see Request::waits_for_piece_write).
+    deferred_requests: VecDeque<Request>,
+    sector_id_allocator: SectorIdAllocator,
     state: SectorBuilderState,
     sealer_input_tx: mpsc::Sender<SealerInput>,
     scheduler_input_tx: mpsc::SyncSender<Request>,
     max_num_staged_sectors: u8,
     max_user_bytes_per_staged_sector: UnpaddedBytesAmount,
+    seal_trigger: SealTrigger,
+    // The sectors whose seal has been scheduled, but whose result hasn't yet
+    // been handled.
+    seals_in_flight: HashSet<SectorId>,
+    // The keys of the sealed sectors' entries, once the persisted state has
+    // been compacted (see compact_sealed_state).
+    compacted_sealed_sector_keys: Option<Vec<SealedSectorKey>>,
+    // Whether the sealed sectors of a compacted state have been loaded.
+    sealed_state_loaded: bool,
+    sealed_state_compaction_failed: bool,
+    unpersisted_merkle_tree_states: usize,
+    abandoned_post_generators: Arc<AtomicUsize>,
+    state_diagram_publisher: StateDiagramPublisher,
+    proof_index: ProofDeduplicationIndex,
+    sealing_tracker: Arc<SealingTracker>,
+    staged_capacity: Arc<StagedCapacity>,
+    sector_locks: Arc<SectorLocks>,
+    fill_durations: FillDurationLog,
+    // Kept for the life of the scheduler, so that the log's tail is read
+    // only once.
+    audit_log: Option<AuditLog>,
+    config: SectorBuilderConfig,
 }
 
 impl<T: KeyValueStore> SectorMetadataManager<T> {
     pub fn generate_post(
-        &self,
+        &mut self,
         comm_rs: &[[u8; 32]],
         challenge_seed: &[u8; 32],
         return_channel: mpsc::SyncSender<Result<GeneratePoStDynamicSectorsCountOutput>>,
     ) {
+        // There's nothing to prove until a sector has been sealed.
+        if self.state.sealed.sectors.is_empty() {
+            return_channel
+                .send(Err(err_unrecov("no sealed sectors to prove").into()))
+                .expects(FATAL_HUNGUP);
+            return;
+        }
+
         // reduce our sealed sector state-map to a mapping of comm_r to sealed
         // sector access (AKA path to sealed sector file)
         let comm_r_to_sector_access: HashMap<[u8; 32], String> = self
@@ -186,44 +615,183 @@ impl<T: KeyValueStore> SectorMetadataManager<T> {
         let mut seed = [0; 32];
         seed.copy_from_slice(challenge_seed);
 
-        let output = internal::generate_post(GeneratePoStDynamicSectorsCountInput {
+        let input = GeneratePoStDynamicSectorsCountInput {
             post_config: self.sector_store.inner.proofs_config().post_config(),
             challenge_seed: seed,
             input_parts,
+        };
+
+        let timeout = self.config.post_generation_timeout;
+        let abandoned_post_generators = self.abandoned_post_generators.clone();
+
+        let output = generate_post_deduplicated(
+            &mut self.proof_index,
+            self.config.max_cached_proofs,
+            comm_rs,
+            challenge_seed,
+            || {
+                generate_post_with_timeout(timeout, &abandoned_post_generators, move |_| {
+                    internal::generate_post(input)
+                })
+            },
+        )
+        .map(|(output, index_changed)| {
+            if index_changed {
+                if let Err(err) = self.proof_index.persist(&self.kv_store, &self.state.prover_id) {
+                    warn!(FCP_LOG, "failed to persist proof deduplication index"; "error" => format!("{}", err));
+                }
+            }
+
+            output
         });
 
         // TODO: Where should this work be scheduled? New worker type?
         return_channel.send(output).expects(FATAL_HUNGUP);
     }
 
+    // Caches a PoSt generated by the PoSt scheduler (on its own thread), so
+    // that a later request for the same challenge is served from the proof
+    // deduplication index.
+    pub fn handle_scheduled_post(
+        &mut self,
+        comm_rs: &[[u8; 32]],
+        challenge_seed: &[u8; 32],
+        output: &GeneratePoStDynamicSectorsCountOutput,
+    ) {
+        let index_changed = record_post(
+            &mut self.proof_index,
+            self.config.max_cached_proofs,
+            comm_rs,
+            challenge_seed,
+            output,
+        )
+        .and_then(|index_changed| {
+            if index_changed {
+                self.proof_index
+                    .persist(&self.kv_store, &self.state.prover_id)?;
+            }
+
+            Ok(index_changed)
+        });
+
+        if let Err(err) = index_changed {
+            warn!(FCP_LOG, "failed to record scheduled PoSt"; "error" => format!("{}", err));
+        }
+    }
+
+    // Schedules the sector containing the referenced piece to be unsealed into
+    // the piece read buffer. Produces an error if this sector builder does not
+    // have a sealed sector containing the referenced piece, or if the piece's
+    // access is restricted and the provided token doesn't grant it.
+    pub fn prefetch_piece(
+        &self,
+        piece_key: String,
+        token: Option<PieceCapabilityToken>,
+    ) -> Result<()> {
+        let sealed_sector = Box::new(self.get_readable_piece_sector(&piece_key, token.as_ref())?);
+        let task = SealerInput::Prefetch(piece_key, sealed_sector);
+
+        self.sealer_input_tx
+            .clone()
+            .send(task)
+            .expects(FATAL_SLRSND);
+
+        Ok(())
+    }
+
     // Unseals the sector containing the referenced piece and returns its
     // bytes. Produces an error if this sector builder does not have a sealed
-    // sector containing the referenced piece.
+    // sector containing the referenced piece, if the piece has been redacted,
+    // or if the piece's access is restricted and the provided token doesn't
+    // grant it.
     pub fn retrieve_piece(
         &self,
         piece_key: String,
-        return_channel: mpsc::SyncSender<Result<Vec<u8>>>,
+        token: Option<PieceCapabilityToken>,
+        started_at: Instant,
+        return_channel: mpsc::SyncSender<Result<(Vec<u8>, PieceTelemetry)>>,
     ) {
-        let opt_sealed_sector = self.state.sealed.sectors.values().find(|sector| {
-            sector
-                .pieces
-                .iter()
-                .any(|piece| piece.piece_key == piece_key)
-        });
+        match self.get_readable_piece_sector(&piece_key, token.as_ref()) {
+            Ok(sealed_sector) => {
+                let sealed_sector = Box::new(sealed_sector);
+                let task =
+                    SealerInput::Unseal(piece_key, sealed_sector, started_at, return_channel);
+
+                self.sealer_input_tx
+                    .clone()
+                    .send(task)
+                    .expects(FATAL_SLRSND);
+            }
+            Err(err) => return_channel.send(Err(err)).expects(FATAL_HUNGUP),
+        }
+    }
+
+    // Returns the sealed sector from which the referenced piece may be read.
+    // Produces an error if this sector builder does not have a sealed sector
+    // containing the referenced piece, if the piece has been redacted, or if
+    // the piece's access is restricted and the provided token doesn't grant
+    // it. Every read of a sealed piece, whether from its local or its archived
+    // replica, is checked here.
+    pub fn get_readable_piece_sector(
+        &self,
+        piece_key: &str,
+        token: Option<&PieceCapabilityToken>,
+    ) -> Result<SealedSectorMetadata> {
+        if is_piece_redacted(&self.state.staged, piece_key) {
+            return Err(err_piece_redacted(piece_key.to_string()).into());
+        }
 
-        if let Some(sealed_sector) = opt_sealed_sector {
-            let sealed_sector = Box::new(sealed_sector.clone());
-            let task = SealerInput::Unseal(piece_key, sealed_sector, return_channel);
+        let sealed_sector = self
+            .find_sealed_sector(piece_key)
+            .ok_or_else(|| err_piecenotfound(piece_key.to_string()))?;
 
-            self.sealer_input_tx
-                .clone()
-                .send(task)
-                .expects(FATAL_SLRSND);
-        } else {
-            return_channel
-                .send(Err(err_piecenotfound(piece_key.to_string()).into()))
-                .expects(FATAL_HUNGUP);
+        let piece = sealed_sector
+            .pieces
+            .iter()
+            .find(|piece| piece.piece_key == piece_key)
+            .expects(FATAL_NOSECT);
+
+        check_piece_access(piece, token)?;
+
+        Ok(sealed_sector.clone())
+    }
+
+    // Returns the sealed sector whose replica may be exported whole. Produces
+    // an error if there's no such sealed sector, if any of its pieces has been
+    // redacted, or if any of their access is restricted and not granted by one
+    // of the provided tokens.
+    pub fn get_exportable_sector(
+        &self,
+        sector_id: SectorId,
+        tokens: &[PieceCapabilityToken],
+    ) -> Result<SealedSectorMetadata> {
+        let sealed_sector = self
+            .state
+            .sealed
+            .sectors
+            .get(&sector_id)
+            .ok_or_else(|| err_unrecov(format!("no sealed sector with id {}", sector_id)))?;
+
+        if let Some(piece) = sealed_sector
+            .pieces
+            .iter()
+            .find(|piece| is_piece_redacted(&self.state.staged, &piece.piece_key))
+        {
+            return Err(err_piece_redacted(piece.piece_key.clone()).into());
         }
+
+        check_pieces_access(&sealed_sector.pieces, tokens)?;
+
+        Ok(sealed_sector.clone())
+    }
+
+    // Produces an unsigned manifest of the sealed pieces matching the filter.
+    pub fn generate_piece_manifest(&self, filter: &PieceManifestFilter) -> Result<PieceManifest> {
+        Ok(generate_piece_manifest(
+            &self.state.prover_id,
+            &self.state.sealed,
+            filter,
+        ))
     }
 
     // Returns sealing status for the sector with specified id. If no sealed or
@@ -232,119 +800,1207 @@ impl<T: KeyValueStore> SectorMetadataManager<T> {
         get_seal_status(&self.state.staged, &self.state.sealed, sector_id)
     }
 
-    // Write the piece to storage, obtaining the sector id with which the
-    // piece-bytes are now associated.
+    // Writes the piece to storage (see PieceWriter), replying with the id of
+    // the sector with which the piece-bytes are associated once they've been
+    // written.
     pub fn add_piece(
         &mut self,
         piece_key: String,
         piece_bytes_amount: u64,
         piece_path: String,
-    ) -> Result<u64> {
-        let destination_sector_id = add_piece(
-            &self.sector_store,
-            &mut self.state.staged,
+        return_channel: mpsc::SyncSender<Result<SectorId>>,
+    ) {
+        self.write_piece(
             piece_key,
             piece_bytes_amount,
             piece_path,
+            None,
+            return_channel,
+        )
+    }
+
+    // Writes the pieces of a sealed sector (read from the provided paths) to a
+    // new staged sector of their own, which is scheduled for sealing once
+    // they've been written (see sector_restaged). The new sector has its own
+    // id, so it's sealed into a new replica. The sealed sector is left in
+    // place, but gives up its pieces to the new sector.
+    pub fn restage_sector(
+        &mut self,
+        sealed_sector_id: SectorId,
+        pieces: Vec<(PieceMetadata, String)>,
+        return_channel: mpsc::SyncSender<Result<SectorId>>,
+    ) {
+        let restageable = check_staged_capacity(&self.state.staged, self.config.max_staged_sectors)
+            .and_then(|_| self.check_sealed_pieces(sealed_sector_id));
+
+        if let Err(err) = restageable {
+            return return_channel.send(Err(err)).expects(FATAL_NOSEND);
+        }
+
+        let sector_access_root = self
+            .config
+            .sector_access_root
+            .as_ref()
+            .map(PathBuf::as_path);
+
+        let provisioned = provision_new_staged_sector(
+            self.sector_store.inner.manager(),
+            &mut self.state.staged,
+            &mut self.state.reserved_ranges,
+            &mut self.sector_id_allocator,
+            sector_access_root,
+        )
+        .and_then(|sector_id| {
+            let sector_access = self.state.staged.sectors[&sector_id].sector_access.clone();
+            validate_sector_access(&sector_access, sector_access_root)?;
+
+            Ok((sector_id, sector_access))
+        });
+
+        match provisioned {
+            Ok((sector_id, sector_access)) => {
+                let operation =
+                    StateOperation::sector_provisioned(&self.state.staged.sectors[&sector_id]);
+                self.export(operation);
+
+                let write = PieceWrite {
+                    sector_id,
+                    sector_access,
+                    pieces: pieces
+                        .iter()
+                        .map(|(piece, piece_path)| {
+                            (piece.piece_key.clone(), piece.num_bytes, piece_path.clone())
+                        })
+                        .collect(),
+                    retain_bytes: false,
+                };
+
+                let pieces = pieces.into_iter().map(|(piece, _)| piece).collect();
+
+                self.start_piece_write(
+                    write,
+                    PieceWriteFollowUp::RestageSector(sealed_sector_id, pieces, return_channel),
+                );
+            }
+            Err(err) => return_channel.send(Err(err)).expects(FATAL_NOSEND),
+        }
+    }
+
+    // Adds a sector, whose pieces were written and verified on the caller's
+    // thread (see helpers::import_sector), to the staged sectors. If it can't
+    // be added, the caller deletes its access.
+    pub fn handle_imported_sector(&mut self, imported: ImportedSector) -> Result<SectorId> {
+        check_staged_capacity(&self.state.staged, self.config.max_staged_sectors)?;
+
+        let piece_manifest = imported.pieces.clone();
+
+        let sector_id = commit_imported_sector(
+            &mut self.state.staged,
+            &self.state.sealed,
+            &mut self.state.reserved_ranges,
+            &mut self.sector_id_allocator,
+            self.config
+                .sector_access_root
+                .as_ref()
+                .map(PathBuf::as_path),
+            imported,
         )?;
 
+        {
+            let mut piece_read_buffer = self.piece_read_buffer.lock().expects(FATAL_NOLOCK);
+            for piece in &piece_manifest {
+                piece_read_buffer.invalidate(&piece.piece_key);
+            }
+        }
+
+        for piece in piece_manifest {
+            let operation =
+                StateOperation::add_piece(&self.state.staged.sectors[&sector_id], piece);
+            self.export(operation);
+
+            if let Some(ref metrics_sink) = self.config.metrics_sink {
+                metrics_sink.piece_added();
+            }
+        }
+
+        info!(FCP_LOG, "imported sector"; "sector_id" => sector_id);
+
+        // An imported sector which is full is sealed like any other.
         self.check_and_schedule(false)?;
         self.checkpoint()?;
 
-        Ok(destination_sector_id)
+        Ok(sector_id)
     }
 
-    // For demo purposes. Schedules sealing of all staged sectors.
-    pub fn seal_all_staged_sectors(&mut self) -> Result<()> {
-        self.check_and_schedule(true)?;
-        self.checkpoint()
+    // Like add_piece, except that a piece added again with the same
+    // idempotency key (before the key expires) isn't written again: the id of
+    // the sector to which it was first written is returned.
+    pub fn add_piece_idempotent(
+        &mut self,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+        idempotency_key: [u8; 16],
+        return_channel: mpsc::SyncSender<Result<SectorId>>,
+    ) {
+        match self.idempotently_added_to(&idempotency_key) {
+            Ok(None) => self.write_piece(
+                piece_key,
+                piece_bytes_amount,
+                piece_path,
+                Some(idempotency_key),
+                return_channel,
+            ),
+            Ok(Some(sector_id)) => return_channel.send(Ok(sector_id)).expects(FATAL_NOSEND),
+            Err(err) => return_channel.send(Err(err)).expects(FATAL_NOSEND),
+        }
     }
 
-    // Produces a vector containing metadata for all sealed sectors that this
-    // SectorBuilder knows about.
-    pub fn get_sealed_sectors(&self) -> Result<Vec<SealedSectorMetadata>> {
-        Ok(self.state.sealed.sectors.values().cloned().collect())
-    }
+    // Forgets expired idempotency keys, then produces the sector to which a
+    // piece added with the given key was written, if any.
+    fn idempotently_added_to(&mut self, idempotency_key: &[u8; 16]) -> Result<Option<SectorId>> {
+        let now = SystemTime::now();
 
-    // Produces a vector containing metadata for all staged sectors that this
-    // SectorBuilder knows about.
-    pub fn get_staged_sectors(&self) -> Result<Vec<StagedSectorMetadata>> {
-        Ok(self.state.staged.sectors.values().cloned().collect())
+        let expired = expire_idempotency_keys(&mut self.state.staged, now);
+        if !expired.is_empty() {
+            self.export(StateOperation::IdempotencyKeysExpired {
+                idempotency_keys: expired,
+            });
+            self.checkpoint()?;
+        }
+
+        Ok(idempotent_add_sector_id(
+            &self.state.staged,
+            idempotency_key,
+        ))
     }
 
-    // Update metadata to reflect the sealing results.
-    pub fn handle_seal_result(
+    // Chooses the staged sector to which the piece is to be written and hands
+    // the piece to the piece writer. The piece is recorded, and the caller
+    // replied to, once it has been written (see piece_written).
+    fn write_piece(
         &mut self,
-        sector_id: SectorId,
-        result: Result<SealedSectorMetadata>,
+        piece_key: String,
+        piece_bytes_amount: u64,
+        piece_path: String,
+        idempotency_key: Option<[u8; 16]>,
+        return_channel: mpsc::SyncSender<Result<SectorId>>,
     ) {
-        // scope exists to end the mutable borrow of self so that we can
-        // checkpoint
-        {
-            let staged_state = &mut self.state.staged;
-            let sealed_state = &mut self.state.sealed;
-
-            if result.is_err() {
-                if let Some(staged_sector) = staged_state.sectors.get_mut(&sector_id) {
-                    staged_sector.seal_status =
-                        SealStatus::Failed(format!("{}", err_unrecov(result.unwrap_err())));
+        match self.choose_piece_destination(&piece_key, piece_bytes_amount) {
+            Ok((sector_id, sector_access)) => {
+                let write = PieceWrite {
+                    sector_id,
+                    sector_access,
+                    pieces: vec![(
+                        piece_key,
+                        UnpaddedBytesAmount(piece_bytes_amount),
+                        piece_path,
+                    )],
+                    retain_bytes: true,
                 };
-            } else {
-                // Remove the staged sector from the state map.
-                let _ = staged_state.sectors.remove(&sector_id);
 
-                // Insert the newly-sealed sector into the other state map.
-                let sealed_sector = result.expects(FATAL_SECMAP);
+                self.start_piece_write(
+                    write,
+                    PieceWriteFollowUp::AddPiece(idempotency_key, return_channel),
+                );
+            }
+            Err(err) => return_channel.send(Err(err)).expects(FATAL_NOSEND),
+        }
+    }
+
+    // Produces the id and access of the staged sector to which a piece of the
+    // given size is to be written, provisioning one if need be.
+    fn choose_piece_destination(
+        &mut self,
+        piece_key: &str,
+        piece_bytes_amount: u64,
+    ) -> Result<(SectorId, String)> {
+        check_staged_capacity(&self.state.staged, self.config.max_staged_sectors)?;
+
+        // Any bytes buffered for a piece with this key are now stale.
+        self.piece_read_buffer
+            .lock()
+            .expects(FATAL_NOLOCK)
+            .invalidate(piece_key);
+
+        let sector_access_root = self
+            .config
+            .sector_access_root
+            .as_ref()
+            .map(PathBuf::as_path);
+
+        let sector_id = piece_destination(
+            &self.sector_store,
+            &mut self.state.staged,
+            &mut self.state.reserved_ranges,
+            &mut self.sector_id_allocator,
+            piece_bytes_amount,
+            sector_access_root,
+        )?;
+
+        let sector_access = self.state.staged.sectors[&sector_id].sector_access.clone();
+        validate_sector_access(&sector_access, sector_access_root)?;
+
+        Ok((sector_id, sector_access))
+    }
+
+    fn start_piece_write(&mut self, write: PieceWrite, follow_up: PieceWriteFollowUp) {
+        self.piece_writer.write(write.clone());
+        self.piece_write_in_flight = Some((write, follow_up));
+    }
+
+    // Follows up on the piece writer having written (or failed to write) the
+    // pieces in flight, replying to whoever asked for them to be written.
+    pub fn handle_piece_written(&mut self, written: Result<WrittenPieces>) {
+        let (write, follow_up) = match self.piece_write_in_flight.take() {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
 
-                sealed_state.sectors.insert(sector_id, sealed_sector);
+        match follow_up {
+            PieceWriteFollowUp::AddPiece(idempotency_key, return_channel) => {
+                let result = self.piece_written(write, idempotency_key, written);
+                return_channel.send(result).expects(FATAL_NOSEND);
+            }
+            PieceWriteFollowUp::RestageSector(sealed_sector_id, pieces, return_channel) => {
+                let result = self.sector_restaged(sealed_sector_id, write, pieces, written);
+                return_channel.send(result).expects(FATAL_NOSEND);
             }
         }
+    }
 
-        self.checkpoint().expects(FATAL_SNPSHT);
+    // The oldest request deferred while pieces were being written, once
+    // they've been written.
+    fn take_deferred_request(&mut self) -> Option<Request> {
+        if self.piece_write_in_flight.is_some() {
+            None
+        } else {
+            self.deferred_requests.pop_front()
+        }
     }
 
-    // Check for sectors which should no longer receive new user piece-bytes and
-    // schedule them for sealing.
-    fn check_and_schedule(&mut self, seal_all_staged_sectors: bool) -> Result<()> {
-        let staged_state = &mut self.state.staged;
+    // Records the piece written for add_piece (or add_piece_idempotent).
+    fn piece_written(
+        &mut self,
+        write: PieceWrite,
+        idempotency_key: Option<[u8; 16]>,
+        written: Result<WrittenPieces>,
+    ) -> Result<SectorId> {
+        let written = written?;
+        let sector_id = write.sector_id;
 
-        let to_be_sealed = get_sectors_ready_for_sealing(
-            staged_state,
-            self.max_user_bytes_per_staged_sector,
-            self.max_num_staged_sectors,
-            seal_all_staged_sectors,
-        );
+        let (piece_key, piece_bytes_len, _) = write.pieces.into_iter().next().expects(FATAL_NOPIEC);
+        let checksum = written.checksums.first().cloned().expects(FATAL_NOPIEC);
+        let piece_bytes = written.piece_bytes.into_iter().next().expects(FATAL_NOPIEC);
 
-        // Mark the to-be-sealed sectors as no longer accepting data and then
-        // schedule sealing.
-        for sector_id in to_be_sealed {
-            let mut sector = staged_state
+        {
+            let sector = self
+                .state
+                .staged
                 .sectors
                 .get_mut(&sector_id)
                 .expects(FATAL_NOSECT);
-            sector.seal_status = SealStatus::Sealing;
 
-            self.sealer_input_tx
-                .clone()
-                .send(SealerInput::Seal(
-                    sector.clone(),
-                    self.scheduler_input_tx.clone(),
-                ))
-                .expects(FATAL_SLRSND);
+            push_written_piece(sector, piece_key, piece_bytes_len, checksum);
+            sector.last_crc32 = written.last_crc32;
         }
 
-        Ok(())
+        if let Some(idempotency_key) = idempotency_key {
+            let expires_at = SystemTime::now() + self.config.idempotency_key_ttl;
+            self.state
+                .staged
+                .idempotency_keys
+                .insert(idempotency_key, (sector_id, expires_at));
+
+            // Exported ahead of the piece itself so that both are checkpointed
+            // (in piece_added) along with the sequence number of their deltas.
+            self.export(StateOperation::IdempotencyKeyRecorded {
+                idempotency_key,
+                sector_id,
+                expires_at,
+            });
+        }
+
+        self.piece_added(sector_id, piece_bytes)?;
+
+        Ok(sector_id)
     }
 
-    // Create and persist metadata snapshot.
-    fn checkpoint(&self) -> Result<()> {
-        let snapshot = make_snapshot(
-            &self.state.prover_id,
-            &self.state.staged,
-            &self.state.sealed,
-        );
-        persist_snapshot(&self.kv_store, &snapshot)?;
+    // Returns an error unless the sealed sector has pieces which haven't been
+    // restaged.
+    fn check_sealed_pieces(&self, sealed_sector_id: SectorId) -> Result<()> {
+        match self.state.sealed.sectors.get(&sealed_sector_id) {
+            Some(sector) if !sector.pieces.is_empty() => Ok(()),
+            Some(_) => Err(err_unrecov(format!(
+                "sealed sector {} has no pieces to restage",
+                sealed_sector_id
+            ))
+            .into()),
+            None => {
+                Err(err_unrecov(format!("no sealed sector with id {}", sealed_sector_id)).into())
+            }
+        }
+    }
 
-        Ok(())
+    // Records the pieces written for restage_sector, retires them from the
+    // sealed sector, and schedules their sector for sealing.
+    fn sector_restaged(
+        &mut self,
+        sealed_sector_id: SectorId,
+        write: PieceWrite,
+        pieces: Vec<PieceMetadata>,
+        written: Result<WrittenPieces>,
+    ) -> Result<SectorId> {
+        let sector_id = write.sector_id;
+
+        // A partly-restaged sector would otherwise be filled with other
+        // pieces, alongside copies of some of the sealed sector's. So would a
+        // sector whose pieces were restaged (or deleted) while these were
+        // being written.
+        let written = written.and_then(|written| {
+            self.check_sealed_pieces(sealed_sector_id)?;
+            Ok(written)
+        });
+
+        let written = match written {
+            Ok(written) => written,
+            Err(err) => {
+                if let Some(staged_sector) = self.state.staged.sectors.remove(&sector_id) {
+                    let _ = self
+                        .sector_store
+                        .inner
+                        .manager()
+                        .delete_staging_sector_access(&staged_sector.sector_access);
+
+                    self.export(StateOperation::ProvisionedSectorDiscarded { sector_id });
+                    self.checkpoint()?;
+                }
+
+                return Err(err);
+            }
+        };
+
+        let operations: Vec<StateOperation> = {
+            let staged_sector = self
+                .state
+                .staged
+                .sectors
+                .get_mut(&sector_id)
+                .expects(FATAL_NOSECT);
+
+            let restaged_pieces = pieces
+                .into_iter()
+                .zip(written.checksums)
+                .map(|(piece, checksum)| {
+                    push_written_piece(staged_sector, piece.piece_key, piece.num_bytes, checksum);
+
+                    // A piece whose access was restricted stays restricted.
+                    let restaged = staged_sector
+                        .pieces
+                        .values_mut()
+                        .next_back()
+                        .expects(FATAL_NOSECT);
+                    restaged.access_token_hash = piece.access_token_hash;
+
+                    restaged.clone()
+                })
+                .collect::<Vec<_>>();
+
+            // The sector's CRC32 is exported with each of its pieces.
+            staged_sector.last_crc32 = written.last_crc32;
+
+            restaged_pieces
+                .into_iter()
+                .map(|piece| StateOperation::add_piece(staged_sector, piece))
+                .collect::<Vec<_>>()
+        };
+
+        for operation in operations {
+            self.export(operation);
+        }
+
+        // The pieces now belong to the new sector alone. Both changes are
+        // checkpointed together, below.
+        self.state
+            .sealed
+            .sectors
+            .get_mut(&sealed_sector_id)
+            .expects(FATAL_NOSECT)
+            .pieces
+            .clear();
+
+        self.export(StateOperation::SealedPiecesRetired {
+            sector_id: sealed_sector_id,
+        });
+
+        info!(FCP_LOG, "restaged sealed sector for resealing"; "sealed_sector_id" => sealed_sector_id, "sector_id" => sector_id);
+
+        self.schedule_seal(sector_id, self.config.default_api_version)?;
+        self.checkpoint()?;
+
+        Ok(sector_id)
+    }
+
+    // Follows up on a piece having been written to the staged sector: its
+    // commD is precomputed, the addition exported, and the sector scheduled
+    // for sealing if it's now full.
+    fn piece_added(&mut self, destination_sector_id: SectorId, piece_bytes: Vec<u8>) -> Result<()> {
+        // A failure to precompute commD only means that sealing will take
+        // longer; the piece has been written.
+        if let Err(err) = self.precompute.piece_added(
+            &self.sector_store,
+            &self.state.staged,
+            destination_sector_id,
+            piece_bytes,
+        ) {
+            let err = format!("{}", err);
+            warn!(FCP_LOG, "could not precompute commD"; "sector_id" => destination_sector_id, "error" => err);
+        }
+
+        if let Some(sector) = self.state.staged.sectors.get(&destination_sector_id) {
+            let operation = StateOperation::add_piece(
+                sector,
+                sector
+                    .pieces
+                    .values()
+                    .next_back()
+                    .cloned()
+                    .expects(FATAL_NOSECT),
+            );
+
+            self.export(operation);
+        }
+
+        if let Some(ref metrics_sink) = self.config.metrics_sink {
+            metrics_sink.piece_added();
+        }
+
+        self.check_and_schedule(false)?;
+        self.preprovision_staged_sector()?;
+        self.checkpoint()
+    }
+
+    // Reserves count consecutive sector ids for use by future staged sectors.
+    pub fn reserve_sector_id_range(&mut self, count: u32) -> Result<(SectorId, SectorId)> {
+        let range = reserve_sector_id_range(
+            &mut self.state.staged,
+            &mut self.state.reserved_ranges,
+            count,
+        )?;
+
+        self.export(StateOperation::SectorIdsReserved {
+            start: range.0,
+            end: range.1,
+        });
+        self.checkpoint()?;
+
+        Ok(range)
+    }
+
+    // Deletes the provided sectors, skipping those with an active deal unless
+    // force is set.
+    pub fn delete_sectors_batch(
+        &mut self,
+        sector_ids: &[SectorId],
+        force: bool,
+    ) -> Result<BatchDeleteResult> {
+        let result = delete_sectors_batch(
+            &self.sector_store,
+            &mut self.state.staged,
+            &mut self.state.sealed,
+            self.config.deal_registry.as_ref(),
+            self.audit_log.as_mut(),
+            sector_ids,
+            force,
+        );
+
+        for sector_id in &result.deleted {
+            self.precompute.discard(*sector_id);
+        }
+
+        if !result.deleted.is_empty() {
+            self.export(StateOperation::SectorsDeleted {
+                sector_ids: result.deleted.clone(),
+            });
+        }
+
+        self.checkpoint()?;
+
+        Ok(result)
+    }
+
+    // For demo purposes. Schedules sealing of all staged sectors, producing a
+    // SealAlreadyInProgress error (once the others have been scheduled) if
+    // one of them was already being sealed.
+    pub fn seal_all_staged_sectors(&mut self) -> Result<()> {
+        let scheduled = self.check_and_schedule(true);
+        self.checkpoint()?;
+
+        scheduled
+    }
+
+    // Schedules sealing of a pending staged sector with a specific version of
+    // the sealing API or, if not provided, the configured default. Sectors
+    // which aren't full enough to seal are only sealed if forced.
+    pub fn seal_sector(
+        &mut self,
+        sector_id: SectorId,
+        api_version: Option<ApiVersion>,
+        force_seal_even_if_empty: bool,
+    ) -> Result<()> {
+        if self.seals_in_flight.contains(&sector_id) {
+            return Err(err_seal_in_progress(sector_id).into());
+        }
+
+        match self.state.staged.sectors.get(&sector_id) {
+            Some(sector) if sector.seal_status == SealStatus::Pending => {
+                check_seal_fill_ratio(
+                    sector,
+                    self.max_user_bytes_per_staged_sector,
+                    self.config.min_seal_fill_ratio,
+                    force_seal_even_if_empty,
+                )?;
+            }
+            Some(_) => {
+                return Err(
+                    err_unrecov(format!("staged sector {} is not pending", sector_id)).into(),
+                );
+            }
+            None => {
+                return Err(err_unrecov(format!("no staged sector with id {}", sector_id)).into());
+            }
+        }
+
+        let api_version = api_version.unwrap_or(self.config.default_api_version);
+
+        self.schedule_seal(sector_id, api_version)?;
+        self.checkpoint()
+    }
+
+    // Compares sector metadata to the sectors on disk, and produces the
+    // staged sectors whose CRC32s are to be checked. Those are checked by the
+    // caller (see check_staged_sector_crc32s), as doing so reads the sectors'
+    // files in full.
+    pub fn check_health(&self) -> (Vec<Discrepancy>, Vec<StagedSectorMetadata>) {
+        let discrepancies = check_sector_builder_health(
+            &self.state.staged,
+            &self.state.sealed,
+            u64::from(self.sector_store.inner.sector_config().sector_bytes()),
+            self.config.sector_access_root.as_ref().map(|x| x.as_path()),
+        );
+
+        (discrepancies, staged_sectors_to_check(&self.state.staged))
+    }
+
+    // Produces the CRC32 last recorded for each of the staged sectors, if
+    // it's still accepting data.
+    pub fn get_last_crc32s(&self, sector_ids: &[SectorId]) -> Vec<Option<(u32, SystemTime)>> {
+        sector_ids
+            .iter()
+            .map(|sector_id| {
+                self.state
+                    .staged
+                    .sectors
+                    .get(sector_id)
+                    .filter(|sector| sector.seal_status == SealStatus::Pending)
+                    .and_then(|sector| sector.last_crc32)
+            })
+            .collect()
+    }
+
+    // Produces a vector containing metadata for all sealed sectors that this
+    // SectorBuilder knows about.
+    pub fn get_sealed_sectors(&self) -> Result<Vec<SealedSectorMetadata>> {
+        Ok(self.state.sealed.sectors.values().cloned().collect())
+    }
+
+    // Produces the ids of the sealed sectors which were sealed in the country
+    // with the provided (ISO 3166-1 alpha-2) code.
+    pub fn get_sectors_by_region(&self, country_code: [u8; 2]) -> Result<Vec<SectorId>> {
+        Ok(get_sectors_by_region(&self.state.sealed, country_code))
+    }
+
+    pub fn sample_sectors_for_audit(
+        &self,
+        rng_seed: [u8; 32],
+        sample_fraction: f64,
+    ) -> Result<Vec<SectorId>> {
+        Ok(sample_sectors_for_audit(
+            &self.state.sealed,
+            rng_seed,
+            sample_fraction,
+        ))
+    }
+
+    // Produces the sealed sectors whose pre-commit deadline is fewer than
+    // warning_epochs away, most urgent first.
+    pub fn get_sectors_approaching_pre_commit_deadline(
+        &self,
+        current_epoch: u64,
+        warning_epochs: u64,
+    ) -> Result<Vec<SealedSectorMetadata>> {
+        Ok(get_sectors_approaching_pre_commit_deadline(
+            &self.state.sealed,
+            current_epoch,
+            warning_epochs,
+        )
+        .into_iter()
+        .filter_map(|sector_id| self.state.sealed.sectors.get(&sector_id).cloned())
+        .collect())
+    }
+
+    // Produces a vector containing metadata for all staged sectors that this
+    // SectorBuilder knows about.
+    pub fn get_staged_sectors(&self) -> Result<Vec<StagedSectorMetadata>> {
+        Ok(self.state.staged.sectors.values().cloned().collect())
+    }
+
+    // Produces the metadata of every piece, sealed or staged. Both states are
+    // read at once, so that a piece whose sector is being sealed is listed
+    // exactly once.
+    pub fn list_pieces(&self) -> Result<Vec<PieceMetadata>> {
+        let sealed = self
+            .state
+            .sealed
+            .sectors
+            .values()
+            .flat_map(|sector| sector.pieces.iter());
+
+        let staged = self
+            .state
+            .staged
+            .sectors
+            .values()
+            .flat_map(|sector| sector.pieces.values());
+
+        Ok(sealed.chain(staged).cloned().collect())
+    }
+
+    // Update metadata to reflect the sealing results.
+    pub fn handle_seal_result(
+        &mut self,
+        sector_id: SectorId,
+        result: Result<SealedSectorMetadata>,
+    ) {
+        self.seals_in_flight.remove(&sector_id);
+
+        // scope exists to end the mutable borrow of self so that we can
+        // checkpoint
+        let operation = {
+            let staged_state = &mut self.state.staged;
+            let sealed_state = &mut self.state.sealed;
+            let config = &self.config;
+
+            if result.is_err() {
+                if let Some(staged_sector) = staged_state.sectors.get_mut(&sector_id) {
+                    let error = format!("{}", err_unrecov(result.unwrap_err()));
+                    staged_sector.seal_status = SealStatus::Failed(error.clone());
+
+                    Some(StateOperation::SealFailed { sector_id, error })
+                } else {
+                    None
+                }
+            } else {
+                // Remove the staged sector from the state map.
+                let _ = staged_state.sectors.remove(&sector_id);
+
+                // Insert the newly-sealed sector into the other state map.
+                let mut sealed_sector = result.expects(FATAL_SECMAP);
+                sealed_sector.sealing_location = config.sealing_location.clone();
+
+                // The sealer worker records the epoch at which the sector
+                // was sealed, if it could. Without a deadline, the sector is
+                // never warned about (nor proven early).
+                if let Some(epoch) = sealed_sector.sealed_at_epoch {
+                    sealed_sector.pre_commit_deadline =
+                        Some(epoch + config.pre_commit_window_epochs);
+                }
+
+                sealed_state
+                    .sectors
+                    .insert(sector_id, sealed_sector.clone());
+
+                Some(StateOperation::SealComplete { sealed_sector })
+            }
+        };
+
+        // A sealed sector's pieces are no longer written to its staged file.
+        if let Some(StateOperation::SealComplete { .. }) = operation {
+            self.piece_writer.discard_log(sector_id);
+        }
+
+        if let Some(operation) = operation {
+            self.export(operation);
+        }
+
+        self.checkpoint().expects(FATAL_SNPSHT);
+        self.compact_sealed_state_if_large();
+    }
+
+    // Loads the sealed sectors of a compacted state, if they haven't been
+    // already.
+    fn load_sealed_state(&mut self) {
+        if self.sealed_state_loaded {
+            return;
+        }
+
+        if let Some(ref sealed_sector_keys) = self.compacted_sealed_sector_keys {
+            self.state.sealed =
+                load_sealed_sectors(&self.kv_store, sealed_sector_keys).expects(FATAL_NOLOAD);
+        }
+
+        self.sealed_state_loaded = true;
+        check_state_consistency(&self.state);
+    }
+
+    // Compacts the persisted state once it holds enough sealed sectors. The
+    // state remains compacted thereafter, so this happens at most once. A
+    // failure to compact only means that checkpoints stay large, so it isn't
+    // retried until the sector builder is restarted.
+    fn compact_sealed_state_if_large(&mut self) {
+        if self.compacted_sealed_sector_keys.is_some()
+            || self.sealed_state_compaction_failed
+            || self.state.sealed.sectors.len() < self.config.sealed_state_compaction_threshold
+        {
+            return;
+        }
+
+        let compacted = compact_sealed_state(&self.kv_store, &self.state.prover_id)
+            .and_then(|stats| Ok((stats, load_index(&self.kv_store, &self.state.prover_id)?)));
+
+        match compacted {
+            Ok((stats, index)) => {
+                self.compacted_sealed_sector_keys = index.map(|index| index.sealed_sector_keys);
+                info!(FCP_LOG, "compacted sealed state"; "original_size" => stats.original_size, "new_size" => stats.new_size, "num_entries" => stats.num_entries);
+            }
+            Err(err) => {
+                self.sealed_state_compaction_failed = true;
+
+                let err = format!("{}", err);
+                warn!(FCP_LOG, "could not compact sealed state"; "error" => err);
+            }
+        }
+    }
+
+    // Returns the sealed sector containing the referenced piece, if any.
+    fn find_sealed_sector(&self, piece_key: &str) -> Option<&SealedSectorMetadata> {
+        self.state.sealed.sectors.values().find(|sector| {
+            sector
+                .pieces
+                .iter()
+                .any(|piece| piece.piece_key == piece_key)
+        })
+    }
+
+    // Check for sectors which should no longer receive new user piece-bytes and
+    // schedule them for sealing. When asked to seal all staged sectors, produces
+    // the first error (e.g. SectorTooEmpty or SealAlreadyInProgress) with
+    // which a sector was skipped, having scheduled the remaining sectors.
+    fn check_and_schedule(&mut self, seal_all_staged_sectors: bool) -> Result<()> {
+        let staged_state = &mut self.state.staged;
+
+        // Full sectors, and those sealed to make room for a new staged sector,
+        // are sealed however full they are, lest the staged sectors grow
+        // without bound.
+        let needed = get_sectors_ready_for_sealing(
+            staged_state,
+            self.max_user_bytes_per_staged_sector,
+            self.max_num_staged_sectors,
+            seal_all_staged_sectors,
+        );
+
+        let mut to_be_sealed = needed.clone();
+
+        // Sectors which are full enough are sealed without waiting for them
+        // to fill up completely.
+        for sector_id in self
+            .seal_trigger
+            .check(staged_state, self.max_user_bytes_per_staged_sector)
+        {
+            if !to_be_sealed.contains(&sector_id) {
+                to_be_sealed.push(sector_id);
+            }
+        }
+
+        let api_version = self.config.default_api_version;
+        let mut skipped: Vec<failure::Error> = Vec::new();
+
+        for sector_id in to_be_sealed {
+            let fill_checked = if seal_all_staged_sectors || !needed.contains(&sector_id) {
+                let sector = self
+                    .state
+                    .staged
+                    .sectors
+                    .get(&sector_id)
+                    .expects(FATAL_NOSECT);
+
+                check_seal_fill_ratio(
+                    sector,
+                    self.max_user_bytes_per_staged_sector,
+                    self.config.min_seal_fill_ratio,
+                    false,
+                )
+            } else {
+                Ok(())
+            };
+
+            let scheduled = fill_checked.and_then(|_| self.schedule_seal(sector_id, api_version));
+
+            if let Err(err) = scheduled {
+                let reason = format!("{}", err);
+                info!(FCP_LOG, "not sealing staged sector"; "sector_id" => sector_id, "reason" => reason);
+                skipped.push(err);
+            }
+        }
+
+        match skipped.into_iter().next() {
+            Some(err) if seal_all_staged_sectors => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    // Marks the staged sector as no longer accepting data and then schedules
+    // it to be sealed with the given version of the sealing API, unless it's
+    // already being sealed.
+    fn schedule_seal(&mut self, sector_id: SectorId, api_version: ApiVersion) -> Result<()> {
+        if !self.seals_in_flight.insert(sector_id) {
+            return Err(err_seal_in_progress(sector_id).into());
+        }
+
+        let sector = self
+            .state
+            .staged
+            .sectors
+            .get_mut(&sector_id)
+            .expects(FATAL_NOSECT);
+        sector.seal_status = SealStatus::Sealing;
+
+        if let Some(fill_duration) = self.fill_durations.record(sector, SystemTime::now()) {
+            if let Some(ref metrics_sink) = self.config.metrics_sink {
+                metrics_sink.sector_filled(fill_duration);
+            }
+        }
+
+        // Queued before it's sent, lest a sealer start on it first.
+        self.sealing_tracker.queued(sector_id);
+
+        self.sealer_input_tx
+            .clone()
+            .send(SealerInput::Seal(
+                sector.clone(),
+                api_version,
+                self.config.obfuscate_fill_time,
+                self.precompute.finish(sector_id),
+                self.scheduler_input_tx.clone(),
+            ))
+            .expects(FATAL_SLRSND);
+        self.export(StateOperation::SealStarted { sector_id });
+
+        Ok(())
+    }
+
+    // Provisions a new staged sector ahead of time if, judging by the sizes of
+    // the pieces added to the staged sectors so far, none of them is likely
+    // to have room for the next piece. This keeps provisioning out of the
+    // path of the add_piece call which would otherwise have needed it.
+    fn preprovision_staged_sector(&mut self) -> Result<()> {
+        let pending: Vec<&StagedSectorMetadata> = self
+            .state
+            .staged
+            .sectors
+            .values()
+            .filter(|x| x.seal_status == SealStatus::Pending)
+            .collect();
+
+        // Another sector would see the oldest sealed before it was full.
+        if pending.len() >= self.max_num_staged_sectors as usize {
+            return Ok(());
+        }
+
+        let piece_history: Vec<UnpaddedBytesAmount> = pending
+            .iter()
+            .flat_map(|x| x.pieces.values().map(|p| p.num_bytes))
+            .collect();
+
+        if piece_history.len() < MIN_PIECE_HISTORY {
+            return Ok(());
+        }
+
+        let model = fit_piece_size_model(&piece_history);
+
+        let has_room = pending.iter().any(|x| {
+            let remaining = self.max_user_bytes_per_staged_sector - sum_piece_bytes(x);
+            let (estimate, _) =
+                predict_pieces_until_full(&model, remaining, PREPROVISION_CONFIDENCE);
+
+            estimate >= 1.0
+        });
+
+        if !has_room {
+            let sector_id = provision_new_staged_sector(
+                self.sector_store.inner.manager(),
+                &mut self.state.staged,
+                &mut self.state.reserved_ranges,
+                &mut self.sector_id_allocator,
+                self.config
+                    .sector_access_root
+                    .as_ref()
+                    .map(PathBuf::as_path),
+            )?;
+
+            let operation =
+                StateOperation::sector_provisioned(&self.state.staged.sectors[&sector_id]);
+            self.export(operation);
+
+            info!(FCP_LOG, "provisioned staged sector ahead of time"; "sector_id" => sector_id);
+        }
+
+        Ok(())
+    }
+
+    // Produces a Mermaid.js state diagram of every sector.
+    pub fn render_state_diagram(&self) -> String {
+        render_state_diagram(
+            &self.state.staged,
+            &self.state.sealed,
+            self.max_user_bytes_per_staged_sector,
+        )
+    }
+
+    // Returns a channel over which a new state diagram is sent after every
+    // change to the state.
+    pub fn subscribe_state_diagram_updates(&mut self) -> mpsc::Receiver<String> {
+        self.state_diagram_publisher.subscribe()
+    }
+
+    // Records that a sealed sector's replica has been archived.
+    pub fn handle_archive_receipt(
+        &mut self,
+        sector_id: SectorId,
+        archive_receipt: ArchiveReceipt,
+    ) -> Result<()> {
+        let sector = self
+            .state
+            .sealed
+            .sectors
+            .get_mut(&sector_id)
+            .ok_or_else(|| err_unrecov(format!("no sealed sector with id {}", sector_id)))?;
+
+        sector.archive_receipt = Some(archive_receipt.clone());
+
+        self.export(StateOperation::SectorArchived {
+            sector_id,
+            archive_receipt,
+        });
+        self.checkpoint()
+    }
+
+    // Records that the Merkle tree of a sealed sector's replica has been
+    // written to its sidecar file.
+    pub fn handle_merkle_snapshot(&mut self, sector_id: SectorId) -> Result<()> {
+        self.state
+            .sealed
+            .sectors
+            .get_mut(&sector_id)
+            .ok_or_else(|| err_unrecov(format!("no sealed sector with id {}", sector_id)))?
+            .has_merkle_snapshot = true;
+
+        self.export(StateOperation::MerkleSnapshotTaken { sector_id });
+        self.checkpoint()
+    }
+
+    // Restricts reads of the referenced (staged or sealed) piece to holders
+    // of the token with the provided hash.
+    pub fn restrict_piece_access(
+        &mut self,
+        piece_key: String,
+        access_token_hash: [u8; 32],
+    ) -> Result<()> {
+        let staged_pieces = self
+            .state
+            .staged
+            .sectors
+            .values_mut()
+            .flat_map(|sector| sector.pieces.values_mut());
+
+        let sealed_pieces = self
+            .state
+            .sealed
+            .sectors
+            .values_mut()
+            .flat_map(|sector| sector.pieces.iter_mut());
+
+        let piece = staged_pieces
+            .chain(sealed_pieces)
+            .find(|piece| piece.piece_key == piece_key)
+            .ok_or_else(|| err_piecenotfound(piece_key.clone()))?;
+
+        piece.access_token_hash = Some(access_token_hash);
+
+        self.export(StateOperation::PieceAccessRestricted {
+            piece_key,
+            access_token_hash,
+        });
+        self.checkpoint()
+    }
+
+    // Redacts the referenced (staged or sealed) piece, overwriting its bytes
+    // if its sector is still accepting data.
+    pub fn redact_piece(&mut self, piece_key: String) -> Result<RedactionReceipt> {
+        let receipt = redact_piece(
+            &self.sector_store,
+            &mut self.state.staged,
+            &self.state.sealed,
+            &self.sector_locks,
+            &piece_key,
+        )?;
+
+        // Any bytes buffered for the piece mustn't be read.
+        self.piece_read_buffer
+            .lock()
+            .expects(FATAL_NOLOCK)
+            .invalidate(&piece_key);
+
+        let last_crc32 = self
+            .state
+            .staged
+            .sectors
+            .get(&receipt.sector_id)
+            .and_then(|sector| sector.last_crc32);
+
+        self.export(StateOperation::PieceRedacted {
+            receipt: receipt.clone(),
+            last_crc32,
+        });
+        self.checkpoint()?;
+
+        Ok(receipt)
+    }
+
+    // Records the data tree state computed for a staged sector by the
+    // precompute pipeline. Updates for sectors which have since stopped
+    // accepting data (or been deleted) are dropped. States are checkpointed
+    // in batches; one lost to a crash only means that its sector is sealed
+    // without a precomputed commD.
+    pub fn handle_merkle_tree_state(&mut self, sector_id: SectorId, state: MerkleTreeState) {
+        if let Some(sector) = self.state.staged.sectors.get_mut(&sector_id) {
+            if sector.seal_status == SealStatus::Pending {
+                sector.merkle_tree_state = Some(state.clone());
+                self.unpersisted_merkle_tree_states += 1;
+
+                self.export(StateOperation::MerkleTreeStateUpdated {
+                    sector_id,
+                    merkle_tree_state: state,
+                });
+
+                if self.unpersisted_merkle_tree_states >= MERKLE_TREE_STATES_PER_CHECKPOINT {
+                    self.checkpoint().expects(FATAL_SNPSHT);
+                }
+            }
+        }
+    }
+
+    // Hands a delta describing the change just made to the state to the
+    // configured exporter, if any. A standby which misses a delta will refuse
+    // those which follow, so failures are logged rather than failing the
+    // change itself.
+    fn export(&mut self, operation: StateOperation) {
+        if let Some(exporter) = self.config.state_exporter.clone() {
+            self.state.delta_sequence_number += 1;
+
+            let delta = StateDelta {
+                prover_id: self.state.prover_id,
+                sequence_number: self.state.delta_sequence_number,
+                operation,
+            };
+
+            if let Err(err) = exporter.export(&delta) {
+                let err = format!("{}", err);
+                warn!(FCP_LOG, "failed to export state delta"; "sequence_number" => delta.sequence_number, "error" => err);
+            }
+        }
+    }
+
+    // Records (and exports to any standby) the public key of the key with
+    // which the sector builder signs manifests.
+    fn set_public_key(&mut self, public_key: [u8; 32]) -> Result<()> {
+        self.state.public_key = Some(public_key);
+        self.export(StateOperation::PublicKeySet { public_key });
+
+        // The delta's sequence number mustn't be reused after a restart.
+        self.checkpoint()
+    }
+
+    // Create and persist metadata snapshot, and let anyone watching the
+    // state diagram know that the state has changed.
+    fn checkpoint(&mut self) -> Result<()> {
+        let snapshot = make_snapshot(
+            &self.state.prover_id,
+            &self.state.staged,
+            &self.state.sealed,
+            &self.state.reserved_ranges,
+            self.state.delta_sequence_number,
+        );
+
+        // The index of a compacted state is kept in memory, so only sealed
+        // sectors which have been loaded (and may have changed) are compared
+        // against it.
+        if let Some(ref previous) = self.compacted_sealed_sector_keys {
+            if self.sealed_state_loaded {
+                let (sealed_sector_keys, _) =
+                    persist_compacted(&self.kv_store, &snapshot, Some(&previous[..]))?;
+                self.compacted_sealed_sector_keys = Some(sealed_sector_keys);
+            } else {
+                persist_compacted_index(&self.kv_store, &snapshot, previous)?;
+            }
+        } else {
+            persist_uncompacted_snapshot(&self.kv_store, &snapshot)?;
+        }
+        self.unpersisted_merkle_tree_states = 0;
+
+        // Every change to the staged sectors is checkpointed, so this is where
+        // waiting callers learn that a sector has been sealed (or failed to
+        // be).
+        self.staged_capacity
+            .publish(num_queued_sectors(&self.state.staged));
+
+        if let Some(ref metrics_sink) = self.config.metrics_sink {
+            let fill_ratios: Vec<(SectorId, f64)> = self
+                .state
+                .staged
+                .sectors
+                .values()
+                .map(|sector| {
+                    (
+                        sector.sector_id,
+                        sector_fill_ratio(sector, self.max_user_bytes_per_staged_sector),
+                    )
+                })
+                .collect();
+
+            metrics_sink.staged_sectors_changed(&fill_ratios);
+        }
+
+        if self.state_diagram_publisher.has_subscribers() {
+            let diagram = self.render_state_diagram();
+            self.state_diagram_publisher.publish(&diagram);
+        }
+
+        Ok(())
+    }
+}
+
+// An interrupted transition between the staged and sealed states can leave
+// them disagreeing, which an operator needs to know about before the sector
+// builder compounds the problem.
+fn check_state_consistency(state: &SectorBuilderState) {
+    match verify_cross_state_consistency(&state.staged, &state.sealed) {
+        Ok(ref report) if !report.is_consistent() => {
+            crit!(FCP_LOG, "loaded inconsistent sector builder state"; "violations" => format!("{:?}", report.violations));
+        }
+        Ok(_) => {}
+        Err(err) => {
+            warn!(FCP_LOG, "could not verify sector builder state consistency"; "error" => format!("{}", err));
+        }
     }
 }
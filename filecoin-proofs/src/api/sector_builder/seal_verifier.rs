@@ -0,0 +1,306 @@
+use crate::api::internal;
+use crate::api::post_adapter::VerifyPoStDynamicSectorsCountInput;
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::metadata::{sector_id_as_bytes, ApiVersion, SealedSectorMetadata};
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use sector_base::api::porep_config::PoRepConfig;
+use sector_base::api::post_config::PoStConfig;
+#[cfg(feature = "seal-verifier-remote")]
+use serde::{Deserialize, Serialize};
+
+// Verifies the proofs produced by a sector builder. Verification may be
+// offloaded to dedicated hardware (e.g. an FPGA) by implementing this trait.
+// Seal proofs are verified according to the version of the sealing API with
+// which the sector was sealed.
+pub trait SealVerifier: Send + Sync {
+    #[allow(clippy::too_many_arguments)]
+    fn verify_seal(
+        &self,
+        api_version: ApiVersion,
+        prover_id: &[u8; 31],
+        sector_id: SectorId,
+        comm_r: [u8; 32],
+        comm_d: [u8; 32],
+        comm_r_star: [u8; 32],
+        proof: &[u8],
+    ) -> Result<bool>;
+
+    fn verify_post(
+        &self,
+        prover_id: &[u8; 31],
+        challenge_seed: [u8; 32],
+        comm_rs: &[[u8; 32]],
+        proofs: &[Vec<u8>],
+        faults: &[u64],
+    ) -> Result<bool>;
+}
+
+// Verifies proofs on the CPU, in-process. Only V1 seals are supported.
+pub struct CpuSealVerifier {
+    porep_config: PoRepConfig,
+    post_config: PoStConfig,
+}
+
+impl CpuSealVerifier {
+    pub fn new(porep_config: PoRepConfig, post_config: PoStConfig) -> CpuSealVerifier {
+        CpuSealVerifier {
+            porep_config,
+            post_config,
+        }
+    }
+}
+
+impl SealVerifier for CpuSealVerifier {
+    fn verify_seal(
+        &self,
+        api_version: ApiVersion,
+        prover_id: &[u8; 31],
+        sector_id: SectorId,
+        comm_r: [u8; 32],
+        comm_d: [u8; 32],
+        comm_r_star: [u8; 32],
+        proof: &[u8],
+    ) -> Result<bool> {
+        match api_version {
+            ApiVersion::V1 => internal::verify_seal(
+                self.porep_config,
+                comm_r,
+                comm_d,
+                comm_r_star,
+                prover_id,
+                &sector_id_as_bytes(sector_id)?,
+                proof,
+            ),
+            _ => Err(err_unrecov(format!(
+                "CPU verification of {:?} seals is not supported",
+                api_version
+            ))
+            .into()),
+        }
+    }
+
+    fn verify_post(
+        &self,
+        _prover_id: &[u8; 31],
+        challenge_seed: [u8; 32],
+        comm_rs: &[[u8; 32]],
+        proofs: &[Vec<u8>],
+        faults: &[u64],
+    ) -> Result<bool> {
+        let output = internal::verify_post(VerifyPoStDynamicSectorsCountInput {
+            post_config: self.post_config,
+            comm_rs: comm_rs.to_vec(),
+            challenge_seed,
+            proofs: proofs.to_vec(),
+            faults: faults.to_vec(),
+        })?;
+
+        Ok(output.is_valid)
+    }
+}
+
+// The body of a request made to a remote verification service.
+#[cfg(feature = "seal-verifier-remote")]
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum VerificationRequest<'a> {
+    Seal {
+        api_version: ApiVersion,
+        prover_id: &'a [u8],
+        sector_id: SectorId,
+        comm_r: [u8; 32],
+        comm_d: [u8; 32],
+        comm_r_star: [u8; 32],
+        proof: &'a [u8],
+    },
+    Post {
+        prover_id: &'a [u8],
+        challenge_seed: [u8; 32],
+        comm_rs: &'a [[u8; 32]],
+        proofs: &'a [Vec<u8>],
+        faults: &'a [u64],
+    },
+}
+
+#[cfg(feature = "seal-verifier-remote")]
+#[derive(Deserialize)]
+struct VerificationResponse {
+    is_valid: bool,
+}
+
+// Verifies proofs by POSTing them (as JSON) to a verification service, which
+// responds with {"is_valid": bool}.
+#[cfg(feature = "seal-verifier-remote")]
+pub struct RemoteSealVerifier {
+    pub endpoint: url::Url,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "seal-verifier-remote")]
+impl RemoteSealVerifier {
+    pub fn new(endpoint: url::Url) -> RemoteSealVerifier {
+        RemoteSealVerifier {
+            endpoint,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn verify(&self, request: &VerificationRequest) -> Result<bool> {
+        let response: VerificationResponse = self
+            .client
+            .post(self.endpoint.clone())
+            .json(request)
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        Ok(response.is_valid)
+    }
+}
+
+#[cfg(feature = "seal-verifier-remote")]
+impl SealVerifier for RemoteSealVerifier {
+    fn verify_seal(
+        &self,
+        api_version: ApiVersion,
+        prover_id: &[u8; 31],
+        sector_id: SectorId,
+        comm_r: [u8; 32],
+        comm_d: [u8; 32],
+        comm_r_star: [u8; 32],
+        proof: &[u8],
+    ) -> Result<bool> {
+        self.verify(&VerificationRequest::Seal {
+            api_version,
+            prover_id,
+            sector_id,
+            comm_r,
+            comm_d,
+            comm_r_star,
+            proof,
+        })
+    }
+
+    fn verify_post(
+        &self,
+        prover_id: &[u8; 31],
+        challenge_seed: [u8; 32],
+        comm_rs: &[[u8; 32]],
+        proofs: &[Vec<u8>],
+        faults: &[u64],
+    ) -> Result<bool> {
+        self.verify(&VerificationRequest::Post {
+            prover_id,
+            challenge_seed,
+            comm_rs,
+            proofs,
+            faults,
+        })
+    }
+}
+
+// Checks the proof of a newly-sealed sector, producing an error if the proof
+// is invalid or couldn't be verified.
+pub fn verify_sealed_sector(
+    seal_verifier: &SealVerifier,
+    prover_id: &[u8; 31],
+    sealed_sector: &SealedSectorMetadata,
+) -> Result<()> {
+    let is_valid = seal_verifier.verify_seal(
+        sealed_sector.api_version,
+        prover_id,
+        sealed_sector.sector_id,
+        sealed_sector.comm_r,
+        sealed_sector.comm_d,
+        sealed_sector.comm_r_star,
+        &sealed_sector.proof,
+    )?;
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(err_unrecov(format!(
+            "proof of sealed sector {} is invalid",
+            sealed_sector.sector_id
+        ))
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::config::SectorBuilderConfig;
+    use crate::api::sector_builder::helpers::snapshots::{
+        load_snapshot, make_snapshot, persist_snapshot,
+    };
+    use crate::api::sector_builder::kv_store::{FileSystemKvs, KeyValueStore};
+    use crate::api::sector_builder::state::SealedState;
+    use crate::api::sector_builder::test_utils::MockSealVerifier;
+    use crate::api::sector_builder::WrappedKeyValueStore;
+    use std::sync::Arc;
+
+    fn sealed_sector(sector_id: SectorId) -> SealedSectorMetadata {
+        SealedSectorMetadata {
+            sector_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_sealed_sector() {
+        let verifier = MockSealVerifier::new(true).with_result(2, false);
+
+        assert!(verify_sealed_sector(&verifier, &[0; 31], &sealed_sector(1)).is_ok());
+        assert!(verify_sealed_sector(&verifier, &[0; 31], &sealed_sector(2)).is_err());
+
+        let verifier = MockSealVerifier::new(false).with_result(2, true);
+
+        assert!(verify_sealed_sector(&verifier, &[0; 31], &sealed_sector(1)).is_err());
+        assert!(verify_sealed_sector(&verifier, &[0; 31], &sealed_sector(2)).is_ok());
+        assert_eq!(vec![1, 2], *verifier.verified.lock().unwrap());
+    }
+
+    #[test]
+    fn test_verifies_with_sealed_api_version() {
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let kv_store = Arc::new(WrappedKeyValueStore {
+            inner: Box::new(FileSystemKvs::initialize(metadata_dir.path()).unwrap()),
+        });
+
+        let prover_id = [0; 31];
+        let mut config: SectorBuilderConfig = Default::default();
+
+        // a sector sealed (with V1) before the network upgrade
+        let mut sealed_state: SealedState = Default::default();
+        sealed_state.sectors.insert(
+            1,
+            SealedSectorMetadata {
+                sector_id: 1,
+                api_version: config.default_api_version,
+                ..Default::default()
+            },
+        );
+
+        let snapshot = make_snapshot(&prover_id, &Default::default(), &sealed_state, &[], 0);
+        persist_snapshot(&kv_store, &snapshot).unwrap();
+
+        config.default_api_version = ApiVersion::V2;
+
+        // V1 proofs aren't valid V2 proofs
+        let verifier = MockSealVerifier::new(false).with_api_version_result(ApiVersion::V1, true);
+
+        let loaded = load_snapshot(&kv_store, &prover_id).unwrap().unwrap();
+        let sealed_sector = &loaded.sealed.sectors[&1];
+
+        assert_eq!(ApiVersion::V1, sealed_sector.api_version);
+        assert!(verify_sealed_sector(&verifier, &prover_id, sealed_sector).is_ok());
+
+        let relabelled = SealedSectorMetadata {
+            api_version: config.default_api_version,
+            ..sealed_sector.clone()
+        };
+        assert!(verify_sealed_sector(&verifier, &prover_id, &relabelled).is_err());
+    }
+}
@@ -1,14 +1,34 @@
-use crate::api::sector_builder::helpers::retrieve_piece::retrieve_piece;
+use crate::api::sector_builder::dashboard::SealingTracker;
+use crate::api::sector_builder::errors::{err_seal_in_progress, SectorBuilderErr};
+use crate::api::sector_builder::gpu::SealProver;
+use crate::api::sector_builder::helpers::prefetch_piece::{
+    get_piece, prefetch_piece, PieceReadBuffer,
+};
+use crate::api::sector_builder::helpers::retrieve_piece::{retrieve_piece, retrieve_piece_timed};
 use crate::api::sector_builder::helpers::seal::seal;
+use crate::api::sector_builder::http_export::replica_checksum;
+use crate::api::sector_builder::io_scheduler::IoScheduler;
+use crate::api::sector_builder::merkle_snapshot::write_merkle_snapshot;
+use crate::api::sector_builder::metadata::ApiVersion;
 use crate::api::sector_builder::metadata::SealedSectorMetadata;
 use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::api::sector_builder::metrics::MetricsSink;
+use crate::api::sector_builder::post_scheduler::EpochSource;
 use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::seal_verifier::SealVerifier;
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::telemetry::PieceTelemetry;
+use crate::api::sector_builder::SectorId;
 use crate::api::sector_builder::WrappedSectorStore;
 use crate::error::ExpectWithBacktrace;
 use crate::error::Result;
+use crate::FCP_LOG;
+use slog::*;
+use std::collections::HashSet;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Instant, SystemTime};
 
 const FATAL_NOLOCK: &str = "error acquiring task lock";
 const FATAL_RCVTSK: &str = "error receiving seal task";
@@ -20,22 +40,66 @@ pub struct SealerWorker {
     pub thread: Option<thread::JoinHandle<()>>,
 }
 
+// The sectors being sealed, shared by the sealer workers so that no two of
+// them seal the same sector (i.e. compete on its files) at once.
+#[derive(Default)]
+pub struct SealsInProgress {
+    sealing: Mutex<HashSet<SectorId>>,
+}
+
+impl SealsInProgress {
+    // Runs seal, unless the sector is already being sealed.
+    pub fn seal_exclusively<T, F>(&self, sector_id: SectorId, seal: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        if !self.sealing.lock().expects(FATAL_NOLOCK).insert(sector_id) {
+            return Err(err_seal_in_progress(sector_id).into());
+        }
+
+        let result = seal();
+
+        self.sealing.lock().expects(FATAL_NOLOCK).remove(&sector_id);
+
+        result
+    }
+}
+
 pub enum SealerInput {
-    Seal(StagedSectorMetadata, mpsc::SyncSender<Request>),
+    Seal(
+        StagedSectorMetadata,
+        ApiVersion,
+        bool,
+        Option<mpsc::Receiver<Option<[u8; 32]>>>,
+        mpsc::SyncSender<Request>,
+    ),
     Unseal(
         String,
         Box<SealedSectorMetadata>,
-        mpsc::SyncSender<Result<Vec<u8>>>,
+        Instant,
+        mpsc::SyncSender<Result<(Vec<u8>, PieceTelemetry)>>,
     ),
+    Prefetch(String, Box<SealedSectorMetadata>),
     Shutdown,
 }
 
 impl SealerWorker {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         id: usize,
         seal_task_rx: Arc<Mutex<mpsc::Receiver<SealerInput>>>,
         sector_store: Arc<WrappedSectorStore>,
+        piece_read_buffer: Arc<Mutex<PieceReadBuffer>>,
+        seal_verifier: Option<Arc<SealVerifier>>,
+        seal_prover: SealProver,
+        seals_in_progress: Arc<SealsInProgress>,
+        io_scheduler: Arc<IoScheduler>,
+        sealing_tracker: Arc<SealingTracker>,
+        sector_locks: Arc<SectorLocks>,
+        metrics_sink: Option<Arc<MetricsSink>>,
         prover_id: [u8; 31],
+        snapshot_merkle_trees: bool,
+        epoch_source: Option<Arc<EpochSource>>,
     ) -> SealerWorker {
         let thread = thread::spawn(move || loop {
             // Acquire a lock on the rx end of the channel, get a task,
@@ -48,22 +112,151 @@ impl SealerWorker {
 
             // Dispatch to the appropriate task-handler.
             match task {
-                SealerInput::Seal(staged_sector, return_channel) => {
+                SealerInput::Seal(
+                    staged_sector,
+                    api_version,
+                    obfuscate_fill_time,
+                    precomputed_comm_d,
+                    return_channel,
+                ) => {
                     let sector_id = staged_sector.sector_id;
-                    let result = seal(&sector_store.clone(), &prover_id, staged_sector);
+                    sealing_tracker.dequeued(sector_id);
+
+                    let result = seals_in_progress.seal_exclusively(sector_id, || {
+                        sealing_tracker.started(sector_id, SystemTime::now());
+                        let seal_started_at = Instant::now();
+
+                        let result = seal(
+                            &sector_store.clone(),
+                            &prover_id,
+                            staged_sector,
+                            api_version,
+                            obfuscate_fill_time,
+                            precomputed_comm_d,
+                            seal_verifier.as_ref().map(Arc::as_ref),
+                            &seal_prover,
+                            &io_scheduler,
+                            &sector_locks,
+                            &|phase, progress| {
+                                sealing_tracker.progressed(sector_id, phase, progress)
+                            },
+                        );
+
+                        // The sector is sealed all the same if its replica
+                        // can't be hashed, or its tree snapshotted.
+                        let result = result.map(|mut sealed_sector| {
+                            match replica_checksum(
+                                &sealed_sector.sector_access,
+                                sector_store.inner.manager().bandwidth_accounting(),
+                            ) {
+                                Ok(checksum) => sealed_sector.replica_checksum = Some(checksum),
+                                Err(err) => {
+                                    let err = format!("{}", err);
+                                    warn!(FCP_LOG, "could not hash replica of sealed sector"; "sector_id" => sector_id, "error" => err);
+                                }
+                            }
+
+                            if snapshot_merkle_trees {
+                                match write_merkle_snapshot(&sector_store, &sealed_sector) {
+                                    Ok(_) => sealed_sector.has_merkle_snapshot = true,
+                                    Err(err) => {
+                                        let err = format!("{}", err);
+                                        warn!(FCP_LOG, "could not snapshot Merkle tree of sealed sector"; "sector_id" => sector_id, "error" => err);
+                                    }
+                                }
+                            }
+
+                            // The scheduler derives the sector's pre-commit
+                            // deadline from the epoch, without querying the
+                            // chain itself. Without it, the sector has no
+                            // deadline.
+                            if let Some(ref epoch_source) = epoch_source {
+                                match epoch_source.current_epoch() {
+                                    Ok(epoch) => sealed_sector.sealed_at_epoch = Some(epoch),
+                                    Err(err) => {
+                                        let err = format!("{}", err);
+                                        warn!(FCP_LOG, "could not read epoch at which sector was sealed"; "sector_id" => sector_id, "error" => err);
+                                    }
+                                }
+                            }
+
+                            sealed_sector
+                        });
+
+                        sealing_tracker.finished(sector_id, result.is_ok(), SystemTime::now());
+
+                        if let (Ok(_), Some(metrics_sink)) = (&result, &metrics_sink) {
+                            metrics_sink.sector_sealed(seal_started_at.elapsed());
+                        }
+
+                        result
+                    });
+
+                    // The seal already in progress will report its result,
+                    // which this (failed) attempt mustn't clobber.
+                    if let Err(ref err) = result {
+                        if let Some(SectorBuilderErr::SealAlreadyInProgress(_)) = err.downcast_ref()
+                        {
+                            warn!(FCP_LOG, "ignoring request to seal sector which is already being sealed"; "sector_id" => sector_id);
+                            continue;
+                        }
+                    }
+
                     let task = Request::HandleSealResult(sector_id, Box::new(result));
 
                     return_channel.send(task).expects(FATAL_SNDTSK);
                 }
-                SealerInput::Unseal(piece_key, sealed_sector, return_channel) => {
-                    let result = retrieve_piece(
-                        &sector_store.clone(),
-                        &sealed_sector,
-                        &prover_id,
-                        &piece_key,
+                SealerInput::Unseal(piece_key, sealed_sector, started_at, return_channel) => {
+                    let read_started_at = Instant::now();
+                    let mut decode_started_at = None;
+
+                    let result = get_piece(&piece_read_buffer, &piece_key, |key| {
+                        let (bytes, decoding_at) = retrieve_piece_timed(
+                            &sector_store.clone(),
+                            &sealed_sector,
+                            &prover_id,
+                            key,
+                            &io_scheduler,
+                            &sector_locks,
+                        )?;
+
+                        decode_started_at = Some(decoding_at);
+
+                        Ok(bytes)
+                    });
+
+                    // Bytes which weren't read from the sealed sector came
+                    // from the prefetch buffer.
+                    let finished_at = Instant::now();
+                    let telemetry = PieceTelemetry::from_checkpoints(
+                        started_at,
+                        read_started_at,
+                        decode_started_at.unwrap_or(finished_at),
+                        finished_at,
+                        decode_started_at.is_none(),
+                        sealed_sector.sector_id,
                     );
 
-                    return_channel.send(result).expects(FATAL_SNDRLT);
+                    return_channel
+                        .send(result.map(|bytes| (bytes, telemetry)))
+                        .expects(FATAL_SNDRLT);
+                }
+                SealerInput::Prefetch(piece_key, sealed_sector) => {
+                    let result = prefetch_piece(&piece_read_buffer, &piece_key, |key| {
+                        retrieve_piece(
+                            &sector_store.clone(),
+                            &sealed_sector,
+                            &prover_id,
+                            key,
+                            &io_scheduler,
+                            &sector_locks,
+                        )
+                    });
+
+                    if let Err(err) = result {
+                        let err = format!("{}", err);
+                        warn!(FCP_LOG, "failed to prefetch piece"; "piece_key" => &piece_key, "error" => err);
+                    }
                 }
                 SealerInput::Shutdown => break,
             }
@@ -75,3 +268,62 @@ impl SealerWorker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_rejects_concurrent_seal_of_same_sector() {
+        let seals_in_progress: Arc<SealsInProgress> = Default::default();
+        let barrier = Arc::new(Barrier::new(2));
+
+        // Each seal waits for the other thread to have attempted its own
+        // before completing, so the two attempts overlap.
+        let handles: Vec<thread::JoinHandle<Result<()>>> = (0..2)
+            .map(|_| {
+                let seals_in_progress = seals_in_progress.clone();
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    let result = seals_in_progress.seal_exclusively(1, || {
+                        barrier.wait();
+                        Ok(())
+                    });
+
+                    if result.is_err() {
+                        barrier.wait();
+                    }
+
+                    result
+                })
+            })
+            .collect();
+
+        let results: Vec<Result<()>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        let rejected: Vec<&failure::Error> =
+            results.iter().filter_map(|x| x.as_ref().err()).collect();
+        assert_eq!(1, rejected.len());
+
+        match rejected[0].downcast_ref() {
+            Some(SectorBuilderErr::SealAlreadyInProgress(1)) => {}
+            _ => panic!("expected a seal already in progress"),
+        }
+
+        // once the seal completes, the sector may be sealed again
+        assert!(seals_in_progress.seal_exclusively(1, || Ok(())).is_ok());
+    }
+
+    #[test]
+    fn test_releases_sector_when_seal_fails() {
+        let seals_in_progress: SealsInProgress = Default::default();
+
+        let result: Result<()> =
+            seals_in_progress.seal_exclusively(1, || Err(format_err!("seal failed")));
+        assert!(result.is_err());
+
+        assert!(seals_in_progress.seal_exclusively(1, || Ok(())).is_ok());
+    }
+}
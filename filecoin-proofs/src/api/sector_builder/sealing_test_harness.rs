@@ -0,0 +1,303 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::api::sector_builder::factory::{SectorBuilderApi, SectorBuilderFactory};
+use crate::api::sector_builder::metadata::SealStatus;
+use crate::api::sector_builder::test_utils::{sector_builder_factory, TEST_CLASS};
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use tempfile::TempDir;
+
+// How long to wait for a sector to seal before failing the scenario.
+const SEAL_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+const SEAL_STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// An end-to-end sealing scenario: the pieces to add, and how to seal them.
+#[derive(Clone, Debug)]
+pub struct TestScenario {
+    pub name: &'static str,
+    // the number of bytes in each piece, in the order they're added
+    pub piece_sizes: Vec<u64>,
+    // If set, every staged sector is sealed at once and the scenario waits for
+    // them all to finish (polling their status). Otherwise each sector is
+    // sealed, and waited for, in turn.
+    pub use_async_api: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TestStep {
+    InitSectorBuilder,
+    AddPiece(usize),
+    TriggerSeal,
+    AwaitSeal(SectorId),
+    RetrievePiece(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepResult {
+    pub step: TestStep,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+// The outcome of each step run. A scenario stops at its first failed step.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestReport {
+    pub scenario: &'static str,
+    pub steps: Vec<StepResult>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        !self.steps.is_empty() && self.steps.iter().all(|result| result.passed)
+    }
+
+    // Records the outcome of a step, returning its output if it passed.
+    fn record<T>(&mut self, step: TestStep, result: Result<T>) -> Option<T> {
+        let (passed, error, output) = match result {
+            Ok(output) => (true, None, Some(output)),
+            Err(err) => (false, Some(format!("{}", err)), None),
+        };
+
+        self.steps.push(StepResult {
+            step,
+            passed,
+            error,
+        });
+
+        output
+    }
+}
+
+// Drives a SectorBuilder for a test sector class through adding, sealing and
+// retrieving pieces, with all of its state in a temporary directory.
+pub struct SealingTestHarness {
+    dir: TempDir,
+}
+
+impl SealingTestHarness {
+    pub fn new() -> Result<SealingTestHarness> {
+        Ok(SealingTestHarness {
+            dir: tempfile::tempdir()?,
+        })
+    }
+
+    // Runs the scenario against a new sector builder (and directory).
+    pub fn run_scenario(scenario: TestScenario) -> TestReport {
+        let mut report = TestReport {
+            scenario: scenario.name,
+            steps: Vec::new(),
+        };
+
+        let harness = SealingTestHarness::new();
+        let sector_builder = harness.and_then(|harness| {
+            let sector_builder = harness.init_sector_builder()?;
+            Ok((harness, sector_builder))
+        });
+
+        if let Some((harness, sector_builder)) =
+            report.record(TestStep::InitSectorBuilder, sector_builder)
+        {
+            harness.run(&*sector_builder, &scenario, &mut report);
+        }
+
+        report
+    }
+
+    fn init_sector_builder(&self) -> Result<Box<SectorBuilderApi>> {
+        sector_builder_factory(TEST_CLASS, self.dir.path())?
+            .create_sector_builder(Default::default())
+    }
+
+    fn run(
+        &self,
+        sector_builder: &SectorBuilderApi,
+        scenario: &TestScenario,
+        report: &mut TestReport,
+    ) {
+        let mut pieces = Vec::new();
+
+        for (i, num_bytes) in scenario.piece_sizes.iter().enumerate() {
+            let added = self.add_piece(sector_builder, i, *num_bytes);
+
+            match report.record(TestStep::AddPiece(i), added) {
+                Some(piece) => pieces.push(piece),
+                None => return,
+            }
+        }
+
+        let sector_ids: BTreeSet<SectorId> =
+            pieces.iter().map(|(_, _, sector_id)| *sector_id).collect();
+
+        if scenario.use_async_api {
+            let triggered = sector_builder.seal_all_staged_sectors();
+            if report.record(TestStep::TriggerSeal, triggered).is_none() {
+                return;
+            }
+
+            for sector_id in &sector_ids {
+                let sealed = await_seal(sector_builder, *sector_id);
+                if report
+                    .record(TestStep::AwaitSeal(*sector_id), sealed)
+                    .is_none()
+                {
+                    return;
+                }
+            }
+        } else {
+            for sector_id in &sector_ids {
+                let triggered = sector_builder.seal_sector(*sector_id);
+                if report.record(TestStep::TriggerSeal, triggered).is_none() {
+                    return;
+                }
+
+                let sealed = await_seal(sector_builder, *sector_id);
+                if report
+                    .record(TestStep::AwaitSeal(*sector_id), sealed)
+                    .is_none()
+                {
+                    return;
+                }
+            }
+        }
+
+        for (i, (piece_key, piece_bytes, _)) in pieces.into_iter().enumerate() {
+            let retrieved = sector_builder
+                .read_piece_from_sealed_sector(piece_key.clone())
+                .and_then(|bytes| {
+                    if bytes == piece_bytes {
+                        Ok(())
+                    } else {
+                        Err(format_err!("retrieved bytes of {} differ", piece_key))
+                    }
+                });
+
+            if report
+                .record(TestStep::RetrievePiece(i), retrieved)
+                .is_none()
+            {
+                return;
+            }
+        }
+    }
+
+    // Writes the ith piece to a file and adds it, returning its key, bytes
+    // and destination sector.
+    fn add_piece(
+        &self,
+        sector_builder: &SectorBuilderApi,
+        i: usize,
+        num_bytes: u64,
+    ) -> Result<(String, Vec<u8>, SectorId)> {
+        let piece_key = format!("piece-{}", i);
+        let piece_bytes: Vec<u8> = (0..num_bytes).map(|n| (n as usize + i) as u8).collect();
+
+        let piece_path = self.dir.path().join(&piece_key);
+        fs::write(&piece_path, &piece_bytes)?;
+
+        let sector_id = sector_builder.add_piece(
+            piece_key.clone(),
+            num_bytes,
+            piece_path.to_string_lossy().into_owned(),
+        )?;
+
+        Ok((piece_key, piece_bytes, sector_id))
+    }
+}
+
+// Polls the sector's seal status until it has sealed (or failed to), or
+// SEAL_TIMEOUT has passed.
+pub fn await_seal(sector_builder: &SectorBuilderApi, sector_id: SectorId) -> Result<()> {
+    let started_at = Instant::now();
+
+    loop {
+        match sector_builder.get_seal_status(sector_id)? {
+            SealStatus::Sealed(_) => return Ok(()),
+            SealStatus::Failed(err) => {
+                return Err(format_err!("sector {} failed to seal: {}", sector_id, err))
+            }
+            SealStatus::Pending | SealStatus::Sealing => {}
+        }
+
+        if started_at.elapsed() > SEAL_TIMEOUT {
+            return Err(format_err!("sector {} did not seal in time", sector_id));
+        }
+
+        thread::sleep(SEAL_STATUS_POLL_INTERVAL);
+    }
+}
+
+// Scenarios covering the edges of a test sector, which holds 1016 unpadded
+// bytes.
+pub fn default_scenarios() -> Vec<TestScenario> {
+    vec![
+        TestScenario {
+            name: "single piece filling a sector",
+            piece_sizes: vec![1016],
+            use_async_api: false,
+        },
+        TestScenario {
+            name: "single one-byte piece",
+            piece_sizes: vec![1],
+            use_async_api: true,
+        },
+        TestScenario {
+            name: "two pieces filling a sector exactly",
+            piece_sizes: vec![508, 508],
+            use_async_api: true,
+        },
+        TestScenario {
+            name: "pieces spilling into a second sector",
+            piece_sizes: vec![600, 600],
+            use_async_api: false,
+        },
+        TestScenario {
+            name: "many small pieces",
+            piece_sizes: vec![100; 10],
+            use_async_api: true,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore] // Slow test – run only when compiled for release.
+    fn test_default_scenarios() {
+        for scenario in default_scenarios() {
+            let report = SealingTestHarness::run_scenario(scenario);
+            assert!(report.passed(), "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn test_stops_at_first_failed_step() {
+        let report = SealingTestHarness::run_scenario(TestScenario {
+            name: "oversized piece",
+            piece_sizes: vec![100, 1017, 100],
+            use_async_api: true,
+        });
+
+        assert!(!report.passed());
+
+        let steps: Vec<(TestStep, bool)> = report
+            .steps
+            .into_iter()
+            .map(|result| (result.step, result.passed))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (TestStep::InitSectorBuilder, true),
+                (TestStep::AddPiece(0), true),
+                (TestStep::AddPiece(1), false),
+            ],
+            steps
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::api::sector_builder::helpers::reserve_sector_id_range::{
+    next_reserved_sector_id, next_sector_id,
+};
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::SectorId;
+use rand::{Rng, SeedableRng, StdRng};
+
+// Produces the sector ids of newly-provisioned staged sectors. Reserved ids
+// are always handed out first.
+pub struct SectorIdAllocator {
+    rng: Option<StdRng>,
+}
+
+impl SectorIdAllocator {
+    // Allocates ids by incrementing the staged state's sector id nonce. This
+    // is the only allocator suitable for production use.
+    pub fn nonce() -> SectorIdAllocator {
+        SectorIdAllocator { rng: None }
+    }
+
+    // Allocates ids from a seeded random number generator, so that tests
+    // produce the same sector ids on every run.
+    //
+    // DO NOT USE IN PRODUCTION. The nonce is never advanced, so ids are not
+    // monotonic, and two sector builders given the same seed will allocate
+    // the same ids.
+    pub fn deterministic(seed: u64) -> SectorIdAllocator {
+        let seed = [seed as usize, (seed >> 32) as usize];
+
+        SectorIdAllocator {
+            rng: Some(StdRng::from_seed(&seed[..])),
+        }
+    }
+
+    pub fn allocate(
+        &mut self,
+        staged_state: &mut StagedState,
+        reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    ) -> SectorId {
+        let rng = match self.rng.as_mut() {
+            Some(rng) => rng,
+            None => return next_sector_id(staged_state, reserved_ranges),
+        };
+
+        if let Some(sector_id) = next_reserved_sector_id(staged_state, reserved_ranges) {
+            return sector_id;
+        }
+
+        loop {
+            let sector_id: SectorId = rng.gen();
+
+            let is_reserved = reserved_ranges
+                .iter()
+                .any(|(start, end)| *start <= sector_id && sector_id <= *end);
+
+            if !is_reserved && !staged_state.sectors.contains_key(&sector_id) {
+                return sector_id;
+            }
+        }
+    }
+}
+
+impl Default for SectorIdAllocator {
+    fn default() -> SectorIdAllocator {
+        SectorIdAllocator::nonce()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocate_n(allocator: &mut SectorIdAllocator, n: usize) -> Vec<SectorId> {
+        let mut staged_state: StagedState = Default::default();
+        let mut reserved_ranges = Vec::new();
+
+        (0..n)
+            .map(|_| allocator.allocate(&mut staged_state, &mut reserved_ranges))
+            .collect()
+    }
+
+    #[test]
+    fn test_deterministic_allocator_is_reproducible() {
+        let a = allocate_n(&mut SectorIdAllocator::deterministic(42), 10);
+        let b = allocate_n(&mut SectorIdAllocator::deterministic(42), 10);
+        let c = allocate_n(&mut SectorIdAllocator::deterministic(43), 10);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_nonce_allocator_increments() {
+        assert_eq!(
+            vec![1, 2, 3],
+            allocate_n(&mut SectorIdAllocator::nonce(), 3)
+        );
+    }
+}
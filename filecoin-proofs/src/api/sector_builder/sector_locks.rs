@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::api::sector_builder::SectorId;
+use crate::error::ExpectWithBacktrace;
+
+const FATAL_NOLOCK: &str = "error acquiring sector lock";
+
+// A memory-mapping of a sector file may not see a write to the file until
+// the write has been synced (on some platforms, not even then), so sector
+// files are never written while they're mapped for reading: writers hold
+// the sector's lock exclusively, from before they write until they've synced,
+// and readers hold it shared for as long as they have the file mapped.
+#[derive(Debug, Default)]
+pub struct SectorLocks {
+    locks: Mutex<HashMap<SectorId, SectorLock>>,
+}
+
+// The lock of one sector's files.
+#[derive(Clone, Debug, Default)]
+pub struct SectorLock {
+    inner: Arc<RwLock<()>>,
+}
+
+// Held by a writer from before it writes to a sector file until it has synced
+// the file. Mappings of the file wait for it to be dropped.
+pub struct MmapInvalidationGuard<'a> {
+    _guard: RwLockWriteGuard<'a, ()>,
+}
+
+// Held by a reader for as long as it has a sector file mapped.
+pub struct MmapReadGuard<'a> {
+    _guard: RwLockReadGuard<'a, ()>,
+}
+
+impl SectorLocks {
+    pub fn get(&self, sector_id: SectorId) -> SectorLock {
+        let mut locks = self.locks.lock().expects(FATAL_NOLOCK);
+
+        // Locks which nobody else holds a handle to are forgotten, so that
+        // there's only ever a lock for the sectors being read or written.
+        locks.retain(|_, lock| Arc::strong_count(&lock.inner) > 1);
+
+        locks.entry(sector_id).or_default().clone()
+    }
+}
+
+impl SectorLock {
+    pub fn invalidate_mmaps(&self) -> MmapInvalidationGuard {
+        MmapInvalidationGuard {
+            _guard: self.inner.write().expects(FATAL_NOLOCK),
+        }
+    }
+
+    pub fn map_for_reading(&self) -> MmapReadGuard {
+        MmapReadGuard {
+            _guard: self.inner.read().expects(FATAL_NOLOCK),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memmap::Mmap;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::thread;
+
+    const SECTOR_BYTES: usize = 64 * 1024;
+
+    #[test]
+    fn test_mapped_reads_see_whole_writes() {
+        let sector_locks: Arc<SectorLocks> = Default::default();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sector");
+
+        std::fs::write(&path, vec![0u8; SECTOR_BYTES]).unwrap();
+
+        // Each write fills the sector with one byte, in small chunks, so a
+        // read which overlapped a write would see more than one byte.
+        let writer = {
+            let sector_locks = sector_locks.clone();
+            let path = path.clone();
+
+            thread::spawn(move || {
+                let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+
+                for n in 1..=50u8 {
+                    let lock = sector_locks.get(7);
+                    let _guard = lock.invalidate_mmaps();
+
+                    file.seek(SeekFrom::Start(0)).unwrap();
+                    for _ in 0..(SECTOR_BYTES / 512) {
+                        file.write_all(&[n; 512]).unwrap();
+                    }
+                    file.sync_all().unwrap();
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let sector_locks = sector_locks.clone();
+                let path = path.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let lock = sector_locks.get(7);
+                        let _guard = lock.map_for_reading();
+
+                        let file = OpenOptions::new().read(true).open(&path).unwrap();
+                        let mmap = unsafe { Mmap::map(&file).unwrap() };
+
+                        assert_eq!(SECTOR_BYTES, mmap.len());
+                        assert!(mmap.iter().all(|b| *b == mmap[0]));
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert!(std::fs::read(&path).unwrap().iter().all(|b| *b == 50));
+    }
+
+    #[test]
+    fn test_forgets_unheld_locks() {
+        let sector_locks = SectorLocks::default();
+
+        let held = sector_locks.get(1);
+        drop(sector_locks.get(2));
+        sector_locks.get(3);
+
+        let locks = sector_locks.locks.lock().unwrap();
+        assert!(locks.contains_key(&1));
+        assert!(!locks.contains_key(&2));
+        assert!(Arc::ptr_eq(&held.inner, &locks[&1].inner));
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::Result;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+use rand::{OsRng, Rng};
+
+// The Ed25519 key with which a sector builder signs the manifests (and seal
+// certificates) it hands out. The secret key is the 32-byte secret followed
+// by the public key, as ed25519-dalek serializes a keypair, and is what's
+// kept in the key file.
+#[derive(Clone)]
+pub struct SectorBuilderKey {
+    pub public_key: [u8; 32],
+    pub secret_key: [u8; 64],
+}
+
+impl SectorBuilderKey {
+    pub fn generate() -> Result<SectorBuilderKey> {
+        let mut secret = [0; 32];
+        OsRng::new()?.fill_bytes(&mut secret);
+
+        SectorBuilderKey::from_secret(&secret)
+    }
+
+    // Loads the key from the key file, first generating the file if there
+    // isn't one.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<SectorBuilderKey> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let bytes = fs::read(path)?;
+            let keypair = Keypair::from_bytes(&bytes).map_err(|err| {
+                format_err!("could not read key file {}: {}", path.display(), err)
+            })?;
+
+            return Ok(SectorBuilderKey::from_keypair(&keypair));
+        }
+
+        let key = SectorBuilderKey::generate()?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        // Nobody but the sector builder's user need read the secret key.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)?;
+        file.write_all(&key.secret_key)?;
+        file.sync_all()?;
+
+        Ok(key)
+    }
+
+    pub fn from_secret(secret: &[u8; 32]) -> Result<SectorBuilderKey> {
+        let secret = SecretKey::from_bytes(secret)?;
+        let public = PublicKey::from(&secret);
+
+        Ok(SectorBuilderKey::from_keypair(&Keypair { secret, public }))
+    }
+
+    fn from_keypair(keypair: &Keypair) -> SectorBuilderKey {
+        SectorBuilderKey {
+            public_key: keypair.public.to_bytes(),
+            secret_key: keypair.to_bytes(),
+        }
+    }
+}
+
+// Signs the manifest, as serialized, with the sector builder's key.
+pub fn sign_manifest(key: &SectorBuilderKey, manifest: &[u8]) -> Result<[u8; 64]> {
+    let keypair = Keypair::from_bytes(&key.secret_key)
+        .map_err(|err| format_err!("sector builder key is malformed: {}", err))?;
+
+    Ok(keypair.sign(manifest).to_bytes())
+}
+
+// Returns true if the signature is of the manifest, as serialized, by the
+// secret key corresponding to the public key.
+pub fn verify_manifest(public_key: &[u8; 32], manifest: &[u8], signature: &[u8; 64]) -> bool {
+    let public_key = match PublicKey::from_bytes(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+
+    let signature = match Signature::from_bytes(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    public_key.verify(manifest, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verifies_signed_manifest() {
+        let key = SectorBuilderKey::from_secret(&[3; 32]).unwrap();
+        let mut manifest = br#"{"prover_id":[1,2,3],"entries":[]}"#.to_vec();
+
+        let signature = sign_manifest(&key, &manifest).unwrap();
+        assert!(verify_manifest(&key.public_key, &manifest, &signature));
+
+        // a signature by another key
+        let other = SectorBuilderKey::from_secret(&[4; 32]).unwrap();
+        assert!(!verify_manifest(&other.public_key, &manifest, &signature));
+
+        // a tampered manifest
+        manifest[5] ^= 1;
+        assert!(!verify_manifest(&key.public_key, &manifest, &signature));
+    }
+
+    #[test]
+    fn test_loads_generated_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sector-builder.key");
+
+        let generated = SectorBuilderKey::load_or_generate(&path).unwrap();
+        let loaded = SectorBuilderKey::load_or_generate(&path).unwrap();
+
+        assert_eq!(generated.public_key, loaded.public_key);
+        assert_eq!(&generated.secret_key[..], &loaded.secret_key[..]);
+        assert_eq!(&generated.public_key[..], &loaded.secret_key[32..]);
+
+        let signature = sign_manifest(&loaded, b"manifest").unwrap();
+        assert!(verify_manifest(
+            &generated.public_key,
+            b"manifest",
+            &signature
+        ));
+
+        fs::write(&path, [0; 10]).unwrap();
+        assert!(SectorBuilderKey::load_or_generate(&path).is_err());
+    }
+}
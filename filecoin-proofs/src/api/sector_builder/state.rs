@@ -1,11 +1,21 @@
-use crate::api::sector_builder::metadata::{SealedSectorMetadata, StagedSectorMetadata};
+use crate::api::sector_builder::metadata::{
+    RedactionReceipt, SealedSectorMetadata, StagedSectorMetadata,
+};
 use crate::api::sector_builder::SectorId;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
 
 #[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct StagedState {
     pub sector_id_nonce: SectorId,
     pub sectors: HashMap<SectorId, StagedSectorMetadata>,
+    // The sector to which the piece added with each idempotency key was
+    // written, and when the key expires.
+    #[serde(default)]
+    pub idempotency_keys: BTreeMap<[u8; 16], (SectorId, SystemTime)>,
+    // The pieces (staged or sealed) which have been redacted, by piece key.
+    #[serde(default)]
+    pub redacted_pieces: BTreeMap<String, RedactionReceipt>,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
@@ -18,6 +28,13 @@ pub struct SectorBuilderState {
     pub prover_id: [u8; 31],
     pub staged: StagedState,
     pub sealed: SealedState,
+    pub reserved_ranges: Vec<(SectorId, SectorId)>,
+    pub delta_sequence_number: u64,
+    // The public key of the sector builder's signing key. Not persisted, as
+    // it's read from the key file (if not generated) each time the sector
+    // builder starts, but exported to standbys.
+    #[serde(default)]
+    pub public_key: Option<[u8; 32]>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -25,6 +42,10 @@ pub struct StateSnapshot {
     pub prover_id: [u8; 31],
     pub staged: StagedState,
     pub sealed: SealedState,
+    #[serde(default)]
+    pub reserved_ranges: Vec<(SectorId, SectorId)>,
+    #[serde(default)]
+    pub delta_sequence_number: u64,
 }
 
 impl Into<SectorBuilderState> for StateSnapshot {
@@ -33,6 +54,9 @@ impl Into<SectorBuilderState> for StateSnapshot {
             prover_id: self.prover_id,
             staged: self.staged,
             sealed: self.sealed,
+            reserved_ranges: self.reserved_ranges,
+            delta_sequence_number: self.delta_sequence_number,
+            public_key: None,
         }
     }
 }
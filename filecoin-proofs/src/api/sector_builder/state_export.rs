@@ -0,0 +1,901 @@
+use crate::api::sector_builder::helpers::reserve_sector_id_range::{
+    next_reserved_sector_id, reserve_sector_id_range,
+};
+use crate::api::sector_builder::metadata::{
+    push_piece, unix_epoch, ArchiveReceipt, MerkleTreeState, PieceMetadata, RedactionReceipt,
+    SealStatus, SealedSectorMetadata, StagedSectorMetadata,
+};
+use crate::api::sector_builder::state::{SectorBuilderState, StagedState};
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+// A change made to a sector builder's state, carrying everything a standby
+// needs to make the same change to its own copy.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum StateOperation {
+    // A staged sector was provisioned ahead of the pieces which are to be
+    // written to it (e.g. ahead of time, or to restage a sealed sector's
+    // pieces into). The standby adopts its sector id rather than allocating
+    // one of its own.
+    SectorProvisioned {
+        sector_id: SectorId,
+        #[serde(default)]
+        sector_access: String,
+        #[serde(default = "unix_epoch")]
+        provisioned_at: SystemTime,
+    },
+    // A provisioned staged sector was removed before any of its pieces were
+    // recorded (e.g. as its pieces couldn't be restaged).
+    ProvisionedSectorDiscarded {
+        sector_id: SectorId,
+    },
+    // A piece was written to a staged sector, which may have been provisioned
+    // (at provisioned_at) to hold it. The sector's CRC32 is that computed
+    // once the piece had been written.
+    AddPiece {
+        sector_id: SectorId,
+        sector_access: String,
+        piece: PieceMetadata,
+        #[serde(default = "unix_epoch")]
+        provisioned_at: SystemTime,
+        #[serde(default)]
+        last_crc32: Option<(u32, SystemTime)>,
+    },
+    // The state of the incremental commD computation of a staged sector was
+    // updated (see SectorMetadataManager::handle_merkle_tree_state).
+    MerkleTreeStateUpdated {
+        sector_id: SectorId,
+        merkle_tree_state: MerkleTreeState,
+    },
+    SealStarted {
+        sector_id: SectorId,
+    },
+    SealComplete {
+        sealed_sector: SealedSectorMetadata,
+    },
+    SealFailed {
+        sector_id: SectorId,
+        error: String,
+    },
+    SectorsDeleted {
+        sector_ids: Vec<SectorId>,
+    },
+    SectorIdsReserved {
+        start: SectorId,
+        end: SectorId,
+    },
+    SectorArchived {
+        sector_id: SectorId,
+        archive_receipt: ArchiveReceipt,
+    },
+    PieceAccessRestricted {
+        piece_key: String,
+        access_token_hash: [u8; 32],
+    },
+    MerkleSnapshotTaken {
+        sector_id: SectorId,
+    },
+    // A piece was redacted. If its bytes were overwritten, its sector's CRC32
+    // is that computed afterwards.
+    PieceRedacted {
+        receipt: RedactionReceipt,
+        #[serde(default)]
+        last_crc32: Option<(u32, SystemTime)>,
+    },
+    // The sealed sector's pieces were restaged into a new sector (see
+    // reseal::reseal_sector), and no longer belong to it.
+    SealedPiecesRetired {
+        sector_id: SectorId,
+    },
+    // The sector builder started with the signing key whose public key this
+    // is.
+    PublicKeySet {
+        public_key: [u8; 32],
+    },
+    // A piece was added with an idempotency key (see
+    // SectorBuilder::add_piece_idempotent), which expires at expires_at.
+    IdempotencyKeyRecorded {
+        idempotency_key: [u8; 16],
+        sector_id: SectorId,
+        expires_at: SystemTime,
+    },
+    IdempotencyKeysExpired {
+        idempotency_keys: Vec<[u8; 16]>,
+    },
+}
+
+impl StateOperation {
+    // The provisioning of the (empty) staged sector.
+    pub fn sector_provisioned(sector: &StagedSectorMetadata) -> StateOperation {
+        StateOperation::SectorProvisioned {
+            sector_id: sector.sector_id,
+            sector_access: sector.sector_access.clone(),
+            provisioned_at: sector.provisioned_at,
+        }
+    }
+
+    // The addition of the piece, just written, to the staged sector.
+    pub fn add_piece(sector: &StagedSectorMetadata, piece: PieceMetadata) -> StateOperation {
+        StateOperation::AddPiece {
+            sector_id: sector.sector_id,
+            sector_access: sector.sector_access.clone(),
+            piece,
+            provisioned_at: sector.provisioned_at,
+            last_crc32: sector.last_crc32,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct StateDelta {
+    pub prover_id: [u8; 31],
+    pub sequence_number: u64,
+    pub operation: StateOperation,
+}
+
+impl StateDelta {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<StateDelta> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+// Ships state deltas off to a hot standby, in sequence-number order.
+pub trait StateExporter: Send + Sync {
+    fn export(&self, delta: &StateDelta) -> Result<()>;
+}
+
+// Appends each delta (as JSON) to a Redis stream under the "delta" field.
+#[cfg(feature = "state-export-redis")]
+pub struct RedisStreamExporter {
+    client: redis::Client,
+    stream_key: String,
+}
+
+#[cfg(feature = "state-export-redis")]
+impl RedisStreamExporter {
+    pub fn new<S: Into<String>>(redis_url: &str, stream_key: S) -> Result<RedisStreamExporter> {
+        Ok(RedisStreamExporter {
+            client: redis::Client::open(redis_url)?,
+            stream_key: stream_key.into(),
+        })
+    }
+}
+
+#[cfg(feature = "state-export-redis")]
+impl StateExporter for RedisStreamExporter {
+    fn export(&self, delta: &StateDelta) -> Result<()> {
+        let connection = self.client.get_connection()?;
+
+        let _: String = redis::cmd("XADD")
+            .arg(&self.stream_key)
+            .arg("*")
+            .arg("delta")
+            .arg(delta.to_json()?)
+            .query(&connection)?;
+
+        Ok(())
+    }
+}
+
+// Maintains a standby's copy of a primary sector builder's state by replaying
+// the primary's deltas. Deltas must be applied in order and without gaps.
+pub struct StateImporter {
+    state: SectorBuilderState,
+}
+
+impl StateImporter {
+    // Deltas must be replayed from the primary's first, so the standby starts
+    // from an empty state.
+    pub fn new(prover_id: [u8; 31]) -> StateImporter {
+        StateImporter {
+            state: SectorBuilderState {
+                prover_id,
+                staged: Default::default(),
+                sealed: Default::default(),
+                reserved_ranges: Default::default(),
+                delta_sequence_number: 0,
+                public_key: None,
+            },
+        }
+    }
+
+    pub fn state(&self) -> &SectorBuilderState {
+        &self.state
+    }
+
+    pub fn apply_delta(&mut self, delta: StateDelta) -> Result<()> {
+        if delta.prover_id != self.state.prover_id {
+            return Err(format_err!("delta is for a different prover"));
+        }
+
+        let expected = self.state.delta_sequence_number + 1;
+        if delta.sequence_number != expected {
+            return Err(format_err!(
+                "expected delta {} but received {}",
+                expected,
+                delta.sequence_number
+            ));
+        }
+
+        apply_operation(&mut self.state, delta.operation)?;
+        self.state.delta_sequence_number = delta.sequence_number;
+
+        Ok(())
+    }
+}
+
+fn apply_operation(state: &mut SectorBuilderState, operation: StateOperation) -> Result<()> {
+    let staged = &mut state.staged;
+    let sealed = &mut state.sealed;
+
+    match operation {
+        StateOperation::SectorProvisioned {
+            sector_id,
+            sector_access,
+            provisioned_at,
+        } => {
+            if staged.sectors.contains_key(&sector_id) {
+                return Err(format_err!(
+                    "standby already has staged sector {}",
+                    sector_id
+                ));
+            }
+
+            adopt_sector_id(staged, &mut state.reserved_ranges, sector_id);
+
+            staged.sectors.insert(
+                sector_id,
+                StagedSectorMetadata {
+                    sector_id,
+                    sector_access,
+                    provisioned_at,
+                    ..Default::default()
+                },
+            );
+        }
+        StateOperation::ProvisionedSectorDiscarded { sector_id } => {
+            staged
+                .sectors
+                .remove(&sector_id)
+                .ok_or_else(|| format_err!("standby has no staged sector {}", sector_id))?;
+        }
+        StateOperation::AddPiece {
+            sector_id,
+            sector_access,
+            piece,
+            provisioned_at,
+            last_crc32,
+        } => {
+            // The sector was provisioned to hold the piece.
+            if !staged.sectors.contains_key(&sector_id) {
+                adopt_sector_id(staged, &mut state.reserved_ranges, sector_id);
+
+                staged.sectors.insert(
+                    sector_id,
+                    StagedSectorMetadata {
+                        sector_id,
+                        sector_access,
+                        provisioned_at,
+                        ..Default::default()
+                    },
+                );
+            }
+
+            let sector = staged_sector(staged, sector_id)?;
+            push_piece(sector, piece);
+            sector.last_crc32 = last_crc32;
+        }
+        StateOperation::MerkleTreeStateUpdated {
+            sector_id,
+            merkle_tree_state,
+        } => {
+            staged_sector(staged, sector_id)?.merkle_tree_state = Some(merkle_tree_state);
+        }
+        StateOperation::SealStarted { sector_id } => {
+            staged_sector(staged, sector_id)?.seal_status = SealStatus::Sealing;
+        }
+        StateOperation::SealComplete { sealed_sector } => {
+            staged.sectors.remove(&sealed_sector.sector_id);
+            sealed
+                .sectors
+                .insert(sealed_sector.sector_id, sealed_sector);
+        }
+        StateOperation::SealFailed { sector_id, error } => {
+            staged_sector(staged, sector_id)?.seal_status = SealStatus::Failed(error);
+        }
+        StateOperation::SectorsDeleted { sector_ids } => {
+            for sector_id in sector_ids {
+                staged.sectors.remove(&sector_id);
+                sealed.sectors.remove(&sector_id);
+            }
+        }
+        StateOperation::SectorIdsReserved { start, end } => {
+            let count = (end - start + 1) as u32;
+            let reserved = reserve_sector_id_range(staged, &mut state.reserved_ranges, count)?;
+
+            if reserved != (start, end) {
+                return Err(format_err!(
+                    "primary reserved {:?} but standby reserved {:?}",
+                    (start, end),
+                    reserved
+                ));
+            }
+        }
+        StateOperation::SectorArchived {
+            sector_id,
+            archive_receipt,
+        } => {
+            sealed
+                .sectors
+                .get_mut(&sector_id)
+                .ok_or_else(|| format_err!("standby has no sealed sector {}", sector_id))?
+                .archive_receipt = Some(archive_receipt);
+        }
+        StateOperation::MerkleSnapshotTaken { sector_id } => {
+            sealed
+                .sectors
+                .get_mut(&sector_id)
+                .ok_or_else(|| format_err!("standby has no sealed sector {}", sector_id))?
+                .has_merkle_snapshot = true;
+        }
+        StateOperation::PieceAccessRestricted {
+            piece_key,
+            access_token_hash,
+        } => {
+            let staged_pieces = staged
+                .sectors
+                .values_mut()
+                .flat_map(|sector| sector.pieces.values_mut());
+
+            let sealed_pieces = sealed
+                .sectors
+                .values_mut()
+                .flat_map(|sector| sector.pieces.iter_mut());
+
+            staged_pieces
+                .chain(sealed_pieces)
+                .find(|piece| piece.piece_key == piece_key)
+                .ok_or_else(|| format_err!("standby has no piece {}", piece_key))?
+                .access_token_hash = Some(access_token_hash);
+        }
+        StateOperation::PieceRedacted {
+            receipt,
+            last_crc32,
+        } => {
+            // The piece's bytes were overwritten on the primary, if at all.
+            if let Some(sector) = staged.sectors.get_mut(&receipt.sector_id) {
+                if receipt.was_overwritten {
+                    sector.merkle_tree_state = None;
+                    sector.last_crc32 = last_crc32;
+                }
+            }
+
+            let staged_pieces = staged
+                .sectors
+                .values_mut()
+                .flat_map(|sector| sector.pieces.values_mut());
+
+            let sealed_pieces = sealed
+                .sectors
+                .values_mut()
+                .flat_map(|sector| sector.pieces.iter_mut());
+
+            staged_pieces
+                .chain(sealed_pieces)
+                .find(|piece| piece.piece_key == receipt.piece_key)
+                .ok_or_else(|| format_err!("standby has no piece {}", receipt.piece_key))?
+                .checksum = None;
+
+            staged
+                .redacted_pieces
+                .insert(receipt.piece_key.clone(), receipt);
+        }
+        StateOperation::SealedPiecesRetired { sector_id } => {
+            sealed
+                .sectors
+                .get_mut(&sector_id)
+                .ok_or_else(|| format_err!("standby has no sealed sector {}", sector_id))?
+                .pieces
+                .clear();
+        }
+        StateOperation::PublicKeySet { public_key } => {
+            state.public_key = Some(public_key);
+        }
+        StateOperation::IdempotencyKeyRecorded {
+            idempotency_key,
+            sector_id,
+            expires_at,
+        } => {
+            staged
+                .idempotency_keys
+                .insert(idempotency_key, (sector_id, expires_at));
+        }
+        StateOperation::IdempotencyKeysExpired { idempotency_keys } => {
+            for idempotency_key in idempotency_keys {
+                staged.idempotency_keys.remove(&idempotency_key);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Keeps the standby's allocation of sector ids in step with the primary's,
+// which allocated the sector id: either it was the next reserved id, which is
+// consumed, or the nonce was advanced to it.
+fn adopt_sector_id(
+    staged: &mut StagedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    sector_id: SectorId,
+) {
+    if reserved_ranges.first().map(|(start, _)| *start) == Some(sector_id) {
+        next_reserved_sector_id(staged, reserved_ranges);
+    } else {
+        staged.sector_id_nonce = std::cmp::max(staged.sector_id_nonce, sector_id);
+    }
+}
+
+fn staged_sector(
+    staged: &mut StagedState,
+    sector_id: SectorId,
+) -> Result<&mut StagedSectorMetadata> {
+    staged
+        .sectors
+        .get_mut(&sector_id)
+        .ok_or_else(|| format_err!("standby has no staged sector {}", sector_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::config::SectorBuilderConfig;
+    use crate::api::sector_builder::factory::{DefaultSectorBuilderFactory, SectorBuilderFactory};
+    use crate::api::sector_builder::helpers::add_piece::provision_new_staged_sector;
+    use crate::api::sector_builder::helpers::snapshots::{load_snapshot, make_snapshot};
+    use crate::api::sector_builder::kv_store::{KeyValueStore, SledKvs};
+    use crate::api::sector_builder::metrics::Histogram;
+    use crate::api::sector_builder::sector_id_allocator::SectorIdAllocator;
+    use crate::api::sector_builder::state::StateSnapshot;
+    use crate::api::sector_builder::test_utils::add_piece;
+    use crate::api::sector_builder::test_utils::{mock_sector_store, subdir, TEST_CLASS};
+    use crate::api::sector_builder::WrappedKeyValueStore;
+    use std::fs;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::UNIX_EPOCH;
+
+    #[derive(Default)]
+    struct RecordingExporter {
+        deltas: Mutex<Vec<String>>,
+    }
+
+    impl StateExporter for RecordingExporter {
+        fn export(&self, delta: &StateDelta) -> Result<()> {
+            self.deltas.lock().unwrap().push(delta.to_json()?);
+            Ok(())
+        }
+    }
+
+    // Mirrors SectorMetadataManager: each change to the primary's state is
+    // followed by the export of a delta describing it.
+    fn export(
+        primary: &mut SectorBuilderState,
+        exporter: &StateExporter,
+        operation: StateOperation,
+    ) {
+        primary.delta_sequence_number += 1;
+
+        let delta = StateDelta {
+            prover_id: primary.prover_id,
+            sequence_number: primary.delta_sequence_number,
+            operation,
+        };
+
+        exporter.export(&delta).unwrap();
+    }
+
+    fn snapshot(state: &SectorBuilderState) -> StateSnapshot {
+        make_snapshot(
+            &state.prover_id,
+            &state.staged,
+            &state.sealed,
+            &state.reserved_ranges,
+            state.delta_sequence_number,
+        )
+    }
+
+    #[test]
+    fn test_standby_tracks_primary() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let prover_id = [7; 31];
+        let metadata_dir = subdir(dir.path(), "metadata");
+        let pieces_dir = subdir(dir.path(), "pieces");
+
+        let factory = DefaultSectorBuilderFactory {
+            sector_class: TEST_CLASS,
+            last_committed_sector_id: 0,
+            metadata_dir: metadata_dir.clone(),
+            prover_id,
+            sealed_sector_dir: subdir(dir.path(), "sealed"),
+            staged_sector_dir: subdir(dir.path(), "staged"),
+            max_num_staged_sectors: 4,
+        };
+
+        let exporter = Arc::new(RecordingExporter::default());
+
+        {
+            let sector_builder = factory
+                .create_sector_builder(SectorBuilderConfig {
+                    state_exporter: Some(exporter.clone()),
+                    ..Default::default()
+                })
+                .unwrap();
+
+            let piece = |key: &str, num_bytes: usize| {
+                let path = format!("{}/{}", pieces_dir, key);
+                fs::write(&path, vec![1; num_bytes]).unwrap();
+                path
+            };
+
+            // None of the sectors fills up, so none is sealed.
+            let first = sector_builder
+                .add_piece("a".to_string(), 100, piece("a", 100))
+                .unwrap();
+            sector_builder
+                .add_piece("b".to_string(), 200, piece("b", 200))
+                .unwrap();
+
+            let (start, _) = sector_builder.reserve_sector_id_range(2).unwrap();
+            let second = sector_builder
+                .add_piece("c".to_string(), 800, piece("c", 800))
+                .unwrap();
+            assert_eq!(start, second);
+
+            for _ in 0..2 {
+                let sector_id = sector_builder
+                    .add_piece_idempotent("d".to_string(), 50, piece("d", 50), [1; 16])
+                    .unwrap();
+                assert_eq!(first, sector_id);
+            }
+
+            // Enough pieces (all of which fit in the first sector) for it to
+            // be judged nearly full, so that another is provisioned ahead of
+            // time.
+            for i in 0..6 {
+                let key = format!("e{}", i);
+                sector_builder
+                    .add_piece(key.clone(), 100, piece(&key, 100))
+                    .unwrap();
+            }
+
+            let deleted = sector_builder
+                .delete_sectors_batch(&[second], false)
+                .unwrap();
+            assert_eq!(vec![second], deleted.deleted);
+        }
+
+        let mut standby = StateImporter::new(prover_id);
+        for json in exporter.deltas.lock().unwrap().iter() {
+            standby
+                .apply_delta(StateDelta::from_json(json).unwrap())
+                .unwrap();
+        }
+
+        let kv_store = Arc::new(WrappedKeyValueStore {
+            inner: Box::new(SledKvs::initialize(&metadata_dir).unwrap()),
+        });
+        let primary = load_snapshot(&kv_store, &prover_id).unwrap().unwrap();
+
+        assert_eq!(1, standby.state().staged.idempotency_keys.len());
+        assert_eq!(2, standby.state().staged.sectors.len());
+        assert_eq!(primary, snapshot(standby.state()));
+    }
+
+    // Covers the operations (e.g. sealing) which a live sector builder
+    // can't be driven through quickly.
+    #[test]
+    fn test_standby_replays_deltas() {
+        let (sector_store, _) = mock_sector_store();
+        let exporter = RecordingExporter::default();
+        let prover_id = [7; 31];
+
+        let mut primary = StateImporter::new(prover_id).state;
+
+        let add = |primary: &mut SectorBuilderState, key: &str, num_bytes: usize| {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(&vec![1; num_bytes]).unwrap();
+
+            let sector_id = add_piece(
+                &sector_store,
+                &mut primary.staged,
+                &mut primary.reserved_ranges,
+                // the standby replays nonce-based allocation
+                &mut SectorIdAllocator::nonce(),
+                key.to_string(),
+                num_bytes as u64,
+                file.path().to_str().unwrap().to_string(),
+                None,
+                &Histogram::new(vec![]).unwrap(),
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap();
+
+            let sector = &primary.staged.sectors[&sector_id];
+            let operation = StateOperation::add_piece(
+                sector,
+                sector.pieces.values().next_back().unwrap().clone(),
+            );
+
+            export(primary, &exporter, operation);
+
+            sector_id
+        };
+
+        // 1-3: fill one sector and start another
+        let first = add(&mut primary, "a", 500);
+        add(&mut primary, "b", 500);
+        let second = add(&mut primary, "c", 600);
+        assert_ne!(first, second);
+
+        // 4: reserve some sector ids
+        let (start, end) =
+            reserve_sector_id_range(&mut primary.staged, &mut primary.reserved_ranges, 2).unwrap();
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::SectorIdsReserved { start, end },
+        );
+
+        // 5: which the next sector uses
+        assert_eq!(start, add(&mut primary, "d", 900));
+
+        // 6: whose commD is precomputed
+        let merkle_tree_state: MerkleTreeState = Default::default();
+        primary
+            .staged
+            .sectors
+            .get_mut(&start)
+            .unwrap()
+            .merkle_tree_state = Some(merkle_tree_state.clone());
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::MerkleTreeStateUpdated {
+                sector_id: start,
+                merkle_tree_state,
+            },
+        );
+
+        // 7-8: seal the first sector
+        primary.staged.sectors.get_mut(&first).unwrap().seal_status = SealStatus::Sealing;
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::SealStarted { sector_id: first },
+        );
+
+        let staged = primary.staged.sectors.remove(&first).unwrap();
+        let sealed_sector = SealedSectorMetadata {
+            sector_id: first,
+            sector_access: "sealed".to_string(),
+            pieces: staged.pieces.into_iter().map(|(_, piece)| piece).collect(),
+            comm_r: [1; 32],
+            ..Default::default()
+        };
+        primary.sealed.sectors.insert(first, sealed_sector.clone());
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::SealComplete { sealed_sector },
+        );
+
+        // 9-10: fail to seal the second
+        primary.staged.sectors.get_mut(&second).unwrap().seal_status = SealStatus::Sealing;
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::SealStarted { sector_id: second },
+        );
+
+        let error = "out of disk".to_string();
+        primary.staged.sectors.get_mut(&second).unwrap().seal_status =
+            SealStatus::Failed(error.clone());
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::SealFailed {
+                sector_id: second,
+                error,
+            },
+        );
+
+        // 11: and delete it
+        primary.staged.sectors.remove(&second);
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::SectorsDeleted {
+                sector_ids: vec![second],
+            },
+        );
+
+        let deltas = exporter.deltas.lock().unwrap();
+        assert_eq!(11, deltas.len());
+
+        let mut standby = StateImporter::new(prover_id);
+        for json in deltas.iter() {
+            standby
+                .apply_delta(StateDelta::from_json(json).unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(snapshot(&primary), snapshot(standby.state()));
+    }
+
+    #[test]
+    fn test_standby_adopts_provisioned_sectors() {
+        let (sector_store, _) = mock_sector_store();
+        let exporter = RecordingExporter::default();
+        let prover_id = [7; 31];
+
+        let mut primary = StateImporter::new(prover_id).state;
+
+        // ids which the standby's nonce couldn't have predicted
+        let mut allocator = SectorIdAllocator::deterministic(42);
+
+        let mut provision = |primary: &mut SectorBuilderState| {
+            let sector_id = provision_new_staged_sector(
+                sector_store.inner.manager(),
+                &mut primary.staged,
+                &mut primary.reserved_ranges,
+                &mut allocator,
+                None,
+            )
+            .unwrap();
+
+            let operation = StateOperation::sector_provisioned(&primary.staged.sectors[&sector_id]);
+            export(primary, &exporter, operation);
+
+            sector_id
+        };
+
+        let first = provision(&mut primary);
+        let second = provision(&mut primary);
+
+        primary.staged.sectors.remove(&second);
+        export(
+            &mut primary,
+            &exporter,
+            StateOperation::ProvisionedSectorDiscarded { sector_id: second },
+        );
+
+        let mut standby = StateImporter::new(prover_id);
+        for json in exporter.deltas.lock().unwrap().iter() {
+            standby
+                .apply_delta(StateDelta::from_json(json).unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(primary.staged.sectors, standby.state().staged.sectors);
+        assert!(standby.state().staged.sectors.contains_key(&first));
+
+        // a sector can't be provisioned twice
+        let delta = StateDelta {
+            prover_id,
+            sequence_number: standby.state().delta_sequence_number + 1,
+            operation: StateOperation::sector_provisioned(&primary.staged.sectors[&first]),
+        };
+        assert!(standby.apply_delta(delta).is_err());
+    }
+
+    #[test]
+    fn test_reads_deltas_without_sector_times() {
+        let operation = StateOperation::add_piece(&Default::default(), Default::default());
+
+        // as exported before AddPiece carried the sector's provisioning time
+        // and CRC32
+        let mut json = serde_json::to_value(&operation).unwrap();
+        let fields = json["AddPiece"].as_object_mut().unwrap();
+        assert!(fields.remove("provisioned_at").is_some());
+        assert!(fields.remove("last_crc32").is_some());
+
+        match serde_json::from_value(json).unwrap() {
+            StateOperation::AddPiece {
+                provisioned_at,
+                last_crc32,
+                ..
+            } => {
+                assert_eq!(UNIX_EPOCH, provisioned_at);
+                assert_eq!(None, last_crc32);
+            }
+            operation => panic!("unexpected operation {:?}", operation),
+        }
+    }
+
+    #[test]
+    fn test_rejects_gaps_and_foreign_deltas() {
+        let mut standby = StateImporter::new([0; 31]);
+
+        let delta = |prover_id, sequence_number| StateDelta {
+            prover_id,
+            sequence_number,
+            operation: StateOperation::SectorIdsReserved { start: 1, end: 1 },
+        };
+
+        assert!(standby.apply_delta(delta([1; 31], 1)).is_err());
+        assert!(standby.apply_delta(delta([0; 31], 2)).is_err());
+        assert!(standby.apply_delta(delta([0; 31], 1)).is_ok());
+        assert!(standby.apply_delta(delta([0; 31], 1)).is_err());
+        assert_eq!(1, standby.state().delta_sequence_number);
+    }
+
+    #[test]
+    fn test_standby_tracks_idempotency_keys() {
+        let mut standby = StateImporter::new([0; 31]);
+        let expires_at = SystemTime::now();
+
+        let operations = vec![
+            StateOperation::IdempotencyKeyRecorded {
+                idempotency_key: [1; 16],
+                sector_id: 1,
+                expires_at,
+            },
+            StateOperation::IdempotencyKeyRecorded {
+                idempotency_key: [2; 16],
+                sector_id: 2,
+                expires_at,
+            },
+            StateOperation::IdempotencyKeysExpired {
+                idempotency_keys: vec![[1; 16]],
+            },
+        ];
+
+        for (i, operation) in operations.into_iter().enumerate() {
+            let delta = StateDelta {
+                prover_id: [0; 31],
+                sequence_number: i as u64 + 1,
+                operation,
+            };
+
+            standby
+                .apply_delta(StateDelta::from_json(&delta.to_json().unwrap()).unwrap())
+                .unwrap();
+        }
+
+        let idempotency_keys: Vec<_> = standby
+            .state()
+            .staged
+            .idempotency_keys
+            .iter()
+            .map(|(key, value)| (*key, *value))
+            .collect();
+        assert_eq!(vec![([2; 16], (2, expires_at))], idempotency_keys);
+    }
+
+    #[test]
+    fn test_standby_learns_public_key() {
+        let mut standby = StateImporter::new([0; 31]);
+        assert_eq!(None, standby.state().public_key);
+
+        let delta = StateDelta {
+            prover_id: [0; 31],
+            sequence_number: 1,
+            operation: StateOperation::PublicKeySet {
+                public_key: [9; 32],
+            },
+        };
+
+        standby
+            .apply_delta(StateDelta::from_json(&delta.to_json().unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(Some([9; 32]), standby.state().public_key);
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use crate::api::sector_builder::scheduler::Request;
+use crate::api::sector_builder::{log_unrecov, SectorBuilder, SectorId};
+use crate::error::Result;
+
+// Where the time went while retrieving a piece. The phases are consecutive,
+// so their latencies add up to the total (less the rounding of each to whole
+// microseconds).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PieceTelemetry {
+    // finding the sealed sector containing the piece, and waiting for a worker
+    // to retrieve it
+    pub lookup_latency_us: u64,
+    // reading the sealed sector's replica (or, on a cache hit, copying the
+    // prefetched bytes)
+    pub read_latency_us: u64,
+    // unsealing the piece-bytes from the replica (zero on a cache hit)
+    pub decode_latency_us: u64,
+    pub total_latency_us: u64,
+    // whether the piece had been prefetched
+    pub cache_hit: bool,
+    pub sector_id: SectorId,
+}
+
+impl PieceTelemetry {
+    // Measures each phase of a retrieval from the (monotonic) instants at
+    // which it started, its read and decode phases began, and it finished.
+    pub fn from_checkpoints(
+        started_at: Instant,
+        read_started_at: Instant,
+        decode_started_at: Instant,
+        finished_at: Instant,
+        cache_hit: bool,
+        sector_id: SectorId,
+    ) -> PieceTelemetry {
+        PieceTelemetry {
+            lookup_latency_us: micros_between(started_at, read_started_at),
+            read_latency_us: micros_between(read_started_at, decode_started_at),
+            decode_latency_us: micros_between(decode_started_at, finished_at),
+            total_latency_us: micros_between(started_at, finished_at),
+            cache_hit,
+            sector_id,
+        }
+    }
+}
+
+fn micros_between(earlier: Instant, later: Instant) -> u64 {
+    let elapsed = if later > earlier {
+        later - earlier
+    } else {
+        Duration::from_secs(0)
+    };
+
+    elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros())
+}
+
+// Unseals the sector containing the referenced piece and returns its bytes,
+// along with how long each phase of retrieving them took. See
+// SectorBuilder::read_piece_from_sealed_sector.
+pub fn get_piece_with_telemetry(
+    sector_builder: &SectorBuilder,
+    piece_key: String,
+) -> Result<(Vec<u8>, PieceTelemetry)> {
+    let started_at = Instant::now();
+
+    log_unrecov(
+        sector_builder.run_blocking(|tx| Request::RetrievePiece(piece_key, None, started_at, tx)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_latencies_sum_to_total() {
+        let started_at = Instant::now();
+        let read_started_at = started_at + Duration::from_nanos(1_500_700);
+        let decode_started_at = read_started_at + Duration::from_nanos(2_000_900);
+        let finished_at = decode_started_at + Duration::from_nanos(35_100_600);
+
+        let telemetry = PieceTelemetry::from_checkpoints(
+            started_at,
+            read_started_at,
+            decode_started_at,
+            finished_at,
+            false,
+            7,
+        );
+
+        assert_eq!(1_500, telemetry.lookup_latency_us);
+        assert_eq!(2_000, telemetry.read_latency_us);
+        assert_eq!(35_100, telemetry.decode_latency_us);
+        assert_eq!(38_602, telemetry.total_latency_us);
+
+        // each component is rounded down by less than a microsecond
+        let sum =
+            telemetry.lookup_latency_us + telemetry.read_latency_us + telemetry.decode_latency_us;
+        assert!(sum <= telemetry.total_latency_us);
+        assert!(telemetry.total_latency_us - sum < 3);
+    }
+
+    #[test]
+    fn test_cache_hit_has_no_decode_phase() {
+        let started_at = Instant::now();
+        let read_started_at = started_at + Duration::from_micros(40);
+        let finished_at = read_started_at + Duration::from_micros(10);
+
+        let telemetry = PieceTelemetry::from_checkpoints(
+            started_at,
+            read_started_at,
+            finished_at,
+            finished_at,
+            true,
+            7,
+        );
+
+        assert_eq!(0, telemetry.decode_latency_us);
+        assert_eq!(50, telemetry.total_latency_us);
+        assert!(telemetry.cache_hit);
+    }
+}
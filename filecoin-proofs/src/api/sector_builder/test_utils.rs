@@ -0,0 +1,563 @@
+use crate::api::sector_builder::archive::ArchiveBackend;
+use crate::api::sector_builder::config::SectorBuilderConfig;
+use crate::api::sector_builder::factory::DefaultSectorBuilderFactory;
+use crate::api::sector_builder::helpers::add_piece::{
+    piece_destination, push_written_piece, write_piece_bytes,
+};
+use crate::api::sector_builder::helpers::check_staged_sector_crc32::record_sector_crc32;
+use crate::api::sector_builder::helpers::incremental_merkle_tree::{
+    domain_to_bytes, TreeDomain, NODE_SIZE,
+};
+use crate::api::sector_builder::helpers::validate_sector_access::validate_sector_access;
+use crate::api::sector_builder::io_scheduler::IoScheduler;
+use crate::api::sector_builder::metadata::ApiVersion;
+use crate::api::sector_builder::metrics::{Clock, Histogram};
+use crate::api::sector_builder::seal_verifier::SealVerifier;
+use crate::api::sector_builder::sector_id_allocator::SectorIdAllocator;
+use crate::api::sector_builder::sector_locks::SectorLocks;
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::{SectorBuilder, SectorId, WrappedSectorStore};
+use crate::error;
+use sector_base::api::bandwidth::NetworkBandwidthAccounting;
+use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+use sector_base::api::disk_backed_storage::{new_sector_store, Config};
+use sector_base::api::errors::SectorManagerErr;
+use sector_base::api::porep_proof_partitions::PoRepProofPartitions;
+use sector_base::api::post_proof_partitions::PoStProofPartitions;
+use sector_base::api::sector_class::SectorClass;
+use sector_base::api::sector_size::SectorSize;
+use sector_base::api::sector_store::{ProofsConfig, SectorConfig, SectorManager, SectorStore};
+use sector_base::api::staged_sector_file::{SectorFileHandle, StagedSectorFile};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use storage_proofs::hasher::pedersen::PedersenFunction;
+use storage_proofs::hasher::Domain;
+use storage_proofs::merkle::VecMerkleTree;
+
+pub const TEST_CLASS: SectorClass = SectorClass(
+    SectorSize::OneKiB,
+    PoRepProofPartitions::Two,
+    PoStProofPartitions::One,
+);
+
+// Sectors large enough that the pieces a test adds all fit in one live
+// sector, so that none is sealed.
+pub const LARGE_CLASS: SectorClass = SectorClass(
+    SectorSize::TwoHundredFiftySixMiB,
+    PoRepProofPartitions::Two,
+    PoStProofPartitions::One,
+);
+
+// A clock which only moves when it's advanced.
+#[derive(Debug)]
+pub struct MockClock {
+    started_at: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock {
+            started_at: Instant::now(),
+            elapsed: Default::default(),
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.started_at + *self.elapsed.lock().unwrap()
+    }
+}
+
+// An in-memory SectorManager which counts the I/O performed through it.
+// Piece-bytes are stored as-is (no preprocessing) so that tests can reason
+// about offsets without accounting for Fr32 padding.
+#[derive(Default)]
+pub struct MockSectorManager {
+    pub files: Mutex<HashMap<String, Vec<u8>>>,
+    pub num_reads: AtomicUsize,
+    pub num_writes: AtomicUsize,
+    // when set, each write advances the clock by this much
+    pub write_delay: Mutex<Option<Duration>>,
+    pub clock: Arc<MockClock>,
+    bandwidth: NetworkBandwidthAccounting,
+    nonce: AtomicUsize,
+}
+
+impl MockSectorManager {
+    pub fn num_reads(&self) -> usize {
+        self.num_reads.load(Ordering::SeqCst)
+    }
+
+    pub fn num_writes(&self) -> usize {
+        self.num_writes.load(Ordering::SeqCst)
+    }
+
+    pub fn contents(&self, access: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(access).cloned()
+    }
+
+    fn new_access(&self, prefix: &str) -> String {
+        let n = self.nonce.fetch_add(1, Ordering::SeqCst);
+        let access = format!("{}/{}", prefix, n);
+        self.files.lock().unwrap().insert(access.clone(), vec![]);
+
+        access
+    }
+
+    fn remove(&self, access: &str) -> Result<(), SectorManagerErr> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(access)
+            .map(|_| ())
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))
+    }
+}
+
+impl SectorManager for MockSectorManager {
+    fn new_sealed_sector_access(&self) -> Result<String, SectorManagerErr> {
+        Ok(self.new_access("sealed"))
+    }
+
+    fn new_staging_sector_access(&self) -> Result<String, SectorManagerErr> {
+        Ok(self.new_access("staged"))
+    }
+
+    // Opens a copy of the sector's bytes, written (with a header) to a
+    // temporary file, which is removed once the handle is dropped. Writes
+    // through the handle aren't seen by the mock.
+    fn open_and_verify_sector(&self, access: &str) -> Result<SectorFileHandle, SectorManagerErr> {
+        let bytes = self
+            .contents(access)
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))?;
+
+        let copy = tempfile::NamedTempFile::new()
+            .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))?;
+
+        // the header's sector size is only checked against itself
+        let sector_size = 0;
+
+        StagedSectorFile::create(copy.path(), sector_size)?
+            .write_all(&bytes)
+            .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))?;
+
+        SectorFileHandle::open_and_verify(copy.path(), sector_size, false)
+    }
+
+    fn num_unsealed_bytes(&self, access: &str) -> Result<u64, SectorManagerErr> {
+        self.contents(access)
+            .map(|bytes| bytes.len() as u64)
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))
+    }
+
+    fn truncate_unsealed(&self, access: &str, size: u64) -> Result<(), SectorManagerErr> {
+        self.files
+            .lock()
+            .unwrap()
+            .get_mut(access)
+            .map(|bytes| bytes.truncate(size as usize))
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))
+    }
+
+    fn write_and_preprocess(
+        &self,
+        access: &str,
+        data: &mut dyn Read,
+    ) -> Result<UnpaddedBytesAmount, SectorManagerErr> {
+        self.num_writes.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(delay) = *self.write_delay.lock().unwrap() {
+            self.clock.advance(delay);
+        }
+
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf)
+            .map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))?;
+
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .get_mut(access)
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))?;
+        file.extend_from_slice(&buf);
+        self.bandwidth.record_sent(buf.len() as u64);
+
+        Ok(UnpaddedBytesAmount(buf.len() as u64))
+    }
+
+    fn sync_staging_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
+        self.contents(access)
+            .map(|_| ())
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))
+    }
+
+    fn delete_staging_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
+        self.remove(access)
+    }
+
+    fn delete_sealed_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
+        self.remove(access)
+    }
+
+    fn rename_sector_access(&self, old: &str, new: &str) -> Result<(), SectorManagerErr> {
+        let mut files = self.files.lock().unwrap();
+        let bytes = files
+            .remove(old)
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", old)))?;
+        files.insert(new.to_string(), bytes);
+
+        Ok(())
+    }
+
+    fn list_sealed_sector_accesses(&self) -> Result<Vec<String>, SectorManagerErr> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|access| access.starts_with("sealed/"))
+            .cloned()
+            .collect())
+    }
+
+    fn read_raw(
+        &self,
+        access: &str,
+        start_offset: u64,
+        num_bytes: UnpaddedBytesAmount,
+    ) -> Result<Vec<u8>, SectorManagerErr> {
+        self.num_reads.fetch_add(1, Ordering::SeqCst);
+
+        let files = self.files.lock().unwrap();
+        let file = files
+            .get(access)
+            .ok_or_else(|| SectorManagerErr::CallerError(format!("no such access: {}", access)))?;
+
+        let start = start_offset as usize;
+        let end = start + usize::from(num_bytes);
+
+        if end > file.len() {
+            return Err(SectorManagerErr::CallerError(format!(
+                "read past end of {}",
+                access
+            )));
+        }
+
+        self.bandwidth.record_received(num_bytes.into());
+
+        Ok(file[start..end].to_vec())
+    }
+
+    fn bandwidth_accounting(&self) -> &NetworkBandwidthAccounting {
+        &self.bandwidth
+    }
+}
+
+pub struct MockSectorStore {
+    config: Config,
+    manager: Arc<MockSectorManager>,
+}
+
+impl SectorStore for MockSectorStore {
+    fn sector_config(&self) -> &SectorConfig {
+        &self.config
+    }
+
+    fn proofs_config(&self) -> &ProofsConfig {
+        &self.config
+    }
+
+    fn manager(&self) -> &SectorManager {
+        self.manager.as_ref()
+    }
+}
+
+// A SealVerifier which accepts or rejects seal proofs according to their
+// sector id (or else their API version), and records the sector ids whose
+// proofs it was asked to verify.
+pub struct MockSealVerifier {
+    pub verified: Mutex<Vec<SectorId>>,
+    results: HashMap<SectorId, bool>,
+    api_version_results: HashMap<ApiVersion, bool>,
+    default_result: bool,
+}
+
+impl MockSealVerifier {
+    pub fn new(default_result: bool) -> MockSealVerifier {
+        MockSealVerifier {
+            verified: Default::default(),
+            results: Default::default(),
+            api_version_results: Default::default(),
+            default_result,
+        }
+    }
+
+    pub fn with_result(mut self, sector_id: SectorId, result: bool) -> MockSealVerifier {
+        self.results.insert(sector_id, result);
+        self
+    }
+
+    pub fn with_api_version_result(
+        mut self,
+        api_version: ApiVersion,
+        result: bool,
+    ) -> MockSealVerifier {
+        self.api_version_results.insert(api_version, result);
+        self
+    }
+}
+
+impl SealVerifier for MockSealVerifier {
+    fn verify_seal(
+        &self,
+        api_version: ApiVersion,
+        _prover_id: &[u8; 31],
+        sector_id: SectorId,
+        _comm_r: [u8; 32],
+        _comm_d: [u8; 32],
+        _comm_r_star: [u8; 32],
+        _proof: &[u8],
+    ) -> error::Result<bool> {
+        self.verified.lock().unwrap().push(sector_id);
+
+        Ok(*self
+            .results
+            .get(&sector_id)
+            .or_else(|| self.api_version_results.get(&api_version))
+            .unwrap_or(&self.default_result))
+    }
+
+    fn verify_post(
+        &self,
+        _prover_id: &[u8; 31],
+        _challenge_seed: [u8; 32],
+        _comm_rs: &[[u8; 32]],
+        _proofs: &[Vec<u8>],
+        _faults: &[u64],
+    ) -> error::Result<bool> {
+        Ok(self.default_result)
+    }
+}
+
+// An ArchiveBackend which keeps archived replicas in memory and counts the
+// bytes it serves.
+pub struct MockArchiveBackend {
+    pub objects: Mutex<HashMap<String, Vec<u8>>>,
+    supports_ranges: bool,
+    num_bytes_served: AtomicUsize,
+}
+
+impl MockArchiveBackend {
+    pub fn new(supports_ranges: bool) -> MockArchiveBackend {
+        MockArchiveBackend {
+            objects: Default::default(),
+            supports_ranges,
+            num_bytes_served: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn object(&self, url: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn num_bytes_served(&self) -> usize {
+        self.num_bytes_served.load(Ordering::SeqCst)
+    }
+}
+
+impl ArchiveBackend for MockArchiveBackend {
+    fn upload(&self, sector_id: SectorId, source: &mut Read) -> error::Result<String> {
+        let mut object = Vec::new();
+        source.read_to_end(&mut object)?;
+
+        let url = format!("mock://archive/{}", sector_id);
+        self.objects.lock().unwrap().insert(url.clone(), object);
+
+        Ok(url)
+    }
+
+    fn download(&self, url: &str, sink: &mut Write) -> error::Result<()> {
+        let object = self
+            .object(url)
+            .ok_or_else(|| format_err!("nothing archived at {}", url))?;
+
+        sink.write_all(&object)?;
+        self.num_bytes_served
+            .fetch_add(object.len(), Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn download_range(
+        &self,
+        url: &str,
+        offset: u64,
+        num_bytes: u64,
+    ) -> error::Result<Option<Vec<u8>>> {
+        if !self.supports_ranges {
+            return Ok(None);
+        }
+
+        let object = self
+            .object(url)
+            .ok_or_else(|| format_err!("nothing archived at {}", url))?;
+
+        let start = std::cmp::min(offset as usize, object.len());
+        let end = std::cmp::min((offset + num_bytes) as usize, object.len());
+
+        self.num_bytes_served
+            .fetch_add(end - start, Ordering::SeqCst);
+
+        Ok(Some(object[start..end].to_vec()))
+    }
+}
+
+// Returns a sector store backed by a MockSectorManager, along with a handle
+// to that manager so that tests can inspect it.
+pub fn mock_sector_store() -> (Arc<WrappedSectorStore>, Arc<MockSectorManager>) {
+    let manager: Arc<MockSectorManager> = Default::default();
+
+    let store = MockSectorStore {
+        config: Config::from(TEST_CLASS),
+        manager: manager.clone(),
+    };
+
+    let wrapped = Arc::new(WrappedSectorStore {
+        inner: Box::new(store),
+    });
+
+    (wrapped, manager)
+}
+
+// Adds the piece to the staged sectors in the manner of the scheduler, writing
+// it on the caller's thread.
+#[allow(clippy::too_many_arguments)]
+pub fn add_piece(
+    sector_store: &Arc<WrappedSectorStore>,
+    staged_state: &mut StagedState,
+    reserved_ranges: &mut Vec<(SectorId, SectorId)>,
+    sector_id_allocator: &mut SectorIdAllocator,
+    piece_key: String,
+    piece_bytes_amount: u64,
+    piece_path: String,
+    sector_access_root: Option<&Path>,
+    piece_ingestion_histogram: &Histogram,
+    io_scheduler: &IoScheduler,
+    sector_locks: &SectorLocks,
+) -> error::Result<SectorId> {
+    let sector_id = piece_destination(
+        sector_store,
+        staged_state,
+        reserved_ranges,
+        sector_id_allocator,
+        piece_bytes_amount,
+        sector_access_root,
+    )?;
+
+    let s = staged_state.sectors.get_mut(&sector_id).unwrap();
+    validate_sector_access(&s.sector_access, sector_access_root)?;
+
+    let piece_bytes_len = UnpaddedBytesAmount(piece_bytes_amount);
+    let checksum = write_piece_bytes(
+        sector_store,
+        sector_id,
+        &s.sector_access,
+        piece_bytes_len,
+        &piece_path,
+        piece_ingestion_histogram,
+        io_scheduler,
+        sector_locks,
+        None,
+        &mut io::sink(),
+    )?;
+
+    push_written_piece(s, piece_key, piece_bytes_len, checksum);
+    record_sector_crc32(sector_store, s);
+
+    Ok(sector_id)
+}
+
+// Creates the named subdirectory of dir (if it doesn't exist), returning its
+// path.
+pub fn subdir(dir: &Path, name: &str) -> String {
+    let path = dir.join(name);
+    fs::create_dir_all(&path).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+// Returns a factory for sector builders of the given class, which keep their
+// metadata and sectors in subdirectories of dir.
+pub fn sector_builder_factory(
+    sector_class: SectorClass,
+    dir: &Path,
+) -> error::Result<DefaultSectorBuilderFactory> {
+    Ok(DefaultSectorBuilderFactory {
+        sector_class,
+        last_committed_sector_id: 0,
+        metadata_dir: subdir(dir, "metadata"),
+        prover_id: [0; 31],
+        sealed_sector_dir: subdir(dir, "sealed"),
+        staged_sector_dir: subdir(dir, "staged"),
+        max_num_staged_sectors: 2,
+    })
+}
+
+// Returns a started sector builder of LARGE_CLASS sectors, which keeps its
+// metadata and sectors in subdirectories of dir.
+pub fn sector_builder(dir: &Path, config: SectorBuilderConfig) -> SectorBuilder {
+    sector_builder_factory(LARGE_CLASS, dir)
+        .unwrap()
+        .create_concrete_sector_builder(config)
+        .unwrap()
+}
+
+// Returns a disk-backed store of TEST_CLASS sectors, kept in subdirectories
+// of dir.
+pub fn disk_sector_store(dir: &Path) -> Arc<WrappedSectorStore> {
+    Arc::new(WrappedSectorStore {
+        inner: Box::new(new_sector_store(
+            TEST_CLASS,
+            subdir(dir, "sealed"),
+            subdir(dir, "staged"),
+        )),
+    })
+}
+
+// A replica of num_leaves nodes, each a valid field element, and its commR.
+pub fn replica(num_leaves: u64) -> (Vec<u8>, [u8; 32]) {
+    let mut bytes: Vec<u8> = (0..(num_leaves as usize * NODE_SIZE))
+        .map(|x| (x * 13) as u8)
+        .collect();
+
+    for node in bytes.chunks_mut(NODE_SIZE) {
+        node[NODE_SIZE - 1] &= 0x0f;
+    }
+
+    let comm_r = batch_merkle_root(&bytes);
+
+    (bytes, comm_r)
+}
+
+// The root of the Merkle tree of the nodes, built all at once by
+// storage-proofs, as sealing builds it.
+pub fn batch_merkle_root(nodes: &[u8]) -> [u8; 32] {
+    let tree: VecMerkleTree<TreeDomain, PedersenFunction> = VecMerkleTree::new(
+        nodes
+            .chunks(NODE_SIZE)
+            .map(|node| TreeDomain::try_from_bytes(node).unwrap()),
+    );
+
+    domain_to_bytes(&tree.root())
+}
@@ -0,0 +1,508 @@
+use std::fs::{read_dir, remove_file, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::metadata::StagedSectorMetadata;
+use crate::api::sector_builder::state::StagedState;
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use sector_base::api::sector_store::SectorManager;
+
+// A staged sector's write-ahead log holds the bytes written to the sector, so
+// that writes interrupted by an unclean shutdown can be replayed. The piece
+// writer logs each piece before writing it (when the sector builder is
+// configured with a staged_sector_wal_dir), and the logs are replayed when the
+// sector builder is initialized. Each entry
+// is laid out as
+//
+//   [magic: 4, version: 2, committed: 1, reserved: 1, offset: 8, len: 8,
+//    sector_max: 8, data: len, crc32: 4]
+//
+// with integers in little-endian order. The CRC32 covers every other byte of
+// the entry except the committed flag, which is set (in place) once the entry
+// has been durably written.
+const WAL_ENTRY_MAGIC: [u8; 4] = *b"FCWL";
+
+const WAL_ENTRY_VERSION: u16 = 1;
+
+const WAL_ENTRY_HEADER_LEN: usize = 32;
+
+const WAL_ENTRY_CRC_LEN: usize = 4;
+
+const COMMITTED_FLAG_OFFSET: usize = 6;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalEntry {
+    pub committed: bool,
+    // the unpadded offset in the staged sector at which data is written
+    pub offset: u64,
+    // the number of unpadded bytes which the sector can hold
+    pub sector_max: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WalReplayReport {
+    // committed entries which were missing from the sector, and rewritten
+    pub replayed: usize,
+    // committed entries already in the sector, and entries never committed
+    pub skipped: usize,
+    // entries which failed validation, or which couldn't be applied
+    pub corrupted: usize,
+}
+
+fn encode_wal_entry(entry: &WalEntry) -> Vec<u8> {
+    let mut bytes = vec![0; WAL_ENTRY_HEADER_LEN];
+    bytes[0..4].copy_from_slice(&WAL_ENTRY_MAGIC);
+    LittleEndian::write_u16(&mut bytes[4..6], WAL_ENTRY_VERSION);
+    bytes[COMMITTED_FLAG_OFFSET] = entry.committed as u8;
+    LittleEndian::write_u64(&mut bytes[8..16], entry.offset);
+    LittleEndian::write_u64(&mut bytes[16..24], entry.data.len() as u64);
+    LittleEndian::write_u64(&mut bytes[24..32], entry.sector_max);
+    bytes.extend_from_slice(&entry.data);
+
+    let mut crc = [0; WAL_ENTRY_CRC_LEN];
+    LittleEndian::write_u32(&mut crc, entry_crc32(&bytes));
+    bytes.extend_from_slice(&crc);
+
+    bytes
+}
+
+// The CRC32 of an entry's header and data, excluding its committed flag.
+fn entry_crc32(header_and_data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header_and_data[..COMMITTED_FLAG_OFFSET]);
+    hasher.update(&header_and_data[COMMITTED_FLAG_OFFSET + 1..]);
+    hasher.finalize()
+}
+
+// Parses and checks a single (whole) entry.
+pub fn validate_wal_entry(entry_bytes: &[u8]) -> Result<WalEntry> {
+    if entry_bytes.len() < WAL_ENTRY_HEADER_LEN + WAL_ENTRY_CRC_LEN {
+        return Err(err_unrecov("WAL entry is truncated").into());
+    }
+
+    if entry_bytes[0..4] != WAL_ENTRY_MAGIC {
+        return Err(err_unrecov("WAL entry has bad magic").into());
+    }
+
+    let version = LittleEndian::read_u16(&entry_bytes[4..6]);
+    if version != WAL_ENTRY_VERSION {
+        return Err(err_unrecov(format!("unknown WAL entry version: {}", version)).into());
+    }
+
+    let offset = LittleEndian::read_u64(&entry_bytes[8..16]);
+    let len = LittleEndian::read_u64(&entry_bytes[16..24]);
+    let sector_max = LittleEndian::read_u64(&entry_bytes[24..32]);
+
+    if entry_bytes.len() as u64 != WAL_ENTRY_HEADER_LEN as u64 + len + WAL_ENTRY_CRC_LEN as u64 {
+        return Err(err_unrecov(format!(
+            "WAL entry of {} bytes can't hold {} bytes of data",
+            entry_bytes.len(),
+            len
+        ))
+        .into());
+    }
+
+    let (header_and_data, crc) = entry_bytes.split_at(entry_bytes.len() - WAL_ENTRY_CRC_LEN);
+    if LittleEndian::read_u32(crc) != entry_crc32(header_and_data) {
+        return Err(err_unrecov("WAL entry failed its CRC32 check").into());
+    }
+
+    if offset.checked_add(len).map_or(true, |end| end > sector_max) {
+        return Err(err_unrecov(format!(
+            "WAL entry of {} bytes at offset {} overflows sector of {} bytes",
+            len, offset, sector_max
+        ))
+        .into());
+    }
+
+    Ok(WalEntry {
+        committed: entry_bytes[COMMITTED_FLAG_OFFSET] == 1,
+        offset,
+        sector_max,
+        data: header_and_data[WAL_ENTRY_HEADER_LEN..].to_vec(),
+    })
+}
+
+// Appends entries to a staged sector's write-ahead log. An entry must be
+// committed before its data is written to the sector.
+pub struct WalWriter {
+    file: File,
+    sector_max: u64,
+}
+
+impl WalWriter {
+    pub fn open(wal_path: &Path, sector_max: u64) -> Result<WalWriter> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(wal_path)?;
+
+        Ok(WalWriter { file, sector_max })
+    }
+
+    // Durably appends an (uncommitted) entry, returning its position in the
+    // log.
+    pub fn append(&mut self, offset: u64, data: &[u8]) -> Result<u64> {
+        let entry = WalEntry {
+            committed: false,
+            offset,
+            sector_max: self.sector_max,
+            data: data.to_vec(),
+        };
+
+        let position = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&encode_wal_entry(&entry))?;
+        self.file.sync_data()?;
+
+        Ok(position)
+    }
+
+    // Durably marks the entry at the given position as committed.
+    pub fn commit(&mut self, position: u64) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(position + COMMITTED_FLAG_OFFSET as u64))?;
+        self.file.write_all(&[1])?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+// The write-ahead log of the staged sector with the given id.
+pub fn sector_wal_path(wal_dir: &Path, sector_id: SectorId) -> PathBuf {
+    wal_dir.join(format!("{}.wal", sector_id))
+}
+
+// Reads the next whole entry from the log, or None at the end of the log. A
+// partially-written entry is returned as-is, to fail validation.
+fn read_wal_entry<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut bytes = vec![0; WAL_ENTRY_HEADER_LEN];
+
+    let num_read = read_fully(reader, &mut bytes)?;
+    if num_read == 0 {
+        return Ok(None);
+    }
+    if num_read < WAL_ENTRY_HEADER_LEN {
+        bytes.truncate(num_read);
+        return Ok(Some(bytes));
+    }
+
+    let len = LittleEndian::read_u64(&bytes[16..24]);
+    let mut rest = Vec::new();
+    reader
+        .take(len.saturating_add(WAL_ENTRY_CRC_LEN as u64))
+        .read_to_end(&mut rest)?;
+    bytes.extend(rest);
+
+    Ok(Some(bytes))
+}
+
+// Like read_exact, but reports how much was read when the reader runs out.
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut num_read = 0;
+
+    while num_read < buf.len() {
+        match reader.read(&mut buf[num_read..]) {
+            Ok(0) => break,
+            Ok(n) => num_read += n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(num_read)
+}
+
+// Rewrites the committed entries of the staged sector's write-ahead log which
+// are missing from the sector, e.g. after an unclean shutdown. Entries are
+// applied in the order in which they were logged. Replay stops at the first
+// entry which fails validation, as the entries after it can't be located.
+pub fn replay_sector_wal(
+    sector_manager: &SectorManager,
+    staged_sector: &StagedSectorMetadata,
+    wal_path: &Path,
+) -> Result<WalReplayReport> {
+    let access = &staged_sector.sector_access;
+    let mut reader = BufReader::new(File::open(wal_path)?);
+    let mut report: WalReplayReport = Default::default();
+
+    while let Some(entry_bytes) = read_wal_entry(&mut reader)? {
+        let entry = match validate_wal_entry(&entry_bytes) {
+            Ok(entry) => entry,
+            Err(_) => {
+                report.corrupted += 1;
+                break;
+            }
+        };
+
+        if !entry.committed {
+            report.skipped += 1;
+            continue;
+        }
+
+        let num_bytes = sector_manager.num_unsealed_bytes(access)?;
+        let end = entry.offset + entry.data.len() as u64;
+
+        if num_bytes >= end {
+            report.skipped += 1;
+            continue;
+        }
+
+        // an earlier write is missing, and wasn't logged
+        if num_bytes < entry.offset {
+            report.corrupted += 1;
+            continue;
+        }
+
+        // discard the interrupted write's partial output
+        if num_bytes > entry.offset {
+            sector_manager.truncate_unsealed(access, entry.offset)?;
+        }
+
+        let written = sector_manager.write_and_preprocess(access, &mut &entry.data[..])?;
+        if u64::from(written) != entry.data.len() as u64 {
+            return Err(err_unrecov(format!(
+                "replayed {} of {} bytes at offset {}",
+                u64::from(written),
+                entry.data.len(),
+                entry.offset
+            ))
+            .into());
+        }
+
+        report.replayed += 1;
+    }
+
+    Ok(report)
+}
+
+// Replays the write-ahead log of each staged sector, before anything else
+// writes to them. The logs of sectors which are no longer staged (having been
+// sealed or deleted, or never recorded) are removed.
+pub fn replay_staged_sector_wals(
+    sector_manager: &SectorManager,
+    staged_state: &StagedState,
+    wal_dir: &Path,
+) -> Result<WalReplayReport> {
+    let mut report: WalReplayReport = Default::default();
+
+    let entries = match read_dir(wal_dir) {
+        Ok(entries) => entries,
+        // nothing has been logged yet
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(report),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let wal_path = entry?.path();
+
+        let sector_id = wal_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| name.ends_with(".wal"))
+            .and_then(|name| name.trim_end_matches(".wal").parse::<SectorId>().ok());
+
+        let sector_id = match sector_id {
+            Some(sector_id) => sector_id,
+            None => continue,
+        };
+
+        match staged_state.sectors.get(&sector_id) {
+            Some(staged_sector) => {
+                let replayed = replay_sector_wal(sector_manager, staged_sector, &wal_path)?;
+
+                report.replayed += replayed.replayed;
+                report.skipped += replayed.skipped;
+                report.corrupted += replayed.corrupted;
+            }
+            None => remove_file(&wal_path)?,
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sector_builder::helpers::add_piece::write_piece_bytes;
+    use crate::api::sector_builder::metrics::Histogram;
+    use crate::api::sector_builder::test_utils::mock_sector_store;
+    use sector_base::api::bytes_amount::UnpaddedBytesAmount;
+    use std::io;
+
+    fn entry(committed: bool, offset: u64, data: &[u8]) -> WalEntry {
+        WalEntry {
+            committed,
+            offset,
+            sector_max: 1016,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_validates_entries() {
+        let valid = entry(true, 10, b"hello");
+        let bytes = encode_wal_entry(&valid);
+        assert_eq!(valid, validate_wal_entry(&bytes).unwrap());
+
+        // the committed flag isn't covered by the CRC32
+        let mut uncommitted = bytes.clone();
+        uncommitted[COMMITTED_FLAG_OFFSET] = 0;
+        assert!(!validate_wal_entry(&uncommitted).unwrap().committed);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(validate_wal_entry(&bad_magic).is_err());
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 9;
+        assert!(validate_wal_entry(&bad_version).is_err());
+
+        let mut bad_data = bytes.clone();
+        bad_data[WAL_ENTRY_HEADER_LEN] ^= 1;
+        assert!(validate_wal_entry(&bad_data).is_err());
+
+        assert!(validate_wal_entry(&bytes[..bytes.len() - 1]).is_err());
+
+        let overflowing = encode_wal_entry(&entry(true, 1012, b"hello"));
+        assert!(validate_wal_entry(&overflowing).is_err());
+    }
+
+    #[test]
+    fn test_replays_committed_entries_only() {
+        let (_, mgr) = mock_sector_store();
+        let access = mgr.new_staging_sector_access().unwrap();
+        let staged_sector = StagedSectorMetadata {
+            sector_access: access.clone(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let wal_path = dir.path().join("wal");
+
+        // The first write was committed, but the shutdown came before it
+        // reached the sector. The second never was.
+        let mut writer = WalWriter::open(&wal_path, 1016).unwrap();
+        let position = writer.append(0, &[1; 10]).unwrap();
+        writer.commit(position).unwrap();
+        writer.append(10, &[2; 5]).unwrap();
+
+        let report = replay_sector_wal(&*mgr, &staged_sector, &wal_path).unwrap();
+
+        assert_eq!(
+            WalReplayReport {
+                replayed: 1,
+                skipped: 1,
+                corrupted: 0,
+            },
+            report
+        );
+        assert_eq!(vec![1; 10], mgr.contents(&access).unwrap());
+
+        // replaying again writes nothing
+        let report = replay_sector_wal(&*mgr, &staged_sector, &wal_path).unwrap();
+        assert_eq!(0, report.replayed);
+        assert_eq!(2, report.skipped);
+    }
+
+    #[test]
+    fn test_stops_at_torn_entry() {
+        let (_, mgr) = mock_sector_store();
+        let access = mgr.new_staging_sector_access().unwrap();
+        let staged_sector = StagedSectorMetadata {
+            sector_access: access.clone(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let wal_path = dir.path().join("wal");
+
+        let mut bytes = encode_wal_entry(&entry(true, 0, &[1; 10]));
+        let torn = encode_wal_entry(&entry(true, 10, &[2; 10]));
+        bytes.extend_from_slice(&torn[..torn.len() - 3]);
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        let report = replay_sector_wal(&*mgr, &staged_sector, &wal_path).unwrap();
+
+        assert_eq!(1, report.replayed);
+        assert_eq!(1, report.corrupted);
+        assert_eq!(vec![1; 10], mgr.contents(&access).unwrap());
+    }
+
+    #[test]
+    fn test_replays_logged_piece_writes() {
+        let (sector_store, mgr) = mock_sector_store();
+        let access = mgr.new_staging_sector_access().unwrap();
+
+        let mut staged_state: StagedState = Default::default();
+        staged_state.sectors.insert(
+            7,
+            StagedSectorMetadata {
+                sector_id: 7,
+                sector_access: access.clone(),
+                ..Default::default()
+            },
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let wal_path = sector_wal_path(dir.path(), 7);
+        let mut wal = WalWriter::open(&wal_path, 1016).unwrap();
+
+        for byte in &[3u8, 5] {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(&[*byte; 100]).unwrap();
+
+            write_piece_bytes(
+                &sector_store,
+                7,
+                &access,
+                UnpaddedBytesAmount(100),
+                file.path().to_str().unwrap(),
+                &Histogram::new(vec![]).unwrap(),
+                &Default::default(),
+                &Default::default(),
+                Some(&mut wal),
+                &mut io::sink(),
+            )
+            .unwrap();
+        }
+
+        let written = [vec![3; 100], vec![5; 100]].concat();
+        assert_eq!(Some(written.clone()), mgr.contents(&access));
+
+        // the second piece never reached the sector
+        mgr.truncate_unsealed(&access, 100).unwrap();
+
+        let report = replay_staged_sector_wals(&*mgr, &staged_state, dir.path()).unwrap();
+
+        assert_eq!(1, report.replayed);
+        assert_eq!(1, report.skipped);
+        assert_eq!(Some(written), mgr.contents(&access));
+    }
+
+    #[test]
+    fn test_removes_logs_of_unstaged_sectors() {
+        let (_, mgr) = mock_sector_store();
+        let dir = tempfile::tempdir().unwrap();
+
+        let wal_path = sector_wal_path(dir.path(), 7);
+        let mut writer = WalWriter::open(&wal_path, 1016).unwrap();
+        let position = writer.append(0, &[1; 10]).unwrap();
+        writer.commit(position).unwrap();
+
+        let report = replay_staged_sector_wals(&*mgr, &Default::default(), dir.path()).unwrap();
+
+        assert_eq!(WalReplayReport::default(), report);
+        assert!(!wal_path.exists());
+
+        // nor is a missing directory an error
+        let missing = dir.path().join("missing");
+        assert!(replay_staged_sector_wals(&*mgr, &Default::default(), &missing).is_ok());
+    }
+}
@@ -0,0 +1,220 @@
+use crate::api::post_adapter::GeneratePoStDynamicSectorsCountOutput;
+use crate::api::sector_builder::errors::err_unrecov;
+use crate::api::sector_builder::factory::SectorBuilderApi;
+use crate::api::sector_builder::metadata::SealedSectorMetadata;
+use crate::api::sector_builder::SectorId;
+use crate::error::Result;
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+
+// PLACEHOLDER: nothing in this module is the actors ABI. There is no miner
+// actor to submit windowed PoSts to yet, so the number of deadlines, how
+// sectors are assigned to them and the layout of the submitted parameters
+// are this crate's own stand-ins, to be replaced by the actors' definitions
+// once those exist.
+
+// The number of deadlines in a proving period, each of which requires a
+// proof for the sectors assigned to it (placeholder; see above).
+pub const WPOST_PERIOD_DEADLINES: u64 = 48;
+
+// Sectors are assigned to deadlines round-robin, by sector id (placeholder:
+// on-chain, the miner actor will assign them).
+pub fn deadline_for_sector(sector_id: SectorId) -> u64 {
+    sector_id % WPOST_PERIOD_DEADLINES
+}
+
+// The parameters with which a windowed PoSt would be submitted on-chain
+// (placeholder; see above). Serialized as a CBOR array of its fields, in
+// declaration order, in which byte arrays are CBOR byte strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubmitWindowedPoStParams {
+    pub deadline: u64,
+    // the proven sectors, in ascending order
+    pub sector_ids: Vec<SectorId>,
+    // the replica commitment of each proven sector
+    pub comm_rs: Vec<[u8; 32]>,
+    pub proofs: Vec<Vec<u8>>,
+    // the chain randomness from which the challenges were derived
+    pub randomness: [u8; 32],
+}
+
+impl SubmitWindowedPoStParams {
+    pub fn to_cbor(&self) -> Result<Vec<u8>> {
+        serde_cbor::to_vec(self).map_err(Into::into)
+    }
+}
+
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Serialize for Bytes<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl Serialize for SubmitWindowedPoStParams {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let comm_rs: Vec<Bytes> = self.comm_rs.iter().map(|x| Bytes(x)).collect();
+        let proofs: Vec<Bytes> = self.proofs.iter().map(|x| Bytes(x)).collect();
+
+        let mut tuple = serializer.serialize_tuple(5)?;
+        tuple.serialize_element(&self.deadline)?;
+        tuple.serialize_element(&self.sector_ids)?;
+        tuple.serialize_element(&comm_rs)?;
+        tuple.serialize_element(&proofs)?;
+        tuple.serialize_element(&Bytes(&self.randomness))?;
+        tuple.end()
+    }
+}
+
+// Generates the proofs for every sealed sector in the given deadline, and
+// assembles them into the parameters with which they're submitted on-chain.
+// Blocks the calling thread while proofs are generated.
+pub fn build_submit_windowed_post_params(
+    sector_builder: &SectorBuilderApi,
+    randomness: &[u8; 32],
+    deadline_index: u64,
+) -> Result<SubmitWindowedPoStParams> {
+    check_deadline_index(deadline_index)?;
+
+    let mut sectors: Vec<SealedSectorMetadata> = sector_builder
+        .get_sealed_sectors()?
+        .into_iter()
+        .filter(|sector| deadline_for_sector(sector.sector_id) == deadline_index)
+        .collect();
+
+    if sectors.is_empty() {
+        return Err(
+            err_unrecov(format!("no sealed sectors in deadline {}", deadline_index)).into(),
+        );
+    }
+
+    sectors.sort_by_key(|sector| sector.sector_id);
+
+    let comm_rs: Vec<[u8; 32]> = sectors.iter().map(|sector| sector.comm_r).collect();
+    let output = sector_builder.generate_post(&comm_rs, randomness)?;
+
+    make_submit_windowed_post_params(deadline_index, randomness, &sectors, output)
+}
+
+fn check_deadline_index(deadline_index: u64) -> Result<()> {
+    if deadline_index < WPOST_PERIOD_DEADLINES {
+        Ok(())
+    } else {
+        Err(err_unrecov(format!(
+            "deadline index {} out of range (a proving period has {} deadlines)",
+            deadline_index, WPOST_PERIOD_DEADLINES
+        ))
+        .into())
+    }
+}
+
+// Pairs the proofs generated for the provided sectors with the sectors, all of
+// which must have been proven (i.e. none may be faulty).
+fn make_submit_windowed_post_params(
+    deadline_index: u64,
+    randomness: &[u8; 32],
+    sectors: &[SealedSectorMetadata],
+    output: GeneratePoStDynamicSectorsCountOutput,
+) -> Result<SubmitWindowedPoStParams> {
+    check_deadline_index(deadline_index)?;
+
+    // faults are indices into the proven sectors
+    let faulty_sector_ids: Vec<SectorId> = output
+        .faults
+        .iter()
+        .filter_map(|i| sectors.get(*i as usize).map(|sector| sector.sector_id))
+        .collect();
+
+    if !faulty_sector_ids.is_empty() {
+        return Err(err_unrecov(format!(
+            "sectors {:?} in deadline {} could not be proven",
+            faulty_sector_ids, deadline_index
+        ))
+        .into());
+    }
+
+    if output.proofs.is_empty() {
+        return Err(err_unrecov(format!("no proofs for deadline {}", deadline_index)).into());
+    }
+
+    Ok(SubmitWindowedPoStParams {
+        deadline: deadline_index,
+        sector_ids: sectors.iter().map(|sector| sector.sector_id).collect(),
+        comm_rs: sectors.iter().map(|sector| sector.comm_r).collect(),
+        proofs: output.proofs,
+        randomness: *randomness,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed_sector(sector_id: SectorId) -> SealedSectorMetadata {
+        SealedSectorMetadata {
+            sector_id,
+            comm_r: [sector_id as u8; 32],
+            ..Default::default()
+        }
+    }
+
+    // the CBOR encoding of a 32-byte byte string
+    fn cbor_bytes32(byte: u8) -> Vec<u8> {
+        let mut encoded = vec![0x58, 0x20];
+        encoded.extend_from_slice(&[byte; 32]);
+        encoded
+    }
+
+    #[test]
+    fn test_builds_params_for_three_sectors() {
+        let sectors = vec![sealed_sector(7), sealed_sector(55), sealed_sector(103)];
+        let output = GeneratePoStDynamicSectorsCountOutput {
+            proofs: vec![vec![1; 4], vec![2; 4]],
+            faults: vec![],
+        };
+
+        let params = make_submit_windowed_post_params(7, &[9; 32], &sectors, output).unwrap();
+
+        assert_eq!(vec![7, 55, 103], params.sector_ids);
+        assert_eq!(vec![[7; 32], [55; 32], [103; 32]], params.comm_rs);
+
+        let mut expected = vec![
+            0x85, // array(5)
+            0x07, // deadline
+            0x83, 0x07, 0x18, 0x37, 0x18, 0x67, // sector ids
+            0x83, // comm_rs
+        ];
+        expected.extend(cbor_bytes32(7));
+        expected.extend(cbor_bytes32(55));
+        expected.extend(cbor_bytes32(103));
+        expected.extend(vec![0x82, 0x44, 1, 1, 1, 1, 0x44, 2, 2, 2, 2]); // proofs
+        expected.extend(cbor_bytes32(9)); // randomness
+
+        assert_eq!(expected, params.to_cbor().unwrap());
+    }
+
+    #[test]
+    fn test_requires_every_sector_proven() {
+        let sectors = vec![sealed_sector(7), sealed_sector(55), sealed_sector(103)];
+
+        let faulty = GeneratePoStDynamicSectorsCountOutput {
+            proofs: vec![vec![1; 4]],
+            faults: vec![1],
+        };
+        assert!(make_submit_windowed_post_params(7, &[9; 32], &sectors, faulty).is_err());
+
+        let unproven = GeneratePoStDynamicSectorsCountOutput {
+            proofs: vec![],
+            faults: vec![],
+        };
+        assert!(make_submit_windowed_post_params(7, &[9; 32], &sectors, unproven).is_err());
+    }
+
+    #[test]
+    fn test_deadline_index_in_range() {
+        assert!(check_deadline_index(0).is_ok());
+        assert!(check_deadline_index(WPOST_PERIOD_DEADLINES - 1).is_ok());
+        assert!(check_deadline_index(WPOST_PERIOD_DEADLINES).is_err());
+        assert_eq!(7, deadline_for_sector(55));
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::api::sector_store::SectorManager;
+
+/// the number of bytes which have crossed the wire between a sector manager and its backing store
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthUsage {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// counters which record nothing, reported by sector managers whose data transfer isn't metered
+pub static UNMETERED: NetworkBandwidthAccounting = NetworkBandwidthAccounting::unmetered();
+
+/// thread-safe counters of the bytes written to (sent) and read from (received) a sector store,
+/// for stores whose data transfer is metered (e.g. NFS or S3-backed)
+#[derive(Debug)]
+pub struct NetworkBandwidthAccounting {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    metered: bool,
+}
+
+impl Default for NetworkBandwidthAccounting {
+    fn default() -> Self {
+        NetworkBandwidthAccounting {
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            metered: true,
+        }
+    }
+}
+
+impl NetworkBandwidthAccounting {
+    /// counters which stay at zero, whatever is recorded
+    pub const fn unmetered() -> Self {
+        NetworkBandwidthAccounting {
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            metered: false,
+        }
+    }
+
+    pub fn record_sent(&self, num_bytes: u64) {
+        if self.metered {
+            self.bytes_sent.fetch_add(num_bytes, Ordering::SeqCst);
+        }
+    }
+
+    pub fn record_received(&self, num_bytes: u64) {
+        if self.metered {
+            self.bytes_received.fetch_add(num_bytes, Ordering::SeqCst);
+        }
+    }
+
+    pub fn usage(&self) -> BandwidthUsage {
+        BandwidthUsage {
+            bytes_sent: self.bytes_sent.load(Ordering::SeqCst),
+            bytes_received: self.bytes_received.load(Ordering::SeqCst),
+        }
+    }
+
+    /// zeroes the counters, returning their values beforehand
+    pub fn reset(&self) -> BandwidthUsage {
+        BandwidthUsage {
+            bytes_sent: self.bytes_sent.swap(0, Ordering::SeqCst),
+            bytes_received: self.bytes_received.swap(0, Ordering::SeqCst),
+        }
+    }
+}
+
+/// a reader of data held by a sector store (e.g. a replica, which is read without going through
+/// the sector manager), which records the bytes read through it as received
+pub struct MeteredReader<'a, R> {
+    pub inner: R,
+    pub accounting: &'a NetworkBandwidthAccounting,
+}
+
+impl<'a, R: Read> Read for MeteredReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.accounting.record_received(n as u64);
+
+        Ok(n)
+    }
+}
+
+impl<'a, R: Seek> Seek for MeteredReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// reports the bytes transferred by `sector_manager` since it was created or last reset
+pub fn get_bandwidth_usage(sector_manager: &SectorManager) -> BandwidthUsage {
+    sector_manager.bandwidth_accounting().usage()
+}
+
+/// zeroes the bandwidth counters of `sector_manager` (e.g. at the start of a billing period),
+/// returning the usage accrued during the period which just ended
+pub fn reset_bandwidth_counters(sector_manager: &SectorManager) -> BandwidthUsage {
+    sector_manager.bandwidth_accounting().reset()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn counts_across_threads() {
+        let accounting = Arc::new(NetworkBandwidthAccounting::default());
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let accounting = accounting.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        accounting.record_sent(3);
+                        accounting.record_received(5);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let expected = BandwidthUsage {
+            bytes_sent: 1200,
+            bytes_received: 2000,
+        };
+
+        assert_eq!(expected, accounting.usage());
+        assert_eq!(expected, accounting.reset());
+        assert_eq!(BandwidthUsage::default(), accounting.usage());
+    }
+
+    #[test]
+    fn counts_bytes_read_through_metered_reader() {
+        let accounting = NetworkBandwidthAccounting::default();
+        let data = vec![7u8; 1000];
+
+        let mut reader = MeteredReader {
+            inner: io::Cursor::new(&data),
+            accounting: &accounting,
+        };
+        reader.seek(SeekFrom::Start(400)).unwrap();
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+
+        assert_eq!(600, accounting.usage().bytes_received);
+
+        // unmetered counters stay at zero
+        let unmetered = NetworkBandwidthAccounting::unmetered();
+        unmetered.record_sent(10);
+        unmetered.record_received(10);
+
+        assert_eq!(BandwidthUsage::default(), unmetered.usage());
+    }
+}
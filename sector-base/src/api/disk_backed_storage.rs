@@ -1,7 +1,8 @@
-use std::fs::{create_dir_all, remove_file, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
 
+use crate::api::bandwidth::NetworkBandwidthAccounting;
 use crate::api::bytes_amount::{PaddedBytesAmount, UnpaddedBytesAmount};
 use crate::api::errors::SectorManagerErr;
 use crate::api::porep_config::PoRepConfig;
@@ -11,6 +12,7 @@ use crate::api::sector_store::ProofsConfig;
 use crate::api::sector_store::SectorConfig;
 use crate::api::sector_store::SectorManager;
 use crate::api::sector_store::SectorStore;
+use crate::api::staged_sector_file::{SectorFileHandle, StagedSectorFile};
 use crate::api::util;
 use crate::io::fr32::almost_truncate_to_unpadded_bytes;
 use crate::io::fr32::target_unpadded_bytes;
@@ -30,6 +32,9 @@ pub const LIVE_SECTOR_SIZE: u64 = 1 << 28; // 256MiB
 pub struct DiskManager {
     staging_path: String,
     sealed_path: String,
+    // the number of bytes in a sealed sector, recorded in staging sector file headers
+    sector_bytes: u64,
+    bandwidth: NetworkBandwidthAccounting,
 }
 
 impl SectorManager for DiskManager {
@@ -38,14 +43,18 @@ impl SectorManager for DiskManager {
     }
 
     fn new_staging_sector_access(&self) -> Result<String, SectorManagerErr> {
-        self.new_sector_access(Path::new(&self.staging_path))
+        let access = self.new_sector_access(Path::new(&self.staging_path))?;
+        StagedSectorFile::create(Path::new(&access), self.sector_bytes)?;
+
+        Ok(access)
+    }
+
+    fn open_and_verify_sector(&self, access: &str) -> Result<SectorFileHandle, SectorManagerErr> {
+        self.open_staged(access, true)
     }
 
     fn num_unsealed_bytes(&self, access: &str) -> Result<u64, SectorManagerErr> {
-        OpenOptions::new()
-            .read(true)
-            .open(access)
-            .map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))
+        self.open_staged(access, false)
             .map(|mut f| {
                 target_unpadded_bytes(&mut f)
                     .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
@@ -55,7 +64,7 @@ impl SectorManager for DiskManager {
 
     fn truncate_unsealed(&self, access: &str, size: u64) -> Result<(), SectorManagerErr> {
         // I couldn't wrap my head around all ths result mapping, so here it is all laid out.
-        match OpenOptions::new().write(true).open(&access) {
+        match self.open_staged(access, true) {
             Ok(mut file) => match almost_truncate_to_unpadded_bytes(&mut file, size) {
                 Ok(padded_size) => match file.set_len(padded_size as u64) {
                     Ok(_) => Ok(()),
@@ -63,7 +72,7 @@ impl SectorManager for DiskManager {
                 },
                 Err(err) => Err(SectorManagerErr::ReceiverError(format!("{:?}", err))),
             },
-            Err(err) => Err(SectorManagerErr::CallerError(format!("{:?}", err))),
+            Err(err) => Err(err),
         }
     }
 
@@ -73,22 +82,66 @@ impl SectorManager for DiskManager {
         access: &str,
         data: &mut dyn Read,
     ) -> Result<UnpaddedBytesAmount, SectorManagerErr> {
+        self.open_and_verify_sector(access).and_then(|mut file| {
+            // What's sent to the backing store is the preprocessed (padded)
+            // bytes, which the sector file grows by.
+            let written = file.seek(SeekFrom::End(0)).and_then(|len_before| {
+                let n = write_padded(data, &mut file)?;
+                let len_after = file.seek(SeekFrom::End(0))?;
+
+                Ok((n, len_after - len_before))
+            });
+
+            written
+                .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
+                .map(|(n, num_padded_bytes)| {
+                    self.bandwidth.record_sent(num_padded_bytes);
+                    UnpaddedBytesAmount(n as u64)
+                })
+        })
+    }
+
+    fn sync_staging_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
         OpenOptions::new()
-            .read(true)
             .write(true)
             .open(access)
-            .map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))
-            .and_then(|mut file| {
-                write_padded(data, &mut file)
-                    .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
-                    .map(|n| UnpaddedBytesAmount(n as u64))
-            })
+            .and_then(|file| file.sync_all())
+            .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
     }
 
     fn delete_staging_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
         remove_file(access).map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))
     }
 
+    fn delete_sealed_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
+        remove_file(access).map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))
+    }
+
+    fn rename_sector_access(&self, old: &str, new: &str) -> Result<(), SectorManagerErr> {
+        rename(old, new).map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))
+    }
+
+    fn list_sealed_sector_accesses(&self) -> Result<Vec<String>, SectorManagerErr> {
+        let entries = match read_dir(&self.sealed_path) {
+            Ok(entries) => entries,
+            // nothing has been sealed yet
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(SectorManagerErr::ReceiverError(format!("{:?}", err))),
+        };
+
+        entries
+            .map(|entry| {
+                entry
+                    .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
+                    .and_then(|entry| {
+                        entry.path().to_str().map(str::to_owned).ok_or_else(|| {
+                            SectorManagerErr::ReceiverError("non-UTF-8 sector access".to_string())
+                        })
+                    })
+            })
+            .collect()
+    }
+
     fn read_raw(
         &self,
         access: &str,
@@ -108,12 +161,28 @@ impl SectorManager for DiskManager {
                 file.read_exact(buf.as_mut_slice())
                     .map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))?;
 
+                self.bandwidth.record_received(buf.len() as u64);
+
                 Ok(buf)
             })
     }
+
+    fn bandwidth_accounting(&self) -> &NetworkBandwidthAccounting {
+        &self.bandwidth
+    }
 }
 
 impl DiskManager {
+    // Opens the body of a staging sector file, which must belong to a sector
+    // of the size managed by this store.
+    fn open_staged(
+        &self,
+        access: &str,
+        writable: bool,
+    ) -> Result<SectorFileHandle, SectorManagerErr> {
+        SectorFileHandle::open_and_verify(Path::new(access), self.sector_bytes, writable)
+    }
+
     fn new_sector_access(&self, root: &Path) -> Result<String, SectorManagerErr> {
         let pbuf = root.join(util::rand_alpha_string(32));
 
@@ -167,12 +236,15 @@ pub fn new_sector_store(
     sealed_path: String,
     staging_path: String,
 ) -> ConcreteSectorStore {
+    let sector_config = Box::new(Config::from(sector_class));
+
     let manager = Box::new(DiskManager {
         staging_path,
         sealed_path,
+        sector_bytes: u64::from(sector_config.sector_bytes()),
+        bandwidth: Default::default(),
     });
 
-    let sector_config = Box::new(Config::from(sector_class));
     let proofs_config = Box::new(Config::from(sector_class));
 
     ConcreteSectorStore {
@@ -217,12 +289,13 @@ impl From<SectorClass> for Config {
 pub mod tests {
     use super::*;
 
+    use crate::api::bandwidth::{get_bandwidth_usage, reset_bandwidth_counters, BandwidthUsage};
     use crate::api::porep_proof_partitions::PoRepProofPartitions;
     use crate::api::post_proof_partitions::PoStProofPartitions;
     use crate::api::sector_size::SectorSize;
+    use crate::api::staged_sector_file::*;
     use crate::io::fr32::FR32_PADDING_MAP;
     use std::fs::create_dir_all;
-    use std::fs::File;
     use std::io::Read;
     use std::io::Write;
     use tempfile;
@@ -242,8 +315,9 @@ pub mod tests {
         ))
     }
 
-    fn read_all_bytes(access: &str) -> Vec<u8> {
-        let mut file = File::open(access).unwrap();
+    // reads the bytes following the staging sector file's header
+    fn read_body_bytes(access: &str) -> Vec<u8> {
+        let mut file = StagedSectorFile::open(Path::new(access), false).unwrap();
         let mut buf = Vec::new();
         file.read_to_end(&mut buf).unwrap();
 
@@ -311,7 +385,7 @@ pub mod tests {
                 .expect("failed to write");
 
             // buffer the file's bytes into memory after writing bytes
-            let buf = read_all_bytes(&access);
+            let buf = read_body_bytes(&access);
             let output_bytes_written = buf.len();
 
             // ensure that we reported the correct number of written bytes
@@ -322,7 +396,7 @@ pub mod tests {
             assert_eq!(8u8, buf[32]);
 
             // read the file into memory again - this time after we truncate
-            let buf = read_all_bytes(&access);
+            let buf = read_body_bytes(&access);
 
             // ensure the file we wrote to contains the expected bytes
             assert_eq!(504, buf.len());
@@ -348,7 +422,7 @@ pub mod tests {
                     .expect("failed to truncate");
 
                 // read the file into memory again - this time after we truncate
-                let buf = read_all_bytes(&access);
+                let buf = read_body_bytes(&access);
 
                 // All but last bytes are identical.
                 assert_eq!(contents[0..num_bytes], buf[0..num_bytes]);
@@ -373,6 +447,189 @@ pub mod tests {
         }
     }
 
+    fn write_header(access: &str, version: u16) {
+        let header = StagedSectorFileHeader {
+            version,
+            sector_size: 1024,
+        };
+
+        OpenOptions::new()
+            .write(true)
+            .open(access)
+            .and_then(|mut file| file.write_all(&header.to_bytes()))
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_staging_sector_file() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let mgr = store.manager();
+        let access = mgr.new_staging_sector_access().unwrap();
+
+        let header = StagedSectorFile::open(Path::new(&access), false)
+            .unwrap()
+            .header;
+        assert_eq!(CURRENT_STAGED_SECTOR_FILE_VERSION, header.version);
+        assert_eq!(1024, header.sector_size);
+
+        // a file written by a build which knew only version 1
+        write_header(&access, STAGED_SECTOR_FILE_VERSION_1);
+        mgr.write_and_preprocess(&access, &mut &[1u8; 100][..])
+            .unwrap();
+        assert_eq!(100, mgr.num_unsealed_bytes(&access).unwrap());
+
+        migrate_staging_sector_file(&access, STAGED_SECTOR_FILE_VERSION_2).unwrap();
+
+        let header = StagedSectorFile::open(Path::new(&access), false)
+            .unwrap()
+            .header;
+        assert_eq!(STAGED_SECTOR_FILE_VERSION_2, header.version);
+        assert_eq!(1024, header.sector_size);
+
+        // the data survives migration, and the file remains writable
+        assert_eq!(100, mgr.num_unsealed_bytes(&access).unwrap());
+        mgr.write_and_preprocess(&access, &mut &[2u8; 27][..])
+            .unwrap();
+        assert_eq!(127, mgr.num_unsealed_bytes(&access).unwrap());
+        assert_eq!(vec![1u8; 31], read_body_bytes(&access)[0..31].to_vec());
+
+        // migrating to the current version is a no-op
+        migrate_staging_sector_file(&access, STAGED_SECTOR_FILE_VERSION_2).unwrap();
+        assert_eq!(127, mgr.num_unsealed_bytes(&access).unwrap());
+
+        match migrate_staging_sector_file(&access, 9) {
+            Err(SectorManagerErr::CallerError(_)) => {}
+            x => panic!("expected caller error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn adds_header_to_headerless_staging_sector_file() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let mgr = store.manager();
+
+        // files staged before headers were introduced, one of them empty
+        for body in &[vec![], vec![1u8; 32]] {
+            let access = mgr.new_staging_sector_access().unwrap();
+            std::fs::write(&access, body).unwrap();
+
+            let handle = mgr.open_and_verify_sector(&access).unwrap();
+            assert_eq!(StagedSectorFileHeader::new(1024), handle.header());
+            assert_eq!(body, &read_body_bytes(&access));
+
+            // the rewritten file is written after its header
+            mgr.write_and_preprocess(&access, &mut &[2u8; 10][..])
+                .unwrap();
+            assert_eq!(
+                StagedSectorFileHeader::new(1024),
+                mgr.open_and_verify_sector(&access).unwrap().header()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_staging_sector_file_version() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let mgr = store.manager();
+        let access = mgr.new_staging_sector_access().unwrap();
+
+        write_header(&access, 3);
+
+        match mgr.num_unsealed_bytes(&access) {
+            Err(SectorManagerErr::UnknownSectorFileVersion { version: 3, .. }) => {}
+            x => panic!("expected unknown version, got {:?}", x),
+        }
+
+        assert!(mgr
+            .write_and_preprocess(&access, &mut &[1u8; 10][..])
+            .is_err());
+        assert!(mgr.truncate_unsealed(&access, 0).is_err());
+    }
+
+    #[test]
+    fn detects_corrupted_staging_sector_file_header() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let mgr = store.manager();
+
+        // each corruption (a byte of the header, replaced) and the offset at
+        // which it should be detected
+        let corruptions: Vec<(usize, u8, u64)> = vec![
+            // magic
+            (0, b'X', 0),
+            (3, b'X', 3),
+            // version: not corruption, but unknown to this build
+            (4, 9, 4),
+            (5, 1, 5),
+            // sector size: a 2KiB sector, then a garbage high byte
+            (7, 8, 7),
+            (13, 1, 13),
+            // padding
+            (14, 1, 14),
+            (15, 1, 15),
+        ];
+
+        for (index, byte, expected_offset) in corruptions {
+            let access = mgr.new_staging_sector_access().unwrap();
+
+            let mut header = StagedSectorFileHeader::new(1024).to_bytes();
+            header[index] = byte;
+
+            OpenOptions::new()
+                .write(true)
+                .open(&access)
+                .and_then(|mut file| file.write_all(&header))
+                .unwrap();
+
+            match mgr.open_and_verify_sector(&access) {
+                Err(SectorManagerErr::CorruptedSectorHeader { offset, .. })
+                | Err(SectorManagerErr::UnknownSectorFileVersion { offset, .. }) => {
+                    assert_eq!(expected_offset, offset, "corrupted byte {}", index)
+                }
+                Err(err) => panic!("corrupted byte {}: unexpected error {:?}", index, err),
+                Ok(_) => panic!("corrupted byte {} went undetected", index),
+            }
+
+            assert!(mgr
+                .write_and_preprocess(&access, &mut &[1u8; 10][..])
+                .is_err());
+        }
+
+        // a file too short to hold a header
+        let access = mgr.new_staging_sector_access().unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&access)
+            .and_then(|file| file.set_len(5))
+            .unwrap();
+
+        match mgr.open_and_verify_sector(&access) {
+            Err(SectorManagerErr::CorruptedSectorHeader { offset: 5, .. }) => {}
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("truncated header went undetected"),
+        }
+
+        // an intact header
+        let access = mgr.new_staging_sector_access().unwrap();
+        let handle = mgr.open_and_verify_sector(&access).unwrap();
+        assert_eq!(StagedSectorFileHeader::new(1024), handle.header());
+    }
+
     #[test]
     fn deletes_staging_access() {
         let store = create_sector_store(SectorClass(
@@ -397,4 +654,87 @@ pub mod tests {
             .read_raw(&access, 0, UnpaddedBytesAmount(0))
             .is_err());
     }
+
+    #[test]
+    fn deletes_sealed_access() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let access = store.manager().new_sealed_sector_access().unwrap();
+
+        assert!(store.manager().delete_sealed_sector_access(&access).is_ok());
+
+        assert!(store
+            .manager()
+            .read_raw(&access, 0, UnpaddedBytesAmount(0))
+            .is_err());
+
+        // deleting twice is a caller error
+        assert!(store
+            .manager()
+            .delete_sealed_sector_access(&access)
+            .is_err());
+    }
+
+    #[test]
+    fn renames_sealed_sector_access() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let mgr = store.manager();
+
+        let access = mgr.new_sealed_sector_access().unwrap();
+        let partial = format!("{}.tmp", access);
+
+        std::fs::write(&partial, b"sealed bytes").unwrap();
+
+        let mut accesses = mgr.list_sealed_sector_accesses().unwrap();
+        accesses.sort();
+        assert_eq!(vec![access.clone(), partial.clone()], accesses);
+
+        mgr.rename_sector_access(&partial, &access).unwrap();
+
+        assert_eq!(
+            vec![access.clone()],
+            mgr.list_sealed_sector_accesses().unwrap()
+        );
+        assert_eq!(b"sealed bytes".to_vec(), std::fs::read(&access).unwrap());
+    }
+
+    #[test]
+    fn counts_bytes_transferred() {
+        let store = create_sector_store(SectorClass(
+            SectorSize::OneKiB,
+            PoRepProofPartitions::Two,
+            PoStProofPartitions::One,
+        ));
+        let mgr = store.manager();
+        let access = mgr.new_staging_sector_access().unwrap();
+
+        assert_eq!(BandwidthUsage::default(), get_bandwidth_usage(mgr));
+
+        mgr.write_and_preprocess(&access, &mut &[1u8; 100][..])
+            .unwrap();
+        mgr.write_and_preprocess(&access, &mut &[2u8; 27][..])
+            .unwrap();
+        mgr.read_raw(&access, 0, UnpaddedBytesAmount(64)).unwrap();
+        mgr.read_raw(&access, 10, UnpaddedBytesAmount(16)).unwrap();
+
+        // failed reads transfer nothing
+        assert!(mgr.read_raw(&access, 0, UnpaddedBytesAmount(1024)).is_err());
+
+        // the 127 bytes written are sent preprocessed
+        let expected = BandwidthUsage {
+            bytes_sent: 128,
+            bytes_received: 80,
+        };
+
+        assert_eq!(expected, get_bandwidth_usage(mgr));
+        assert_eq!(expected, reset_bandwidth_counters(mgr));
+        assert_eq!(BandwidthUsage::default(), get_bandwidth_usage(mgr));
+    }
 }
@@ -8,4 +8,10 @@ pub enum SectorManagerErr {
 
     #[fail(display = "receiver error: {}", _0)]
     ReceiverError(String),
+
+    #[fail(display = "unknown sector file version {} at byte {}", version, offset)]
+    UnknownSectorFileVersion { offset: u64, version: u16 },
+
+    #[fail(display = "corrupted sector header at byte {}: {}", offset, reason)]
+    CorruptedSectorHeader { offset: u64, reason: String },
 }
@@ -1,3 +1,4 @@
+pub mod bandwidth;
 pub mod bytes_amount;
 pub mod disk_backed_storage;
 pub mod errors;
@@ -8,6 +9,7 @@ pub mod post_proof_partitions;
 pub mod sector_class;
 pub mod sector_size;
 pub mod sector_store;
+pub mod staged_sector_file;
 pub mod util;
 
 pub const SINGLE_PARTITION_PROOF_LEN: usize = 192;
@@ -1,9 +1,12 @@
-use std::io::Read;
+use std::io::{self, Read};
 
+use crate::api::bandwidth::{NetworkBandwidthAccounting, UNMETERED};
 use crate::api::bytes_amount::{PaddedBytesAmount, UnpaddedBytesAmount};
 use crate::api::errors::SectorManagerErr;
 use crate::api::porep_config::PoRepConfig;
 use crate::api::post_config::PoStConfig;
+use crate::api::staged_sector_file::SectorFileHandle;
+use crate::io::checksum::{ChecksummingWriter, TeeReader};
 
 pub trait SectorConfig {
     /// returns the number of user-provided bytes that will fit into a sector managed by this store
@@ -28,6 +31,16 @@ pub trait SectorManager {
     /// provisions a new staging sector and reports the corresponding access
     fn new_staging_sector_access(&self) -> Result<String, SectorManagerErr>;
 
+    /// opens the staging sector identified by `access` for reading and writing, failing if its
+    /// header is corrupted or is for sectors of another size; managers which don't keep staging
+    /// sectors in files report an error
+    fn open_and_verify_sector(&self, access: &str) -> Result<SectorFileHandle, SectorManagerErr> {
+        Err(SectorManagerErr::CallerError(format!(
+            "staging sector {} has no file to open",
+            access
+        )))
+    }
+
     /// reports the number of bytes written to an unsealed sector
     fn num_unsealed_bytes(&self, access: &str) -> Result<u64, SectorManagerErr>;
 
@@ -41,14 +54,70 @@ pub trait SectorManager {
         data: &mut dyn Read,
     ) -> Result<UnpaddedBytesAmount, SectorManagerErr>;
 
+    /// like `write_and_preprocess`, but also reports the BLAKE3 checksum of `data`, which is
+    /// computed as `data` is written rather than by reading the piece a second time
+    fn write_and_preprocess_with_checksum(
+        &self,
+        access: &str,
+        data: &mut dyn Read,
+    ) -> Result<(UnpaddedBytesAmount, [u8; 32]), SectorManagerErr> {
+        let mut tee = TeeReader {
+            source: data,
+            sink: ChecksummingWriter::new(io::sink()),
+        };
+
+        let num_bytes = self.write_and_preprocess(access, &mut tee)?;
+        let (_, checksum) = tee.sink.finalize();
+
+        Ok((num_bytes, checksum))
+    }
+
+    /// flushes everything written to the staging sector identified by `access` to the backing store;
+    /// managers whose writes reach the backing store as they're made needn't do anything
+    fn sync_staging_sector_access(&self, _access: &str) -> Result<(), SectorManagerErr> {
+        Ok(())
+    }
+
     fn delete_staging_sector_access(&self, access: &str) -> Result<(), SectorManagerErr>;
 
+    /// removes the sealed sector identified by `access`; managers which can't remove sealed
+    /// sectors report an error, so that they're kept rather than forgotten
+    fn delete_sealed_sector_access(&self, access: &str) -> Result<(), SectorManagerErr> {
+        Err(SectorManagerErr::CallerError(format!(
+            "sealed sector {} can't be deleted by this sector manager",
+            access
+        )))
+    }
+
+    /// atomically moves the sector identified by `old` to `new`, replacing anything at `new`;
+    /// managers which can't move sectors report an error
+    fn rename_sector_access(&self, old: &str, new: &str) -> Result<(), SectorManagerErr> {
+        Err(SectorManagerErr::CallerError(format!(
+            "sector {} can't be moved to {} by this sector manager",
+            old, new
+        )))
+    }
+
+    /// reports the accesses of all sealed sectors, including any which are only partially written;
+    /// managers which can't list their sealed sectors report none
+    fn list_sealed_sector_accesses(&self) -> Result<Vec<String>, SectorManagerErr> {
+        Ok(vec![])
+    }
+
     fn read_raw(
         &self,
         access: &str,
         start_offset: u64,
         num_bytes: UnpaddedBytesAmount,
     ) -> Result<Vec<u8>, SectorManagerErr>;
+
+    /// reports the counters of bytes transferred to and from the backing store, including those
+    /// of replicas which are read and written without going through the manager (see
+    /// `MeteredReader`); managers whose data transfer isn't metered report counters which stay
+    /// at zero
+    fn bandwidth_accounting(&self) -> &NetworkBandwidthAccounting {
+        &UNMETERED
+    }
 }
 
 pub trait SectorStore {
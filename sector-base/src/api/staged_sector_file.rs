@@ -0,0 +1,459 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::api::errors::SectorManagerErr;
+
+// Every staging sector file starts with a 16-byte header, laid out as
+// [magic: 4, version: 2, sector_size: 8, padding: 2] with integers in
+// little-endian order. The (FR32-padded) sector data follows the header.
+// Files staged before headers were introduced (version 0) are all sector
+// data, and are rewritten with a header when they're first opened.
+pub const STAGED_SECTOR_FILE_HEADER_LEN: u64 = 16;
+
+pub const STAGED_SECTOR_FILE_MAGIC: [u8; 4] = *b"FCSS";
+
+pub const STAGED_SECTOR_FILE_VERSION_1: u16 = 1;
+
+// Version 2 shares version 1's body layout, so migrating between the two
+// rewrites the header only. Versions which change the layout must also
+// convert the body in migrate_staging_sector_file.
+pub const STAGED_SECTOR_FILE_VERSION_2: u16 = 2;
+
+// The version with which new staging sector files are written.
+pub const CURRENT_STAGED_SECTOR_FILE_VERSION: u16 = STAGED_SECTOR_FILE_VERSION_2;
+
+fn is_supported_version(version: u16) -> bool {
+    version == STAGED_SECTOR_FILE_VERSION_1 || version == STAGED_SECTOR_FILE_VERSION_2
+}
+
+// Reports the offset of the first byte in which actual differs from expected.
+fn first_difference(field_offset: u64, actual: &[u8], expected: &[u8]) -> Option<u64> {
+    actual
+        .iter()
+        .zip(expected.iter())
+        .position(|(a, e)| a != e)
+        .map(|i| field_offset + i as u64)
+}
+
+fn corrupted(offset: u64, reason: &str) -> SectorManagerErr {
+    SectorManagerErr::CorruptedSectorHeader {
+        offset,
+        reason: reason.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StagedSectorFileHeader {
+    pub version: u16,
+    // the number of bytes in the sector once sealed
+    pub sector_size: u64,
+}
+
+impl StagedSectorFileHeader {
+    pub fn new(sector_size: u64) -> StagedSectorFileHeader {
+        StagedSectorFileHeader {
+            version: CURRENT_STAGED_SECTOR_FILE_VERSION,
+            sector_size,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..4].copy_from_slice(&STAGED_SECTOR_FILE_MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..14].copy_from_slice(&self.sector_size.to_le_bytes());
+
+        bytes
+    }
+
+    // Parses the header, reporting the offset of the first corrupted byte if
+    // it's malformed. A version which this build doesn't know (e.g. one
+    // written by a newer build) isn't considered corruption.
+    pub fn from_bytes(bytes: &[u8; 16]) -> Result<StagedSectorFileHeader, SectorManagerErr> {
+        if let Some(offset) = first_difference(0, &bytes[0..4], &STAGED_SECTOR_FILE_MAGIC) {
+            return Err(corrupted(offset, "not a staging sector file (bad magic)"));
+        }
+
+        let mut version = [0; 2];
+        version.copy_from_slice(&bytes[4..6]);
+        let version = u16::from_le_bytes(version);
+
+        if !is_supported_version(version) {
+            let current = CURRENT_STAGED_SECTOR_FILE_VERSION.to_le_bytes();
+
+            return Err(SectorManagerErr::UnknownSectorFileVersion {
+                offset: first_difference(4, &bytes[4..6], &current).unwrap_or(4),
+                version,
+            });
+        }
+
+        if let Some(offset) = first_difference(14, &bytes[14..16], &[0, 0]) {
+            return Err(corrupted(offset, "nonzero header padding"));
+        }
+
+        let mut sector_size = [0; 8];
+        sector_size.copy_from_slice(&bytes[6..14]);
+
+        Ok(StagedSectorFileHeader {
+            version,
+            sector_size: u64::from_le_bytes(sector_size),
+        })
+    }
+
+    // Like from_bytes, but also checks that the header is that of a staging
+    // sector file for sectors of the expected size.
+    pub fn verify(
+        bytes: &[u8; 16],
+        sector_size: u64,
+    ) -> Result<StagedSectorFileHeader, SectorManagerErr> {
+        let header = StagedSectorFileHeader::from_bytes(bytes)?;
+
+        if let Some(offset) = first_difference(6, &bytes[6..14], &sector_size.to_le_bytes()) {
+            return Err(SectorManagerErr::CorruptedSectorHeader {
+                offset,
+                reason: format!(
+                    "header is for {}-byte sectors, but {}-byte sectors were expected",
+                    header.sector_size, sector_size
+                ),
+            });
+        }
+
+        Ok(header)
+    }
+}
+
+// The body of a staging sector file, i.e. everything after its header, which
+// reads, writes and seeks as though it were the whole file.
+pub struct StagedSectorFile {
+    file: File,
+    pub header: StagedSectorFileHeader,
+}
+
+impl StagedSectorFile {
+    // Creates (or truncates) the file at path, writing a header with the
+    // current version.
+    pub fn create(path: &Path, sector_size: u64) -> Result<StagedSectorFile, SectorManagerErr> {
+        let header = StagedSectorFileHeader::new(sector_size);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))?;
+
+        file.write_all(&header.to_bytes())
+            .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))?;
+
+        Ok(StagedSectorFile { file, header })
+    }
+
+    // Opens the file at path, parsing and validating its header. The file is
+    // positioned at the start of its body. Outside this crate, sector files
+    // are opened as SectorFileHandles, whose headers have been verified.
+    pub(crate) fn open(path: &Path, writable: bool) -> Result<StagedSectorFile, SectorManagerErr> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))?;
+
+        let bytes = read_header_bytes(&mut file)?;
+        let header = StagedSectorFileHeader::from_bytes(&bytes)?;
+
+        Ok(StagedSectorFile { file, header })
+    }
+
+    // Truncates or extends the body to len bytes.
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(STAGED_SECTOR_FILE_HEADER_LEN + len)
+    }
+}
+
+impl Read for StagedSectorFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for StagedSectorFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for StagedSectorFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => SeekFrom::Start(STAGED_SECTOR_FILE_HEADER_LEN + n),
+            x => x,
+        };
+
+        let current = self.file.seek(pos)?;
+
+        if current < STAGED_SECTOR_FILE_HEADER_LEN {
+            self.file
+                .seek(SeekFrom::Start(STAGED_SECTOR_FILE_HEADER_LEN))?;
+
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "cannot seek before the start of a staging sector file's body",
+            ));
+        }
+
+        Ok(current - STAGED_SECTOR_FILE_HEADER_LEN)
+    }
+}
+
+// The body of a staging sector file whose header has been verified, which
+// can only be had from open_and_verify (and SectorManager's
+// open_and_verify_sector), so that no caller reads or writes a sector file
+// without having checked its header.
+pub struct SectorFileHandle(StagedSectorFile);
+
+impl SectorFileHandle {
+    // Opens the file at path, verifying that its header is intact and that
+    // it's for sectors of sector_size bytes. A headerless (version 0) file is
+    // first rewritten with a header. The handle is positioned at the start of
+    // the body.
+    pub fn open_and_verify(
+        path: &Path,
+        sector_size: u64,
+        writable: bool,
+    ) -> Result<SectorFileHandle, SectorManagerErr> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(|err| SectorManagerErr::CallerError(format!("{:?}", err)))?;
+
+        let bytes = match read_header_bytes_if_any(&mut file, sector_size)? {
+            Some(bytes) => bytes,
+            None => {
+                drop(file);
+                add_header(path, sector_size)?;
+
+                return SectorFileHandle::open_and_verify(path, sector_size, writable);
+            }
+        };
+
+        let header = StagedSectorFileHeader::verify(&bytes, sector_size)?;
+
+        Ok(SectorFileHandle(StagedSectorFile { file, header }))
+    }
+
+    pub fn header(&self) -> StagedSectorFileHeader {
+        self.0.header
+    }
+
+    // Truncates or extends the body to len bytes.
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.0.file.sync_all()
+    }
+}
+
+impl Read for SectorFileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for SectorFileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Seek for SectorFileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+// Reads as many of the file's first 16 bytes as it has, returning how many
+// there were.
+fn read_first_bytes(file: &mut File, bytes: &mut [u8; 16]) -> Result<usize, SectorManagerErr> {
+    let mut num_read = 0;
+
+    while num_read < bytes.len() {
+        match file.read(&mut bytes[num_read..]) {
+            Ok(0) => break,
+            Ok(n) => num_read += n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(SectorManagerErr::ReceiverError(format!("{:?}", err))),
+        }
+    }
+
+    Ok(num_read)
+}
+
+// Reads the header of the file, which must be positioned at its start. A
+// file too short to hold a header is corrupted from its end onward.
+fn read_header_bytes(file: &mut File) -> Result<[u8; 16], SectorManagerErr> {
+    let mut bytes = [0; 16];
+    let num_read = read_first_bytes(file, &mut bytes)?;
+
+    if num_read < bytes.len() {
+        return Err(corrupted(
+            num_read as u64,
+            "staging sector file has no header",
+        ));
+    }
+
+    Ok(bytes)
+}
+
+// Like read_header_bytes, but produces None for a headerless (version 0)
+// file. A file has a header if it starts with the magic, or if all but its
+// magic is an intact header for sectors of sector_size bytes (i.e. its magic
+// is corrupted), which sector data is vanishingly unlikely to be.
+fn read_header_bytes_if_any(
+    file: &mut File,
+    sector_size: u64,
+) -> Result<Option<[u8; 16]>, SectorManagerErr> {
+    let mut bytes = [0; 16];
+    let num_read = read_first_bytes(file, &mut bytes)?;
+
+    let magic_len = std::cmp::min(num_read, STAGED_SECTOR_FILE_MAGIC.len());
+    let starts_with_magic =
+        num_read > 0 && bytes[0..magic_len] == STAGED_SECTOR_FILE_MAGIC[0..magic_len];
+
+    if num_read < bytes.len() {
+        if starts_with_magic {
+            return Err(corrupted(
+                num_read as u64,
+                "staging sector file has no header",
+            ));
+        }
+
+        return Ok(None);
+    }
+
+    let mut repaired = bytes;
+    repaired[0..4].copy_from_slice(&STAGED_SECTOR_FILE_MAGIC);
+
+    if starts_with_magic || StagedSectorFileHeader::verify(&repaired, sector_size).is_ok() {
+        Ok(Some(bytes))
+    } else {
+        Ok(None)
+    }
+}
+
+// Rewrites the headerless (version 0) staging sector file at path with a
+// header of the current version. Version 0 shares version 1's body layout, so
+// the data is copied as-is. The rewritten file replaces the original by
+// rename, so that a crash leaves one or the other.
+fn add_header(path: &Path, sector_size: u64) -> Result<(), SectorManagerErr> {
+    let rewritten_path = path.with_extension("rewriting");
+
+    File::open(path)
+        .and_then(|mut original| {
+            let mut rewritten = File::create(&rewritten_path)?;
+            rewritten.write_all(&StagedSectorFileHeader::new(sector_size).to_bytes())?;
+            io::copy(&mut original, &mut rewritten)?;
+            rewritten.sync_all()
+        })
+        .and_then(|_| fs::rename(&rewritten_path, path))
+        .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
+}
+
+// Migrates the staging sector file identified by access, in place, to the
+// target version of the file format.
+pub fn migrate_staging_sector_file(
+    access: &str,
+    target_version: u16,
+) -> Result<(), SectorManagerErr> {
+    if !is_supported_version(target_version) {
+        return Err(SectorManagerErr::CallerError(format!(
+            "cannot migrate to unknown sector file version {}",
+            target_version
+        )));
+    }
+
+    let mut staged = StagedSectorFile::open(Path::new(access), true)?;
+
+    if staged.header.version == target_version {
+        return Ok(());
+    }
+
+    // The supported versions share a body layout, so only the header changes.
+    let header = StagedSectorFileHeader {
+        version: target_version,
+        sector_size: staged.header.sector_size,
+    };
+
+    staged
+        .file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| staged.file.write_all(&header.to_bytes()))
+        .and_then(|_| staged.file.sync_all())
+        .map_err(|err| SectorManagerErr::ReceiverError(format!("{:?}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = StagedSectorFileHeader::new(1024);
+        let bytes = header.to_bytes();
+
+        assert_eq!(b"FCSS", &bytes[0..4]);
+        assert_eq!(&[0, 0], &bytes[14..16]);
+        assert_eq!(header, StagedSectorFileHeader::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut bytes = StagedSectorFileHeader::new(1024).to_bytes();
+        bytes[4..6].copy_from_slice(&7u16.to_le_bytes());
+
+        match StagedSectorFileHeader::from_bytes(&bytes) {
+            Err(SectorManagerErr::UnknownSectorFileVersion {
+                offset: 4,
+                version: 7,
+            }) => {}
+            x => panic!("expected unknown version, got {:?}", x),
+        }
+
+        bytes[0] = b'X';
+        assert!(StagedSectorFileHeader::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_seeks_within_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("staged");
+
+        let mut staged = StagedSectorFile::create(&path, 1024).unwrap();
+        staged.write_all(b"abcdef").unwrap();
+
+        assert_eq!(6, staged.seek(SeekFrom::End(0)).unwrap());
+        assert_eq!(2, staged.seek(SeekFrom::Start(2)).unwrap());
+        assert!(staged.seek(SeekFrom::Current(-3)).is_err());
+
+        staged.set_len(4).unwrap();
+
+        let mut body = Vec::new();
+        let mut staged = StagedSectorFile::open(&path, false).unwrap();
+        staged.read_to_end(&mut body).unwrap();
+
+        assert_eq!(b"abcd".to_vec(), body);
+        assert_eq!(20, std::fs::metadata(&path).unwrap().len());
+    }
+}
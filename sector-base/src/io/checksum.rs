@@ -0,0 +1,91 @@
+use std::io::{self, Read, Write};
+
+/// forwards writes to the wrapped writer, hashing (BLAKE3) each byte which the wrapped writer
+/// accepts
+pub struct ChecksummingWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> ChecksummingWriter<W> {
+    pub fn new(inner: W) -> ChecksummingWriter<W> {
+        ChecksummingWriter {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// returns the wrapped writer along with the checksum of everything written to it
+    pub fn finalize(self) -> (W, [u8; 32]) {
+        let checksum = *self.hasher.finalize().as_bytes();
+
+        (self.inner, checksum)
+    }
+}
+
+impl<W: Write> Write for ChecksummingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// copies everything read from `source` into `sink`, e.g. so that a checksum of the source may
+/// be computed while it is being consumed
+pub struct TeeReader<'a, R: ?Sized, W> {
+    pub source: &'a mut R,
+    pub sink: W,
+}
+
+impl<'a, R: Read + ?Sized, W: Write> Read for TeeReader<'a, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.source.read(buf)?;
+        self.sink.write_all(&buf[..n])?;
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_hash_of_full_input() {
+        let data: Vec<u8> = (0..10_000).map(|x| (x * 31) as u8).collect();
+
+        let mut writer = ChecksummingWriter::new(Vec::new());
+        for chunk in data.chunks(997) {
+            writer.write_all(chunk).unwrap();
+        }
+
+        let (written, checksum) = writer.finalize();
+
+        assert_eq!(data, written);
+        assert_eq!(*blake3::hash(&data).as_bytes(), checksum);
+    }
+
+    #[test]
+    fn tee_checksums_bytes_read() {
+        let data = vec![7u8; 4096];
+
+        let mut tee = TeeReader {
+            source: &mut &data[..],
+            sink: ChecksummingWriter::new(io::sink()),
+        };
+
+        let mut read = Vec::new();
+        tee.read_to_end(&mut read).unwrap();
+
+        let (_, checksum) = tee.sink.finalize();
+
+        assert_eq!(data, read);
+        assert_eq!(*blake3::hash(&data).as_bytes(), checksum);
+    }
+}
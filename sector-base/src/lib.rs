@@ -2,6 +2,7 @@
 #![allow(clippy::unreadable_literal)]
 
 extern crate bitvec;
+extern crate blake3;
 #[macro_use]
 extern crate failure;
 extern crate ffi_toolkit;